@@ -15,6 +15,7 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use anyhow::{anyhow, Error};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 #[derive(Copy, Clone, Debug)]
 pub struct ByteSize(usize);
@@ -35,6 +36,29 @@ impl From<ByteSize> for usize {
     }
 }
 
+/// Byte sizes are serialized as a number of bytes, and can be deserialized from
+/// either a number of bytes or a string like "256k".
+impl Serialize for ByteSize {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.0 as u64)
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Bytes(usize),
+            Str(String),
+        }
+        match Repr::deserialize(deserializer)? {
+            Repr::Bytes(n) => Ok(ByteSize(n)),
+            Repr::Str(s) => s.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
 impl std::str::FromStr for ByteSize {
     type Err = Error;
 
@@ -105,4 +129,13 @@ mod test {
             assert_eq!(b.num_bytes(), expected);
         }
     }
+
+    #[test]
+    fn can_deserialize_numbers_and_strings() {
+        let b: ByteSize = serde_json::from_str("256000").unwrap();
+        assert_eq!(b.num_bytes(), 256000);
+        let b: ByteSize = serde_json::from_str("\"256k\"").unwrap();
+        assert_eq!(b.num_bytes(), 256000);
+        assert!(serde_json::from_str::<ByteSize>("\"256x\"").is_err());
+    }
 }
@@ -0,0 +1,150 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Load the configuration for the shard and core binaries. Settings are layered, with
+//! later layers taking precedence over earlier ones:
+//!
+//! 1. The defaults.
+//! 2. A TOML config file, whose keys are the names of the command line flags with any
+//!    '-' replaced by '_' (eg `feed_timeout = 20`).
+//! 3. Environment variables and command line flags. Anything left out of these should
+//!    be serialized as `null` (or skipped entirely) so that it doesn't override the above.
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::path::Path;
+
+/// Build a config from the defaults, the config file at `path` (if given) and then the
+/// `overrides`. Unknown keys in the config file are only rejected if `C` denies them.
+pub fn load<C, O>(path: Option<&Path>, overrides: &O) -> anyhow::Result<C>
+where
+    C: Default + Serialize + DeserializeOwned,
+    O: Serialize,
+{
+    let file_config = match path {
+        Some(path) => {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("Cannot read config file {}", path.display()))?;
+            let file_config = parse_toml(&contents)
+                .with_context(|| format!("Cannot parse config file {}", path.display()))?;
+            Some(file_config)
+        }
+        None => None,
+    };
+
+    build(file_config, overrides)
+}
+
+fn build<C, O>(file_config: Option<Value>, overrides: &O) -> anyhow::Result<C>
+where
+    C: Default + Serialize + DeserializeOwned,
+    O: Serialize,
+{
+    let mut config = serde_json::to_value(C::default())?;
+    if let Some(file_config) = file_config {
+        merge(&mut config, file_config);
+    }
+    merge(&mut config, serde_json::to_value(overrides)?);
+
+    serde_json::from_value(config).context("Invalid configuration")
+}
+
+fn parse_toml(contents: &str) -> anyhow::Result<Value> {
+    let toml: toml::Value = toml::from_str(contents)?;
+    Ok(serde_json::to_value(toml)?)
+}
+
+/// Overwrite top level keys in `config` with any non-null values in `layer`.
+fn merge(config: &mut Value, layer: Value) {
+    let (Value::Object(config), Value::Object(layer)) = (config, layer) else {
+        return;
+    };
+    for (key, value) in layer {
+        if !value.is_null() {
+            config.insert(key, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    #[serde(deny_unknown_fields)]
+    struct Config {
+        feed_timeout: u64,
+        listen: String,
+        denylist: Vec<String>,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                feed_timeout: 10,
+                listen: "127.0.0.1:8000".to_owned(),
+                denylist: vec![],
+            }
+        }
+    }
+
+    #[derive(Serialize)]
+    struct Overrides {
+        feed_timeout: Option<u64>,
+        listen: Option<String>,
+    }
+
+    fn load_str(contents: &str, overrides: Overrides) -> anyhow::Result<Config> {
+        build(Some(parse_toml(contents)?), &overrides)
+    }
+
+    #[test]
+    fn later_layers_take_precedence() {
+        let config = load_str(
+            "feed_timeout = 20\ndenylist = [\"Foo\"]\nlisten = \"0.0.0.0:80\"",
+            Overrides {
+                feed_timeout: None,
+                listen: Some("0.0.0.0:90".to_owned()),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                feed_timeout: 20,
+                listen: "0.0.0.0:90".to_owned(),
+                denylist: vec!["Foo".to_owned()],
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_config_files_are_rejected() {
+        let no_overrides = || Overrides {
+            feed_timeout: None,
+            listen: None,
+        };
+        // Unknown keys:
+        assert!(load_str("feed_timout = 20", no_overrides()).is_err());
+        // Values of the wrong type:
+        assert!(load_str("feed_timeout = \"soon\"", no_overrides()).is_err());
+        // Not TOML:
+        assert!(load_str("feed_timeout: 20", no_overrides()).is_err());
+    }
+}
@@ -14,38 +14,205 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::tls::TlsAcceptor;
+use crate::ws_deflate::{DeflateOpts, PerMessageDeflate};
 use futures::io::{BufReader, BufWriter};
 use hyper::server::conn::AddrStream;
 use hyper::{Body, Request, Response, Server};
 use std::future::Future;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
 use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
 
-/// A convenience function to start up a Hyper server and handle requests.
-pub async fn start_server<H, F>(addr: SocketAddr, handler: H) -> Result<(), anyhow::Error>
+/// How long a client has to complete the TLS handshake before we give up on it.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A convenience function to start up a Hyper server and handle requests. Once `shutdown`
+/// resolves, we stop accepting new connections and wait for in-flight requests to complete.
+/// Connections that have been upgraded to websockets are not waited for.
+///
+/// If `tls` is provided, connections are expected to be TLS encrypted, and requests made
+/// over connections on which the client presented a verified certificate can be identified
+/// with [`has_client_certificate`].
+pub async fn start_server<H, F, S>(
+    addr: SocketAddr,
+    tls: Option<TlsAcceptor>,
+    shutdown: S,
+    handler: H,
+) -> Result<(), anyhow::Error>
 where
     H: Clone + Send + Sync + 'static + FnMut(SocketAddr, Request<Body>) -> F,
     F: Send + 'static + Future<Output = Result<Response<Body>, anyhow::Error>>,
+    S: Future<Output = ()> + Send + 'static,
 {
-    let service = hyper::service::make_service_fn(move |addr: &AddrStream| {
+    let tls = match tls {
+        Some(tls) => tls,
+        None => {
+            let service = hyper::service::make_service_fn(move |addr: &AddrStream| {
+                let mut handler = handler.clone();
+                let addr = addr.remote_addr();
+                async move {
+                    Ok::<_, hyper::Error>(hyper::service::service_fn(move |r| handler(addr, r)))
+                }
+            });
+            let server = Server::bind(&addr).serve(service);
+
+            log::info!("listening on http://{}", server.local_addr());
+            server.with_graceful_shutdown(shutdown).await?;
+            return Ok(());
+        }
+    };
+
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    let incoming = hyper::server::accept::from_stream(accept_tls(listener, tls));
+
+    let service = hyper::service::make_service_fn(move |conn: &TlsConnection| {
         let mut handler = handler.clone();
-        let addr = addr.remote_addr();
-        async move { Ok::<_, hyper::Error>(hyper::service::service_fn(move |r| handler(addr, r))) }
+        let addr = conn.remote_addr;
+        let has_client_cert = conn.has_client_cert();
+        async move {
+            Ok::<_, hyper::Error>(hyper::service::service_fn(move |mut r: Request<Body>| {
+                if has_client_cert {
+                    r.extensions_mut().insert(ClientCertificate);
+                }
+                handler(addr, r)
+            }))
+        }
     });
-    let server = Server::bind(&addr).serve(service);
+    let server = Server::builder(incoming).serve(service);
 
-    log::info!("listening on http://{}", server.local_addr());
-    server.await?;
+    log::info!("listening on https://{}", local_addr);
+    server.with_graceful_shutdown(shutdown).await?;
 
     Ok(())
 }
 
+/// Added to the extensions of requests made over TLS connections on which the
+/// client presented a certificate that we verified.
+#[derive(Debug, Clone, Copy)]
+struct ClientCertificate;
+
+/// Was this request made over a TLS connection on which the client presented a
+/// certificate signed by one of the CAs we were configured to trust?
+pub fn has_client_certificate(req: &Request<Body>) -> bool {
+    req.extensions().get::<ClientCertificate>().is_some()
+}
+
+/// Accept TCP connections and perform the TLS handshake on each of them concurrently,
+/// so that one slow client can't hold up others. Handshake failures are logged and
+/// otherwise ignored. The background task ends when the returned stream is dropped.
+fn accept_tls(
+    listener: TcpListener,
+    tls: TlsAcceptor,
+) -> impl futures::Stream<Item = std::io::Result<TlsConnection>> {
+    let (tx, mut rx) = tokio::sync::mpsc::channel(32);
+
+    tokio::spawn(async move {
+        loop {
+            let res = tokio::select! {
+                res = listener.accept() => res,
+                _ = tx.closed() => break,
+            };
+            let (stream, remote_addr) = match res {
+                Ok(conn) => conn,
+                Err(e) => {
+                    // Likely to be something like running out of file descriptors, so
+                    // rather than busy-looping, give things a moment to settle.
+                    log::warn!("Error accepting connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+
+            let tls = tls.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = tx
+                            .send(Ok(TlsConnection {
+                                stream,
+                                remote_addr,
+                            }))
+                            .await;
+                    }
+                    Ok(Err(e)) => log::debug!("TLS handshake with {} failed: {}", remote_addr, e),
+                    Err(_) => log::debug!("TLS handshake with {} timed out", remote_addr),
+                }
+            });
+        }
+    });
+
+    futures::stream::poll_fn(move |cx| rx.poll_recv(cx))
+}
+
+/// A TLS connection that has completed its handshake.
+struct TlsConnection {
+    stream: tokio_rustls::server::TlsStream<TcpStream>,
+    remote_addr: SocketAddr,
+}
+
+impl TlsConnection {
+    fn has_client_cert(&self) -> bool {
+        let (_, conn) = self.stream.get_ref();
+        conn.peer_certificates()
+            .is_some_and(|certs| !certs.is_empty())
+    }
+}
+
+impl AsyncRead for TlsConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
 type WsStream = BufReader<BufWriter<Compat<hyper::upgrade::Upgraded>>>;
 pub type WsSender = soketto::connection::Sender<WsStream>;
 pub type WsReceiver = soketto::connection::Receiver<WsStream>;
 
 /// A convenience function to upgrade a Hyper request into a Soketto Websocket.
 pub fn upgrade_to_websocket<H, F>(req: Request<Body>, on_upgrade: H) -> hyper::Response<Body>
+where
+    H: 'static + Send + FnOnce(WsSender, WsReceiver) -> F,
+    F: Send + Future<Output = ()>,
+{
+    upgrade_to_websocket_with_deflate(req, None, on_upgrade)
+}
+
+/// Like [`upgrade_to_websocket`], but if `deflate` is provided and the client supports it,
+/// messages sent and received on the websocket are compressed using permessage-deflate.
+pub fn upgrade_to_websocket_with_deflate<H, F>(
+    req: Request<Body>,
+    deflate: Option<DeflateOpts>,
+    on_upgrade: H,
+) -> hyper::Response<Body>
 where
     H: 'static + Send + FnOnce(WsSender, WsReceiver) -> F,
     F: Send + Future<Output = ()>,
@@ -80,12 +247,29 @@ where
     let mut accept_key_buf = [0; 32];
     let accept_key = generate_websocket_accept_key(key.as_bytes(), &mut accept_key_buf);
 
+    // Accept the first permessage-deflate offer we can work with, if any:
+    let deflate = deflate.and_then(|opts| {
+        req.headers()
+            .get_all("Sec-WebSocket-Extensions")
+            .iter()
+            .filter_map(|offers| offers.to_str().ok())
+            .find_map(|offers| PerMessageDeflate::negotiate(offers, opts))
+    });
+
     // Tell the client that we accept the upgrade-to-WS request:
-    let response = Response::builder()
+    let mut response = Response::builder()
         .status(hyper::StatusCode::SWITCHING_PROTOCOLS)
         .header(hyper::header::CONNECTION, "upgrade")
         .header(hyper::header::UPGRADE, "websocket")
-        .header("Sec-WebSocket-Accept", accept_key)
+        .header("Sec-WebSocket-Accept", accept_key);
+    let deflate = match deflate {
+        Some((ext, response_header)) => {
+            response = response.header("Sec-WebSocket-Extensions", response_header);
+            Some(ext)
+        }
+        None => None,
+    };
+    let response = response
         .body(Body::empty())
         .expect("bug: failed to build response");
 
@@ -101,8 +285,11 @@ where
         };
 
         // Start a Soketto server with it:
-        let server =
+        let mut server =
             soketto::handshake::Server::new(BufReader::new(BufWriter::new(stream.compat())));
+        if let Some(ext) = deflate {
+            server.add_extension(Box::new(ext));
+        }
 
         // Get hold of a way to send and receive messages:
         let (sender, receiver) = server.into_builder().finish();
@@ -173,3 +360,197 @@ fn header_contains_value(
     }
     false
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tls::{self, ClientTlsOpts, ServerTlsOpts};
+    use crate::ws_client::{self, RecvMessage};
+    use futures::StreamExt;
+    use std::path::{Path, PathBuf};
+
+    /// Write a CA, a server certificate for 'localhost' and a client certificate
+    /// (both signed by the CA) into `dir`, returning the paths to each.
+    fn write_certs(dir: &Path) -> [PathBuf; 5] {
+        use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+
+        let mut ca_params = CertificateParams::new(vec![]);
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(ca_params).unwrap();
+        let server = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let client = rcgen::generate_simple_self_signed(vec!["shard".into()]).unwrap();
+
+        let files = [
+            ("ca.pem", ca.serialize_pem().unwrap()),
+            ("server.pem", server.serialize_pem_with_signer(&ca).unwrap()),
+            ("server.key", server.serialize_private_key_pem()),
+            ("client.pem", client.serialize_pem_with_signer(&ca).unwrap()),
+            ("client.key", client.serialize_private_key_pem()),
+        ];
+        files.map(|(name, contents)| {
+            let path = dir.join(name);
+            std::fs::write(&path, contents).unwrap();
+            path
+        })
+    }
+
+    async fn recv_text(uri: &http::Uri, opts: &ClientTlsOpts) -> String {
+        let config = tls::client_config(opts).unwrap();
+        let (_tx, mut rx) = ws_client::connect_with_tls(uri, Some(config))
+            .await
+            .unwrap()
+            .into_channels();
+        match rx.next().await {
+            Some(Ok(RecvMessage::Text(text))) => text,
+            other => panic!("expected a text message, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn client_certificates_are_verified_over_tls() {
+        let dir = std::env::temp_dir().join(format!("telemetry-http-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let [ca, server_cert, server_key, client_cert, client_key] = write_certs(&dir);
+
+        let tls = TlsAcceptor::new(ServerTlsOpts {
+            cert: server_cert,
+            key: server_key,
+            client_ca: Some(ca.clone()),
+            reload_interval: Duration::from_secs(60),
+        })
+        .unwrap();
+
+        // Find a free port to listen on:
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (tx_shutdown, rx_shutdown) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(start_server(
+            addr,
+            Some(tls),
+            async move {
+                let _ = rx_shutdown.await;
+            },
+            |_addr, req| async move {
+                let has_cert = has_client_certificate(&req);
+                Ok(upgrade_to_websocket(req, move |mut tx, _rx| async move {
+                    let msg = if has_cert { "cert" } else { "no cert" };
+                    let _ = tx.send_text(msg).await;
+                    let _ = tx.flush().await;
+                }))
+            },
+        ));
+
+        let uri: http::Uri = format!("wss://localhost:{}/", addr.port()).parse().unwrap();
+        let with_cert = ClientTlsOpts {
+            ca: Some(ca.clone()),
+            cert: Some(client_cert),
+            key: Some(client_key),
+        };
+        let without_cert = ClientTlsOpts {
+            ca: Some(ca),
+            ..Default::default()
+        };
+
+        // Wait for the server to start listening:
+        let mut attempts = 0;
+        while tokio::net::TcpStream::connect(addr).await.is_err() {
+            attempts += 1;
+            assert!(attempts < 50, "server didn't start listening");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        assert_eq!(recv_text(&uri, &with_cert).await, "cert");
+        assert_eq!(recv_text(&uri, &without_cert).await, "no cert");
+
+        // Without trusting our CA, the client can't verify the server:
+        let untrusted = ws_client::connect_with_tls(&uri, None).await;
+        assert!(untrusted.is_err());
+
+        let _ = tx_shutdown.send(());
+        server.await.unwrap().unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn feed_messages_can_be_deflated() {
+        use soketto::extension::{deflate::Deflate, Extension};
+
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let message = r#"[1,{"name":"node","version":"1.0.0"}]"#.repeat(500);
+        let (tx_shutdown, rx_shutdown) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(start_server(
+            addr,
+            None,
+            async move {
+                let _ = rx_shutdown.await;
+            },
+            {
+                let message = message.clone();
+                move |_addr, req| {
+                    let message = message.clone();
+                    async move {
+                        let deflate = DeflateOpts {
+                            level: 6,
+                            threshold: 1024,
+                        };
+                        Ok(upgrade_to_websocket_with_deflate(
+                            req,
+                            Some(deflate),
+                            move |mut tx, mut rx| async move {
+                                // Echo back whatever we're sent, and then a big message:
+                                let mut data = Vec::new();
+                                rx.receive_data(&mut data).await.unwrap();
+                                tx.send_binary(&data).await.unwrap();
+                                tx.send_text(&message).await.unwrap();
+                                tx.flush().await.unwrap();
+                            },
+                        ))
+                    }
+                }
+            },
+        ));
+
+        let mut attempts = 0;
+        let socket = loop {
+            match tokio::net::TcpStream::connect(addr).await {
+                Ok(socket) => break socket,
+                Err(_) if attempts < 50 => attempts += 1,
+                Err(e) => panic!("server didn't start listening: {e}"),
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        };
+
+        // Soketto's own deflate extension should be able to talk to ours:
+        let mut client = soketto::handshake::Client::new(socket.compat(), "localhost", "/");
+        client.add_extension(Box::new(Deflate::new(soketto::Mode::Client)));
+        let res = client.handshake().await.unwrap();
+        assert!(matches!(
+            res,
+            soketto::handshake::ServerResponse::Accepted { .. }
+        ));
+        let extensions: Vec<_> = client.drain_extensions().collect();
+        assert!(extensions.iter().all(|e| e.is_enabled()));
+        let mut builder = client.into_builder();
+        builder.add_extensions(extensions);
+        let (mut tx, mut rx) = builder.finish();
+
+        tx.send_text("subscribe:0x1234").await.unwrap();
+        tx.flush().await.unwrap();
+
+        let mut data = Vec::new();
+        rx.receive_data(&mut data).await.unwrap();
+        assert_eq!(data, b"subscribe:0x1234");
+
+        data.clear();
+        rx.receive_data(&mut data).await.unwrap();
+        assert_eq!(data, message.as_bytes());
+
+        let _ = tx_shutdown.send(());
+        server.await.unwrap().unwrap();
+    }
+}
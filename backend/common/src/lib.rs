@@ -15,21 +15,27 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 pub mod byte_size;
+pub mod config;
 pub mod http_utils;
 pub mod id_type;
 pub mod internal_messages;
+pub mod logging;
 pub mod node_message;
 pub mod node_types;
 pub mod ready_chunks_all;
 pub mod rolling_total;
+pub mod shutdown;
 pub mod time;
+pub mod tls;
 pub mod ws_client;
+pub mod ws_deflate;
 
 mod assign_id;
 mod dense_map;
 mod either_sink;
 mod mean_list;
 mod most_seen;
+mod multi_map;
 mod multi_map_unique;
 mod num_stats;
 
@@ -37,7 +43,8 @@ mod num_stats;
 pub use assign_id::AssignId;
 pub use dense_map::DenseMap;
 pub use either_sink::EitherSink;
-pub use mean_list::MeanList;
+pub use mean_list::{MeanList, MeanListChange};
 pub use most_seen::MostSeen;
+pub use multi_map::MultiMap;
 pub use multi_map_unique::MultiMapUnique;
 pub use num_stats::NumStats;
@@ -0,0 +1,282 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Logger setup shared by the shard and core binaries. Logs are either written as
+//! human readable lines (the default), or as one JSON object per line so that log
+//! pipelines can ingest them without having to parse the message text. In JSON mode,
+//! any key-values attached to a log line (eg `log::info!(conn_id = 1; "...")`) are
+//! written out as top level fields alongside the message.
+
+use log::kv::{self, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+/// How should log lines be written out?
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow::anyhow!(
+                "Expecting a log format of 'text' or 'json'"
+            )),
+        }
+    }
+}
+
+/// Override the log level for any log target beginning with the given module
+/// path. This is parsed from strings like `soketto=warn`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleLevel {
+    pub module: String,
+    pub level: LevelFilter,
+}
+
+impl FromStr for ModuleLevel {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (module, level) = match s.split_once('=') {
+            Some((module, level)) => (module.trim(), level.trim()),
+            None => return Err(anyhow::anyhow!("Expecting format `MODULE=LEVEL`")),
+        };
+        if module.is_empty() {
+            return Err(anyhow::anyhow!("Expecting a module name before the '='"));
+        }
+        Ok(ModuleLevel {
+            module: module.to_owned(),
+            level: level.parse()?,
+        })
+    }
+}
+
+impl fmt::Display for ModuleLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}={}",
+            self.module,
+            self.level.as_str().to_ascii_lowercase()
+        )
+    }
+}
+
+impl Serialize for ModuleLevel {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ModuleLevel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Install a global logger which logs at the level given, unless a more
+/// specific level has been provided for the module that a log line comes from.
+pub fn init(
+    level: LevelFilter,
+    format: LogFormat,
+    module_levels: &[ModuleLevel],
+) -> Result<(), SetLoggerError> {
+    match format {
+        LogFormat::Text => {
+            let mut logger = simple_logger::SimpleLogger::new().with_level(level);
+            for m in module_levels {
+                logger = logger.with_module_level(&m.module, m.level);
+            }
+            logger.init()
+        }
+        LogFormat::Json => {
+            let logger = JsonLogger::new(level, module_levels);
+            log::set_max_level(logger.max_level());
+            log::set_boxed_logger(Box::new(logger))
+        }
+    }
+}
+
+/// A logger which writes each log line out as a JSON object on stdout.
+struct JsonLogger {
+    default_level: LevelFilter,
+    /// Sorted so that the most specific (longest) module paths come first.
+    module_levels: Vec<ModuleLevel>,
+}
+
+impl JsonLogger {
+    fn new(default_level: LevelFilter, module_levels: &[ModuleLevel]) -> Self {
+        let mut module_levels = module_levels.to_vec();
+        module_levels.sort_by_key(|m| std::cmp::Reverse(m.module.len()));
+        JsonLogger {
+            default_level,
+            module_levels,
+        }
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.module_levels
+            .iter()
+            .map(|m| m.level)
+            .fold(self.default_level, std::cmp::max)
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let level = self
+            .module_levels
+            .iter()
+            .find(|m| metadata.target().starts_with(&m.module))
+            .map(|m| m.level)
+            .unwrap_or(self.default_level);
+        metadata.level() <= level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = json_line(crate::time::now(), record);
+        let _ = writeln!(std::io::stdout().lock(), "{line}");
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+/// Turn a log record into a single line JSON object.
+fn json_line(timestamp_unix_ms: u64, record: &Record) -> String {
+    let mut fields = Fields(Map::new());
+    fields.0.insert("ts".into(), timestamp_unix_ms.into());
+    fields
+        .0
+        .insert("level".into(), record.level().as_str().into());
+    fields.0.insert("target".into(), record.target().into());
+    fields
+        .0
+        .insert("msg".into(), record.args().to_string().into());
+    let _ = record.key_values().visit(&mut fields);
+    Value::Object(fields.0).to_string()
+}
+
+/// Collects structured key-values from a log line into a JSON map.
+struct Fields(Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(n) = value.to_u64() {
+            n.into()
+        } else if let Some(n) = value.to_i64() {
+            n.into()
+        } else if let Some(b) = value.to_bool() {
+            b.into()
+        } else if let Some(n) = value.to_f64() {
+            n.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.as_str().to_owned(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn module_levels_can_be_parsed() {
+        let m: ModuleLevel = "soketto=warn".parse().unwrap();
+        assert_eq!(m.module, "soketto");
+        assert_eq!(m.level, LevelFilter::Warn);
+
+        let m: ModuleLevel = " telemetry_core::aggregator = DEBUG ".parse().unwrap();
+        assert_eq!(m.module, "telemetry_core::aggregator");
+        assert_eq!(m.level, LevelFilter::Debug);
+
+        assert!("soketto".parse::<ModuleLevel>().is_err());
+        assert!("=warn".parse::<ModuleLevel>().is_err());
+        assert!("soketto=loud".parse::<ModuleLevel>().is_err());
+    }
+
+    #[test]
+    fn most_specific_module_level_wins() {
+        let logger = JsonLogger::new(
+            LevelFilter::Info,
+            &[
+                "telemetry_core=warn".parse().unwrap(),
+                "telemetry_core::aggregator=debug".parse().unwrap(),
+            ],
+        );
+        let enabled = |target: &str, level: log::Level| {
+            logger.enabled(&Metadata::builder().target(target).level(level).build())
+        };
+
+        assert!(enabled(
+            "telemetry_core::aggregator::inner_loop",
+            log::Level::Debug
+        ));
+        assert!(!enabled("telemetry_core::state", log::Level::Info));
+        assert!(enabled("soketto", log::Level::Info));
+        assert!(!enabled("soketto", log::Level::Debug));
+        assert_eq!(logger.max_level(), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn json_lines_include_key_values() {
+        let kvs: &[(&str, kv::Value)] = &[
+            ("conn_id", kv::Value::from(12u64)),
+            ("ip", kv::Value::from("127.0.0.1")),
+        ];
+        let line = json_line(
+            1000,
+            &Record::builder()
+                .args(format_args!("Hello {}", "there"))
+                .level(log::Level::Warn)
+                .target("telemetry_shard")
+                .key_values(&kvs)
+                .build(),
+        );
+
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "ts": 1000,
+                "level": "WARN",
+                "target": "telemetry_shard",
+                "msg": "Hello there",
+                "conn_id": 12,
+                "ip": "127.0.0.1",
+            })
+        );
+    }
+}
@@ -15,8 +15,29 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use num_traits::{Float, Zero};
+use serde::{Deserialize, Serialize};
 use std::ops::AddAssign;
 
+/// How the means in a [`MeanList`] changed as a result of [`MeanList::push`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeanListChange {
+    /// The means are as they were.
+    Unchanged,
+    /// A new mean was added to the end.
+    Appended,
+    /// The first mean was dropped, and a new mean was added to the end.
+    Shifted,
+    /// Adjacent pairs of means were averaged, halving the number of them.
+    Squashed,
+}
+
+impl MeanListChange {
+    pub fn is_changed(self) -> bool {
+        self != MeanListChange::Unchanged
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct MeanList<T>
 where
     T: Float + AddAssign + Zero + From<u8>,
@@ -51,8 +72,25 @@ where
         &self.means[..usize::from(self.mean_index)]
     }
 
-    pub fn push(&mut self, val: T) -> bool {
-        if self.mean_index == 20 && self.ticks_per_mean < 32 {
+    /// Describe a change returned from [`MeanList::push`] as the number of means to drop
+    /// from the start of a copy of the previous means, followed by the means to append,
+    /// such that the copy then matches [`MeanList::slice`].
+    pub fn delta(&self, change: MeanListChange) -> (u8, &[T]) {
+        let means = self.slice();
+        match change {
+            MeanListChange::Unchanged => (0, &[]),
+            MeanListChange::Appended => (0, &means[means.len() - 1..]),
+            MeanListChange::Shifted => (1, &means[means.len() - 1..]),
+            // We only squash full lists, so drop every mean and send the new ones:
+            MeanListChange::Squashed => (20, means),
+        }
+    }
+
+    pub fn push(&mut self, val: T) -> MeanListChange {
+        // A squash happens before a full period of values has been gathered (a period is
+        // at least two values long after squashing), so it's never combined with a new mean.
+        let squashed = self.mean_index == 20 && self.ticks_per_mean < 32;
+        if squashed {
             self.squash_means();
         }
 
@@ -60,26 +98,30 @@ where
         self.period_count += 1;
 
         if self.period_count == self.ticks_per_mean {
-            self.push_mean();
-            true
+            self.push_mean()
+        } else if squashed {
+            MeanListChange::Squashed
         } else {
-            false
+            MeanListChange::Unchanged
         }
     }
 
-    fn push_mean(&mut self) {
+    fn push_mean(&mut self) -> MeanListChange {
         let mean = self.period_sum / std::convert::From::from(self.period_count);
 
-        if self.mean_index == 20 && self.ticks_per_mean == 32 {
+        let change = if self.mean_index == 20 && self.ticks_per_mean == 32 {
             self.means.rotate_left(1);
             self.means[19] = mean;
+            MeanListChange::Shifted
         } else {
             self.means[usize::from(self.mean_index)] = mean;
             self.mean_index += 1;
-        }
+            MeanListChange::Appended
+        };
 
         self.period_sum = T::zero();
         self.period_count = 0;
+        change
     }
 
     fn squash_means(&mut self) {
@@ -93,3 +135,25 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn changes_describe_how_the_means_were_updated() {
+        let mut list = MeanList::<f64>::default();
+        let mut copy: Vec<f64> = Vec::new();
+
+        for n in 0..2000 {
+            let change = list.push(f64::from(n));
+            let (drop, append) = list.delta(change);
+            copy.drain(..usize::from(drop));
+            copy.extend_from_slice(append);
+            assert_eq!(copy, list.slice());
+        }
+        // We've reached the steady state where each new mean shifts the others along:
+        assert_eq!(list.ticks_per_mean, 32);
+        assert_eq!(list.slice().len(), 20);
+    }
+}
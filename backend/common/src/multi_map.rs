@@ -0,0 +1,182 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+/// A map where each key can contain multiple values, and each value can
+/// belong to multiple keys. Unlike [`crate::MultiMapUnique`], inserting a
+/// value against one key leaves it in place against any others.
+pub struct MultiMap<K, V> {
+    value_to_keys: HashMap<V, HashSet<K>>,
+    key_to_values: HashMap<K, HashSet<V>>,
+}
+
+impl<K, V> Default for MultiMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> MultiMap<K, V> {
+    /// Construct a new MultiMap
+    pub fn new() -> Self {
+        Self {
+            value_to_keys: HashMap::new(),
+            key_to_values: HashMap::new(),
+        }
+    }
+
+    /// Return the set of values associated with a key.
+    pub fn get_values(&self, key: &K) -> Option<&HashSet<V>>
+    where
+        K: Eq + Hash,
+    {
+        self.key_to_values.get(key)
+    }
+
+    /// Return the set of keys that a value has been inserted against.
+    pub fn get_keys(&self, value: &V) -> Option<&HashSet<K>>
+    where
+        V: Eq + Hash,
+    {
+        self.value_to_keys.get(value)
+    }
+
+    /// Insert a key+value pair into the multimap. Returns false if the
+    /// pair already existed.
+    ///
+    /// ```
+    /// let mut m = common::MultiMap::new();
+    ///
+    /// assert!(m.insert("a", 1));
+    /// assert!(m.insert("b", 1));
+    /// assert!(!m.insert("b", 1));
+    ///
+    /// assert_eq!(m.num_keys(), 2);
+    /// assert_eq!(m.num_values(), 1);
+    /// ```
+    pub fn insert(&mut self, key: K, value: V) -> bool
+    where
+        V: Clone + Eq + Hash,
+        K: Clone + Eq + Hash,
+    {
+        self.value_to_keys
+            .entry(value.clone())
+            .or_default()
+            .insert(key.clone());
+        self.key_to_values.entry(key).or_default().insert(value)
+    }
+
+    /// Remove a single key+value pair from the multimap, returning true if
+    /// it was found.
+    ///
+    /// ```
+    /// let mut m = common::MultiMap::new();
+    ///
+    /// m.insert("a", 1);
+    /// m.insert("b", 1);
+    ///
+    /// assert!(m.remove(&"a", &1));
+    /// assert!(!m.remove(&"a", &1));
+    ///
+    /// assert_eq!(m.num_keys(), 1);
+    /// assert_eq!(m.num_values(), 1);
+    /// ```
+    pub fn remove(&mut self, key: &K, value: &V) -> bool
+    where
+        V: Eq + Hash,
+        K: Eq + Hash,
+    {
+        let removed = match self.key_to_values.get_mut(key) {
+            Some(values) => {
+                let removed = values.remove(value);
+                if values.is_empty() {
+                    self.key_to_values.remove(key);
+                }
+                removed
+            }
+            None => false,
+        };
+        if let Some(keys) = self.value_to_keys.get_mut(value) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.value_to_keys.remove(value);
+            }
+        }
+        removed
+    }
+
+    /// Remove a value from the MultiMap, returning the keys it was found
+    /// under.
+    pub fn remove_value(&mut self, value: &V) -> HashSet<K>
+    where
+        V: Eq + Hash,
+        K: Eq + Hash,
+    {
+        let keys = self.value_to_keys.remove(value).unwrap_or_default();
+        for key in &keys {
+            if let Some(values) = self.key_to_values.get_mut(key) {
+                values.remove(value);
+                if values.is_empty() {
+                    self.key_to_values.remove(key);
+                }
+            }
+        }
+        keys
+    }
+
+    /// Iterate over the distinct values stored in the map
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.value_to_keys.keys()
+    }
+
+    /// Number of distinct values stored in the map
+    pub fn num_values(&self) -> usize {
+        self.value_to_keys.len()
+    }
+
+    /// Number of keys stored in the map
+    pub fn num_keys(&self) -> usize {
+        self.key_to_values.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn values_can_belong_to_several_keys() {
+        let mut m = MultiMap::new();
+
+        m.insert("a", 1);
+        m.insert("a", 2);
+        m.insert("b", 1);
+
+        assert_eq!(m.num_keys(), 2);
+        assert_eq!(m.num_values(), 2);
+        assert_eq!(m.get_keys(&1).unwrap().len(), 2);
+
+        let removed = m.remove_value(&1);
+        assert_eq!(removed, HashSet::from(["a", "b"]));
+        assert_eq!(m.num_keys(), 1);
+        assert_eq!(m.num_values(), 1);
+        assert!(m.get_values(&"b").is_none());
+        assert!(m.get_keys(&1).is_none());
+    }
+}
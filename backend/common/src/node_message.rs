@@ -136,6 +136,10 @@ pub struct Finalized {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AfgAuthoritySet {
     pub authority_id: Box<str>,
+    /// The ID of the authority set that the node is in, if it told us.
+    pub authority_set_id: Option<u64>,
+    /// Every authority in the set, if the node told us.
+    pub authorities: Option<Vec<Box<str>>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -168,6 +172,48 @@ impl Payload {
             _ => None,
         }
     }
+
+    /// How important the message is, should we be too busy to handle every message.
+    pub fn priority(&self) -> Priority {
+        match self {
+            Payload::SystemConnected(_) | Payload::BlockImport(_) | Payload::NotifyFinalized(_) => {
+                Priority::High
+            }
+            Payload::SystemInterval(interval) => {
+                if interval.block.is_some() || interval.finalized_hash.is_some() {
+                    Priority::Normal
+                } else {
+                    Priority::Low
+                }
+            }
+            Payload::AfgAuthoritySet(_) | Payload::HwBench(_) => Priority::Normal,
+            Payload::BlockMetric(_) => Priority::Low,
+        }
+    }
+}
+
+/// How important a message from a node is. When we're too busy to handle every message,
+/// those with a lower priority are dropped first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Samples which the next one replaces, such as bandwidth and peer counts.
+    Low,
+    /// Messages which carry block details alongside samples, or which are sent rarely.
+    Normal,
+    /// New blocks and finality.
+    High,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::Low, Priority::Normal, Priority::High];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
 }
 
 #[cfg(test)]
@@ -252,6 +298,8 @@ mod tests {
         bincode_can_serialize_and_deserialize(NodeMessage::V1 {
             payload: Payload::AfgAuthoritySet(AfgAuthoritySet {
                 authority_id: "foo".into(),
+                authority_set_id: Some(3),
+                authorities: Some(vec!["foo".into(), "bar".into()]),
             }),
         });
     }
@@ -267,4 +315,44 @@ mod tests {
         assert_eq!(raw.hash, deserialized.hash);
         assert_eq!(raw.height, deserialized.height);
     }
+
+    #[test]
+    fn block_and_finality_updates_have_priority_over_samples() {
+        let interval = SystemInterval {
+            peers: Some(1),
+            txcount: None,
+            bandwidth_upload: Some(1.0),
+            bandwidth_download: Some(1.0),
+            finalized_height: None,
+            finalized_hash: None,
+            block: None,
+            used_state_cache_size: None,
+        };
+        assert_eq!(
+            Payload::SystemInterval(interval.clone()).priority(),
+            Priority::Low
+        );
+
+        let interval_with_block = SystemInterval {
+            block: Some(Block::zero()),
+            ..interval
+        };
+        assert_eq!(
+            Payload::SystemInterval(interval_with_block).priority(),
+            Priority::Normal
+        );
+
+        assert_eq!(
+            Payload::BlockImport(Block::zero()).priority(),
+            Priority::High
+        );
+        assert_eq!(
+            Payload::NotifyFinalized(Finalized {
+                hash: BlockHash::zero(),
+                height: "1".into(),
+            })
+            .priority(),
+            Priority::High
+        );
+    }
 }
@@ -178,6 +178,8 @@ pub struct NodeLocation {
     pub latitude: f32,
     pub longitude: f32,
     pub city: Box<str>,
+    /// The ISO code of the country, if we know it.
+    pub country: Option<Box<str>>,
 }
 
 impl Serialize for NodeLocation {
@@ -185,10 +187,15 @@ impl Serialize for NodeLocation {
     where
         S: Serializer,
     {
-        let mut tup = serializer.serialize_tuple(3)?;
+        // The country is left off the end if we don't know it:
+        let len = if self.country.is_some() { 4 } else { 3 };
+        let mut tup = serializer.serialize_tuple(len)?;
         tup.serialize_element(&self.latitude)?;
         tup.serialize_element(&self.longitude)?;
         tup.serialize_element(&&*self.city)?;
+        if let Some(country) = &self.country {
+            tup.serialize_element(&**country)?;
+        }
         tup.end()
     }
 }
@@ -198,12 +205,28 @@ impl<'de> Deserialize<'de> for NodeLocation {
     where
         D: serde::Deserializer<'de>,
     {
-        let (latitude, longitude, city) = <(f32, f32, Box<str>)>::deserialize(deserializer)?;
-        Ok(NodeLocation {
-            latitude,
-            longitude,
-            city,
-        })
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            WithCountry(f32, f32, Box<str>, Box<str>),
+            WithoutCountry(f32, f32, Box<str>),
+        }
+
+        let location = match Repr::deserialize(deserializer)? {
+            Repr::WithCountry(latitude, longitude, city, country) => NodeLocation {
+                latitude,
+                longitude,
+                city,
+                country: Some(country),
+            },
+            Repr::WithoutCountry(latitude, longitude, city) => NodeLocation {
+                latitude,
+                longitude,
+                city,
+                country: None,
+            },
+        };
+        Ok(location)
     }
 }
 
@@ -259,3 +282,36 @@ impl<'de> Deserialize<'de> for BlockDetails {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn node_location_country_is_optional() {
+        let location = NodeLocation {
+            latitude: 1.5,
+            longitude: 2.5,
+            city: "Berlin".into(),
+            country: Some("DE".into()),
+        };
+        let json = serde_json::to_string(&location).unwrap();
+        assert_eq!(json, r#"[1.5,2.5,"Berlin","DE"]"#);
+        assert_eq!(
+            serde_json::from_str::<NodeLocation>(&json).unwrap(),
+            location
+        );
+
+        // Locations without a country (such as those in older snapshots) are fine too:
+        let location = NodeLocation {
+            country: None,
+            ..location
+        };
+        let json = serde_json::to_string(&location).unwrap();
+        assert_eq!(json, r#"[1.5,2.5,"Berlin"]"#);
+        assert_eq!(
+            serde_json::from_str::<NodeLocation>(&json).unwrap(),
+            location
+        );
+    }
+}
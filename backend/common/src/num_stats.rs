@@ -65,6 +65,13 @@ impl<T: NumOps + Zero + Bounded + Copy + Sum + TryFrom<usize>> NumStats<T> {
             *val = T::zero();
         }
     }
+
+    /// Iterate over the numbers currently being averaged, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let len = self.stack.len();
+        let start = self.index.saturating_sub(len);
+        (start..self.index).map(move |idx| &self.stack[idx % len])
+    }
 }
 
 #[cfg(test)]
@@ -117,4 +124,17 @@ mod tests {
 
         assert_eq!(stats.average(), 5);
     }
+
+    #[test]
+    fn iterates_oldest_first() {
+        let mut stats: NumStats<u64> = NumStats::new(3);
+
+        stats.push(1);
+        stats.push(2);
+        assert_eq!(stats.iter().copied().collect::<Vec<_>>(), vec![1, 2]);
+
+        stats.push(3);
+        stats.push(4);
+        assert_eq!(stats.iter().copied().collect::<Vec<_>>(), vec![2, 3, 4]);
+    }
 }
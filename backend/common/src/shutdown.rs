@@ -0,0 +1,150 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Helpers to shut down gracefully: stop accepting new connections, ask the
+//! existing ones to wrap up, and wait (for a while) for them to do so.
+
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// Resolves when the process is asked to shut down, either via SIGTERM (as sent by
+/// Kubernetes and friends when stopping a pod) or via SIGINT (ie ctrl-c).
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm = signal(SignalKind::terminate()).expect("can listen for SIGTERM");
+        tokio::select! {
+            _ = sigterm.recv() => log::info!("Received SIGTERM"),
+            _ = tokio::signal::ctrl_c() => log::info!("Received SIGINT"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        log::info!("Received ctrl-c");
+    }
+}
+
+/// Hands out [`ShutdownListener`]s to anything that should be given the chance
+/// to wrap up before we exit, and lets us tell them when to do so.
+pub struct Shutdown {
+    token: CancellationToken,
+    // Each listener holds a copy of this sender; once they're all dropped, the
+    // receiver below is closed, and so we know that every listener has finished.
+    tx_done: mpsc::Sender<()>,
+    rx_done: mpsc::Receiver<()>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (tx_done, rx_done) = mpsc::channel(1);
+        Shutdown {
+            token: CancellationToken::new(),
+            tx_done,
+            rx_done,
+        }
+    }
+
+    /// Hand out a listener. We'll wait for this (and every clone of it) to be
+    /// dropped when we shut down.
+    pub fn listener(&self) -> ShutdownListener {
+        ShutdownListener {
+            token: self.token.clone(),
+            _tx_done: self.tx_done.clone(),
+        }
+    }
+
+    /// A future which resolves as soon as we begin shutting down. Unlike a
+    /// [`ShutdownListener`], holding this won't hold up the shutdown.
+    pub fn started(&self) -> impl Future<Output = ()> + Send + 'static {
+        let token = self.token.clone();
+        async move { token.cancelled().await }
+    }
+
+    /// Tell every listener to wrap up, and wait for them all to finish, or for
+    /// the deadline to pass. Returns `false` if the deadline was hit.
+    pub async fn shutdown(self, deadline: Duration) -> bool {
+        let Shutdown {
+            token,
+            tx_done,
+            mut rx_done,
+        } = self;
+
+        token.cancel();
+        drop(tx_done);
+        tokio::time::timeout(deadline, rx_done.recv()).await.is_ok()
+    }
+}
+
+/// Connection handlers and the like hold one of these to be told when
+/// we're shutting down. Shutdown waits until every listener is dropped.
+#[derive(Clone)]
+pub struct ShutdownListener {
+    token: CancellationToken,
+    _tx_done: mpsc::Sender<()>,
+}
+
+impl ShutdownListener {
+    /// Resolves once we've been asked to shut down.
+    pub async fn recv(&self) {
+        self.token.cancelled().await
+    }
+
+    /// Have we been asked to shut down?
+    pub fn is_shutdown(&self) -> bool {
+        self.token.is_cancelled()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn shutdown_waits_for_listeners() {
+        let shutdown = Shutdown::new();
+        let listener = shutdown.listener();
+        let started = shutdown.started();
+
+        let handle = tokio::spawn(async move {
+            listener.recv().await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(listener);
+        });
+
+        assert!(shutdown.shutdown(Duration::from_secs(5)).await);
+        started.await;
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_gives_up_after_deadline() {
+        let shutdown = Shutdown::new();
+        let listener = shutdown.listener();
+
+        assert!(!shutdown.shutdown(Duration::from_millis(100)).await);
+        assert!(listener.is_shutdown());
+    }
+}
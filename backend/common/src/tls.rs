@@ -0,0 +1,330 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Helpers for terminating TLS connections in our servers, and for configuring
+//! the TLS used when connecting out to them.
+
+use anyhow::{anyhow, Context};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::rustls::{
+    self,
+    server::{AllowAnyAnonymousOrAuthenticatedClient, ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    Certificate, OwnedTrustAnchor, PrivateKey, RootCertStore,
+};
+
+pub use tokio_rustls::rustls::ClientConfig;
+
+/// How a server should terminate TLS.
+#[derive(Debug, Clone)]
+pub struct ServerTlsOpts {
+    /// A PEM file containing the certificate chain to present, leaf first.
+    pub cert: PathBuf,
+    /// A PEM file containing the private key for the certificate.
+    pub key: PathBuf,
+    /// If provided, clients may present a certificate signed by one of the CAs in
+    /// this PEM file. Clients that don't present one are still allowed to connect;
+    /// see [`crate::http_utils::has_client_certificate`] to check per request.
+    pub client_ca: Option<PathBuf>,
+    /// How often to check the certificate and key files for changes.
+    pub reload_interval: Duration,
+}
+
+/// Accepts TLS connections using a certificate that is reloaded from disk
+/// whenever the files backing it change.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    inner: tokio_rustls::TlsAcceptor,
+}
+
+impl TlsAcceptor {
+    /// Load the certificate and key, and spawn a task which reloads them when they
+    /// change on disk. The task ends once every clone of the acceptor is dropped.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if not called within the context of a tokio runtime.
+    pub fn new(opts: ServerTlsOpts) -> anyhow::Result<TlsAcceptor> {
+        let resolver = Arc::new(ReloadingCertResolver::new(opts.cert, opts.key)?);
+
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+        let builder = match &opts.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(client_ca)? {
+                    roots
+                        .add(&cert)
+                        .with_context(|| format!("Invalid CA certificate in {client_ca:?}"))?;
+                }
+                builder
+                    .with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder.with_cert_resolver(resolver.clone());
+
+        tokio::spawn(reload_loop(Arc::downgrade(&resolver), opts.reload_interval));
+
+        Ok(TlsAcceptor {
+            inner: tokio_rustls::TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    /// Perform the TLS handshake on the stream given.
+    pub async fn accept<IO>(
+        &self,
+        stream: IO,
+    ) -> std::io::Result<tokio_rustls::server::TlsStream<IO>>
+    where
+        IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        self.inner.accept(stream).await
+    }
+}
+
+/// Hands out whichever certificate was most recently loaded from disk.
+struct ReloadingCertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<LoadedCert>,
+}
+
+struct LoadedCert {
+    key: Arc<CertifiedKey>,
+    modified: Option<(SystemTime, SystemTime)>,
+}
+
+impl ReloadingCertResolver {
+    fn new(cert_path: PathBuf, key_path: PathBuf) -> anyhow::Result<Self> {
+        let modified = modified_times(&cert_path, &key_path);
+        let key = load_certified_key(&cert_path, &key_path)?;
+        Ok(ReloadingCertResolver {
+            cert_path,
+            key_path,
+            current: RwLock::new(LoadedCert {
+                key: Arc::new(key),
+                modified,
+            }),
+        })
+    }
+
+    /// Reload the certificate if the files backing it have changed. Returns
+    /// true if a new certificate was loaded.
+    fn reload_if_changed(&self) -> anyhow::Result<bool> {
+        let modified = modified_times(&self.cert_path, &self.key_path);
+        if modified.is_none() || modified == self.current.read().unwrap().modified {
+            return Ok(false);
+        }
+
+        let key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = LoadedCert {
+            key: Arc::new(key),
+            modified,
+        };
+        Ok(true)
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().key.clone())
+    }
+}
+
+async fn reload_loop(resolver: Weak<ReloadingCertResolver>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let resolver = match resolver.upgrade() {
+            Some(resolver) => resolver,
+            None => break,
+        };
+        match resolver.reload_if_changed() {
+            Ok(true) => log::info!("Reloaded TLS certificate from {:?}", resolver.cert_path),
+            Ok(false) => {}
+            // Files are often replaced non-atomically, so we keep serving the old
+            // certificate and try again next time around.
+            Err(e) => log::warn!("Failed to reload TLS certificate: {e:#}"),
+        }
+    }
+}
+
+fn modified_times(cert_path: &Path, key_path: &Path) -> Option<(SystemTime, SystemTime)> {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    Some((modified(cert_path)?, modified(key_path)?))
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> anyhow::Result<CertifiedKey> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+    let key = rustls::sign::any_supported_type(&key)
+        .map_err(|_| anyhow!("Unsupported private key type in {key_path:?}"))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+fn load_certs(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Cannot open {path:?}"))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("Cannot read certificates from {path:?}"))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates found in {path:?}"));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    use rustls_pemfile::Item;
+
+    let file = std::fs::File::open(path).with_context(|| format!("Cannot open {path:?}"))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Cannot read private key from {path:?}"))?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("No private key found in {path:?}"))
+}
+
+/// How to configure TLS when connecting to a server.
+#[derive(Debug, Clone, Default)]
+pub struct ClientTlsOpts {
+    /// A PEM file of CA certificates to trust in addition to the usual web roots.
+    pub ca: Option<PathBuf>,
+    /// A PEM file containing a certificate chain to present to the server.
+    pub cert: Option<PathBuf>,
+    /// A PEM file containing the private key for `cert`.
+    pub key: Option<PathBuf>,
+}
+
+/// Build a TLS client configuration from the options given.
+pub fn client_config(opts: &ClientTlsOpts) -> anyhow::Result<Arc<ClientConfig>> {
+    let mut roots = web_roots();
+    if let Some(ca) = &opts.ca {
+        for cert in load_certs(ca)? {
+            roots
+                .add(&cert)
+                .with_context(|| format!("Invalid CA certificate in {ca:?}"))?;
+        }
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let config = match (&opts.cert, &opts.key) {
+        (Some(cert), Some(key)) => builder
+            .with_single_cert(load_certs(cert)?, load_private_key(key)?)
+            .with_context(|| format!("Invalid client certificate or key in {cert:?}"))?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err(anyhow!(
+                "A client certificate and key must be given together"
+            ))
+        }
+    };
+
+    Ok(Arc::new(config))
+}
+
+/// The TLS client configuration used when nothing more specific is asked for.
+pub fn default_client_config() -> Arc<ClientConfig> {
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(web_roots())
+        .with_no_client_auth();
+    Arc::new(config)
+}
+
+fn web_roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    roots
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_self_signed(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = dir.join(format!("{name}.pem"));
+        let key_path = dir.join(format!("{name}.key"));
+        std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("telemetry-tls-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn certificate_is_reloaded_when_files_change() {
+        let dir = temp_dir("reload");
+        let (cert_path, key_path) = write_self_signed(&dir, "server");
+        let resolver = ReloadingCertResolver::new(cert_path.clone(), key_path.clone()).unwrap();
+        let first = resolver.current.read().unwrap().key.cert.clone();
+        assert!(!resolver.reload_if_changed().unwrap());
+
+        // Make sure the modification time will differ even on coarse filesystems:
+        std::thread::sleep(Duration::from_millis(1100));
+        write_self_signed(&dir, "server");
+        assert!(resolver.reload_if_changed().unwrap());
+        assert_ne!(resolver.current.read().unwrap().key.cert, first);
+
+        // A broken certificate leaves the previous one in place:
+        std::thread::sleep(Duration::from_millis(1100));
+        std::fs::write(&cert_path, "not a certificate").unwrap();
+        let second = resolver.current.read().unwrap().key.cert.clone();
+        assert!(resolver.reload_if_changed().is_err());
+        assert_eq!(resolver.current.read().unwrap().key.cert, second);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn client_cert_and_key_must_be_given_together() {
+        let dir = temp_dir("client");
+        let (cert_path, key_path) = write_self_signed(&dir, "client");
+
+        assert!(client_config(&ClientTlsOpts {
+            ca: Some(cert_path.clone()),
+            cert: Some(cert_path.clone()),
+            key: Some(key_path),
+        })
+        .is_ok());
+        assert!(client_config(&ClientTlsOpts {
+            cert: Some(cert_path),
+            ..Default::default()
+        })
+        .is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -20,7 +20,7 @@ use std::io;
 use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
-use tokio_rustls::rustls::{OwnedTrustAnchor, ServerName};
+use tokio_rustls::rustls::ServerName;
 use tokio_rustls::{rustls, TlsConnector};
 use tokio_util::compat::TokioAsyncReadCompatExt;
 
@@ -132,6 +132,7 @@ impl Connection {
 
         // Send messages to the socket:
         let (tx_to_ws, mut rx_from_external) = channel::mpsc::unbounded::<SentMessage>();
+        let (tx_finished, rx_finished) = tokio::sync::watch::channel(false);
         tokio::spawn(async move {
             loop {
                 // Wait for messages, or bail entirely if asked to close.
@@ -192,6 +193,10 @@ impl Connection {
                             break;
                         }
                     }
+                    SentMessage::Close => {
+                        let _ = ws_to_connection.close().await;
+                        break;
+                    }
                 }
 
                 if let Err(e) = ws_to_connection.flush().await {
@@ -202,6 +207,8 @@ impl Connection {
                     break;
                 }
             }
+
+            let _ = tx_finished.send(true);
         });
 
         // Keep track of whether one of sender or received have
@@ -212,6 +219,7 @@ impl Connection {
             Sender {
                 inner: tx_to_ws,
                 closer: Arc::clone(&on_close),
+                finished: rx_finished,
             },
             Receiver {
                 inner: rx_from_ws,
@@ -235,18 +243,35 @@ pub enum ConnectError {
 
 /// Establish a websocket connection that you can send and receive messages from.
 pub async fn connect(uri: &http::Uri) -> Result<Connection, ConnectError> {
+    connect_with_tls(uri, None).await
+}
+
+/// Like [`connect`], but `wss://` and `https://` connections are made using the TLS
+/// config given rather than one which only trusts the usual web roots.
+pub async fn connect_with_tls(
+    uri: &http::Uri,
+    tls_config: Option<Arc<rustls::ClientConfig>>,
+) -> Result<Connection, ConnectError> {
     let host = uri.host().unwrap_or("127.0.0.1");
     let scheme = uri.scheme_str().unwrap_or("ws");
     let mut port = 80;
     if scheme == "https" || scheme == "wss" {
         port = 443
     }
-    let path = uri.path();
+    let path = uri
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or_else(|| uri.path());
     let port = uri.port_u16().unwrap_or(port);
     let socket = TcpStream::connect((host, port)).await?;
     socket.set_nodelay(true).expect("socket set_nodelay failed");
     // wrap TCP stream with TLS if schema is https or wss
-    let socket = may_connect_tls(socket, host, scheme == "https" || scheme == "wss").await?;
+    let socket = if scheme == "https" || scheme == "wss" {
+        let tls_config = tls_config.unwrap_or_else(crate::tls::default_client_config);
+        connect_tls(socket, host, tls_config).await?
+    } else {
+        Box::new(socket)
+    };
 
     // Establish a WS connection:
     let mut client = Client::new(socket.compat(), host, &path);
@@ -266,27 +291,12 @@ pub async fn connect(uri: &http::Uri) -> Result<Connection, ConnectError> {
     })
 }
 
-async fn may_connect_tls(
+async fn connect_tls(
     socket: TcpStream,
     host: &str,
-    use_https: bool,
+    tls_config: Arc<rustls::ClientConfig>,
 ) -> io::Result<Box<dyn AsyncReadWrite>> {
-    if !use_https {
-        return Ok(Box::new(socket));
-    };
-    let mut root_cert_store = rustls::RootCertStore::empty();
-    root_cert_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-        OwnedTrustAnchor::from_subject_spki_name_constraints(
-            ta.subject,
-            ta.spki,
-            ta.name_constraints,
-        )
-    }));
-    let config = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(root_cert_store)
-        .with_no_client_auth();
-    let connector = TlsConnector::from(Arc::new(config));
+    let connector = TlsConnector::from(tls_config);
     let domain = ServerName::try_from(host)
         .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid dns name"))?;
     let socket = connector.connect(domain, socket).await?;
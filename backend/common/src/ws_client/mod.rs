@@ -23,6 +23,6 @@ mod receiver;
 /// The channel based send interface
 mod sender;
 
-pub use connect::{connect, ConnectError, Connection, RawReceiver, RawSender};
+pub use connect::{connect, connect_with_tls, ConnectError, Connection, RawReceiver, RawSender};
 pub use receiver::{Receiver, RecvError, RecvMessage};
 pub use sender::{SendError, Sender, SentMessage};
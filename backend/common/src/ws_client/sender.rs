@@ -35,6 +35,8 @@ pub enum SentMessage {
     Text(String),
     /// Send owned bytes into the socket.
     Binary(Vec<u8>),
+    /// Close the connection once any messages sent before this have been sent.
+    Close,
 }
 
 /// Send messages into the connection
@@ -42,6 +44,7 @@ pub enum SentMessage {
 pub struct Sender {
     pub(super) inner: channel::mpsc::UnboundedSender<SentMessage>,
     pub(super) closer: Arc<OnClose>,
+    pub(super) finished: tokio::sync::watch::Receiver<bool>,
 }
 
 impl Sender {
@@ -50,6 +53,15 @@ impl Sender {
         self.closer.0.send(()).map_err(|_| SendError::CloseError)?;
         Ok(())
     }
+    /// Wait until we've stopped sending messages to the socket, ie because it's
+    /// been closed (see [`SentMessage::Close`]) or has failed.
+    pub async fn finished(&mut self) {
+        while !*self.finished.borrow_and_update() {
+            if self.finished.changed().await.is_err() {
+                return;
+            }
+        }
+    }
     /// Returns whether this channel is closed.
     pub fn is_closed(&self) -> bool {
         self.inner.is_closed()
@@ -0,0 +1,283 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A server side implementation of the websocket permessage-deflate extension
+//! (RFC 7692). Unlike the one that comes with Soketto, this lets us pick the
+//! compression level, and leaves small messages uncompressed.
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use soketto::base::{Header, OpCode};
+use soketto::extension::{Extension, Param};
+use soketto::{BoxedError, Storage};
+
+const EXTENSION_NAME: &str = "permessage-deflate";
+const SERVER_NO_CONTEXT_TAKEOVER: &str = "server_no_context_takeover";
+const CLIENT_NO_CONTEXT_TAKEOVER: &str = "client_no_context_takeover";
+const SERVER_MAX_WINDOW_BITS: &str = "server_max_window_bits";
+const CLIENT_MAX_WINDOW_BITS: &str = "client_max_window_bits";
+
+/// Every compressed message ends with these bytes, which are left off when sending
+/// and added back on when receiving (RFC 7692, 7.2.1).
+const DEFLATE_TRAILER: [u8; 4] = [0, 0, 0xFF, 0xFF];
+
+/// Don't inflate messages from clients beyond this size.
+const MAX_DECOMPRESSED_LEN: usize = 16 * 1024 * 1024;
+
+/// How to compress messages sent on websocket connections that negotiate permessage-deflate.
+#[derive(Debug, Clone, Copy)]
+pub struct DeflateOpts {
+    /// The zlib compression level, from 1 (fastest) to 9 (smallest).
+    pub level: u32,
+    /// Messages smaller than this many bytes are sent uncompressed.
+    pub threshold: usize,
+}
+
+/// The permessage-deflate extension, once negotiated.
+///
+/// We ask that neither side carries compression state between messages (ie "no
+/// context takeover"), so each message is compressed and decompressed alone. This
+/// costs a little in compression ratio but means we needn't keep a compressor and
+/// decompressor alive for every connection.
+#[derive(Debug)]
+pub struct PerMessageDeflate {
+    opts: DeflateOpts,
+    params: Vec<Param<'static>>,
+    buffer: Vec<u8>,
+    await_last_fragment: bool,
+}
+
+impl PerMessageDeflate {
+    /// Pick the first acceptable permessage-deflate offer from the value of a client's
+    /// `Sec-WebSocket-Extensions` header. If there is one, return the extension, and the
+    /// value to hand back in the `Sec-WebSocket-Extensions` header of our response.
+    pub fn negotiate(offers: &str, opts: DeflateOpts) -> Option<(PerMessageDeflate, String)> {
+        let params = offers.split(',').find_map(accept_offer)?;
+        let ext = PerMessageDeflate {
+            opts,
+            params,
+            buffer: Vec::new(),
+            await_last_fragment: false,
+        };
+
+        let mut response = EXTENSION_NAME.to_string();
+        for p in &ext.params {
+            response.push_str("; ");
+            response.push_str(p.name());
+            if let Some(value) = p.value() {
+                response.push('=');
+                response.push_str(value);
+            }
+        }
+        Some((ext, response))
+    }
+}
+
+/// If this offer is for permessage-deflate and has parameters that we can work with,
+/// return the parameters that we'll respond with.
+fn accept_offer(offer: &str) -> Option<Vec<Param<'static>>> {
+    let mut parts = offer.split(';').map(str::trim);
+    if !parts.next()?.eq_ignore_ascii_case(EXTENSION_NAME) {
+        return None;
+    }
+
+    // We can always honour these, regardless of whether the client asked for them:
+    let mut params = vec![
+        Param::new(SERVER_NO_CONTEXT_TAKEOVER),
+        Param::new(CLIENT_NO_CONTEXT_TAKEOVER),
+    ];
+    for part in parts {
+        let (name, value) = match part.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (part, None),
+        };
+        match name {
+            SERVER_NO_CONTEXT_TAKEOVER | CLIENT_NO_CONTEXT_TAKEOVER => {}
+            // We don't need to limit the window the client compresses with:
+            CLIENT_MAX_WINDOW_BITS => {}
+            // We can't compress with a smaller window than the default (which is also
+            // the maximum), so only accept offers that don't ask us to.
+            SERVER_MAX_WINDOW_BITS if value == Some("15") => {
+                let mut p = Param::new(SERVER_MAX_WINDOW_BITS);
+                p.set_value(Some("15"));
+                params.push(p);
+            }
+            _ => return None,
+        }
+    }
+    Some(params)
+}
+
+impl Extension for PerMessageDeflate {
+    fn is_enabled(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &str {
+        EXTENSION_NAME
+    }
+
+    fn params(&self) -> &[Param<'_>] {
+        &self.params
+    }
+
+    fn configure(&mut self, _params: &[Param]) -> Result<(), BoxedError> {
+        // This is all done in `negotiate`.
+        Ok(())
+    }
+
+    fn reserved_bits(&self) -> (bool, bool, bool) {
+        (true, false, false)
+    }
+
+    fn encode(&mut self, header: &mut Header, data: &mut Storage) -> Result<(), BoxedError> {
+        if !matches!(header.opcode(), OpCode::Text | OpCode::Binary)
+            || data.as_ref().len() < self.opts.threshold.max(1)
+        {
+            return Ok(());
+        }
+
+        let input = data.as_ref();
+        self.buffer.clear();
+        self.buffer.reserve(input.len() / 4 + DEFLATE_TRAILER.len());
+
+        let mut encoder = Compress::new(Compression::new(self.opts.level), false);
+        // With a sync flush, we're done once all of the input is consumed and the
+        // output ends with the empty block that the flush appends.
+        loop {
+            let consumed = encoder.total_in() as usize;
+            encoder.compress_vec(&input[consumed..], &mut self.buffer, FlushCompress::Sync)?;
+            if encoder.total_in() as usize == input.len()
+                && self.buffer.ends_with(&DEFLATE_TRAILER)
+                && self.buffer.len() < self.buffer.capacity()
+            {
+                break;
+            }
+            self.buffer.reserve(4096);
+        }
+        self.buffer
+            .truncate(self.buffer.len() - DEFLATE_TRAILER.len());
+
+        *data = Storage::Owned(std::mem::take(&mut self.buffer));
+        header.set_rsv1(true);
+        header.set_payload_len(data.as_ref().len());
+        Ok(())
+    }
+
+    fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError> {
+        match header.opcode() {
+            OpCode::Text | OpCode::Binary if header.is_rsv1() => {
+                if !header.is_fin() {
+                    // Fragments are accumulated into `data` until the last one arrives.
+                    self.await_last_fragment = true;
+                    return Ok(());
+                }
+            }
+            OpCode::Continue if header.is_fin() && self.await_last_fragment => {
+                self.await_last_fragment = false;
+            }
+            _ => return Ok(()),
+        }
+
+        data.extend_from_slice(&DEFLATE_TRAILER);
+        self.buffer.clear();
+        self.buffer.reserve(data.len() * 4);
+
+        let mut decoder = Decompress::new(false);
+        loop {
+            let consumed = decoder.total_in() as usize;
+            let status = decoder.decompress_vec(
+                &data[consumed..],
+                &mut self.buffer,
+                FlushDecompress::Sync,
+            )?;
+            if self.buffer.len() > MAX_DECOMPRESSED_LEN {
+                return Err("decompressed message is too large".into());
+            }
+            let done = decoder.total_in() as usize == data.len()
+                && self.buffer.len() < self.buffer.capacity();
+            if done || status == Status::StreamEnd {
+                break;
+            }
+            self.buffer.reserve(4096);
+        }
+
+        std::mem::swap(data, &mut self.buffer);
+        header.set_rsv1(false);
+        header.set_payload_len(data.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const OPTS: DeflateOpts = DeflateOpts {
+        level: 6,
+        threshold: 64,
+    };
+
+    #[test]
+    fn offers_are_negotiated() {
+        let (_, response) =
+            PerMessageDeflate::negotiate("permessage-deflate; client_max_window_bits", OPTS)
+                .unwrap();
+        assert_eq!(
+            response,
+            "permessage-deflate; server_no_context_takeover; client_no_context_takeover"
+        );
+
+        // The first acceptable offer is used:
+        let (_, response) = PerMessageDeflate::negotiate(
+            "permessage-deflate; server_max_window_bits=10, permessage-deflate; server_max_window_bits=\"15\"",
+            OPTS,
+        )
+        .unwrap();
+        assert!(response.ends_with("; server_max_window_bits=15"));
+
+        assert!(PerMessageDeflate::negotiate("x-webkit-deflate-frame", OPTS).is_none());
+        assert!(PerMessageDeflate::negotiate("permessage-deflate; foo=bar", OPTS).is_none());
+    }
+
+    #[test]
+    fn messages_can_be_compressed_and_decompressed() {
+        let (mut ext, _) = PerMessageDeflate::negotiate("permessage-deflate", OPTS).unwrap();
+        let message = r#"[1,{"id":1,"name":"node"}]"#.repeat(1000).into_bytes();
+
+        let mut header = Header::new(OpCode::Text);
+        let mut data = Storage::Shared(&message);
+        ext.encode(&mut header, &mut data).unwrap();
+        assert!(header.is_rsv1());
+        assert!(data.as_ref().len() < message.len() / 10);
+
+        let mut data = data.as_ref().to_vec();
+        ext.decode(&mut header, &mut data).unwrap();
+        assert!(!header.is_rsv1());
+        assert_eq!(data, message);
+    }
+
+    #[test]
+    fn small_messages_are_not_compressed() {
+        let (mut ext, _) = PerMessageDeflate::negotiate("permessage-deflate", OPTS).unwrap();
+        let message = b"[0,32]".to_vec();
+
+        let mut header = Header::new(OpCode::Text);
+        let mut data = Storage::Shared(&message);
+        ext.encode(&mut header, &mut data).unwrap();
+        assert!(!header.is_rsv1());
+        assert_eq!(data.as_ref(), &message[..]);
+    }
+}
@@ -0,0 +1,122 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use serde_json::json;
+use std::time::{Duration, Instant};
+use test_utils::feed_message_de::FeedMessage;
+use test_utils::server::channels::FeedReceiver;
+use test_utils::workspace::{start_server, CoreOpts, ServerOpts, ShardOpts};
+use tokio::runtime::Runtime;
+
+/// This benchmark roughly times how long the core takes to remove every node belonging
+/// to a shard once that shard disconnects, while lots of nodes remain connected via
+/// other shards. As with the subscribe benchmark, there's a fair amount of overhead
+/// outside of the disconnect handling itself included in the timings.
+pub fn benchmark_shard_disconnect_speed(c: &mut Criterion) {
+    const NUMBER_OF_SHARDS: usize = 10;
+    const NUMBER_OF_NODES_PER_SHARD: usize = 5_000;
+
+    let rt = Runtime::new().expect("tokio runtime should start");
+
+    c.bench_function("shard disconnect speed: time till nodes removed", move |b| {
+        b.to_async(&rt).iter_custom(|iters| async move {
+            let mut total_time = Duration::ZERO;
+            for _n in 0..iters {
+                // Start a server:
+                let mut server = start_server(
+                    ServerOpts {
+                        release_mode: true,
+                        log_output: false,
+                    },
+                    CoreOpts {
+                        worker_threads: Some(16),
+                        feed_workers: Some(1),
+                        ..Default::default()
+                    },
+                    ShardOpts {
+                        max_nodes_per_connection: Some(usize::MAX),
+                        max_node_data_per_second: Some(usize::MAX),
+                        worker_threads: Some(2),
+                        ..Default::default()
+                    },
+                )
+                .await;
+
+                // Add a bunch of shards, each with lots of nodes on the same chain:
+                let mut shard_ids = vec![];
+                let mut node_connections = vec![];
+                for _ in 0..NUMBER_OF_SHARDS {
+                    let shard_id = server.add_shard().await.unwrap();
+                    let (mut node_tx, _) = server
+                        .get_shard(shard_id)
+                        .unwrap()
+                        .connect_node()
+                        .await
+                        .expect("node can connect");
+
+                    for n in 0..NUMBER_OF_NODES_PER_SHARD {
+                        node_tx
+                            .send_json_text(json!({
+                                "id":n,
+                                "ts":"2021-07-12T10:37:47.714666+01:00",
+                                "payload": {
+                                    "authority":true,
+                                    "chain":"Mainnet",
+                                    "config":"",
+                                    // A first party network, so there's no limit to #nodes:
+                                    "genesis_hash": "0xb91746b45e0346cc2f815a520b9c6cb4d5c0902af848db0a80f85932d2e8276a",
+                                    "implementation":"Substrate Node",
+                                    "msg":"system.connected",
+                                    "name": format!("Node {}", n),
+                                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                                    "startup_time":"1625565542717",
+                                    "version":"2.0.0-07a1af348-aarch64-macos"
+                                }
+                            }))
+                            .unwrap();
+                    }
+                    shard_ids.push(shard_id);
+                    node_connections.push(node_tx);
+                }
+
+                // Wait until the core knows about every node before disconnecting anything:
+                let (_feed_tx, mut feed_rx) = server
+                    .get_core()
+                    .connect_feed()
+                    .await
+                    .expect("feed can connect");
+                wait_for_node_count(&mut feed_rx, NUMBER_OF_SHARDS * NUMBER_OF_NODES_PER_SHARD)
+                    .await;
+
+                // Disconnect one shard and see how long it takes for its nodes to go:
+                let start = Instant::now();
+                server.kill_shard(shard_ids[0]).await;
+                wait_for_node_count(
+                    &mut feed_rx,
+                    (NUMBER_OF_SHARDS - 1) * NUMBER_OF_NODES_PER_SHARD,
+                )
+                .await;
+                total_time += start.elapsed();
+
+                server.shutdown().await;
+            }
+
+            // The total time spent waiting for shard disconnects to be handled:
+            total_time
+        })
+    });
+}
+
+/// Wait until the feed is told that the chain has the given number of nodes.
+async fn wait_for_node_count(feed_rx: &mut FeedReceiver, expected_node_count: usize) {
+    loop {
+        let msgs = feed_rx.recv_feed_messages_once().await.unwrap();
+        let done = msgs.iter().any(|m| {
+            matches!(m, FeedMessage::AddedChain { node_count, .. } if *node_count == expected_node_count)
+        });
+        if done {
+            break;
+        }
+    }
+}
+
+criterion_group!(benches, benchmark_shard_disconnect_speed);
+criterion_main!(benches);
@@ -15,16 +15,18 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use super::inner_loop::{self};
-use crate::endpoints::{BlockHistory, ChainOverview, NodeList};
+use crate::endpoints::{Authorities, BlockHistory, ChainOverview, HardwareCompliance, NodeList};
 use crate::find_location::find_location;
-use crate::state::NodeId;
+use crate::state::{ChainOpts, ChainSnapshot, NodeId, StateSnapshot};
 use common::id_type;
+use common::node_types::BlockHash;
 use futures::{future, Sink, SinkExt};
 use primitive_types::H256;
 use std::collections::HashMap;
 use std::net::IpAddr;
 use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
+use std::time::Duration;
 
 id_type! {
     /// A unique Id is assigned per websocket connection (or more accurately,
@@ -42,7 +44,7 @@ pub struct AggregatorOpts {
     /// Any node from these chains is muted
     pub denylist: Vec<String>,
     /// If our incoming message queue exceeds this length, we start
-    /// dropping non-essential messages.
+    /// dropping non-essential messages, least important first.
     pub max_queue_len: usize,
     /// How many nodes from third party chains are allowed to connect
     /// before we prevent connections from them.
@@ -50,6 +52,22 @@ pub struct AggregatorOpts {
     /// Flag to expose the node's details (IP address, SysInfo, HwBench) of all connected
     /// nodes to the feed subscribers.
     pub expose_node_details: bool,
+    /// Chains which any number of nodes are allowed to connect to.
+    pub first_party_networks: Vec<BlockHash>,
+    /// Settings which affect how every chain is tracked.
+    pub chain_opts: ChainOpts,
+    /// How often to gather metrics and the data for the chain overview, block
+    /// history and node list endpoints from each aggregator loop.
+    pub metrics_interval: Duration,
+    /// How many of the most recent batches of messages to the feeds of each chain
+    /// to keep, so that feeds can resume after reconnecting. 0 turns this off.
+    pub feed_replay_len: usize,
+    /// Feeds which have this many batches of messages queued up for them, despite
+    /// merging those that are redundant, are disconnected.
+    pub feed_max_backlog: usize,
+    /// How many workers to split feeds across. The aggregator serializes the messages
+    /// for feeds once, and these workers send them on to each of their feeds.
+    pub feed_workers: usize,
 }
 
 struct AggregatorInternal {
@@ -149,11 +167,78 @@ impl Aggregator {
         Ok(data)
     }
 
+    pub async fn gather_hardware_compliance_endpoint(
+        &self,
+    ) -> anyhow::Result<HashMap<H256, HardwareCompliance>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherHardwareCompliance(tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let data = rx.recv_async().await?;
+        Ok(data)
+    }
+
+    pub async fn gather_authorities_endpoint(&self) -> anyhow::Result<HashMap<H256, Authorities>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherAuthorities(tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let data = rx.recv_async().await?;
+        Ok(data)
+    }
+
+    /// Take a snapshot of the history of every chain that the aggregator knows about.
+    pub async fn gather_chain_snapshots(&self) -> anyhow::Result<Vec<ChainSnapshot>> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherChainSnapshots(tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let data = rx.recv_async().await?;
+        Ok(data)
+    }
+
+    /// Hand the aggregator some chain history to seed chains with as they are created.
+    pub async fn seed_chains(&self, snapshots: Vec<ChainSnapshot>) -> anyhow::Result<()> {
+        let msg = inner_loop::ToAggregator::SeedChains(snapshots);
+        self.0.tx_to_aggregator.send_async(msg).await?;
+        Ok(())
+    }
+
+    /// Take a snapshot of every chain and node that the aggregator knows about.
+    pub async fn gather_state_snapshot(&self) -> anyhow::Result<StateSnapshot> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherStateSnapshot(tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let data = rx.recv_async().await?;
+        Ok(data)
+    }
+
+    /// Restore chains and nodes from a snapshot.
+    pub async fn restore_state(&self, snapshot: StateSnapshot) -> anyhow::Result<()> {
+        let msg = inner_loop::ToAggregator::RestoreState(snapshot);
+        self.0.tx_to_aggregator.send_async(msg).await?;
+        Ok(())
+    }
+
+    /// Remove any restored nodes that haven't been added again by a shard.
+    pub async fn reap_pending_nodes(&self) -> anyhow::Result<()> {
+        let msg = inner_loop::ToAggregator::ReapPendingNodes;
+        self.0.tx_to_aggregator.send_async(msg).await?;
+        Ok(())
+    }
+
     /// Return a sink that a shard can send messages into to be handled by the aggregator.
     pub fn subscribe_shard(
         &self,
-    ) -> impl Sink<inner_loop::FromShardWebsocket, Error = anyhow::Error> + Send + Sync + Unpin + 'static
-    {
+    ) -> (
+        u64,
+        impl Sink<inner_loop::FromShardWebsocket, Error = anyhow::Error> + Send + Sync + Unpin + 'static,
+    ) {
         // Assign a unique aggregator-local ID to each connection that subscribes, and pass
         // that along with every message to the aggregator loop:
         let shard_conn_id = self
@@ -164,12 +249,15 @@ impl Aggregator {
 
         // Calling `send` on this Sink requires Unpin. There may be a nicer way than this,
         // but pinning by boxing is the easy solution for now:
-        Box::pin(tx_to_aggregator.into_sink().with(move |msg| async move {
-            Ok(inner_loop::ToAggregator::FromShardWebsocket(
-                shard_conn_id.into(),
-                msg,
-            ))
-        }))
+        (
+            shard_conn_id,
+            Box::pin(tx_to_aggregator.into_sink().with(move |msg| async move {
+                Ok(inner_loop::ToAggregator::FromShardWebsocket(
+                    shard_conn_id.into(),
+                    msg,
+                ))
+            })),
+        )
     }
 
     /// Return a sink that a feed can send messages into to be handled by the aggregator.
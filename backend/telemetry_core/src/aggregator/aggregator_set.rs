@@ -1,64 +1,87 @@
-use crate::endpoints::{BlockHistory, ChainOverview, NodeList};
+use crate::endpoints::{Authorities, BlockHistory, ChainOverview, HardwareCompliance, NodeList};
 
 use super::aggregator::{Aggregator, AggregatorOpts};
 use super::inner_loop::{self};
-use common::EitherSink;
+use crate::feed_message::{self, FeedMessageSerializer};
+use crate::state::{ChainSnapshot, StateSnapshot};
+use common::{internal_messages::ShardNodeId, node_types::BlockHash, EitherSink};
 use futures::{Sink, SinkExt};
-use inner_loop::{FromShardWebsocket, Metrics};
+use inner_loop::{FromFeedWebsocket, FromShardWebsocket, Metrics, ToFeedWebsocket};
 use primitive_types::H256;
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-
+use std::time::Duration;
+
+/// The aggregators, along with the latest metrics and endpoint data gathered from them.
+/// Chains are partitioned across the aggregators by genesis hash, and each one keeps track
+/// of the state of its chains and the nodes on them, so that a busy chain only holds up
+/// the chains that share its aggregator. Every feed connects to every aggregator, and
+/// hears from each about the chains that it looks after. Each aggregator serializes the
+/// messages for feeds just once, and splits its feeds across a number of workers (see
+/// [`AggregatorOpts::feed_workers`]) which send these messages on to them.
 #[derive(Clone)]
 pub struct AggregatorSet(Arc<AggregatorSetInner>);
 
 pub struct AggregatorSetInner {
     aggregators: Vec<Aggregator>,
-    next_idx: AtomicUsize,
     metrics: Mutex<Vec<Metrics>>,
     overview: Mutex<Vec<HashMap<H256, ChainOverview>>>,
     block_history: Mutex<Vec<HashMap<H256, BlockHistory>>>,
     node_list: Mutex<Vec<HashMap<H256, NodeList>>>,
+    hardware_compliance: Mutex<Vec<HashMap<H256, HardwareCompliance>>>,
+    authorities: Mutex<Vec<HashMap<H256, Authorities>>>,
+    /// Held while a feed subscribes to every aggregator, so that each one gives it the
+    /// same connection ID.
+    subscribe_feed_lock: Mutex<()>,
+    /// Held while a shard subscribes to every aggregator, so that each one gives it the
+    /// same connection ID.
+    subscribe_shard_lock: Mutex<()>,
 }
 
 impl AggregatorSet {
-    /// Spawn the number of aggregators we're asked to.
+    /// Spawn an aggregator for each of the chain partitions we're asked for.
     pub async fn spawn(
-        num_aggregators: usize,
+        num_partitions: usize,
         opts: AggregatorOpts,
     ) -> anyhow::Result<AggregatorSet> {
-        assert_ne!(num_aggregators, 0, "You must have 1 or more aggregator");
+        assert_ne!(num_partitions, 0, "You must have 1 or more chain partition");
+        assert_ne!(opts.feed_workers, 0, "You must have 1 or more feed worker");
 
         let aggregators = futures::future::try_join_all(
-            (0..num_aggregators).map(|_| Aggregator::spawn(opts.clone())),
+            (0..num_partitions).map(|_| Aggregator::spawn(opts.clone())),
         )
         .await?;
 
-        let initial_metrics = (0..num_aggregators).map(|_| Metrics::default()).collect();
-        let initial_overview = (0..num_aggregators).map(|_| Default::default()).collect();
-        let initial_block_history = (0..num_aggregators).map(|_| Default::default()).collect();
-        let initial_node_list = (0..num_aggregators).map(|_| Default::default()).collect();
+        let initial_metrics = (0..num_partitions).map(|_| Metrics::default()).collect();
+        let initial_overview = (0..num_partitions).map(|_| Default::default()).collect();
+        let initial_block_history = (0..num_partitions).map(|_| Default::default()).collect();
+        let initial_node_list = (0..num_partitions).map(|_| Default::default()).collect();
+        let initial_hardware_compliance = (0..num_partitions).map(|_| Default::default()).collect();
+        let initial_authorities = (0..num_partitions).map(|_| Default::default()).collect();
 
         let this = AggregatorSet(Arc::new(AggregatorSetInner {
             aggregators,
-            next_idx: AtomicUsize::new(0),
             metrics: Mutex::new(initial_metrics),
             overview: Mutex::new(initial_overview),
             block_history: Mutex::new(initial_block_history),
             node_list: Mutex::new(initial_node_list),
+            hardware_compliance: Mutex::new(initial_hardware_compliance),
+            authorities: Mutex::new(initial_authorities),
+            subscribe_feed_lock: Mutex::new(()),
+            subscribe_shard_lock: Mutex::new(()),
         }));
 
         // Start asking for metrics:
-        this.spawn_metrics_loops();
+        this.spawn_metrics_loops(opts.metrics_interval);
 
         // Start asking for overview:
-        this.spawn_overview_loops();
+        this.spawn_overview_loops(opts.metrics_interval);
 
         Ok(this)
     }
 
-    fn spawn_overview_loops(&self) {
+    fn spawn_overview_loops(&self, interval: Duration) {
         let aggregators = self.0.aggregators.clone();
         for (idx, a) in aggregators.into_iter().enumerate() {
             let inner = Arc::clone(&self.0);
@@ -91,6 +114,22 @@ impl AggregatorSet {
                         }
                     };
 
+                    let hardware_compliance = match a.gather_hardware_compliance_endpoint().await {
+                        Ok(data) => data,
+                        Err(e) => {
+                            log::error!("Error obtaining metrics (bailing): {}", e);
+                            return;
+                        }
+                    };
+
+                    let authorities = match a.gather_authorities_endpoint().await {
+                        Ok(data) => data,
+                        Err(e) => {
+                            log::error!("Error obtaining metrics (bailing): {}", e);
+                            return;
+                        }
+                    };
+
                     // Lock, update the stored metrics and drop the lock immediately.
                     // We discard any error; if something went wrong talking to the inner loop,
                     // it's probably a fatal error
@@ -98,11 +137,13 @@ impl AggregatorSet {
                         inner.overview.lock().unwrap()[idx] = overview;
                         inner.block_history.lock().unwrap()[idx] = block_history;
                         inner.node_list.lock().unwrap()[idx] = node_list;
+                        inner.hardware_compliance.lock().unwrap()[idx] = hardware_compliance;
+                        inner.authorities.lock().unwrap()[idx] = authorities;
                     }
 
-                    // Sleep *at least* 10 seconds. If it takes a while to get overview back, we'll
-                    // end up waiting longer between requests.
-                    tokio::time::sleep_until(now + tokio::time::Duration::from_secs(10)).await;
+                    // Sleep *at least* the interval given. If it takes a while to get overview back,
+                    // we'll end up waiting longer between requests.
+                    tokio::time::sleep_until(now + interval).await;
                 }
             });
         }
@@ -111,7 +152,7 @@ impl AggregatorSet {
     /// Spawn loops which periodically ask for metrics from each internal aggregator.
     /// Depending on how busy the aggregators are, these metrics won't necessarily be in
     /// sync with each other.
-    fn spawn_metrics_loops(&self) {
+    fn spawn_metrics_loops(&self, interval: Duration) {
         let aggregators = self.0.aggregators.clone();
         for (idx, a) in aggregators.into_iter().enumerate() {
             let inner = Arc::clone(&self.0);
@@ -135,30 +176,26 @@ impl AggregatorSet {
                         inner.metrics.lock().unwrap()[idx] = metrics;
                     }
 
-                    // Sleep *at least* 10 seconds. If it takes a while to get metrics back, we'll
-                    // end up waiting longer between requests.
-                    tokio::time::sleep_until(now + tokio::time::Duration::from_secs(10)).await;
+                    // Sleep *at least* the interval given. If it takes a while to get metrics back,
+                    // we'll end up waiting longer between requests.
+                    tokio::time::sleep_until(now + interval).await;
                 }
             });
         }
     }
 
-    /// Return the latest metrics we've gathered so far from each internal aggregator.
-    pub fn latest_metrics(&self) -> Vec<Metrics> {
-        self.0.metrics.lock().unwrap().clone()
+    /// Return the latest metrics we've gathered so far, merged across the internal aggregators.
+    pub fn latest_metrics(&self) -> Metrics {
+        merge_metrics(&self.0.metrics.lock().unwrap())
     }
 
-    /// Return the latest overview we've gathered so far from each internal aggregator.
+    /// Return the latest overview we've gathered so far from the aggregator looking after a chain.
     pub fn overview_endpoint(&self, genesis_hash: H256) -> Result<ChainOverview, &str> {
         let Ok(lock) = self.0.overview.lock() else {
             return Err("Failed to acquire lock.");
         };
 
-        let Some(datas) = lock.get(0) else {
-            return Err("Failed to get any Data");
-        };
-
-        let Some(data) = datas.get(&genesis_hash) else {
+        let Some(data) = lock[self.chain_partition(&genesis_hash)].get(&genesis_hash) else {
             return Err("No genesis hash found");
         };
 
@@ -171,11 +208,7 @@ impl AggregatorSet {
             return Err("Failed to acquire lock.");
         };
 
-        let Some(datas) = lock.get(0) else {
-            return Err("Failed to get any Data");
-        };
-
-        let Some(data) = datas.get(&genesis_hash) else {
+        let Some(data) = lock[self.chain_partition(&genesis_hash)].get(&genesis_hash) else {
             return Err("No genesis hash found");
         };
 
@@ -188,17 +221,187 @@ impl AggregatorSet {
             return Err("Failed to acquire lock.");
         };
 
-        let Some(datas) = lock.get(0) else {
-            return Err("Failed to get any Data");
+        let Some(data) = lock[self.chain_partition(&genesis_hash)].get(&genesis_hash) else {
+            return Err("No genesis hash found");
+        };
+
+        Ok(data.clone())
+    }
+
+    /// How the validators on a chain measure up to its hardware requirements.
+    pub fn hardware_compliance_endpoint(
+        &self,
+        genesis_hash: H256,
+    ) -> Result<HardwareCompliance, &str> {
+        let Ok(lock) = self.0.hardware_compliance.lock() else {
+            return Err("Failed to acquire lock.");
+        };
+
+        let Some(data) = lock[self.chain_partition(&genesis_hash)].get(&genesis_hash) else {
+            return Err("No hardware requirements found for genesis hash");
+        };
+
+        Ok(data.clone())
+    }
+
+    /// The authorities of a chain, and how many validators and full nodes run each version.
+    pub fn authorities_endpoint(&self, genesis_hash: H256) -> Result<Authorities, &str> {
+        let Ok(lock) = self.0.authorities.lock() else {
+            return Err("Failed to acquire lock.");
         };
 
-        let Some(data) = datas.get(&genesis_hash) else {
+        let Some(data) = lock[self.chain_partition(&genesis_hash)].get(&genesis_hash) else {
             return Err("No genesis hash found");
         };
 
         Ok(data.clone())
     }
 
+    /// Which of the internal aggregators looks after the chain with the genesis hash given.
+    fn chain_partition(&self, genesis_hash: &BlockHash) -> usize {
+        chain_partition(genesis_hash, self.0.aggregators.len())
+    }
+
+    /// Take a snapshot of the history of every chain.
+    pub async fn chain_snapshots(&self) -> anyhow::Result<Vec<ChainSnapshot>> {
+        let mut snapshots = Vec::new();
+        for a in &self.0.aggregators {
+            snapshots.extend(a.gather_chain_snapshots().await?);
+        }
+        Ok(snapshots)
+    }
+
+    /// Write a snapshot of every chain and node to the path given, so that it can be
+    /// restored after a restart.
+    pub async fn write_state_snapshot(&self, path: &Path) -> anyhow::Result<()> {
+        let mut snapshot = StateSnapshot::default();
+        for a in &self.0.aggregators {
+            let partition = a.gather_state_snapshot().await?;
+            snapshot.chains.extend(partition.chains);
+            snapshot.nodes.extend(partition.nodes);
+        }
+        let bytes = serde_json::to_vec(&snapshot)?;
+
+        // Write to a temporary file first, so that we never leave a half written snapshot behind:
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        tokio::fs::write(&tmp_path, bytes).await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+        Ok(())
+    }
+
+    /// Periodically write a snapshot of every chain and node to the path given.
+    pub fn spawn_snapshot_loop(&self, path: PathBuf, interval: Duration) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if let Err(e) = this.write_state_snapshot(&path).await {
+                    log::error!("Failed to write state snapshot to {}: {e}", path.display());
+                }
+            }
+        });
+    }
+
+    /// Restore chains and nodes from a snapshot written by [`AggregatorSet::write_state_snapshot`].
+    /// Restored nodes are kept until their shard adds them again, and any that haven't been added
+    /// again after `pending_timeout` are removed.
+    pub async fn restore_state_snapshot(
+        &self,
+        path: &Path,
+        pending_timeout: Duration,
+    ) -> anyhow::Result<usize> {
+        let bytes = tokio::fs::read(path).await?;
+        let snapshot: StateSnapshot = serde_json::from_slice(&bytes)?;
+        let num_nodes = snapshot.nodes.len();
+
+        // Hand each aggregator the chains and nodes that it looks after:
+        let num_partitions = self.0.aggregators.len();
+        let mut partitions: Vec<StateSnapshot> =
+            (0..num_partitions).map(|_| Default::default()).collect();
+        for chain in snapshot.chains {
+            partitions[chain_partition(&chain.genesis_hash, num_partitions)]
+                .chains
+                .push(chain);
+        }
+        for node in snapshot.nodes {
+            partitions[chain_partition(&node.genesis_hash, num_partitions)]
+                .nodes
+                .push(node);
+        }
+        for (a, partition) in self.0.aggregators.iter().zip(partitions) {
+            a.restore_state(partition).await?;
+        }
+
+        let aggregators = self.0.aggregators.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(pending_timeout).await;
+            for a in &aggregators {
+                if let Err(e) = a.reap_pending_nodes().await {
+                    log::error!("Error removing restored nodes: {}", e);
+                }
+            }
+        });
+
+        Ok(num_nodes)
+    }
+
+    /// Periodically fetch chain history from another core's `/state_snapshot` endpoint, and
+    /// hand it to each of our aggregators to seed chains with as they are created. This lets
+    /// us take over from that core (ie when shards fail over to us) without losing chain history.
+    pub fn spawn_standby_loop(&self, snapshot_url: String, interval: Duration) {
+        let aggregators = self.0.aggregators.clone();
+        tokio::spawn(async move {
+            let client = reqwest::Client::new();
+            // Only log when fetching starts or stops working, to avoid spamming
+            // the logs once the core we're standing by for has gone away.
+            let mut last_fetch_ok = true;
+            loop {
+                let now = tokio::time::Instant::now();
+                let snapshots = async {
+                    client
+                        .get(&snapshot_url)
+                        .timeout(interval)
+                        .send()
+                        .await?
+                        .error_for_status()?
+                        .json::<Vec<ChainSnapshot>>()
+                        .await
+                }
+                .await;
+
+                match snapshots {
+                    Ok(snapshots) => {
+                        if !last_fetch_ok {
+                            log::info!("Fetching state snapshots from {snapshot_url} again");
+                        }
+                        last_fetch_ok = true;
+                        let mut partitions: Vec<Vec<ChainSnapshot>> =
+                            aggregators.iter().map(|_| Vec::new()).collect();
+                        for snapshot in snapshots {
+                            partitions[chain_partition(&snapshot.genesis_hash, aggregators.len())]
+                                .push(snapshot);
+                        }
+                        for (a, partition) in aggregators.iter().zip(partitions) {
+                            if let Err(e) = a.seed_chains(partition).await {
+                                log::error!("Error seeding chains (bailing): {}", e);
+                                return;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        if last_fetch_ok {
+                            log::warn!("Failed to fetch state snapshot from {snapshot_url} (will keep trying): {e}");
+                        }
+                        last_fetch_ok = false;
+                    }
+                }
+
+                tokio::time::sleep_until(now + interval).await;
+            }
+        });
+    }
+
     /*     /// Return the latest overview we've gathered so far from each internal aggregator.
        pub fn blocks_debug(&self, genesis_hash: H256) -> Result<SerializedStoredOverviewData, &str> {
            let Ok(lock) = self.0.blocks_debug.lock() else {
@@ -216,54 +419,266 @@ impl AggregatorSet {
            Ok(blocks_debug.clone())
        }
     */
-    /// Return a sink that a shard can send messages into to be handled by all aggregators.
+    /// Return a sink that a shard can send messages into to be handled by the aggregators.
+    /// Every aggregator sees every shard connection, and we subscribe to them all under a
+    /// lock so that they hand out the same ID for it, which is returned alongside the sink. Messages about a node
+    /// go to the aggregator looking after the chain that the node was added to.
     pub fn subscribe_shard(
         &self,
-    ) -> impl Sink<inner_loop::FromShardWebsocket, Error = anyhow::Error> + Send + Sync + Unpin + 'static
-    {
+    ) -> (
+        u64,
+        impl Sink<FromShardWebsocket, Error = anyhow::Error> + Send + Sync + Unpin + 'static,
+    ) {
         // Special case 1 aggregator to avoid the extra indirection and so on
         // if we don't actually need it.
         if self.0.aggregators.len() == 1 {
-            let sub = self.0.aggregators[0].subscribe_shard();
-            return EitherSink::a(sub);
+            let (shard_conn_id, sub) = self.0.aggregators[0].subscribe_shard();
+            return (shard_conn_id, EitherSink::a(sub));
         }
 
-        let mut conns: Vec<_> = self
-            .0
-            .aggregators
-            .iter()
-            .map(|a| a.subscribe_shard())
-            .collect();
+        let (shard_conn_ids, mut conns): (Vec<_>, Vec<_>) = {
+            let _lock = self.0.subscribe_shard_lock.lock().unwrap();
+            self.0
+                .aggregators
+                .iter()
+                .map(|a| a.subscribe_shard())
+                .unzip()
+        };
+        debug_assert!(shard_conn_ids.iter().all(|&id| id == shard_conn_ids[0]));
 
         let (tx, rx) = flume::unbounded::<FromShardWebsocket>();
 
-        // Send every incoming message to all aggregators.
+        // Route every incoming message to the aggregator(s) that it concerns.
         tokio::spawn(async move {
+            // Which aggregator each of the shard's nodes was added to:
+            let mut node_partitions: HashMap<ShardNodeId, usize> = HashMap::new();
             while let Ok(msg) = rx.recv_async().await {
-                for conn in &mut conns {
-                    // Unbounded channel under the hood, so this await
-                    // shouldn't ever need to yield.
-                    if let Err(e) = conn.send(msg.clone()).await {
-                        log::error!("Aggregator connection has failed: {}", e);
-                        return;
+                let partition = match &msg {
+                    FromShardWebsocket::Add {
+                        local_id,
+                        genesis_hash,
+                        ..
+                    } => {
+                        let partition = chain_partition(genesis_hash, conns.len());
+                        // The node may have been added to a chain elsewhere before; if so,
+                        // remove it from there first:
+                        if let Some(old) = node_partitions.insert(*local_id, partition) {
+                            if old != partition {
+                                let remove = FromShardWebsocket::Remove {
+                                    local_id: *local_id,
+                                };
+                                if let Err(e) = conns[old].send(remove).await {
+                                    log::error!("Aggregator connection has failed: {}", e);
+                                    return;
+                                }
+                            }
+                        }
+                        Some(partition)
+                    }
+                    FromShardWebsocket::Update { local_id, .. } => {
+                        match node_partitions.get(local_id) {
+                            Some(&partition) => Some(partition),
+                            // We don't know about the node (it was never added), so
+                            // nobody wants to hear about it:
+                            None => continue,
+                        }
                     }
+                    FromShardWebsocket::Remove { local_id } => {
+                        match node_partitions.remove(local_id) {
+                            Some(partition) => Some(partition),
+                            None => continue,
+                        }
+                    }
+                    FromShardWebsocket::Initialize { .. } | FromShardWebsocket::Disconnected => {
+                        None
+                    }
+                };
+
+                // Unbounded channels under the hood, so these awaits
+                // shouldn't ever need to yield.
+                let res = match partition {
+                    Some(partition) => conns[partition].send(msg).await,
+                    None => send_to_all(&mut conns, msg).await,
+                };
+                if let Err(e) = res {
+                    log::error!("Aggregator connection has failed: {}", e);
+                    return;
                 }
             }
         });
 
-        EitherSink::b(tx.into_sink().sink_map_err(|e| anyhow::anyhow!("{}", e)))
+        (
+            shard_conn_ids[0],
+            EitherSink::b(tx.into_sink().sink_map_err(|e| anyhow::anyhow!("{}", e))),
+        )
     }
 
-    /// Return a sink that a feed can send messages into to be handled by a single aggregator.
+    /// Return a sink that a feed can send messages into to be handled by the aggregators.
+    /// Commands about a chain go to the aggregator looking after it, and anything else to
+    /// the first aggregator.
     pub fn subscribe_feed(
         &self,
     ) -> (
         u64,
-        impl Sink<inner_loop::FromFeedWebsocket, Error = anyhow::Error> + Send + Sync + Unpin + 'static,
+        impl Sink<FromFeedWebsocket, Error = anyhow::Error> + Send + Sync + Unpin + 'static,
     ) {
-        let last_val = self.0.next_idx.fetch_add(1, Ordering::Relaxed);
-        let this_idx = (last_val + 1) % self.0.aggregators.len();
+        // Special case 1 aggregator to avoid the extra indirection and so on
+        // if we don't actually need it.
+        if self.0.aggregators.len() == 1 {
+            let (feed_conn_id, sub) = self.0.aggregators[0].subscribe_feed();
+            let sub = sub.with(|msg| async move {
+                send_version_to_new_feed(&msg);
+                Ok::<_, anyhow::Error>(msg)
+            });
+            return (feed_conn_id, EitherSink::a(Box::pin(sub)));
+        }
+
+        let (feed_conn_ids, mut conns): (Vec<_>, Vec<_>) = {
+            let _lock = self.0.subscribe_feed_lock.lock().unwrap();
+            self.0
+                .aggregators
+                .iter()
+                .map(|a| a.subscribe_feed())
+                .unzip()
+        };
+        debug_assert!(feed_conn_ids.iter().all(|&id| id == feed_conn_ids[0]));
+
+        let (tx, rx) = flume::unbounded::<FromFeedWebsocket>();
+
+        // Route every incoming message to the aggregator(s) that it concerns.
+        tokio::spawn(async move {
+            while let Ok(msg) = rx.recv_async().await {
+                send_version_to_new_feed(&msg);
+                let partition = match &msg {
+                    FromFeedWebsocket::Command { command, .. } => Some(
+                        command
+                            .chain()
+                            .map_or(0, |chain| chain_partition(&chain, conns.len())),
+                    ),
+                    FromFeedWebsocket::InvalidCommand { .. } => Some(0),
+                    FromFeedWebsocket::Initialize { .. } | FromFeedWebsocket::Disconnected => None,
+                };
+
+                // Unbounded channels under the hood, so these awaits
+                // shouldn't ever need to yield.
+                let res = match partition {
+                    Some(partition) => conns[partition].send(msg).await,
+                    None => send_to_all(&mut conns, msg).await,
+                };
+                if let Err(e) = res {
+                    log::error!("Aggregator connection has failed: {}", e);
+                    return;
+                }
+            }
+        });
+
+        (
+            feed_conn_ids[0],
+            EitherSink::b(tx.into_sink().sink_map_err(|e| anyhow::anyhow!("{}", e))),
+        )
+    }
+}
+
+/// Merge the metrics from each aggregator. Every aggregator sees every shard and feed, so
+/// those are counted once, feeds subscribed to chains in several aggregators are counted
+/// once, and everything else to do with chains and nodes is added up.
+fn merge_metrics(metrics: &[Metrics]) -> Metrics {
+    let mut merged = metrics[0].clone();
+    for m in &metrics[1..] {
+        merged.timestamp_unix_ms = merged.timestamp_unix_ms.min(m.timestamp_unix_ms);
+        merged.chains_subscribed_to += m.chains_subscribed_to;
+        merged.current_messages_to_aggregator += m.current_messages_to_aggregator;
+        merged.total_messages_to_aggregator += m.total_messages_to_aggregator;
+        merged.dropped_messages_to_aggregator += m.dropped_messages_to_aggregator;
+        for &(priority, n) in &m.dropped_messages_by_priority {
+            match merged
+                .dropped_messages_by_priority
+                .iter_mut()
+                .find(|(p, _)| *p == priority)
+            {
+                Some((_, merged)) => *merged += n,
+                None => merged.dropped_messages_by_priority.push((priority, n)),
+            }
+        }
+        merged.connected_nodes += m.connected_nodes;
+    }
+    merged.dropped_messages_by_priority.sort_unstable();
+    let subscribed_feed_ids: HashSet<_> = metrics
+        .iter()
+        .flat_map(|m| &m.subscribed_feed_ids)
+        .copied()
+        .collect();
+    merged.subscribed_feeds = subscribed_feed_ids.len();
+    merged.subscribed_feed_ids = subscribed_feed_ids.into_iter().collect();
+    merged
+}
 
-        self.0.aggregators[this_idx].subscribe_feed()
+/// Which of `num_partitions` aggregators looks after the chain with the genesis hash given.
+fn chain_partition(genesis_hash: &BlockHash, num_partitions: usize) -> usize {
+    (genesis_hash.to_low_u64_be() % num_partitions as u64) as usize
+}
+
+/// Send a message to every aggregator.
+async fn send_to_all<T: Clone, S: Sink<T, Error = anyhow::Error> + Unpin>(
+    conns: &mut [S],
+    msg: T,
+) -> anyhow::Result<()> {
+    for conn in conns {
+        conn.send(msg.clone()).await?;
+    }
+    Ok(())
+}
+
+/// Every aggregator tells a new feed about the chains that it looks after, but before any
+/// of them do, the feed is told which version of the feed protocol we speak.
+fn send_version_to_new_feed(msg: &FromFeedWebsocket) {
+    if let FromFeedWebsocket::Initialize { channel, format } = msg {
+        let mut feed_serializer = FeedMessageSerializer::with_formats([*format]);
+        feed_serializer.push(feed_message::Version(35));
+        let batch = feed_serializer
+            .into_finalized()
+            .and_then(|bytes| bytes.batch(*format));
+        if let Some(batch) = batch {
+            let _ = channel.send(ToFeedWebsocket::Messages(batch));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::node_message::Priority;
+
+    #[test]
+    fn metrics_are_merged_across_aggregators() {
+        let first = Metrics {
+            subscribed_feeds: 2,
+            subscribed_feed_ids: vec![1.into(), 2.into()],
+            dropped_messages_by_priority: vec![(Priority::Low, 3), (Priority::Normal, 1)],
+            connected_nodes: 10,
+            ..Default::default()
+        };
+        let second = Metrics {
+            subscribed_feeds: 2,
+            subscribed_feed_ids: vec![2.into(), 3.into()],
+            dropped_messages_by_priority: vec![(Priority::High, 5), (Priority::Low, 4)],
+            connected_nodes: 5,
+            ..Default::default()
+        };
+        // An aggregator which hasn't reported any metrics yet:
+        let third = Metrics::default();
+
+        let merged = merge_metrics(&[first, second, third]);
+        // Feed 2 is subscribed to chains in both aggregators, but it's one feed:
+        assert_eq!(merged.subscribed_feeds, 3);
+        assert_eq!(
+            merged.dropped_messages_by_priority,
+            [
+                (Priority::Low, 7),
+                (Priority::Normal, 1),
+                (Priority::High, 5)
+            ]
+        );
+        assert_eq!(merged.connected_nodes, 15);
     }
 }
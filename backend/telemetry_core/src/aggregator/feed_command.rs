@@ -0,0 +1,354 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Feeds send us commands in one of two forms. The original text form looks like
+//! `subscribe:<genesis hash>`, and gets no response beyond the messages that the
+//! command results in. The JSON form wraps each command in an object carrying a
+//! request ID, eg `{"id":1,"cmd":"subscribe","chain":"0x..."}`, and every such
+//! command is answered with a [`crate::feed_message::CommandAck`] or a
+//! [`crate::feed_message::CommandError`] carrying the same ID.
+
+use super::node_filter::{FilteredSubscription, NodeFilter};
+use crate::feed_message::FeedNodeId;
+use common::node_types::BlockHash;
+use serde::Deserialize;
+
+/// Something that a feed has asked us to do.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FeedCommand {
+    /// An explicit ping message.
+    Ping { value: Box<str> },
+    /// Subscribe to a chain, to receive messages relating to it. Feeds can
+    /// subscribe to several chains at once, and can ask to only hear about the
+    /// nodes on a chain which match some filter.
+    Subscribe {
+        chain: BlockHash,
+        filter: Option<NodeFilter>,
+    },
+    /// Subscribe to a chain again after reconnecting, having seen the messages about
+    /// it up to the [`crate::feed_message::ChainSeq`] given. If we can't send just the
+    /// messages that were missed, this is the same as subscribing.
+    Resume { chain: BlockHash, seq: u64 },
+    /// The feed no longer wants messages about a chain.
+    Unsubscribe { chain: BlockHash },
+    /// Always send messages about this node, even if it doesn't match the
+    /// feed's filter for the chain.
+    Pin { chain: BlockHash, node: FeedNodeId },
+    /// Undo a [`FeedCommand::Pin`].
+    Unpin { chain: BlockHash, node: FeedNodeId },
+    /// Send the current state of a chain once, without subscribing to it.
+    Snapshot { chain: BlockHash },
+    /// Send the whole of a node's hardware and IO series again, for feeds which
+    /// missed some of the updates to them.
+    Series { chain: BlockHash, node: FeedNodeId },
+    /// Send updates to the feed at most this often. Between updates, messages that
+    /// later ones supersede are dropped, so that the feed gets one merged update.
+    SetUpdateRate { interval_ms: u64 },
+}
+
+/// The update rates that feeds can ask for by name rather than by interval.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum UpdateTier {
+    /// As often as we send updates to any feed.
+    Realtime,
+    /// A digest once a second.
+    OneSecond,
+    /// A digest every five seconds.
+    FiveSeconds,
+}
+
+impl UpdateTier {
+    const ALL: [UpdateTier; 3] = [
+        UpdateTier::Realtime,
+        UpdateTier::OneSecond,
+        UpdateTier::FiveSeconds,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            UpdateTier::Realtime => "realtime",
+            UpdateTier::OneSecond => "1s",
+            UpdateTier::FiveSeconds => "5s",
+        }
+    }
+
+    fn interval_ms(self) -> u64 {
+        match self {
+            UpdateTier::Realtime => 0,
+            UpdateTier::OneSecond => 1000,
+            UpdateTier::FiveSeconds => 5000,
+        }
+    }
+}
+
+impl TryFrom<String> for UpdateTier {
+    type Error = String;
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        Self::ALL
+            .into_iter()
+            .find(|t| t.name() == name)
+            .ok_or_else(|| format!("Unknown update rate '{name}'; expected realtime, 1s or 5s"))
+    }
+}
+
+impl FeedCommand {
+    /// The chain that the command is about, if any.
+    pub fn chain(&self) -> Option<BlockHash> {
+        match self {
+            FeedCommand::Subscribe { chain, .. }
+            | FeedCommand::Resume { chain, .. }
+            | FeedCommand::Unsubscribe { chain }
+            | FeedCommand::Pin { chain, .. }
+            | FeedCommand::Unpin { chain, .. }
+            | FeedCommand::Snapshot { chain }
+            | FeedCommand::Series { chain, .. } => Some(*chain),
+            FeedCommand::Ping { .. } | FeedCommand::SetUpdateRate { .. } => None,
+        }
+    }
+
+    /// Parse a command in the original `CMD:VALUE` text form.
+    pub fn from_text(s: &str) -> anyhow::Result<FeedCommand> {
+        let (cmd, value) = match s.find(':') {
+            Some(idx) => (&s[..idx], &s[idx + 1..]),
+            None => return Err(anyhow::anyhow!("Expecting format `CMD:CHAIN_NAME`")),
+        };
+        match cmd {
+            "ping" => Ok(FeedCommand::Ping {
+                value: value.into(),
+            }),
+            "subscribe" => Ok(FeedCommand::Subscribe {
+                chain: value.parse()?,
+                filter: None,
+            }),
+            "filter" => {
+                let sub: FilteredSubscription = serde_json::from_str(value)?;
+                Ok(FeedCommand::Subscribe {
+                    chain: sub.chain,
+                    filter: Some(sub.filter),
+                })
+            }
+            "unsubscribe" => Ok(FeedCommand::Unsubscribe {
+                chain: value.parse()?,
+            }),
+            "rate" => {
+                let tier = UpdateTier::try_from(value.to_owned()).map_err(anyhow::Error::msg)?;
+                Ok(FeedCommand::SetUpdateRate {
+                    interval_ms: tier.interval_ms(),
+                })
+            }
+            "resume" => {
+                let (chain, seq) = value
+                    .split_once(':')
+                    .ok_or_else(|| anyhow::anyhow!("Expecting format `resume:CHAIN:SEQ`"))?;
+                Ok(FeedCommand::Resume {
+                    chain: chain.parse()?,
+                    seq: seq.parse()?,
+                })
+            }
+            "series" => {
+                let (chain, node) = value
+                    .split_once(':')
+                    .ok_or_else(|| anyhow::anyhow!("Expecting format `series:CHAIN:NODE`"))?;
+                Ok(FeedCommand::Series {
+                    chain: chain.parse()?,
+                    node: node.parse()?,
+                })
+            }
+            _ => Err(anyhow::anyhow!("Command {} not recognised", cmd)),
+        }
+    }
+}
+
+/// A command in the JSON form.
+#[derive(Debug, PartialEq)]
+pub struct FeedRequest {
+    pub id: u64,
+    /// The command, or a description of what was wrong with it.
+    pub command: Result<FeedCommand, String>,
+}
+
+impl FeedRequest {
+    /// Parse a command in the JSON form. This fails only if we can't find a
+    /// request ID to respond to; other problems are handed back in
+    /// [`FeedRequest::command`] so that the feed can be told about them.
+    pub fn from_json(s: &str) -> anyhow::Result<FeedRequest> {
+        let value: serde_json::Value = serde_json::from_str(s)?;
+        let id = value
+            .get("id")
+            .and_then(|id| id.as_u64())
+            .ok_or_else(|| anyhow::anyhow!("Expecting a numeric request `id`"))?;
+        let command = serde_json::from_value::<JsonCommand>(value)
+            .map(Into::into)
+            .map_err(|e| e.to_string());
+        Ok(FeedRequest { id, command })
+    }
+}
+
+/// The commands that can be sent in the JSON form, as they're laid out there.
+#[derive(Deserialize)]
+#[serde(tag = "cmd", rename_all = "camelCase")]
+enum JsonCommand {
+    Ping { value: Box<str> },
+    Subscribe { chain: BlockHash },
+    Resume { chain: BlockHash, seq: u64 },
+    Unsubscribe { chain: BlockHash },
+    Filter(FilteredSubscription),
+    Pin { chain: BlockHash, node: FeedNodeId },
+    Unpin { chain: BlockHash, node: FeedNodeId },
+    Snapshot { chain: BlockHash },
+    Series { chain: BlockHash, node: FeedNodeId },
+    SetUpdateRate(UpdateRate),
+}
+
+/// Feeds can give an update rate as an interval or by name.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UpdateRate {
+    #[serde(rename_all = "camelCase")]
+    Interval {
+        interval_ms: u64,
+    },
+    Tier {
+        rate: UpdateTier,
+    },
+}
+
+impl From<JsonCommand> for FeedCommand {
+    fn from(cmd: JsonCommand) -> Self {
+        match cmd {
+            JsonCommand::Ping { value } => FeedCommand::Ping { value },
+            JsonCommand::Subscribe { chain } => FeedCommand::Subscribe {
+                chain,
+                filter: None,
+            },
+            JsonCommand::Resume { chain, seq } => FeedCommand::Resume { chain, seq },
+            JsonCommand::Unsubscribe { chain } => FeedCommand::Unsubscribe { chain },
+            JsonCommand::Filter(sub) => FeedCommand::Subscribe {
+                chain: sub.chain,
+                filter: Some(sub.filter),
+            },
+            JsonCommand::Pin { chain, node } => FeedCommand::Pin { chain, node },
+            JsonCommand::Unpin { chain, node } => FeedCommand::Unpin { chain, node },
+            JsonCommand::Snapshot { chain } => FeedCommand::Snapshot { chain },
+            JsonCommand::Series { chain, node } => FeedCommand::Series { chain, node },
+            JsonCommand::SetUpdateRate(UpdateRate::Interval { interval_ms }) => {
+                FeedCommand::SetUpdateRate { interval_ms }
+            }
+            JsonCommand::SetUpdateRate(UpdateRate::Tier { rate }) => FeedCommand::SetUpdateRate {
+                interval_ms: rate.interval_ms(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CHAIN: &str = "0x0000000000000000000000000000000000000000000000000000000000000001";
+
+    #[test]
+    fn text_commands_are_parsed() {
+        assert_eq!(
+            FeedCommand::from_text(&format!("subscribe:{CHAIN}")).unwrap(),
+            FeedCommand::Subscribe {
+                chain: BlockHash::from_low_u64_be(1),
+                filter: None
+            }
+        );
+        assert_eq!(
+            FeedCommand::from_text("ping:hello").unwrap(),
+            FeedCommand::Ping {
+                value: "hello".into()
+            }
+        );
+        assert_eq!(
+            FeedCommand::from_text(&format!("resume:{CHAIN}:1234")).unwrap(),
+            FeedCommand::Resume {
+                chain: BlockHash::from_low_u64_be(1),
+                seq: 1234
+            }
+        );
+        assert_eq!(
+            FeedCommand::from_text("rate:5s").unwrap(),
+            FeedCommand::SetUpdateRate { interval_ms: 5000 }
+        );
+        assert!(FeedCommand::from_text("rate:2s").is_err());
+        assert_eq!(
+            FeedCommand::from_text(&format!("series:{CHAIN}:3")).unwrap(),
+            FeedCommand::Series {
+                chain: BlockHash::from_low_u64_be(1),
+                node: 3
+            }
+        );
+        assert!(FeedCommand::from_text(&format!("resume:{CHAIN}")).is_err());
+        assert!(FeedCommand::from_text(&format!("series:{CHAIN}")).is_err());
+        assert!(FeedCommand::from_text("subscribe").is_err());
+        assert!(FeedCommand::from_text("pin:1").is_err());
+    }
+
+    #[test]
+    fn json_commands_are_parsed() {
+        let req = FeedRequest::from_json(&format!(
+            r#"{{"id":7,"cmd":"filter","chain":"{CHAIN}","validator":true}}"#
+        ))
+        .unwrap();
+        assert_eq!(
+            req,
+            FeedRequest {
+                id: 7,
+                command: Ok(FeedCommand::Subscribe {
+                    chain: BlockHash::from_low_u64_be(1),
+                    filter: Some(NodeFilter {
+                        validator: Some(true),
+                        ..Default::default()
+                    })
+                })
+            }
+        );
+
+        let req =
+            FeedRequest::from_json(r#"{"id":8,"cmd":"setUpdateRate","intervalMs":2000}"#).unwrap();
+        assert_eq!(
+            req.command,
+            Ok(FeedCommand::SetUpdateRate { interval_ms: 2000 })
+        );
+
+        let req =
+            FeedRequest::from_json(r#"{"id":9,"cmd":"setUpdateRate","rate":"realtime"}"#).unwrap();
+        assert_eq!(
+            req.command,
+            Ok(FeedCommand::SetUpdateRate { interval_ms: 0 })
+        );
+        let req = FeedRequest::from_json(r#"{"id":10,"cmd":"setUpdateRate","rate":"2s"}"#).unwrap();
+        assert!(req.command.is_err());
+    }
+
+    #[test]
+    fn bad_json_commands_can_still_be_answered() {
+        let req = FeedRequest::from_json(r#"{"id":9,"cmd":"subscribe","chain":"nope"}"#).unwrap();
+        assert_eq!(req.id, 9);
+        assert!(req.command.is_err());
+
+        let req = FeedRequest::from_json(r#"{"id":10,"cmd":"launch"}"#).unwrap();
+        assert!(req.command.is_err());
+
+        // Without an ID, there's no way to tell the feed what went wrong:
+        assert!(FeedRequest::from_json(r#"{"cmd":"subscribe","chain":"0x01"}"#).is_err());
+        assert!(FeedRequest::from_json("{not json").is_err());
+    }
+}
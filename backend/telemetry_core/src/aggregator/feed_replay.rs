@@ -0,0 +1,183 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::feed_message::{FeedBatch, FeedBytes, FeedFormat};
+use common::node_types::BlockHash;
+use common::time;
+use std::collections::{HashMap, VecDeque};
+
+/// Each batch of messages broadcast to the feeds of a chain is numbered, and the most
+/// recent batches are kept, so that a feed which reconnects can be sent just the
+/// batches that it missed rather than everything about the chain again.
+pub struct FeedReplay {
+    /// How many batches to keep for each chain.
+    max_batches: usize,
+    chains: HashMap<BlockHash, ChainReplay>,
+}
+
+struct ChainReplay {
+    /// The number given to the most recent batch.
+    last_seq: u64,
+    /// Every batch numbered after this is in `batches`.
+    complete_after: u64,
+    batches: VecDeque<(u64, FeedBytes)>,
+}
+
+impl ChainReplay {
+    fn new() -> Self {
+        // Start numbering from the current time, so that numbers handed out before
+        // a restart are unlikely to be mistaken for ones handed out after it.
+        let start = time::now();
+        ChainReplay {
+            last_seq: start,
+            complete_after: start,
+            batches: VecDeque::new(),
+        }
+    }
+}
+
+impl FeedReplay {
+    pub fn new(max_batches: usize) -> Self {
+        FeedReplay {
+            max_batches,
+            chains: HashMap::new(),
+        }
+    }
+
+    /// The number given to the most recent batch of messages about a chain.
+    pub fn last_seq(&mut self, genesis_hash: BlockHash) -> u64 {
+        self.chain(genesis_hash).last_seq
+    }
+
+    /// Hand out the number for the next batch of messages about a chain.
+    pub fn next_seq(&mut self, genesis_hash: BlockHash) -> u64 {
+        let chain = self.chain(genesis_hash);
+        chain.last_seq += 1;
+        chain.last_seq
+    }
+
+    /// Keep a batch of messages that was just broadcast, forgetting the oldest batch
+    /// if we're keeping too many.
+    pub fn push(&mut self, genesis_hash: BlockHash, seq: u64, bytes: FeedBytes) {
+        let max_batches = self.max_batches;
+        let chain = self.chain(genesis_hash);
+        if max_batches == 0 {
+            chain.complete_after = seq;
+            return;
+        }
+        chain.batches.push_back((seq, bytes));
+        while chain.batches.len() > max_batches {
+            if let Some((seq, _)) = chain.batches.pop_front() {
+                chain.complete_after = seq;
+            }
+        }
+    }
+
+    /// The batches about a chain numbered after `seq`, in the format given. Returns
+    /// `None` if we don't have every one of them.
+    pub fn since(
+        &self,
+        genesis_hash: &BlockHash,
+        seq: u64,
+        format: FeedFormat,
+    ) -> Option<Vec<FeedBatch>> {
+        let chain = self.chains.get(genesis_hash)?;
+        if seq < chain.complete_after || seq > chain.last_seq {
+            return None;
+        }
+        chain
+            .batches
+            .iter()
+            .filter(|(batch_seq, _)| *batch_seq > seq)
+            .map(|(_, bytes)| bytes.batch(format))
+            .collect()
+    }
+
+    /// Forget the batches kept for a chain, for instance because it's gone away. The
+    /// numbering carries on from where it was if the chain comes back.
+    pub fn forget(&mut self, genesis_hash: &BlockHash) {
+        if let Some(chain) = self.chains.get_mut(genesis_hash) {
+            chain.batches.clear();
+            chain.complete_after = chain.last_seq;
+        }
+    }
+
+    fn chain(&mut self, genesis_hash: BlockHash) -> &mut ChainReplay {
+        self.chains
+            .entry(genesis_hash)
+            .or_insert_with(ChainReplay::new)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::feed_message::{self, FeedMessageSerializer};
+
+    fn batch(genesis_hash: BlockHash, node_id: usize) -> FeedBytes {
+        let mut ser = FeedMessageSerializer::for_chain([FeedFormat::Json], genesis_hash);
+        ser.push(feed_message::StaleNode(node_id));
+        ser.into_finalized().unwrap()
+    }
+
+    #[test]
+    fn missed_batches_are_replayed_while_kept() {
+        let hash = BlockHash::from_low_u64_be(1);
+        let mut replay = FeedReplay::new(2);
+        let start = replay.last_seq(hash);
+
+        for node_id in 0..3 {
+            let seq = replay.next_seq(hash);
+            assert_eq!(seq, start + 1 + node_id as u64);
+            replay.push(hash, seq, batch(hash, node_id));
+        }
+
+        // Only the last two batches are kept:
+        assert!(replay.since(&hash, start, FeedFormat::Json).is_none());
+        assert_eq!(
+            replay
+                .since(&hash, start + 1, FeedFormat::Json)
+                .unwrap()
+                .len(),
+            2
+        );
+        let missed = replay.since(&hash, start + 2, FeedFormat::Json).unwrap();
+        assert_eq!(missed.len(), 1);
+        assert_eq!(
+            missed[0].bytes(),
+            batch(hash, 2).get(FeedFormat::Json).unwrap()
+        );
+        assert!(replay
+            .since(&hash, start + 3, FeedFormat::Json)
+            .unwrap()
+            .is_empty());
+
+        // Numbers we've not handed out yet, formats we've not kept, and chains we
+        // know nothing about can't be resumed from:
+        assert!(replay.since(&hash, start + 4, FeedFormat::Json).is_none());
+        assert!(replay
+            .since(&hash, start + 2, FeedFormat::MessagePack)
+            .is_none());
+        assert!(replay
+            .since(&BlockHash::zero(), start, FeedFormat::Json)
+            .is_none());
+
+        // Forgetting a chain carries on the numbering:
+        replay.forget(&hash);
+        assert!(replay.since(&hash, start + 2, FeedFormat::Json).is_none());
+        assert_eq!(replay.next_seq(hash), start + 4);
+    }
+}
@@ -0,0 +1,520 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! The aggregator keeps track of every chain and node, and serializes the messages
+//! about them once. Feeds are split across a handful of workers, which take those
+//! messages and do whatever needs doing for each of their feeds, such as leaving out
+//! the nodes that a feed has filtered out, before sending them on.
+
+use super::aggregator::ConnId;
+use super::inner_loop::ToFeedWebsocket;
+use super::node_filter::{FilterDetails, NodeFilter};
+use crate::feed_message::{
+    self, FeedBatch, FeedBytes, FeedFormat, FeedMessageSerializer, FeedNodeId, MessageSubject,
+};
+use common::{node_types::BlockHash, MultiMap};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// The aggregator sends these to the worker responsible for a feed. Everything
+/// about a feed goes through the one worker, in order, so feeds see messages in
+/// the order that the aggregator sent them.
+#[derive(Debug)]
+pub enum ToFeedWorker {
+    /// A feed has connected.
+    AddFeed {
+        feed_id: ConnId,
+        channel: flume::Sender<ToFeedWebsocket>,
+        format: FeedFormat,
+    },
+    /// A feed has disconnected.
+    RemoveFeed { feed_id: ConnId },
+    /// Send these to a single feed.
+    SendToFeed {
+        feed_id: ConnId,
+        msgs: Vec<ToFeedWebsocket>,
+    },
+    /// The feed has subscribed to a chain. Send it these messages (which tell it the
+    /// state of the chain), and then whatever is broadcast about the chain. If a
+    /// filter is given, only messages about the nodes that it lets through are sent.
+    Subscribe {
+        feed_id: ConnId,
+        chain: BlockHash,
+        msgs: Vec<FeedBatch>,
+        filter: Option<ChainFilter>,
+    },
+    /// The feed has unsubscribed from a chain. Send it these messages to say so.
+    Unsubscribe {
+        feed_id: ConnId,
+        chain: BlockHash,
+        msgs: Vec<FeedBatch>,
+    },
+    /// Always send the feed messages about a node on a chain it has filtered. If it's
+    /// not already been told about the node, these messages tell it.
+    Pin {
+        feed_id: ConnId,
+        chain: BlockHash,
+        node: FeedNodeId,
+        msgs: Vec<FeedBatch>,
+    },
+    /// Undo a [`ToFeedWorker::Pin`]. If the node (described here, if it still
+    /// exists) doesn't match the feed's filter, the feed is told to forget about it.
+    Unpin {
+        feed_id: ConnId,
+        chain: BlockHash,
+        node: FeedNodeId,
+        details: Option<FilterDetails>,
+    },
+    /// Send the feed these messages about the state of a chain, sticking to the nodes
+    /// that it knows about if it has filtered the chain.
+    Snapshot {
+        feed_id: ConnId,
+        chain: BlockHash,
+        msgs: Vec<FeedBytes>,
+    },
+    /// Send these messages to every feed subscribed to a chain. Any nodes that the
+    /// messages add are described, so that filters can be applied to them.
+    BroadcastToChain {
+        chain: BlockHash,
+        bytes: FeedBytes,
+        added: Arc<HashMap<FeedNodeId, FilterDetails>>,
+    },
+    /// Send these messages to every feed.
+    BroadcastToAll { bytes: FeedBytes },
+    /// Hand back how many messages are queued up for each feed, by feed ID.
+    GatherQueueLens(flume::Sender<Vec<(u64, usize)>>),
+}
+
+/// Sends messages on to the feeds that it's responsible for.
+pub struct FeedWorker {
+    /// Keep track of how to send messages out to feeds.
+    feed_channels: HashMap<ConnId, FeedChannel>,
+    /// Which feeds are subscribed to a given chain?
+    chain_to_feed_conn_ids: MultiMap<BlockHash, ConnId>,
+    /// Feeds with this many messages queued up for them are disconnected.
+    max_backlog: usize,
+}
+
+impl FeedWorker {
+    /// Spawn a worker, returning a channel to send it messages. The worker ends
+    /// once every sender is dropped.
+    pub fn spawn(max_backlog: usize) -> flume::Sender<ToFeedWorker> {
+        let (tx, rx) = flume::unbounded();
+        let mut worker = FeedWorker {
+            feed_channels: HashMap::new(),
+            chain_to_feed_conn_ids: MultiMap::new(),
+            max_backlog,
+        };
+        tokio::spawn(async move {
+            while let Ok(msg) = rx.recv_async().await {
+                worker.handle(msg);
+            }
+        });
+        tx
+    }
+
+    fn handle(&mut self, msg: ToFeedWorker) {
+        match msg {
+            ToFeedWorker::AddFeed {
+                feed_id,
+                channel,
+                format,
+            } => {
+                let feed_channel = FeedChannel {
+                    tx: Some(channel),
+                    max_backlog: self.max_backlog,
+                    format,
+                    filters: HashMap::new(),
+                };
+                self.feed_channels.insert(feed_id, feed_channel);
+            }
+            ToFeedWorker::RemoveFeed { feed_id } => {
+                self.chain_to_feed_conn_ids.remove_value(&feed_id);
+                self.feed_channels.remove(&feed_id);
+            }
+            ToFeedWorker::SendToFeed { feed_id, msgs } => {
+                if let Some(feed) = self.feed_channels.get_mut(&feed_id) {
+                    for msg in msgs {
+                        feed.send_msg(msg);
+                    }
+                }
+            }
+            ToFeedWorker::Subscribe {
+                feed_id,
+                chain,
+                msgs,
+                filter,
+            } => {
+                let feed = match self.feed_channels.get_mut(&feed_id) {
+                    Some(feed) => feed,
+                    None => return,
+                };
+                for batch in msgs {
+                    feed.send_batch(batch);
+                }
+                match filter {
+                    Some(filter) => feed.filters.insert(chain, filter),
+                    None => feed.filters.remove(&chain),
+                };
+                self.chain_to_feed_conn_ids.insert(chain, feed_id);
+            }
+            ToFeedWorker::Unsubscribe {
+                feed_id,
+                chain,
+                msgs,
+            } => {
+                self.chain_to_feed_conn_ids.remove(&chain, &feed_id);
+                if let Some(feed) = self.feed_channels.get_mut(&feed_id) {
+                    feed.filters.remove(&chain);
+                    for batch in msgs {
+                        feed.send_batch(batch);
+                    }
+                }
+            }
+            ToFeedWorker::Pin {
+                feed_id,
+                chain,
+                node,
+                msgs,
+            } => {
+                let feed = match self.feed_channels.get_mut(&feed_id) {
+                    Some(feed) => feed,
+                    None => return,
+                };
+                // Feeds that haven't filtered the chain hear about every node anyway:
+                let chain_filter = match feed.filters.get_mut(&chain) {
+                    Some(chain_filter) => chain_filter,
+                    None => return,
+                };
+                chain_filter.pinned.insert(node);
+
+                // Tell the feed about the node if its filter had left it out:
+                if chain_filter.nodes.insert(node) {
+                    for batch in msgs {
+                        feed.send_batch(batch);
+                    }
+                }
+            }
+            ToFeedWorker::Unpin {
+                feed_id,
+                chain,
+                node,
+                details,
+            } => {
+                let feed = match self.feed_channels.get_mut(&feed_id) {
+                    Some(feed) => feed,
+                    None => return,
+                };
+                let chain_filter = match feed.filters.get_mut(&chain) {
+                    Some(chain_filter) => chain_filter,
+                    None => return,
+                };
+                if !chain_filter.pinned.remove(&node) {
+                    return;
+                }
+
+                // Tell the feed to forget about the node if its filter would have left it out:
+                let still_matches = details.is_some_and(|n| chain_filter.filter.matches(&n));
+                if !still_matches && chain_filter.nodes.remove(&node) {
+                    let mut feed_serializer =
+                        FeedMessageSerializer::for_chain([feed.format], chain);
+                    feed_serializer.push(feed_message::RemovedNode(node));
+                    if let Some(bytes) = feed_serializer.into_finalized() {
+                        feed.send(&bytes);
+                    }
+                }
+            }
+            ToFeedWorker::Snapshot {
+                feed_id,
+                chain,
+                msgs,
+            } => {
+                let feed = match self.feed_channels.get_mut(&feed_id) {
+                    Some(feed) => feed,
+                    None => return,
+                };
+                // If the feed has filtered the chain, stick to the nodes it knows about:
+                let nodes = feed.filters.get(&chain).map(|f| f.nodes.clone());
+                for bytes in msgs {
+                    let batch = match &nodes {
+                        Some(nodes) => bytes.filtered(feed.format, |subject| match subject {
+                            MessageSubject::NodeAdded(id)
+                            | MessageSubject::NodeRemoved(id)
+                            | MessageSubject::Node(id) => nodes.contains(&id),
+                            MessageSubject::Context | MessageSubject::Chain => true,
+                        }),
+                        None => bytes.batch(feed.format),
+                    };
+                    if let Some(batch) = batch {
+                        feed.send_batch(batch);
+                    }
+                }
+            }
+            ToFeedWorker::BroadcastToChain {
+                chain,
+                bytes,
+                added,
+            } => {
+                self.broadcast_to_chain_feeds(&chain, &bytes, &added);
+            }
+            ToFeedWorker::BroadcastToAll { bytes } => {
+                for feed in self.feed_channels.values_mut() {
+                    feed.send(&bytes);
+                }
+            }
+            ToFeedWorker::GatherQueueLens(tx) => {
+                let queue_lens = self
+                    .feed_channels
+                    .iter()
+                    .map(|(&id, feed)| (id.into(), feed.queue_len()))
+                    .collect();
+                // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+                let _ = tx.send(queue_lens);
+            }
+        }
+    }
+
+    /// Send a message to all chain feeds. Feeds which have filtered the nodes on the
+    /// chain are only sent the messages about nodes which match their filter.
+    fn broadcast_to_chain_feeds(
+        &mut self,
+        genesis_hash: &BlockHash,
+        bytes: &FeedBytes,
+        added: &HashMap<FeedNodeId, FilterDetails>,
+    ) {
+        let feeds = match self.chain_to_feed_conn_ids.get_values(genesis_hash) {
+            Some(feeds) => feeds,
+            None => return,
+        };
+
+        for feed_id in feeds {
+            let feed = match self.feed_channels.get_mut(feed_id) {
+                Some(feed) => feed,
+                None => continue,
+            };
+            let chain_filter = match feed.filters.get_mut(genesis_hash) {
+                Some(chain_filter) => chain_filter,
+                None => {
+                    feed.send(bytes);
+                    continue;
+                }
+            };
+
+            let mut no_longer_matching = Vec::new();
+            let filtered = bytes.filtered(feed.format, |subject| match subject {
+                MessageSubject::Context | MessageSubject::Chain => true,
+                MessageSubject::NodeAdded(id) => {
+                    let wanted = chain_filter.pinned.contains(&id)
+                        || added
+                            .get(&id)
+                            .is_some_and(|n| chain_filter.filter.matches(n));
+                    if wanted {
+                        chain_filter.nodes.insert(id);
+                        true
+                    } else {
+                        // The node's details may have changed such that it no longer
+                        // matches, in which case the feed should forget about it:
+                        if chain_filter.nodes.remove(&id) {
+                            no_longer_matching.push(id);
+                        }
+                        false
+                    }
+                }
+                MessageSubject::NodeRemoved(id) => {
+                    // The ID may be handed to a different node next:
+                    chain_filter.pinned.remove(&id);
+                    chain_filter.nodes.remove(&id)
+                }
+                MessageSubject::Node(id) => chain_filter.nodes.contains(&id),
+            });
+            if let Some(filtered) = filtered {
+                feed.send_batch(filtered);
+            }
+
+            if !no_longer_matching.is_empty() {
+                let mut feed_serializer =
+                    FeedMessageSerializer::for_chain([feed.format], *genesis_hash);
+                for id in no_longer_matching {
+                    feed_serializer.push(feed_message::RemovedNode(id));
+                }
+                if let Some(bytes) = feed_serializer.into_finalized() {
+                    feed.send(&bytes);
+                }
+            }
+        }
+    }
+}
+
+/// How to send messages to a feed.
+struct FeedChannel {
+    /// This is dropped if the feed falls too far behind, which closes the connection.
+    tx: Option<flume::Sender<ToFeedWebsocket>>,
+    /// How many messages can be queued up for the feed before we give up on it.
+    max_backlog: usize,
+    /// The format that the feed wants messages in.
+    format: FeedFormat,
+    /// The chains that the feed only wants to hear about some of the nodes of.
+    filters: HashMap<BlockHash, ChainFilter>,
+}
+
+/// Which nodes on a chain a feed wants to hear about.
+#[derive(Debug)]
+pub struct ChainFilter {
+    pub filter: NodeFilter,
+    /// The nodes which the feed knows about; those which matched the filter when
+    /// the feed was last told about them, and any that it has pinned.
+    pub nodes: HashSet<FeedNodeId>,
+    /// Nodes which the feed wants to hear about whether or not they match the filter.
+    pub pinned: HashSet<FeedNodeId>,
+}
+
+impl FeedChannel {
+    /// Send the messages given to the feed, in its format.
+    fn send(&mut self, bytes: &FeedBytes) {
+        if let Some(batch) = bytes.batch(self.format) {
+            self.send_batch(batch);
+        }
+    }
+
+    fn send_batch(&mut self, batch: FeedBatch) {
+        self.send_msg(ToFeedWebsocket::Messages(batch));
+    }
+
+    /// Send a message to the feed. The feed's connection merges messages which are
+    /// queued up for it, but if it still can't keep up and the queue grows too long,
+    /// we stop sending anything more and let the connection close once it has sent
+    /// what's queued.
+    fn send_msg(&mut self, msg: ToFeedWebsocket) {
+        let tx = match &self.tx {
+            Some(tx) => tx,
+            None => return,
+        };
+        if tx.len() >= self.max_backlog {
+            log::warn!(
+                "Disconnecting feed with {} messages queued up for it",
+                tx.len()
+            );
+            self.tx = None;
+            return;
+        }
+        let _ = tx.send(msg);
+    }
+
+    /// How many messages are queued up for the feed.
+    fn queue_len(&self) -> usize {
+        self.tx.as_ref().map_or(0, |tx| tx.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::test_utils::{self, node_details};
+    use crate::state::Node;
+    use common::node_types::NodeDetails;
+
+    fn node(name: &str) -> Node {
+        test_utils::node(NodeDetails {
+            name: name.into(),
+            ..node_details()
+        })
+    }
+
+    fn received(rx: &flume::Receiver<ToFeedWebsocket>) -> Vec<String> {
+        rx.drain()
+            .map(|msg| match msg {
+                ToFeedWebsocket::Messages(batch) => {
+                    String::from_utf8(batch.bytes().to_vec()).unwrap()
+                }
+                ToFeedWebsocket::SetUpdateRate(_) => panic!("Expected messages"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chain_broadcasts_are_filtered_for_each_feed() {
+        let hash = BlockHash::from_low_u64_be(1);
+        let mut worker = FeedWorker {
+            feed_channels: HashMap::new(),
+            chain_to_feed_conn_ids: MultiMap::new(),
+            max_backlog: 100,
+        };
+
+        // One feed hears about every node, and the other only about "ops-" nodes:
+        let (all_tx, all_rx) = flume::unbounded();
+        let (ops_tx, ops_rx) = flume::unbounded();
+        let ops_filter = NodeFilter {
+            name_prefix: Some("ops-".to_owned()),
+            ..Default::default()
+        };
+        for (id, channel, filter) in [(1, all_tx, None), (2, ops_tx, Some(ops_filter))] {
+            let feed_id = ConnId::from(id);
+            worker.handle(ToFeedWorker::AddFeed {
+                feed_id,
+                channel,
+                format: FeedFormat::Json,
+            });
+            worker.handle(ToFeedWorker::Subscribe {
+                feed_id,
+                chain: hash,
+                msgs: Vec::new(),
+                filter: filter.map(|filter| ChainFilter {
+                    filter,
+                    nodes: HashSet::new(),
+                    pinned: HashSet::new(),
+                }),
+            });
+        }
+
+        for (id, name) in [(0, "ops-1"), (1, "dev-1")] {
+            let node = node(name);
+            let mut feed_serializer = FeedMessageSerializer::for_chain([FeedFormat::Json], hash);
+            feed_serializer.push(feed_message::AddedNode(id, &node, false));
+            worker.handle(ToFeedWorker::BroadcastToChain {
+                chain: hash,
+                bytes: feed_serializer.into_finalized().unwrap(),
+                added: Arc::new(HashMap::from([(id, FilterDetails::new(&node))])),
+            });
+        }
+
+        assert_eq!(received(&all_rx).len(), 2);
+        let ops_msgs = received(&ops_rx);
+        assert_eq!(ops_msgs.len(), 1);
+        assert!(ops_msgs[0].contains("ops-1"));
+
+        // Pinning the other node sends it to the filtered feed, and unpinning it
+        // tells the feed to forget about it again:
+        worker.handle(ToFeedWorker::Pin {
+            feed_id: ConnId::from(2),
+            chain: hash,
+            node: 1,
+            msgs: Vec::new(),
+        });
+        assert!(worker.feed_channels[&ConnId::from(2)].filters[&hash]
+            .nodes
+            .contains(&1));
+        worker.handle(ToFeedWorker::Unpin {
+            feed_id: ConnId::from(2),
+            chain: hash,
+            node: 1,
+            details: Some(FilterDetails::new(&node("dev-1"))),
+        });
+        let ops_msgs = received(&ops_rx);
+        assert_eq!(ops_msgs.len(), 1);
+        assert!(ops_msgs[0].ends_with(",4,1]"));
+        assert!(received(&all_rx).is_empty());
+    }
+}
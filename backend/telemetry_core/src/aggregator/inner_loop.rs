@@ -15,24 +15,34 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use super::aggregator::ConnId;
-use crate::endpoints::{BlockHistory, ChainOverview, NodeList};
-use crate::feed_message::{self, FeedMessageSerializer};
-use crate::state::{self, NodeId, State};
+use super::feed_command::{FeedCommand, FeedRequest};
+use super::feed_replay::FeedReplay;
+use super::feed_worker::{ChainFilter, FeedWorker, ToFeedWorker};
+use super::node_filter::FilterDetails;
+use crate::endpoints::{Authorities, BlockHistory, ChainOverview, HardwareCompliance, NodeList};
+use crate::feed_message::{
+    self, FeedBatch, FeedBytes, FeedFormat, FeedMessageSerializer, FeedNodeId,
+};
+use crate::state::{self, ChainSnapshot, Node, NodeId, State, StateChain, StateSnapshot};
 use crate::{find_location, AggregatorOpts};
 use bimap::BiMap;
 use common::{
     internal_messages::{self, MuteReason, ShardNodeId},
-    node_message,
+    node_message::{self, Priority},
     node_types::BlockHash,
-    time, MultiMapUnique,
+    time, MeanListChange, MultiMap, MultiMapUnique,
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{
     atomic::{AtomicU64, Ordering},
     Arc,
 };
+use std::time::Duration;
 use std::{net::IpAddr, str::FromStr};
 
+/// The longest that feeds can ask to wait between updates.
+const MAX_FEED_UPDATE_INTERVAL_MS: u64 = 60_000;
+
 /// Incoming messages come via subscriptions, and end up looking like this.
 #[derive(Clone, Debug)]
 pub enum ToAggregator {
@@ -45,6 +55,20 @@ pub enum ToAggregator {
     GatherOverview(flume::Sender<HashMap<BlockHash, ChainOverview>>),
     GatherBlocks(flume::Sender<HashMap<BlockHash, BlockHistory>>),
     GatherNodeList(flume::Sender<HashMap<BlockHash, NodeList>>),
+    GatherHardwareCompliance(flume::Sender<HashMap<BlockHash, HardwareCompliance>>),
+    GatherAuthorities(flume::Sender<HashMap<BlockHash, Authorities>>),
+    /// Hand back a snapshot of the history of every chain.
+    GatherChainSnapshots(flume::Sender<Vec<ChainSnapshot>>),
+    /// Seed chains with this history as they are created. This is ignored once
+    /// any shards have connected, since at that point our own history is what counts.
+    SeedChains(Vec<ChainSnapshot>),
+    /// Hand back a snapshot of every chain and node.
+    GatherStateSnapshot(flume::Sender<StateSnapshot>),
+    /// Restore chains and nodes from a snapshot. Restored nodes are pending until
+    /// a shard adds them again.
+    RestoreState(StateSnapshot),
+    /// Remove any restored nodes that are still pending.
+    ReapPendingNodes,
 }
 
 /// An incoming shard connection can send these messages to the aggregator.
@@ -92,12 +116,16 @@ pub enum FromFeedWebsocket {
     /// progress.
     Initialize {
         channel: flume::Sender<ToFeedWebsocket>,
+        format: FeedFormat,
     },
-    /// The feed can subscribe to a chain to receive
-    /// messages relating to it.
-    Subscribe { chain: BlockHash },
-    /// An explicit ping message.
-    Ping { value: Box<str> },
+    /// A command from the feed. Commands sent in the JSON form carry a request
+    /// ID, and are answered with an acknowledgement or error carrying the same ID.
+    Command {
+        request_id: Option<u64>,
+        command: FeedCommand,
+    },
+    /// A command in the JSON form that we couldn't make sense of.
+    InvalidCommand { request_id: u64, reason: String },
     /// The feed is disconnected.
     Disconnected,
 }
@@ -111,6 +139,9 @@ pub struct Metrics {
     pub chains_subscribed_to: usize,
     /// Number of subscribed feeds.
     pub subscribed_feeds: usize,
+    /// The connection IDs of the subscribed feeds. A feed has the same ID in every
+    /// aggregator, so these tell us which feeds are subscribed to chains in several.
+    pub subscribed_feed_ids: Vec<ConnId>,
     /// How many messages are currently queued up in internal channels
     /// waiting to be sent out to feeds.
     pub total_messages_to_feeds: usize,
@@ -120,38 +151,51 @@ pub struct Metrics {
     pub total_messages_to_aggregator: u64,
     /// How many (non-critical) messages have been dropped by the aggregator because it was overwhelmed.
     pub dropped_messages_to_aggregator: u64,
+    /// How many messages of each priority have been dropped by the aggregator.
+    pub dropped_messages_by_priority: Vec<(Priority, u64)>,
     /// How many nodes are currently known to this aggregator.
     pub connected_nodes: usize,
     /// How many feeds are currently connected to this aggregator.
     pub connected_feeds: usize,
     /// How many shards are currently connected to this aggregator.
     pub connected_shards: usize,
+    /// How many batches of messages are queued up waiting to be sent out to each
+    /// feed, by feed connection ID.
+    pub feed_queue_lens: Vec<(u64, usize)>,
 }
 
-// The frontend sends text based commands; parse them into these messages:
+// Feeds send commands either in a `CMD:VALUE` text form or as JSON objects; parse
+// them into these messages:
 impl FromStr for FromFeedWebsocket {
     type Err = anyhow::Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (cmd, value) = match s.find(':') {
-            Some(idx) => (&s[..idx], &s[idx + 1..]),
-            None => return Err(anyhow::anyhow!("Expecting format `CMD:CHAIN_NAME`")),
-        };
-        match cmd {
-            "ping" => Ok(FromFeedWebsocket::Ping {
-                value: value.into(),
-            }),
-            "subscribe" => Ok(FromFeedWebsocket::Subscribe {
-                chain: value.parse()?,
-            }),
-            _ => return Err(anyhow::anyhow!("Command {} not recognised", cmd)),
+        if !s.trim_start().starts_with('{') {
+            return Ok(FromFeedWebsocket::Command {
+                request_id: None,
+                command: FeedCommand::from_text(s)?,
+            });
         }
+
+        let request = FeedRequest::from_json(s)?;
+        Ok(match request.command {
+            Ok(command) => FromFeedWebsocket::Command {
+                request_id: Some(request.id),
+                command,
+            },
+            Err(reason) => FromFeedWebsocket::InvalidCommand {
+                request_id: request.id,
+                reason,
+            },
+        })
     }
 }
 
 /// The aggregator can send these messages back to a feed connection.
 #[derive(Clone, Debug)]
 pub enum ToFeedWebsocket {
-    Bytes(bytes::Bytes),
+    Messages(FeedBatch),
+    /// The feed asked to be sent updates at most this often.
+    SetUpdateRate(Duration),
 }
 
 /// Instances of this are responsible for handling incoming and
@@ -162,14 +206,25 @@ pub struct InnerLoop {
     /// We maintain a mapping between NodeId and ConnId+LocalId, so that we know
     /// which messages are about which nodes.
     node_ids: BiMap<NodeId, (ConnId, ShardNodeId)>,
-
-    /// Keep track of how to send messages out to feeds.
-    feed_channels: HashMap<ConnId, flume::Sender<ToFeedWebsocket>>,
+    /// Which nodes arrived via each shard connection, so that we can find them
+    /// quickly when a shard disconnects. Kept in step with `node_ids`.
+    shard_node_ids: MultiMapUnique<ConnId, NodeId>,
+
+    /// The format that each connected feed wants messages in.
+    feeds: HashMap<ConnId, FeedFormat>,
+    /// Feeds are split across these workers, which send messages on to them.
+    feed_workers: Vec<flume::Sender<ToFeedWorker>>,
+    /// How many feeds want messages in each format, so that we only serialize
+    /// messages to the formats that are in use.
+    feed_formats: FeedFormatCounts,
     /// Keep track of how to send messages out to shards.
     shard_channels: HashMap<ConnId, flume::Sender<ToShardWebsocket>>,
 
     /// Which feeds are subscribed to a given chain?
-    chain_to_feed_conn_ids: MultiMapUnique<BlockHash, ConnId>,
+    chain_to_feed_conn_ids: MultiMap<BlockHash, ConnId>,
+    /// The most recent messages sent to the feeds of each chain, so that feeds
+    /// which reconnect can be sent just the ones they missed.
+    feed_replay: FeedReplay,
 
     /// Send messages here to make geographical location requests.
     tx_to_locator: flume::Sender<(NodeId, IpAddr)>,
@@ -187,11 +242,22 @@ impl InnerLoop {
     /// Create a new inner loop handler with the various state it needs.
     pub fn new(tx_to_locator: flume::Sender<(NodeId, IpAddr)>, opts: AggregatorOpts) -> Self {
         InnerLoop {
-            node_state: State::new(opts.denylist, opts.max_third_party_nodes),
+            node_state: State::new(
+                opts.denylist,
+                opts.max_third_party_nodes,
+                opts.first_party_networks,
+                opts.chain_opts,
+            ),
             node_ids: BiMap::new(),
-            feed_channels: HashMap::new(),
+            shard_node_ids: MultiMapUnique::new(),
+            feeds: HashMap::new(),
+            feed_workers: (0..opts.feed_workers)
+                .map(|_| FeedWorker::spawn(opts.feed_max_backlog))
+                .collect(),
+            feed_formats: FeedFormatCounts::new(opts.feed_replay_len > 0),
             shard_channels: HashMap::new(),
-            chain_to_feed_conn_ids: MultiMapUnique::new(),
+            chain_to_feed_conn_ids: MultiMap::new(),
+            feed_replay: FeedReplay::new(opts.feed_replay_len),
             tx_to_locator,
             max_queue_len: opts.max_queue_len,
             expose_node_details: opts.expose_node_details,
@@ -203,8 +269,9 @@ impl InnerLoop {
         let max_queue_len = self.max_queue_len;
         let (metered_tx, metered_rx) = flume::unbounded();
 
-        // Keep count of the number of dropped/total messages for the sake of metric reporting
-        let dropped_messages = Arc::new(AtomicU64::new(0));
+        // Keep count of the number of dropped (by priority) and total messages for the sake
+        // of metric reporting
+        let dropped_messages: Arc<[AtomicU64; Priority::ALL.len()]> = Arc::default();
         let total_messages = Arc::new(AtomicU64::new(0));
 
         // Actually handle all of our messages, but before we get here, we
@@ -227,12 +294,42 @@ impl InnerLoop {
                     ToAggregator::GatherMetrics(tx) => self.handle_gather_metrics(
                         tx,
                         metered_rx.len(),
-                        dropped_messages2.load(Ordering::Relaxed),
+                        Priority::ALL
+                            .into_iter()
+                            .map(|p| (p, dropped_messages2[p as usize].load(Ordering::Relaxed)))
+                            .collect(),
                         total_messages2.load(Ordering::Relaxed),
                     ),
                     ToAggregator::GatherOverview(tx) => self.handle_gather_overview_endpoint(tx),
                     ToAggregator::GatherBlocks(tx) => self.handle_gather_block_history_endpoint(tx),
                     ToAggregator::GatherNodeList(tx) => self.handle_gather_node_list_endpoint(tx),
+                    ToAggregator::GatherHardwareCompliance(tx) => {
+                        self.handle_gather_hardware_compliance_endpoint(tx)
+                    }
+                    ToAggregator::GatherAuthorities(tx) => {
+                        self.handle_gather_authorities_endpoint(tx)
+                    }
+                    ToAggregator::GatherChainSnapshots(tx) => {
+                        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+                        let _ = tx.send(self.node_state.chain_snapshots());
+                    }
+                    ToAggregator::SeedChains(snapshots) => {
+                        if self.shard_channels.is_empty() {
+                            self.node_state.set_chain_seeds(snapshots);
+                        }
+                    }
+                    ToAggregator::GatherStateSnapshot(tx) => {
+                        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+                        let _ = tx.send(self.node_state.snapshot());
+                    }
+                    ToAggregator::RestoreState(snapshot) => {
+                        let node_ids = self.node_state.restore(snapshot);
+                        self.broadcast_restored_nodes(node_ids);
+                    }
+                    ToAggregator::ReapPendingNodes => {
+                        let node_ids = self.node_state.take_pending_nodes();
+                        self.remove_nodes_and_broadcast_result(node_ids);
+                    }
                 }
             }
         });
@@ -240,17 +337,19 @@ impl InnerLoop {
         while let Ok(msg) = rx_from_external.recv_async().await {
             total_messages.fetch_add(1, Ordering::Relaxed);
 
-            // ignore node updates if we have too many messages to handle, in an attempt
-            // to reduce the queue length back to something reasonable, lest it get out of
-            // control and start consuming a load of memory.
-            if metered_tx.len() > max_queue_len {
-                if matches!(
-                    msg,
-                    ToAggregator::FromShardWebsocket(.., FromShardWebsocket::Update { .. })
-                ) {
+            // ignore less important node updates if we have too many messages to handle, in
+            // an attempt to reduce the queue length back to something reasonable, lest it get
+            // out of control and start consuming a load of memory.
+            if let ToAggregator::FromShardWebsocket(
+                ..,
+                FromShardWebsocket::Update { payload, .. },
+            ) = &msg
+            {
+                let priority = payload.priority();
+                if should_drop(priority, metered_tx.len(), max_queue_len) {
                     // Note: this wraps on overflow (which is probably the best
                     // behaviour for graphing it anyway)
-                    dropped_messages.fetch_add(1, Ordering::Relaxed);
+                    dropped_messages[priority as usize].fetch_add(1, Ordering::Relaxed);
                     continue;
                 }
             }
@@ -267,29 +366,50 @@ impl InnerLoop {
         &mut self,
         rx: flume::Sender<Metrics>,
         current_messages_to_aggregator: usize,
-        dropped_messages_to_aggregator: u64,
+        dropped_messages_by_priority: Vec<(Priority, u64)>,
         total_messages_to_aggregator: u64,
     ) {
+        let dropped_messages_to_aggregator =
+            dropped_messages_by_priority.iter().map(|(_, n)| n).sum();
         let timestamp_unix_ms = time::now();
         let connected_nodes = self.node_ids.len();
         let subscribed_feeds = self.chain_to_feed_conn_ids.num_values();
+        let subscribed_feed_ids = self.chain_to_feed_conn_ids.values().copied().collect();
         let chains_subscribed_to = self.chain_to_feed_conn_ids.num_keys();
         let connected_shards = self.shard_channels.len();
-        let connected_feeds = self.feed_channels.len();
-        let total_messages_to_feeds: usize = self.feed_channels.values().map(|c| c.len()).sum();
+        let connected_feeds = self.feeds.len();
 
-        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
-        let _ = rx.send(Metrics {
-            timestamp_unix_ms,
-            chains_subscribed_to,
-            subscribed_feeds,
-            total_messages_to_feeds,
-            current_messages_to_aggregator,
-            total_messages_to_aggregator,
-            dropped_messages_to_aggregator,
-            connected_nodes,
-            connected_feeds,
-            connected_shards,
+        // Ask each feed worker how many messages are queued up for its feeds. The workers
+        // drop their senders once they've answered, so we hand back the metrics then:
+        let (queue_lens_tx, queue_lens_rx) = flume::unbounded();
+        for worker in &self.feed_workers {
+            let _ = worker.send(ToFeedWorker::GatherQueueLens(queue_lens_tx.clone()));
+        }
+        drop(queue_lens_tx);
+
+        tokio::spawn(async move {
+            let mut feed_queue_lens = Vec::new();
+            while let Ok(queue_lens) = queue_lens_rx.recv_async().await {
+                feed_queue_lens.extend(queue_lens);
+            }
+            let total_messages_to_feeds = feed_queue_lens.iter().map(|(_, len)| len).sum();
+
+            // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+            let _ = rx.send(Metrics {
+                timestamp_unix_ms,
+                chains_subscribed_to,
+                subscribed_feeds,
+                subscribed_feed_ids,
+                total_messages_to_feeds,
+                current_messages_to_aggregator,
+                total_messages_to_aggregator,
+                dropped_messages_to_aggregator,
+                dropped_messages_by_priority,
+                connected_nodes,
+                connected_feeds,
+                connected_shards,
+                feed_queue_lens,
+            });
         });
     }
 
@@ -342,6 +462,39 @@ impl InnerLoop {
         let _ = rx.send(datas);
     }
 
+    fn handle_gather_hardware_compliance_endpoint(
+        &mut self,
+        rx: flume::Sender<HashMap<BlockHash, HardwareCompliance>>,
+    ) {
+        let mut datas: HashMap<BlockHash, HardwareCompliance> = HashMap::new();
+
+        // Only chains with hardware requirements have a report:
+        for chain_state in self.node_state.iter_chains() {
+            if let Some(data) = chain_state.hardware_compliance_endpoint() {
+                datas.insert(chain_state.genesis_hash(), data);
+            }
+        }
+
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = rx.send(datas);
+    }
+
+    fn handle_gather_authorities_endpoint(
+        &mut self,
+        rx: flume::Sender<HashMap<BlockHash, Authorities>>,
+    ) {
+        let mut datas: HashMap<BlockHash, Authorities> = HashMap::new();
+
+        for chain_state in self.node_state.iter_chains() {
+            let data = chain_state.authorities_endpoint();
+            let genesis_hash = chain_state.genesis_hash();
+            datas.insert(genesis_hash, data);
+        }
+
+        // Ignore error sending; assume the receiver stopped caring and dropped the channel:
+        let _ = rx.send(datas);
+    }
+
     /*     /// Gather and return some metrics.\
     fn handle_gather_blocks(
         &mut self,
@@ -366,26 +519,24 @@ impl InnerLoop {
         self.node_state
             .update_node_location(node_id, location.clone());
 
-        if let Some(loc) = location {
-            let mut feed_message_serializer = FeedMessageSerializer::new();
+        let chain_genesis_hash = self
+            .node_state
+            .get_chain_by_node_id(node_id)
+            .map(|chain| chain.genesis_hash());
+
+        if let (Some(loc), Some(chain_genesis_hash)) = (location, chain_genesis_hash) {
+            let mut feed_message_serializer =
+                self.feed_formats.chain_serializer(chain_genesis_hash);
             feed_message_serializer.push(feed_message::LocatedNode(
                 node_id.get_chain_node_id().into(),
                 loc.latitude,
                 loc.longitude,
                 &loc.city,
             ));
-
-            let chain_genesis_hash = self
-                .node_state
-                .get_chain_by_node_id(node_id)
-                .map(|chain| chain.genesis_hash());
-
-            if let Some(chain_genesis_hash) = chain_genesis_hash {
-                self.finalize_and_broadcast_to_chain_feeds(
-                    &chain_genesis_hash,
-                    feed_message_serializer,
-                );
-            }
+            self.finalize_and_broadcast_to_chain_feeds(
+                &chain_genesis_hash,
+                feed_message_serializer,
+            );
         }
     }
 
@@ -424,26 +575,35 @@ impl InnerLoop {
                         let node_id = details.id;
 
                         // Record ID <-> (shardId,localId) for future messages:
-                        self.node_ids.insert(node_id, (shard_conn_id, local_id));
+                        if let bimap::Overwritten::Right(old_node_id, _) =
+                            self.node_ids.insert(node_id, (shard_conn_id, local_id))
+                        {
+                            self.shard_node_ids.remove_value(&old_node_id);
+                        }
+                        self.shard_node_ids.insert(shard_conn_id, node_id);
 
                         // Don't hold onto details too long because we want &mut self later:
                         let new_chain_label = details.new_chain_label.to_owned();
                         let chain_node_count = details.chain_node_count;
                         let has_chain_label_changed = details.has_chain_label_changed;
 
-                        // Tell chain subscribers about the node we've just added:
-                        let mut feed_messages_for_chain = FeedMessageSerializer::new();
-                        feed_messages_for_chain.push(feed_message::AddedNode(
-                            node_id.get_chain_node_id().into(),
-                            &details.node,
-                            self.expose_node_details,
-                        ));
-                        self.finalize_and_broadcast_to_chain_feeds(
-                            &genesis_hash,
-                            feed_messages_for_chain,
-                        );
+                        // Tell chain subscribers about the node we've just added, unless
+                        // they were told about it when it was restored:
+                        if !details.adopted {
+                            let mut feed_messages_for_chain =
+                                self.feed_formats.chain_serializer(genesis_hash);
+                            feed_messages_for_chain.push(feed_message::AddedNode(
+                                node_id.get_chain_node_id().into(),
+                                &details.node,
+                                self.expose_node_details,
+                            ));
+                            self.finalize_and_broadcast_to_chain_feeds(
+                                &genesis_hash,
+                                feed_messages_for_chain,
+                            );
+                        }
                         // Tell everybody about the new node count and potential rename:
-                        let mut feed_messages_for_all = FeedMessageSerializer::new();
+                        let mut feed_messages_for_all = self.feed_formats.serializer();
                         if has_chain_label_changed {
                             feed_messages_for_all.push(feed_message::RemovedChain(genesis_hash));
                         }
@@ -461,12 +621,16 @@ impl InnerLoop {
             }
             FromShardWebsocket::Remove { local_id } => {
                 let node_id = match self.node_ids.remove_by_right(&(shard_conn_id, local_id)) {
-                    Some((node_id, _)) => node_id,
+                    Some((node_id, _)) => {
+                        self.shard_node_ids.remove_value(&node_id);
+                        node_id
+                    }
                     None => {
                         // It's possible that some race between removing and disconnecting shards might lead to
                         // more than one remove message for the same node. This isn't really a problem, but we
                         // hope it won't happen so make a note if it does:
                         log::debug!(
+                            shard:? = shard_conn_id, local_id:? = local_id;
                             "Remove: Cannot find ID for node with shard/connectionId of {shard_conn_id:?}/{local_id:?}"
                         );
                         return;
@@ -479,38 +643,36 @@ impl InnerLoop {
                     Some(id) => *id,
                     None => {
                         log::error!(
+                            shard:? = shard_conn_id, local_id:? = local_id;
                             "Update: Cannot find ID for node with shard/connectionId of {shard_conn_id:?}/{local_id:?}"
                         );
                         return;
                     }
                 };
 
-                let mut feed_message_serializer = FeedMessageSerializer::new();
+                let genesis_hash = match self.node_state.get_chain_by_node_id(node_id) {
+                    Some(chain) => chain.genesis_hash(),
+                    None => return,
+                };
+
+                let mut feed_message_serializer = self.feed_formats.chain_serializer(genesis_hash);
                 self.node_state.update_node(
                     node_id,
                     payload,
                     &mut feed_message_serializer,
                     self.expose_node_details,
                 );
-
-                if let Some(chain) = self.node_state.get_chain_by_node_id(node_id) {
-                    let genesis_hash = chain.genesis_hash();
-                    self.finalize_and_broadcast_to_chain_feeds(
-                        &genesis_hash,
-                        feed_message_serializer,
-                    );
-                }
+                self.finalize_and_broadcast_to_chain_feeds(&genesis_hash, feed_message_serializer);
             }
             FromShardWebsocket::Disconnected => {
                 self.shard_channels.remove(&shard_conn_id);
 
                 // Find all nodes associated with this shard connection ID:
                 let node_ids_to_remove: Vec<NodeId> = self
-                    .node_ids
-                    .iter()
-                    .filter(|(_, &(this_shard_conn_id, _))| shard_conn_id == this_shard_conn_id)
-                    .map(|(&node_id, _)| node_id)
-                    .collect();
+                    .shard_node_ids
+                    .get_values(&shard_conn_id)
+                    .map(|ids| ids.iter().copied().collect())
+                    .unwrap_or_default();
 
                 // ... and remove them:
                 self.remove_nodes_and_broadcast_result(node_ids_to_remove);
@@ -521,12 +683,21 @@ impl InnerLoop {
     /// Handle messages coming from feeds.
     fn handle_from_feed(&mut self, feed_conn_id: ConnId, msg: FromFeedWebsocket) {
         match msg {
-            FromFeedWebsocket::Initialize { channel } => {
-                self.feed_channels.insert(feed_conn_id, channel.clone());
+            FromFeedWebsocket::Initialize { channel, format } => {
+                self.feeds.insert(feed_conn_id, format);
+                self.feed_formats.add(format);
+                self.send_to_feed_worker(
+                    feed_conn_id,
+                    ToFeedWorker::AddFeed {
+                        feed_id: feed_conn_id,
+                        channel,
+                        format,
+                    },
+                );
 
-                // Tell the new feed subscription some basic things to get it going:
-                let mut feed_serializer = FeedMessageSerializer::new();
-                feed_serializer.push(feed_message::Version(32));
+                // Tell the new feed subscription about the chains we know of. It's been told
+                // which version of the feed protocol we speak already:
+                let mut feed_serializer = FeedMessageSerializer::with_formats([format]);
                 for chain in self.node_state.iter_chains() {
                     feed_serializer.push(feed_message::AddedChain(
                         chain.label(),
@@ -536,114 +707,409 @@ impl InnerLoop {
                 }
 
                 // Send this to the channel that subscribed:
-                if let Some(bytes) = feed_serializer.into_finalized() {
-                    let _ = channel.send(ToFeedWebsocket::Bytes(bytes));
+                self.finalize_and_send_to_feed(feed_conn_id, feed_serializer);
+            }
+            FromFeedWebsocket::Command {
+                request_id,
+                command,
+            } => {
+                let result = self.handle_feed_command(feed_conn_id, command);
+                if let Some(request_id) = request_id {
+                    self.respond_to_feed(feed_conn_id, request_id, result);
                 }
             }
-            FromFeedWebsocket::Ping { value } => {
-                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
-                    Some(chan) => chan,
-                    None => return,
-                };
-
-                // Pong!
-                let mut feed_serializer = FeedMessageSerializer::new();
-                feed_serializer.push(feed_message::Pong(&value));
-                if let Some(bytes) = feed_serializer.into_finalized() {
-                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+            FromFeedWebsocket::InvalidCommand { request_id, reason } => {
+                self.respond_to_feed(feed_conn_id, request_id, Err(reason));
+            }
+            FromFeedWebsocket::Disconnected => {
+                // The feed has disconnected; clean up references to it:
+                self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
+                if let Some(format) = self.feeds.remove(&feed_conn_id) {
+                    self.feed_formats.remove(format);
+                    self.send_to_feed_worker(
+                        feed_conn_id,
+                        ToFeedWorker::RemoveFeed {
+                            feed_id: feed_conn_id,
+                        },
+                    );
                 }
             }
-            FromFeedWebsocket::Subscribe { chain } => {
-                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
-                    Some(chan) => chan,
-                    None => return,
-                };
+        }
+    }
 
-                // Unsubscribe from previous chain if subscribed to one:
-                let old_genesis_hash = self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
+    /// Carry out a command from a feed, or describe why we couldn't.
+    fn handle_feed_command(
+        &mut self,
+        feed_conn_id: ConnId,
+        command: FeedCommand,
+    ) -> Result<(), String> {
+        let format = match self.feeds.get(&feed_conn_id) {
+            Some(&format) => format,
+            None => return Ok(()),
+        };
 
-                // Get old chain if there was one:
-                let node_state = &self.node_state;
-                let old_chain =
-                    old_genesis_hash.and_then(|hash| node_state.get_chain_by_genesis_hash(&hash));
+        match command {
+            FeedCommand::Ping { value } => {
+                // Pong!
+                let mut feed_serializer = FeedMessageSerializer::with_formats([format]);
+                feed_serializer.push(feed_message::Pong(&value));
+                self.finalize_and_send_to_feed(feed_conn_id, feed_serializer);
+            }
+            FeedCommand::Subscribe { chain, filter } => {
+                let new_chain = self
+                    .node_state
+                    .get_chain_by_genesis_hash(&chain)
+                    .ok_or_else(|| unknown_chain(&chain))?;
+                let genesis_hash = new_chain.genesis_hash();
+
+                // Work out which nodes the feed wants to hear about, if not all of them:
+                let matching_nodes: Option<HashSet<FeedNodeId>> = filter.as_ref().map(|filter| {
+                    new_chain
+                        .nodes_slice()
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, n)| {
+                            n.as_ref()
+                                .is_some_and(|n| filter.matches(&FilterDetails::new(n)))
+                        })
+                        .map(|(id, _)| id)
+                        .collect()
+                });
+
+                // Send messages to the feed about this subscription. Subscribing to a chain
+                // that the feed is already subscribed to sends it everything again.
+                let seq = self.feed_replay.last_seq(genesis_hash);
+                let msgs = serialize_chain_state(
+                    &new_chain,
+                    format,
+                    Some(seq),
+                    matching_nodes.as_ref(),
+                    self.expose_node_details,
+                )
+                .iter()
+                .filter_map(|bytes| bytes.batch(format))
+                .collect();
+
+                // The feed's worker remembers which nodes the feed knows about, so that it
+                // can keep the feed up to date with those and only those:
+                let filter = filter
+                    .zip(matching_nodes)
+                    .map(|(filter, nodes)| ChainFilter {
+                        filter,
+                        nodes,
+                        pinned: HashSet::new(),
+                    });
+                self.send_to_feed_worker(
+                    feed_conn_id,
+                    ToFeedWorker::Subscribe {
+                        feed_id: feed_conn_id,
+                        chain: genesis_hash,
+                        msgs,
+                        filter,
+                    },
+                );
 
-                // Get new chain, ignoring the rest if it doesn't exist.
-                let new_chain = match self.node_state.get_chain_by_genesis_hash(&chain) {
-                    Some(chain) => chain,
-                    None => return,
+                // Actually make a note of the new chain subscription:
+                self.chain_to_feed_conn_ids
+                    .insert(genesis_hash, feed_conn_id);
+            }
+            FeedCommand::Resume { chain, seq } => {
+                if self.node_state.get_chain_by_genesis_hash(&chain).is_none() {
+                    return Err(unknown_chain(&chain));
+                }
+                let missed = match self.feed_replay.since(&chain, seq, format) {
+                    Some(missed) => missed,
+                    // We can't send just what was missed, so send everything:
+                    None => {
+                        let subscribe = FeedCommand::Subscribe {
+                            chain,
+                            filter: None,
+                        };
+                        return self.handle_feed_command(feed_conn_id, subscribe);
+                    }
                 };
 
-                // Send messages to the feed about this subscription:
-                let mut feed_serializer = FeedMessageSerializer::new();
-                if let Some(old_chain) = old_chain {
-                    feed_serializer.push(feed_message::UnsubscribedFrom(old_chain.genesis_hash()));
-                }
-                feed_serializer.push(feed_message::SubscribedTo(new_chain.genesis_hash()));
-                feed_serializer.push(feed_message::TimeSync(time::now()));
-                feed_serializer.push(feed_message::BestBlock(
-                    new_chain.best_block().height,
-                    new_chain.timestamp(),
-                    new_chain.average_block_time(),
-                ));
-                feed_serializer.push(feed_message::BestFinalized(
-                    new_chain.finalized_block().height,
-                    new_chain.finalized_block().hash,
-                ));
-                feed_serializer.push(feed_message::ChainStatsUpdate(new_chain.stats()));
-                if let Some(bytes) = feed_serializer.into_finalized() {
-                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                let mut feed_serializer = FeedMessageSerializer::for_chain([format], chain);
+                feed_serializer.push(feed_message::Resumed(chain));
+                let msgs = feed_serializer
+                    .into_finalized()
+                    .and_then(|bytes| bytes.batch(format))
+                    .into_iter()
+                    .chain(missed)
+                    .collect();
+                self.send_to_feed_worker(
+                    feed_conn_id,
+                    ToFeedWorker::Subscribe {
+                        feed_id: feed_conn_id,
+                        chain,
+                        msgs,
+                        filter: None,
+                    },
+                );
+                self.chain_to_feed_conn_ids.insert(chain, feed_conn_id);
+            }
+            FeedCommand::Unsubscribe { chain } => {
+                if !self.chain_to_feed_conn_ids.remove(&chain, &feed_conn_id) {
+                    return Err(not_subscribed(&chain));
                 }
 
-                // If many (eg 10k) nodes are connected, serializing all of their info takes time.
-                // So, parallelise this with Rayon, but we still send out messages for each node in order
-                // (which is helpful for the UI as it tries to maintain a sorted list of nodes). The chunk
-                // size is the max number of node info we fit into 1 message; smaller messages allow the UI
-                // to react a little faster and not have to wait for a larger update to come in. A chunk size
-                // of 64 means each message is ~32k.
-                use rayon::prelude::*;
-                let all_feed_messages: Vec<_> = new_chain
+                let mut feed_serializer = FeedMessageSerializer::with_formats([format]);
+                feed_serializer.push(feed_message::UnsubscribedFrom(chain));
+                let msgs = feed_serializer
+                    .into_finalized()
+                    .and_then(|bytes| bytes.batch(format))
+                    .into_iter()
+                    .collect();
+                self.send_to_feed_worker(
+                    feed_conn_id,
+                    ToFeedWorker::Unsubscribe {
+                        feed_id: feed_conn_id,
+                        chain,
+                        msgs,
+                    },
+                );
+            }
+            FeedCommand::Pin {
+                chain,
+                node: node_id,
+            } => {
+                let is_subscribed = self
+                    .chain_to_feed_conn_ids
+                    .get_keys(&feed_conn_id)
+                    .is_some_and(|chains| chains.contains(&chain));
+                if !is_subscribed {
+                    return Err(not_subscribed(&chain));
+                }
+                let state_chain = self
+                    .node_state
+                    .get_chain_by_genesis_hash(&chain)
+                    .ok_or_else(|| unknown_chain(&chain))?;
+                let node = state_chain
                     .nodes_slice()
-                    .par_iter()
-                    .enumerate()
-                    .chunks(64)
-                    .filter_map(|nodes| {
-                        let mut feed_serializer = FeedMessageSerializer::new();
-                        for (node_id, node) in nodes
-                            .iter()
-                            .filter_map(|&(idx, n)| n.as_ref().map(|n| (idx, n)))
-                        {
-                            feed_serializer.push(feed_message::AddedNode(
-                                node_id,
-                                node,
-                                self.expose_node_details,
-                            ));
-                            feed_serializer.push(feed_message::FinalizedBlock(
-                                node_id,
-                                node.finalized().height,
-                                node.finalized().hash,
-                            ));
-                            if node.stale() {
-                                feed_serializer.push(feed_message::StaleNode(node_id));
-                            }
-                        }
-                        feed_serializer.into_finalized()
-                    })
+                    .get(node_id)
+                    .and_then(|n| n.as_ref())
+                    .ok_or_else(|| format!("Unknown node {node_id}"))?;
+
+                // The feed's worker only sends these if the feed's filter had left the node out:
+                let mut feed_serializer = FeedMessageSerializer::for_chain([format], chain);
+                push_node(
+                    &mut feed_serializer,
+                    node_id,
+                    node,
+                    self.expose_node_details,
+                );
+                let msgs = feed_serializer
+                    .into_finalized()
+                    .and_then(|bytes| bytes.batch(format))
+                    .into_iter()
                     .collect();
-                for bytes in all_feed_messages {
-                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                self.send_to_feed_worker(
+                    feed_conn_id,
+                    ToFeedWorker::Pin {
+                        feed_id: feed_conn_id,
+                        chain,
+                        node: node_id,
+                        msgs,
+                    },
+                );
+            }
+            FeedCommand::Unpin {
+                chain,
+                node: node_id,
+            } => {
+                let state_chain = self.node_state.get_chain_by_genesis_hash(&chain);
+                let details = state_chain
+                    .as_ref()
+                    .and_then(|c| c.nodes_slice().get(node_id))
+                    .and_then(|n| n.as_ref())
+                    .map(FilterDetails::new);
+                self.send_to_feed_worker(
+                    feed_conn_id,
+                    ToFeedWorker::Unpin {
+                        feed_id: feed_conn_id,
+                        chain,
+                        node: node_id,
+                        details,
+                    },
+                );
+            }
+            FeedCommand::Snapshot { chain } => {
+                let state_chain = self
+                    .node_state
+                    .get_chain_by_genesis_hash(&chain)
+                    .ok_or_else(|| unknown_chain(&chain))?;
+
+                // The feed's worker sticks to the nodes the feed knows about if it has
+                // filtered the chain:
+                let msgs = serialize_chain_state(
+                    &state_chain,
+                    format,
+                    None,
+                    None,
+                    self.expose_node_details,
+                );
+                self.send_to_feed_worker(
+                    feed_conn_id,
+                    ToFeedWorker::Snapshot {
+                        feed_id: feed_conn_id,
+                        chain,
+                        msgs,
+                    },
+                );
+            }
+            FeedCommand::Series {
+                chain,
+                node: node_id,
+            } => {
+                let is_subscribed = self
+                    .chain_to_feed_conn_ids
+                    .get_keys(&feed_conn_id)
+                    .is_some_and(|chains| chains.contains(&chain));
+                if !is_subscribed {
+                    return Err(not_subscribed(&chain));
                 }
+                let state_chain = self
+                    .node_state
+                    .get_chain_by_genesis_hash(&chain)
+                    .ok_or_else(|| unknown_chain(&chain))?;
+                let node = state_chain
+                    .nodes_slice()
+                    .get(node_id)
+                    .and_then(|n| n.as_ref())
+                    .ok_or_else(|| format!("Unknown node {node_id}"))?;
 
-                // Actually make a note of the new chain subscription:
-                let new_genesis_hash = new_chain.genesis_hash();
-                self.chain_to_feed_conn_ids
-                    .insert(new_genesis_hash, feed_conn_id);
+                // A squash replaces every sample, so it's how we send the whole series:
+                let mut feed_serializer = FeedMessageSerializer::for_chain([format], chain);
+                feed_serializer.push(feed_message::HardwareDelta(
+                    node_id,
+                    node.hardware_seq(),
+                    node.hardware(),
+                    [MeanListChange::Squashed; 3],
+                ));
+                feed_serializer.push(feed_message::NodeIODelta(
+                    node_id,
+                    node.io_seq(),
+                    node.io(),
+                    MeanListChange::Squashed,
+                ));
+                let msgs = feed_serializer
+                    .into_finalized()
+                    .and_then(|bytes| bytes.batch(format))
+                    .map(ToFeedWebsocket::Messages)
+                    .into_iter()
+                    .collect();
+                self.send_to_feed_worker(
+                    feed_conn_id,
+                    ToFeedWorker::SendToFeed {
+                        feed_id: feed_conn_id,
+                        msgs,
+                    },
+                );
             }
-            FromFeedWebsocket::Disconnected => {
-                // The feed has disconnected; clean up references to it:
-                self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
-                self.feed_channels.remove(&feed_conn_id);
+            FeedCommand::SetUpdateRate { interval_ms } => {
+                if interval_ms > MAX_FEED_UPDATE_INTERVAL_MS {
+                    return Err(format!(
+                        "The update interval can be at most {MAX_FEED_UPDATE_INTERVAL_MS}ms"
+                    ));
+                }
+                let msg = ToFeedWebsocket::SetUpdateRate(Duration::from_millis(interval_ms));
+                self.send_to_feed_worker(
+                    feed_conn_id,
+                    ToFeedWorker::SendToFeed {
+                        feed_id: feed_conn_id,
+                        msgs: vec![msg],
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Tell a feed how the command it sent with the request ID given went.
+    fn respond_to_feed(&self, feed_conn_id: ConnId, request_id: u64, result: Result<(), String>) {
+        let format = match self.feeds.get(&feed_conn_id) {
+            Some(&format) => format,
+            None => return,
+        };
+
+        let mut feed_serializer = FeedMessageSerializer::with_formats([format]);
+        match result {
+            Ok(()) => feed_serializer.push(feed_message::CommandAck(request_id)),
+            Err(reason) => feed_serializer.push(feed_message::CommandError(request_id, &reason)),
+        }
+        self.finalize_and_send_to_feed(feed_conn_id, feed_serializer);
+    }
+
+    /// Finalize a [`FeedMessageSerializer`] and send the result to a single feed.
+    fn finalize_and_send_to_feed(&self, feed_conn_id: ConnId, serializer: FeedMessageSerializer) {
+        let format = match self.feeds.get(&feed_conn_id) {
+            Some(&format) => format,
+            None => return,
+        };
+        let msgs = serializer
+            .into_finalized()
+            .and_then(|bytes| bytes.batch(format))
+            .map(ToFeedWebsocket::Messages)
+            .into_iter()
+            .collect();
+        self.send_to_feed_worker(
+            feed_conn_id,
+            ToFeedWorker::SendToFeed {
+                feed_id: feed_conn_id,
+                msgs,
+            },
+        );
+    }
+
+    /// Which of the feed workers is responsible for a feed.
+    fn feed_worker_idx(&self, feed_conn_id: ConnId) -> usize {
+        (u64::from(feed_conn_id) % self.feed_workers.len() as u64) as usize
+    }
+
+    /// Send a message to the worker responsible for a feed.
+    fn send_to_feed_worker(&self, feed_conn_id: ConnId, msg: ToFeedWorker) {
+        // The workers only stop once we've dropped the senders to them:
+        let _ = self.feed_workers[self.feed_worker_idx(feed_conn_id)].send(msg);
+    }
+
+    /// Tell feeds about nodes which were just restored from a snapshot.
+    fn broadcast_restored_nodes(&mut self, node_ids: Vec<NodeId>) {
+        // Group by chain to simplify the handling of feed messages:
+        let mut node_ids_per_chain: HashMap<BlockHash, Vec<NodeId>> = HashMap::new();
+        for node_id in node_ids {
+            if let Some(chain) = self.node_state.get_chain_by_node_id(node_id) {
+                node_ids_per_chain
+                    .entry(chain.genesis_hash())
+                    .or_default()
+                    .push(node_id);
             }
         }
+
+        // Tell chain subscribers about each restored node, and everybody about the chains
+        // they're in:
+        let mut feed_messages_for_all = self.feed_formats.serializer();
+        for (genesis_hash, node_ids) in node_ids_per_chain {
+            let Some(chain) = self.node_state.get_chain_by_genesis_hash(&genesis_hash) else {
+                continue;
+            };
+            let mut feed_messages_for_chain = self.feed_formats.chain_serializer(genesis_hash);
+            for node_id in node_ids {
+                if let Some(node) = chain.get_node(node_id.get_chain_node_id()) {
+                    feed_messages_for_chain.push(feed_message::AddedNode(
+                        node_id.get_chain_node_id().into(),
+                        node,
+                        self.expose_node_details,
+                    ));
+                }
+            }
+            feed_messages_for_all.push(feed_message::AddedChain(
+                chain.label(),
+                genesis_hash,
+                chain.node_count(),
+            ));
+            self.finalize_and_broadcast_to_chain_feeds(&genesis_hash, feed_messages_for_chain);
+        }
+        self.finalize_and_broadcast_to_all_feeds(feed_messages_for_all);
     }
 
     /// Remove all of the node IDs provided and broadcast messages to feeds as needed.
@@ -660,9 +1126,9 @@ impl InnerLoop {
         }
 
         // Remove the nodes for each chain
-        let mut feed_messages_for_all = FeedMessageSerializer::new();
+        let mut feed_messages_for_all = self.feed_formats.serializer();
         for (chain_label, node_ids) in node_ids_per_chain {
-            let mut feed_messages_for_chain = FeedMessageSerializer::new();
+            let mut feed_messages_for_chain = self.feed_formats.chain_serializer(chain_label);
             for node_id in node_ids {
                 self.remove_node(
                     node_id,
@@ -686,6 +1152,7 @@ impl InnerLoop {
     ) {
         // Remove our top level association (this may already have been done).
         self.node_ids.remove_by_left(&node_id);
+        self.shard_node_ids.remove_value(&node_id);
 
         let removed_details = match self.node_state.remove_node(node_id) {
             Some(remove_details) => remove_details,
@@ -696,6 +1163,9 @@ impl InnerLoop {
         };
 
         // The chain has been removed (no nodes left in it, or it was renamed):
+        if removed_details.chain_node_count == 0 {
+            self.feed_replay.forget(&removed_details.chain_genesis_hash);
+        }
         if removed_details.chain_node_count == 0 || removed_details.has_chain_label_changed {
             feed_for_all.push(feed_message::RemovedChain(
                 removed_details.chain_genesis_hash,
@@ -720,38 +1190,212 @@ impl InnerLoop {
     }
 
     /// Finalize a [`FeedMessageSerializer`] and broadcast the result to feeds for the chain.
+    /// Each batch of messages is numbered, and kept for a while in case feeds reconnect.
     fn finalize_and_broadcast_to_chain_feeds(
         &mut self,
         genesis_hash: &BlockHash,
-        serializer: FeedMessageSerializer,
+        mut serializer: FeedMessageSerializer,
     ) {
+        if serializer.is_empty() {
+            return;
+        }
+        let seq = self.feed_replay.next_seq(*genesis_hash);
+        serializer.push(feed_message::ChainSeq(seq));
         if let Some(bytes) = serializer.into_finalized() {
-            self.broadcast_to_chain_feeds(genesis_hash, ToFeedWebsocket::Bytes(bytes));
+            self.broadcast_to_chain_feeds(genesis_hash, &bytes);
+            self.feed_replay.push(*genesis_hash, seq, bytes);
         }
     }
 
-    /// Send a message to all chain feeds.
-    fn broadcast_to_chain_feeds(&mut self, genesis_hash: &BlockHash, message: ToFeedWebsocket) {
-        if let Some(feeds) = self.chain_to_feed_conn_ids.get_values(genesis_hash) {
-            for &feed_id in feeds {
-                if let Some(chan) = self.feed_channels.get_mut(&feed_id) {
-                    let _ = chan.send(message.clone());
-                }
-            }
+    /// Send a message to the workers of all chain feeds. Any nodes that the message adds
+    /// are described, so that feeds which have filtered the nodes on the chain can be
+    /// sent only the messages about nodes which match their filter.
+    fn broadcast_to_chain_feeds(&self, genesis_hash: &BlockHash, bytes: &FeedBytes) {
+        let feeds = match self.chain_to_feed_conn_ids.get_values(genesis_hash) {
+            Some(feeds) => feeds,
+            None => return,
+        };
+        let chain = self.node_state.get_chain_by_genesis_hash(genesis_hash);
+        let nodes = chain.as_ref().map_or(&[][..], |chain| chain.nodes_slice());
+        let added: HashMap<_, _> = bytes
+            .nodes_added()
+            .filter_map(|id| {
+                let node = nodes.get(id)?.as_ref()?;
+                Some((id, FilterDetails::new(node)))
+            })
+            .collect();
+        let added = Arc::new(added);
+
+        // Only the workers with feeds subscribed to the chain need to hear about it:
+        let workers: HashSet<usize> = feeds.iter().map(|&id| self.feed_worker_idx(id)).collect();
+        for idx in workers {
+            let _ = self.feed_workers[idx].send(ToFeedWorker::BroadcastToChain {
+                chain: *genesis_hash,
+                bytes: bytes.clone(),
+                added: Arc::clone(&added),
+            });
         }
     }
 
     /// Finalize a [`FeedMessageSerializer`] and broadcast the result to all feeds
     fn finalize_and_broadcast_to_all_feeds(&mut self, serializer: FeedMessageSerializer) {
         if let Some(bytes) = serializer.into_finalized() {
-            self.broadcast_to_all_feeds(ToFeedWebsocket::Bytes(bytes));
+            self.broadcast_to_all_feeds(&bytes);
         }
     }
 
     /// Send a message to everybody.
-    fn broadcast_to_all_feeds(&mut self, message: ToFeedWebsocket) {
-        for chan in self.feed_channels.values_mut() {
-            let _ = chan.send(message.clone());
+    fn broadcast_to_all_feeds(&self, bytes: &FeedBytes) {
+        for worker in &self.feed_workers {
+            let _ = worker.send(ToFeedWorker::BroadcastToAll {
+                bytes: bytes.clone(),
+            });
+        }
+    }
+}
+
+/// Should a node update of the priority given be dropped, with the queue of messages
+/// to the aggregator as long as it is? Once the queue is longer than `max_queue_len`,
+/// low priority updates are dropped, and normal priority updates are dropped too once
+/// it's twice that. Updates about new blocks and finality are never dropped.
+fn should_drop(priority: Priority, queue_len: usize, max_queue_len: usize) -> bool {
+    match priority {
+        Priority::Low => queue_len > max_queue_len,
+        Priority::Normal => queue_len > max_queue_len.saturating_mul(2),
+        Priority::High => false,
+    }
+}
+
+fn unknown_chain(genesis_hash: &BlockHash) -> String {
+    format!("Unknown chain {genesis_hash:#x}")
+}
+
+fn not_subscribed(genesis_hash: &BlockHash) -> String {
+    format!("Not subscribed to chain {genesis_hash:#x}")
+}
+
+/// Serialize everything that a feed needs to know about a chain, and about the nodes
+/// on it (or just those given). If `subscribed_at` is given, the feed is subscribing
+/// to the chain, and is told that it's up to date as of that [`feed_message::ChainSeq`].
+fn serialize_chain_state(
+    chain: &StateChain,
+    format: FeedFormat,
+    subscribed_at: Option<u64>,
+    nodes: Option<&HashSet<FeedNodeId>>,
+    expose_node_details: bool,
+) -> Vec<FeedBytes> {
+    let genesis_hash = chain.genesis_hash();
+    let mut feed_serializer = FeedMessageSerializer::for_chain([format], genesis_hash);
+    if let Some(seq) = subscribed_at {
+        feed_serializer.push(feed_message::SubscribedTo(genesis_hash));
+        feed_serializer.push(feed_message::ChainSeq(seq));
+    }
+    feed_serializer.push(feed_message::TimeSync(time::now()));
+    feed_serializer.push(feed_message::BestBlock(
+        chain.best_block().height,
+        chain.timestamp(),
+        chain.average_block_time(),
+    ));
+    feed_serializer.push(feed_message::BestFinalized(
+        chain.finalized_block().height,
+        chain.finalized_block().hash,
+    ));
+    feed_serializer.push(feed_message::ChainStatsUpdate(chain.stats()));
+    feed_serializer.push(feed_message::AuthoritiesUpdate(&chain.authority_summary()));
+    let mut all_feed_messages: Vec<_> = feed_serializer.into_finalized().into_iter().collect();
+
+    // If many (eg 10k) nodes are connected, serializing all of their info takes time.
+    // So, parallelise this with Rayon, but we still send out messages for each node in order
+    // (which is helpful for the UI as it tries to maintain a sorted list of nodes). The chunk
+    // size is the max number of node info we fit into 1 message; smaller messages allow the UI
+    // to react a little faster and not have to wait for a larger update to come in. A chunk size
+    // of 64 means each message is ~32k.
+    use rayon::prelude::*;
+    let node_messages: Vec<_> = chain
+        .nodes_slice()
+        .par_iter()
+        .enumerate()
+        .chunks(64)
+        .filter_map(|nodes_chunk| {
+            let mut feed_serializer = FeedMessageSerializer::for_chain([format], genesis_hash);
+            for (node_id, node) in nodes_chunk
+                .iter()
+                .filter_map(|&(idx, n)| n.as_ref().map(|n| (idx, n)))
+                .filter(|(id, _)| nodes.is_none_or(|nodes| nodes.contains(id)))
+            {
+                push_node(&mut feed_serializer, node_id, node, expose_node_details);
+            }
+            feed_serializer.into_finalized()
+        })
+        .collect();
+    all_feed_messages.extend(node_messages);
+    all_feed_messages
+}
+
+/// Push the messages that tell a feed about a node that it hasn't heard of.
+fn push_node(
+    feed_serializer: &mut FeedMessageSerializer,
+    node_id: FeedNodeId,
+    node: &Node,
+    expose_node_details: bool,
+) {
+    feed_serializer.push(feed_message::AddedNode(node_id, node, expose_node_details));
+    feed_serializer.push(feed_message::FinalizedBlock(
+        node_id,
+        node.finalized().height,
+        node.finalized().hash,
+    ));
+    if node.stale() {
+        feed_serializer.push(feed_message::StaleNode(node_id));
+    }
+}
+
+/// Keep count of how many feeds want messages in each format.
+struct FeedFormatCounts {
+    counts: HashMap<FeedFormat, usize>,
+    /// If feeds can resume, every format that a feed has asked for. Messages about
+    /// chains keep being serialized to these, so that a feed which reconnects can
+    /// be sent the messages it missed in its format.
+    resumable: Option<HashSet<FeedFormat>>,
+}
+
+impl FeedFormatCounts {
+    fn new(resumable: bool) -> Self {
+        FeedFormatCounts {
+            counts: HashMap::new(),
+            resumable: resumable.then(HashSet::new),
+        }
+    }
+
+    fn add(&mut self, format: FeedFormat) {
+        *self.counts.entry(format).or_default() += 1;
+        if let Some(resumable) = &mut self.resumable {
+            resumable.insert(format);
+        }
+    }
+
+    fn remove(&mut self, format: FeedFormat) {
+        if let Some(count) = self.counts.get_mut(&format) {
+            *count -= 1;
+            if *count == 0 {
+                self.counts.remove(&format);
+            }
+        }
+    }
+
+    /// A serializer for messages to be broadcast to feeds, which writes each of the
+    /// formats that some feed wants (and nothing at all if there are no feeds).
+    fn serializer(&self) -> FeedMessageSerializer {
+        FeedMessageSerializer::with_formats(self.counts.keys().copied())
+    }
+
+    /// Like [`FeedFormatCounts::serializer`], but for messages about a single chain.
+    fn chain_serializer(&self, genesis_hash: BlockHash) -> FeedMessageSerializer {
+        match &self.resumable {
+            Some(formats) => {
+                FeedMessageSerializer::for_chain(formats.iter().copied(), genesis_hash)
+            }
+            None => FeedMessageSerializer::for_chain(self.counts.keys().copied(), genesis_hash),
         }
     }
 }
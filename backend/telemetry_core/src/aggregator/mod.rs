@@ -16,7 +16,11 @@
 
 mod aggregator;
 mod aggregator_set;
+mod feed_command;
+mod feed_replay;
+mod feed_worker;
 mod inner_loop;
+mod node_filter;
 
 // Expose the various message types that can be worked with externally:
 pub use aggregator::AggregatorOpts;
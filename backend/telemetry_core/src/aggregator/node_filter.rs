@@ -0,0 +1,141 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::state::Node;
+use common::node_types::{BlockHash, NetworkId};
+use serde::Deserialize;
+
+/// Narrows down the nodes that a feed subscribed to a chain hears about. Every
+/// condition that's given must hold for a node to match.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeFilter {
+    /// Only nodes which are (or aren't) validators.
+    pub validator: Option<bool>,
+    /// Only nodes with one of these network IDs.
+    pub network_ids: Option<Vec<String>>,
+    /// Only nodes whose name starts with this.
+    pub name_prefix: Option<String>,
+    /// Only nodes whose version starts with this, eg "1.2" matches "1.2.0-abcdef".
+    pub version: Option<String>,
+}
+
+impl NodeFilter {
+    pub fn matches(&self, node: &FilterDetails) -> bool {
+        self.validator.is_none_or(|v| v == node.is_validator)
+            && self
+                .network_ids
+                .as_ref()
+                .is_none_or(|ids| ids.iter().any(|id| id.as_str() == node.network_id.as_str()))
+            && self
+                .name_prefix
+                .as_ref()
+                .is_none_or(|prefix| node.name.starts_with(prefix.as_str()))
+            && self
+                .version
+                .as_ref()
+                .is_none_or(|version| node.version.starts_with(version.as_str()))
+    }
+}
+
+/// The details of a node that filters look at. Feed workers don't have the node
+/// state to hand, so these are sent to them alongside messages that add a node.
+#[derive(Clone, Debug)]
+pub struct FilterDetails {
+    is_validator: bool,
+    network_id: NetworkId,
+    name: Box<str>,
+    version: Box<str>,
+}
+
+impl FilterDetails {
+    pub fn new(node: &Node) -> Self {
+        let details = node.details();
+        FilterDetails {
+            is_validator: node.is_validator(),
+            network_id: details.network_id,
+            name: details.name.clone(),
+            version: details.version.clone(),
+        }
+    }
+}
+
+/// The value of a `filter` command from a feed: the chain to subscribe to, and which
+/// of its nodes to hear about.
+#[derive(Debug, Deserialize)]
+pub struct FilteredSubscription {
+    pub chain: BlockHash,
+    #[serde(flatten)]
+    pub filter: NodeFilter,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::test_utils::{self, node_details};
+    use common::node_types::NodeDetails;
+
+    fn node(name: &str, version: &str, validator: Option<&str>) -> FilterDetails {
+        FilterDetails::new(&test_utils::node(NodeDetails {
+            name: name.into(),
+            version: version.into(),
+            validator: validator.map(Into::into),
+            ..node_details()
+        }))
+    }
+
+    #[test]
+    fn filter_is_parsed_from_feed_command() {
+        let sub: FilteredSubscription = serde_json::from_str(
+            r#"{"chain":"0x0000000000000000000000000000000000000000000000000000000000000001","validator":true,"namePrefix":"ops-"}"#,
+        )
+        .unwrap();
+        assert_eq!(sub.chain, BlockHash::from_low_u64_be(1));
+        assert_eq!(
+            sub.filter,
+            NodeFilter {
+                validator: Some(true),
+                name_prefix: Some("ops-".to_owned()),
+                ..Default::default()
+            }
+        );
+
+        // The chain must be given:
+        assert!(serde_json::from_str::<FilteredSubscription>(r#"{"validator":true}"#).is_err());
+    }
+
+    #[test]
+    fn all_conditions_must_match() {
+        let filter = NodeFilter {
+            validator: Some(true),
+            name_prefix: Some("ops-".to_owned()),
+            version: Some("1.2".to_owned()),
+            ..Default::default()
+        };
+
+        assert!(filter.matches(&node("ops-1", "1.2.0-abc", Some("5Grw"))));
+        assert!(!filter.matches(&node("ops-1", "1.2.0-abc", None)));
+        assert!(!filter.matches(&node("dev-1", "1.2.0-abc", Some("5Grw"))));
+        assert!(!filter.matches(&node("ops-1", "1.3.0", Some("5Grw"))));
+
+        let filter = NodeFilter {
+            network_ids: Some(vec!["other".to_owned(), "12D3KooWEyop".to_owned()]),
+            ..Default::default()
+        };
+        assert!(filter.matches(&node("any", "0.1", None)));
+        assert!(NodeFilter::default().matches(&node("any", "0.1", None)));
+    }
+}
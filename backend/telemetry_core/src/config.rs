@@ -0,0 +1,342 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::state::{self, ChainOpts, HardwareRequirements, StatsOpts};
+use common::byte_size::ByteSize;
+use common::logging::{LogFormat, ModuleLevel};
+use common::node_types::BlockHash;
+use common::tls::ServerTlsOpts;
+use common::ws_deflate::DeflateOpts;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The effective configuration of the core, built up from the defaults, a config file,
+/// environment variables and command line flags (see [`common::config`]). Each field
+/// corresponds to the command line flag of the same name; see `Opts` for details.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub listen: SocketAddr,
+    pub log: log::LevelFilter,
+    pub log_format: LogFormat,
+    pub log_module: Vec<ModuleLevel>,
+    pub denylist: Vec<String>,
+    pub feed_timeout: u64,
+    pub feed_debounce_ms: u64,
+    pub feed_compression_level: u32,
+    pub feed_compression_threshold: ByteSize,
+    pub feed_replay_len: usize,
+    pub feed_coalesce_backlog: usize,
+    pub feed_max_backlog: usize,
+    pub worker_threads: Option<usize>,
+    pub feed_workers: Option<usize>,
+    /// Deprecated in favour of `feed_workers`.
+    pub num_aggregators: Option<usize>,
+    pub chain_partitions: Option<usize>,
+    pub aggregator_queue_len: usize,
+    pub max_third_party_nodes: usize,
+    pub first_party_network: Vec<BlockHash>,
+    pub expose_node_details: bool,
+    pub stale_timeout: u64,
+    pub stats_update_interval: u64,
+    pub max_block_height_stored: usize,
+    pub throttle_threshold_ms: u64,
+    /// Which stats to collect about the nodes on each chain. These can only be
+    /// given in the config file.
+    pub stats: StatsOpts,
+    /// Which stats to collect about the nodes on specific chains, in place of `stats`.
+    pub chain_stats: HashMap<BlockHash, StatsOpts>,
+    /// The minimum hardware that validators on specific chains are expected to have.
+    /// These can only be given in the config file.
+    pub hardware_requirements: HashMap<BlockHash, HardwareRequirements>,
+    pub metrics_interval: u64,
+    pub standby_of: Option<String>,
+    pub standby_interval: u64,
+    pub snapshot_path: Option<PathBuf>,
+    pub snapshot_interval: u64,
+    pub restore_from: Option<PathBuf>,
+    pub restore_timeout: u64,
+    pub shutdown_deadline: u64,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_client_ca: Option<PathBuf>,
+    pub tls_reload_interval: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let chain_opts = ChainOpts::default();
+        Config {
+            listen: ([127, 0, 0, 1], 8000).into(),
+            log: log::LevelFilter::Info,
+            log_format: LogFormat::Text,
+            log_module: Vec::new(),
+            denylist: Vec::new(),
+            feed_timeout: 10,
+            feed_debounce_ms: 75,
+            feed_compression_level: 1,
+            feed_compression_threshold: ByteSize::new(1000),
+            feed_replay_len: 256,
+            feed_coalesce_backlog: 16,
+            feed_max_backlog: 10_000,
+            worker_threads: None,
+            feed_workers: None,
+            num_aggregators: None,
+            chain_partitions: None,
+            aggregator_queue_len: 10_000,
+            max_third_party_nodes: 1000,
+            first_party_network: state::default_first_party_networks(),
+            expose_node_details: false,
+            stale_timeout: chain_opts.stale_timeout.as_secs(),
+            stats_update_interval: chain_opts.stats_update_interval.as_secs(),
+            max_block_height_stored: chain_opts.max_block_height_stored,
+            throttle_threshold_ms: chain_opts.throttle_threshold.as_millis() as u64,
+            stats: chain_opts.stats,
+            chain_stats: HashMap::new(),
+            hardware_requirements: HashMap::new(),
+            metrics_interval: 10,
+            standby_of: None,
+            standby_interval: 10,
+            snapshot_path: None,
+            snapshot_interval: 60,
+            restore_from: None,
+            restore_timeout: 120,
+            shutdown_deadline: 10,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            tls_reload_interval: 60,
+        }
+    }
+}
+
+impl Config {
+    /// Load the config file at `path` (if given), apply the `overrides` given by
+    /// environment variables and command line flags, and check the result.
+    pub fn load<O: Serialize>(path: Option<&Path>, overrides: &O) -> anyhow::Result<Config> {
+        let config: Config = common::config::load(path, overrides)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let must_be_nonzero = [
+            ("feed_timeout", self.feed_timeout),
+            ("feed_max_backlog", self.feed_max_backlog as u64),
+            ("stale_timeout", self.stale_timeout),
+            ("stats_update_interval", self.stats_update_interval),
+            ("metrics_interval", self.metrics_interval),
+            ("standby_interval", self.standby_interval),
+            ("snapshot_interval", self.snapshot_interval),
+            ("tls_reload_interval", self.tls_reload_interval),
+            (
+                "max_block_height_stored",
+                self.max_block_height_stored as u64,
+            ),
+        ];
+        for (name, value) in must_be_nonzero {
+            if value == 0 {
+                anyhow::bail!("'{name}' must be greater than 0");
+            }
+        }
+
+        if let Some(primary) = &self.standby_of {
+            primary
+                .parse::<http::Uri>()
+                .map_err(|e| anyhow::anyhow!("'standby_of' is not a valid URL: {e}"))?;
+        }
+
+        if let Some(path) = &self.snapshot_path {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            if !dir.is_dir() {
+                anyhow::bail!(
+                    "'snapshot_path' must be in an existing directory, but {} does not exist",
+                    dir.display()
+                );
+            }
+        }
+
+        self.stats
+            .validate()
+            .map_err(|e| anyhow::anyhow!("Invalid 'stats': {e}"))?;
+        for (genesis_hash, stats) in &self.chain_stats {
+            stats
+                .validate()
+                .map_err(|e| anyhow::anyhow!("Invalid 'chain_stats' for {genesis_hash:?}: {e}"))?;
+        }
+
+        if self.feed_compression_level > 9 {
+            anyhow::bail!("'feed_compression_level' must be between 0 and 9");
+        }
+
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            anyhow::bail!("'tls_cert' and 'tls_key' must be given together");
+        }
+        if self.tls_client_ca.is_some() && self.tls_cert.is_none() {
+            anyhow::bail!("'tls_client_ca' requires 'tls_cert' and 'tls_key' to be given");
+        }
+
+        Ok(())
+    }
+
+    /// How to compress messages to feeds that support it, if at all.
+    pub fn feed_deflate_opts(&self) -> Option<DeflateOpts> {
+        if self.feed_compression_level == 0 {
+            return None;
+        }
+        Some(DeflateOpts {
+            level: self.feed_compression_level,
+            threshold: self.feed_compression_threshold.num_bytes(),
+        })
+    }
+
+    /// How to terminate TLS, if we've been asked to.
+    pub fn tls_opts(&self) -> Option<ServerTlsOpts> {
+        Some(ServerTlsOpts {
+            cert: self.tls_cert.clone()?,
+            key: self.tls_key.clone()?,
+            client_ca: self.tls_client_ca.clone(),
+            reload_interval: Duration::from_secs(self.tls_reload_interval),
+        })
+    }
+
+    /// Settings which affect how every chain is tracked.
+    pub fn chain_opts(&self) -> ChainOpts {
+        ChainOpts {
+            stale_timeout: Duration::from_secs(self.stale_timeout),
+            stats_update_interval: Duration::from_secs(self.stats_update_interval),
+            max_block_height_stored: self.max_block_height_stored,
+            throttle_threshold: Duration::from_millis(self.throttle_threshold_ms),
+            stats: self.stats.clone(),
+            chain_stats: Arc::new(self.chain_stats.clone()),
+            hardware_requirements: Arc::new(self.hardware_requirements.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn defaults_are_valid() {
+        let config = Config::default();
+        assert!(config.validate().is_ok());
+
+        // The defaults survive being serialized and deserialized, which is what happens
+        // to them when they're layered with a config file.
+        let json = serde_json::to_value(&config).unwrap();
+        let config: Config = serde_json::from_value(json).unwrap();
+        assert_eq!(config.first_party_network.len(), 2);
+        assert_eq!(config.log, log::LevelFilter::Info);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let config = Config {
+            metrics_interval: 0,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            stats_update_interval: 0,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            snapshot_path: Some("/does/not/exist/snapshot.json".into()),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            snapshot_path: Some("snapshot.json".into()),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = Config {
+            feed_compression_level: 10,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            tls_cert: Some("cert.pem".into()),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            tls_client_ca: Some("ca.pem".into()),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            stats: StatsOpts {
+                score_buckets: vec![0, 50, 50],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn stats_can_be_configured_per_chain() {
+        let toml = r#"
+            [stats]
+            dimensions = ["version", "country"]
+
+            [chain_stats."0x91b171bb158e2d3848fa23a9f1c25182fb8e20313b2c1eb49219da7a70ce90c3"]
+            reference_cpu_score = 2000
+            score_buckets = [0, 50, 100]
+        "#;
+        let dir = std::env::temp_dir().join("telemetry_core_stats_config_test.toml");
+        std::fs::write(&dir, toml).unwrap();
+        let config = Config::load(Some(&dir), &()).unwrap();
+        std::fs::remove_file(&dir).unwrap();
+
+        let chain_opts = config.chain_opts();
+        let polkadot: BlockHash =
+            "0x91b171bb158e2d3848fa23a9f1c25182fb8e20313b2c1eb49219da7a70ce90c3"
+                .parse()
+                .unwrap();
+
+        let stats = chain_opts.stats_for(&BlockHash::zero());
+        assert_eq!(
+            serde_json::to_value(&stats.dimensions).unwrap(),
+            serde_json::json!(["version", "country"])
+        );
+        assert_eq!(stats.reference_cpu_score, 1028);
+
+        // Settings left out for a chain take their default values:
+        let stats = chain_opts.stats_for(&polkadot);
+        assert_eq!(stats.dimensions, StatsOpts::default().dimensions);
+        assert_eq!(stats.reference_cpu_score, 2000);
+        assert_eq!(stats.score_buckets, vec![0, 50, 100]);
+    }
+}
@@ -0,0 +1,54 @@
+use serde::Serialize;
+
+use crate::state::{AuthorityStatus, Chain, VersionCount};
+
+use super::shared::{SDateTime, SUniqueNodeIdentity};
+
+// This is the struct that will returned back by /authorities/ endpoint
+#[derive(Serialize, Debug, Clone)]
+pub struct Authorities {
+    pub authority_set_id: Option<u64>,
+    pub authority_set_size: Option<usize>,
+    pub authorities: Vec<AuthoritiesAuthority>,
+    pub versions: Vec<VersionCount>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct AuthoritiesAuthority {
+    pub authority_id: Box<str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_set: Option<bool>,
+    pub status: AuthorityStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<SDateTime>,
+    pub nodes: Vec<SUniqueNodeIdentity>,
+}
+
+impl From<&Chain> for Authorities {
+    fn from(value: &Chain) -> Self {
+        let authorities = value
+            .authorities()
+            .into_iter()
+            .map(|authority| AuthoritiesAuthority {
+                authority_id: authority.authority_id.into(),
+                in_set: authority.in_set,
+                status: authority.status,
+                last_seen: authority.last_seen.map(Into::into),
+                nodes: authority
+                    .nodes
+                    .into_iter()
+                    .filter_map(|nid| value.get_node(nid))
+                    .map(|node| node.identity().into())
+                    .collect(),
+            })
+            .collect();
+
+        let set = value.authority_set();
+        Self {
+            authority_set_id: set.and_then(|set| set.id),
+            authority_set_size: set.map(|set| set.authorities.len()),
+            authorities,
+            versions: value.versions(),
+        }
+    }
+}
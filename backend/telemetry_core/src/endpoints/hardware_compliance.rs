@@ -0,0 +1,43 @@
+use serde::Serialize;
+
+use crate::state::{Chain, HardwareRequirements, NodeCompliance};
+
+use super::shared::SUniqueNodeIdentity;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct HardwareCompliance {
+    pub requirements: HardwareRequirements,
+    pub validator_count: usize,
+    pub compliant_count: usize,
+    pub validators: Vec<HardwareComplianceValidator>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct HardwareComplianceValidator {
+    pub identity: SUniqueNodeIdentity,
+    pub validator: Option<Box<str>>,
+    pub compliance: NodeCompliance,
+}
+
+impl HardwareCompliance {
+    pub fn new(requirements: &HardwareRequirements, chain: &Chain) -> Self {
+        let validators: Vec<HardwareComplianceValidator> = chain
+            .nodes_slice()
+            .iter()
+            .flatten()
+            .filter(|node| node.is_validator())
+            .map(|node| HardwareComplianceValidator {
+                identity: node.identity().into(),
+                validator: node.details().validator.clone(),
+                compliance: requirements.check(node),
+            })
+            .collect();
+
+        Self {
+            requirements: requirements.clone(),
+            validator_count: validators.len(),
+            compliant_count: validators.iter().filter(|v| v.compliance.compliant).count(),
+            validators,
+        }
+    }
+}
@@ -1,8 +1,12 @@
+mod authorities;
 mod block_history;
+mod hardware_compliance;
 mod node_list;
 mod overview;
 mod shared;
 
+pub use authorities::Authorities;
 pub use block_history::BlockHistory;
+pub use hardware_compliance::HardwareCompliance;
 pub use node_list::NodeList;
 pub use overview::*;
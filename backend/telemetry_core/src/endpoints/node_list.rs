@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use common::node_types::{Block, NodeDetails};
 use serde::Serialize;
 
-use crate::state::{Chain, Node};
+use crate::state::{Chain, HardwareRequirements, Node, NodeCompliance};
 
 use super::shared::SUniqueNodeIdentity;
 
@@ -44,11 +44,12 @@ impl From<&Chain> for NodeList {
 
         implementations.sort_by(|a, b| b.version.cmp(&a.version));
 
+        let requirements = value.hardware_requirements();
         let nodes: Vec<NodeListNodeDetails> = value
             .nodes_slice()
             .iter()
             .flatten()
-            .map(|n| n.into())
+            .map(|n| NodeListNodeDetails::new(n, requirements))
             .collect();
 
         implementations.sort_by(|a, b| b.version.cmp(&a.version));
@@ -72,10 +73,13 @@ pub struct NodeListNodeDetails {
     pub txcount: u64,
     pub stale: bool,
     pub is_authority: Option<bool>,
+    /// How the node measures up to the hardware requirements of the chain, if it has any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hardware_compliance: Option<NodeCompliance>,
 }
 
-impl From<&Node> for NodeListNodeDetails {
-    fn from(value: &Node) -> Self {
+impl NodeListNodeDetails {
+    fn new(value: &Node, requirements: Option<&HardwareRequirements>) -> Self {
         Self {
             identity: value.identity().into(),
             details: value.details().clone(),
@@ -86,6 +90,7 @@ impl From<&Node> for NodeListNodeDetails {
             txcount: value.stats().txcount,
             stale: value.stale(),
             is_authority: value.is_authority(),
+            hardware_compliance: requirements.map(|r| r.check(value)),
         }
     }
 }
@@ -17,18 +17,44 @@
 //! This module provides a way of encoding the various messages that we'll
 //! send to subscribed feeds (browsers).
 
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Serialize};
 
-use crate::state::Node;
+use crate::state::{AuthoritySummary, Node};
 use common::node_types::{
     BlockDetails, BlockHash, BlockNumber, NodeHardware, NodeIO, NodeStats, Timestamp,
 };
+use common::MeanListChange;
 use serde_json::to_writer;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::Arc;
 
-type FeedNodeId = usize;
+pub type FeedNodeId = usize;
 
 pub trait FeedMessage {
     const ACTION: u8;
+
+    /// What the message is about, so that feeds can be sent only the messages about
+    /// nodes that they're interested in.
+    fn subject(&self) -> MessageSubject {
+        MessageSubject::Chain
+    }
+}
+
+/// What a feed message is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageSubject {
+    /// Says which chain the messages alongside it are about, or how far along
+    /// it they are. This isn't worth sending on its own.
+    Context,
+    /// The chain (or everything) rather than any one node.
+    Chain,
+    /// A node was added, or its details changed.
+    NodeAdded(FeedNodeId),
+    /// A node was removed.
+    NodeRemoved(FeedNodeId),
+    /// Some other update about a node.
+    Node(FeedNodeId),
 }
 
 pub trait FeedMessageWrite: FeedMessage {
@@ -44,58 +70,617 @@ where
     }
 }
 
+/// The formats that feeds can ask to receive messages in. Whatever the format, feeds
+/// receive an array of alternating action IDs and payloads, as laid out below.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FeedFormat {
+    Json,
+    MessagePack,
+}
+
+impl FeedFormat {
+    /// All of the formats that we support.
+    pub const ALL: [FeedFormat; 2] = [FeedFormat::Json, FeedFormat::MessagePack];
+
+    /// The name of the format, as given in the `?format=` query parameter of a feed request.
+    pub fn name(self) -> &'static str {
+        match self {
+            FeedFormat::Json => "json",
+            FeedFormat::MessagePack => "msgpack",
+        }
+    }
+
+    /// The websocket subprotocol that a feed can ask for to receive messages in this format.
+    pub fn subprotocol(self) -> &'static str {
+        match self {
+            FeedFormat::Json => "telemetry-json",
+            FeedFormat::MessagePack => "telemetry-msgpack",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<FeedFormat> {
+        Self::ALL.into_iter().find(|f| f.name() == name)
+    }
+
+    pub fn from_subprotocol(protocol: &str) -> Option<FeedFormat> {
+        Self::ALL.into_iter().find(|f| f.subprotocol() == protocol)
+    }
+}
+
 pub struct FeedMessageSerializer {
-    /// Current buffer.
-    buffer: Vec<u8>,
+    /// Current JSON buffer, if we've been asked for JSON.
+    json: Option<Vec<u8>>,
+    /// Current MessagePack buffer, if we've been asked for MessagePack. The array
+    /// header can't be written until we know how many messages there are, so it's
+    /// added in [`FeedMessageSerializer::into_finalized`].
+    msgpack: Option<Vec<u8>>,
+    /// How many messages have been pushed so far.
+    len: usize,
+    /// Where each message is in the buffers, and what it's about.
+    spans: Vec<MessageSpan>,
+    /// The chain that the messages are about, if they're all about one.
+    chain: Option<BlockHash>,
+}
+
+/// The location of a single message (action and payload) in each buffer.
+#[derive(Debug, Clone)]
+struct MessageSpan {
+    action: u8,
+    subject: MessageSubject,
+    json: Range<usize>,
+    msgpack: Range<usize>,
 }
 
 const BUFCAP: usize = 128;
 
 impl FeedMessageSerializer {
-    pub fn new() -> Self {
-        Self {
-            buffer: Vec::with_capacity(BUFCAP),
+    /// Serialize messages to each of the formats given.
+    pub fn with_formats(formats: impl IntoIterator<Item = FeedFormat>) -> Self {
+        let mut ser = Self {
+            json: None,
+            msgpack: None,
+            len: 0,
+            spans: Vec::new(),
+            chain: None,
+        };
+        for format in formats {
+            match format {
+                FeedFormat::Json => ser.json = Some(Vec::with_capacity(BUFCAP)),
+                FeedFormat::MessagePack => ser.msgpack = Some(Vec::with_capacity(BUFCAP)),
+            }
         }
+        ser
+    }
+
+    /// Serialize messages about the chain given to each of the formats given. The messages
+    /// are preceded by a [`ChainContext`], so that feeds subscribed to several chains know
+    /// which chain they're about.
+    pub fn for_chain(
+        formats: impl IntoIterator<Item = FeedFormat>,
+        genesis_hash: BlockHash,
+    ) -> Self {
+        let mut ser = Self::with_formats(formats);
+        ser.chain = Some(genesis_hash);
+        ser.push(ChainContext(genesis_hash));
+        ser
+    }
+
+    /// Have any messages worth sending been pushed?
+    pub fn is_empty(&self) -> bool {
+        self.spans
+            .iter()
+            .all(|s| s.subject == MessageSubject::Context)
     }
 
     pub fn push<Message>(&mut self, msg: Message)
     where
         Message: FeedMessageWrite,
     {
-        let glue = match self.buffer.len() {
-            0 => b'[',
-            _ => b',',
-        };
-
-        self.buffer.push(glue);
-        self.write(&Message::ACTION);
-        self.buffer.push(b',');
+        let mut json_start = 0;
+        if let Some(json) = &mut self.json {
+            let glue = match self.len {
+                0 => b'[',
+                _ => b',',
+            };
+            json.push(glue);
+            json_start = json.len();
+            let _ = to_writer(&mut *json, &Message::ACTION);
+            json.push(b',');
+        }
+        let msgpack_start = self.msgpack.as_ref().map_or(0, |m| m.len());
+        if let Some(msgpack) = &mut self.msgpack {
+            let _ = rmp::encode::write_uint(msgpack, Message::ACTION.into());
+        }
+        self.len += 1;
         msg.write_to_feed(self);
+
+        self.spans.push(MessageSpan {
+            action: Message::ACTION,
+            subject: msg.subject(),
+            json: json_start..self.json.as_ref().map_or(0, |j| j.len()),
+            msgpack: msgpack_start..self.msgpack.as_ref().map_or(0, |m| m.len()),
+        });
     }
 
     fn write<S>(&mut self, value: &S)
     where
         S: Serialize,
     {
-        let _ = to_writer(&mut self.buffer, value);
+        if let Some(json) = &mut self.json {
+            let _ = to_writer(json, value);
+        }
+        if let Some(msgpack) = &mut self.msgpack {
+            // Structs are written as maps rather than arrays, to mirror the JSON.
+            let _ = rmp_serde::encode::write_named(msgpack, value);
+        }
     }
 
     /// Return the bytes that we've serialized so far, consuming the serializer.
-    pub fn into_finalized(mut self) -> Option<bytes::Bytes> {
-        if self.buffer.is_empty() {
+    pub fn into_finalized(self) -> Option<FeedBytes> {
+        if self.is_empty() {
             return None;
         }
 
-        self.buffer.push(b']');
-        Some(self.buffer.into())
+        let json = self.json.map(|mut json| {
+            json.push(b']');
+            json.into()
+        });
+        let mut spans = self.spans;
+        let msgpack = self.msgpack.map(|body| {
+            let mut msgpack = Vec::with_capacity(body.len() + 5);
+            let _ = rmp::encode::write_array_len(&mut msgpack, (self.len * 2) as u32);
+            // Message locations are relative to the body, which now comes after the header:
+            let header_len = msgpack.len();
+            for span in &mut spans {
+                span.msgpack = span.msgpack.start + header_len..span.msgpack.end + header_len;
+            }
+            msgpack.extend_from_slice(&body);
+            msgpack.into()
+        });
+        Some(FeedBytes {
+            json,
+            msgpack,
+            chain: self.chain,
+            spans: spans.into(),
+        })
     }
 }
 
+/// Some messages serialized into each of the formats that were asked for.
+#[derive(Clone, Debug)]
+pub struct FeedBytes {
+    json: Option<bytes::Bytes>,
+    msgpack: Option<bytes::Bytes>,
+    chain: Option<BlockHash>,
+    spans: Arc<[MessageSpan]>,
+}
+
+impl FeedBytes {
+    /// The messages in the format given, if they were serialized to it.
+    pub fn get(&self, format: FeedFormat) -> Option<&bytes::Bytes> {
+        match format {
+            FeedFormat::Json => self.json.as_ref(),
+            FeedFormat::MessagePack => self.msgpack.as_ref(),
+        }
+    }
+
+    /// The messages in the format given, ready to be sent to a feed.
+    pub fn batch(&self, format: FeedFormat) -> Option<FeedBatch> {
+        Some(FeedBatch {
+            bytes: self.get(format)?.clone(),
+            format,
+            chain: self.chain,
+            spans: self.spans.clone(),
+        })
+    }
+
+    /// The nodes that these messages add, or change the details of.
+    pub fn nodes_added(&self) -> impl Iterator<Item = FeedNodeId> + '_ {
+        self.spans.iter().filter_map(|span| match span.subject {
+            MessageSubject::NodeAdded(id) => Some(id),
+            _ => None,
+        })
+    }
+
+    /// The messages in the format given, keeping only those for which `keep` returns true.
+    /// `keep` is called once for each message, in order. Returns `None` if there's nothing
+    /// worth sending.
+    pub fn filtered(
+        &self,
+        format: FeedFormat,
+        mut keep: impl FnMut(MessageSubject) -> bool,
+    ) -> Option<FeedBatch> {
+        self.batch(format)?.retain(|span| keep(span.subject))
+    }
+}
+
+/// Some messages serialized into the format that a feed wants. Unlike plain bytes,
+/// we know enough about each message to drop those that later ones make redundant
+/// when the feed falls behind.
+#[derive(Clone, Debug)]
+pub struct FeedBatch {
+    bytes: bytes::Bytes,
+    format: FeedFormat,
+    chain: Option<BlockHash>,
+    spans: Arc<[MessageSpan]>,
+}
+
+impl FeedBatch {
+    /// The serialized messages.
+    pub fn bytes(&self) -> &bytes::Bytes {
+        &self.bytes
+    }
+
+    /// Drop any messages in these batches (which are assumed to be queued up to be sent
+    /// to one feed, oldest first) that a later message in them supersedes, and then any
+    /// batches left with nothing worth sending. Returns how many messages were dropped.
+    ///
+    /// Only messages which say how things are now, rather than what changed, are
+    /// dropped, such that a feed which is sent the rest ends up in the same state.
+    /// The hardware and IO deltas for each node are merged into the latest of them
+    /// instead, which then carries the sequence number of the first one as well, so
+    /// the feed ends up with the same series without seeing a gap.
+    pub fn coalesce(batches: &mut Vec<FeedBatch>) -> usize {
+        // Going backwards, the first message we see about something is the latest:
+        let mut seen = HashSet::new();
+        let mut superseded: Vec<Vec<bool>> = Vec::with_capacity(batches.len());
+        let mut deltas: HashMap<_, Vec<(usize, usize)>> = HashMap::new();
+        for (batch_idx, batch) in batches.iter().enumerate().rev() {
+            let chain = batch.chain;
+            let mut batch_superseded = vec![false; batch.spans.len()];
+            for (idx, span) in batch.spans.iter().enumerate().rev() {
+                if let Some(key) = supersede_key(chain, span) {
+                    batch_superseded[idx] = !seen.insert(key);
+                } else if let Some(key) = delta_key(chain, span) {
+                    deltas.entry(key).or_default().push((batch_idx, idx));
+                }
+            }
+            superseded.push(batch_superseded);
+        }
+        superseded.reverse();
+
+        // Replace the latest delta for each node with one merged from all of them. If
+        // they can't be merged for some reason, they're all left alone:
+        let mut merged = HashMap::new();
+        for mut locations in deltas.into_values().filter(|l| l.len() > 1) {
+            locations.reverse();
+            let messages = locations.iter().map(|&(batch_idx, idx)| {
+                let batch = &batches[batch_idx];
+                let span = &batch.spans[idx];
+                (span.action, &batch.bytes[batch.range(span)])
+            });
+            if let Some(message) = merge_deltas(batches[0].format, messages) {
+                let (&latest, earlier) = locations.split_last().unwrap();
+                for &(batch_idx, idx) in earlier {
+                    superseded[batch_idx][idx] = true;
+                }
+                merged.insert(latest, message);
+            }
+        }
+
+        let mut dropped = 0;
+        let mut coalesced = Vec::with_capacity(batches.len());
+        for (batch_idx, (batch, superseded)) in batches.drain(..).zip(superseded).enumerate() {
+            dropped += superseded.iter().filter(|&&s| s).count();
+            let rewritten = batch.rewrite(|idx, _| {
+                if superseded[idx] {
+                    Rewrite::Drop
+                } else if let Some(message) = merged.remove(&(batch_idx, idx)) {
+                    Rewrite::Replace(message)
+                } else {
+                    Rewrite::Keep
+                }
+            });
+            if let Some(batch) = rewritten {
+                coalesced.push(batch);
+            }
+        }
+        *batches = coalesced;
+        dropped
+    }
+
+    /// Join these batches (oldest first) into one, so that they can be sent to a feed in
+    /// one websocket message. The messages in each batch about a chain follow a
+    /// [`ChainContext`], so they're still about the right chain once joined, but the
+    /// joined batch only knows which chain it's about if they all are about the same
+    /// one; coalesce before joining rather than after. Returns `None` if there are no
+    /// batches.
+    pub fn join(batches: Vec<FeedBatch>) -> Option<FeedBatch> {
+        if batches.len() < 2 {
+            return batches.into_iter().next();
+        }
+        let first = &batches[0];
+        let chain = first
+            .chain
+            .filter(|&c| batches.iter().all(|b| b.chain == Some(c)));
+        let messages: Vec<_> = batches
+            .iter()
+            .flat_map(|b| {
+                b.spans
+                    .iter()
+                    .map(move |span| (span, &b.bytes[b.range(span)]))
+            })
+            .collect();
+        Some(FeedBatch::from_messages(
+            first.format,
+            chain,
+            messages.into_iter(),
+        ))
+    }
+
+    /// The batch, keeping only the messages for which `keep` returns true. `keep` is
+    /// called once for each message, in order. Returns `None` if there's nothing worth
+    /// sending.
+    fn retain(&self, mut keep: impl FnMut(&MessageSpan) -> bool) -> Option<FeedBatch> {
+        self.rewrite(|_, span| match keep(span) {
+            true => Rewrite::Keep,
+            false => Rewrite::Drop,
+        })
+    }
+
+    /// The batch, with each message kept, dropped or replaced as `rewrite` says. `rewrite`
+    /// is called once for each message (along with its index), in order. Returns `None`
+    /// if there's nothing worth sending.
+    fn rewrite(
+        &self,
+        mut rewrite: impl FnMut(usize, &MessageSpan) -> Rewrite,
+    ) -> Option<FeedBatch> {
+        let kept: Vec<(&MessageSpan, Rewrite)> = self
+            .spans
+            .iter()
+            .enumerate()
+            .map(|(idx, span)| (span, rewrite(idx, span)))
+            .filter(|(_, r)| !matches!(r, Rewrite::Drop))
+            .collect();
+        if kept
+            .iter()
+            .all(|(s, _)| s.subject == MessageSubject::Context)
+        {
+            return None;
+        }
+        if kept.len() == self.spans.len() && kept.iter().all(|(_, r)| matches!(r, Rewrite::Keep)) {
+            return Some(self.clone());
+        }
+
+        let messages = kept.iter().map(|(span, rewrite)| {
+            let bytes = match rewrite {
+                Rewrite::Replace(message) => &message[..],
+                _ => &self.bytes[self.range(span)],
+            };
+            (*span, bytes)
+        });
+        Some(FeedBatch::from_messages(self.format, self.chain, messages))
+    }
+
+    /// Build a batch from messages (action and payload) already serialized in the format
+    /// given, along with what each of them is about.
+    fn from_messages<'a>(
+        format: FeedFormat,
+        chain: Option<BlockHash>,
+        messages: impl ExactSizeIterator<Item = (&'a MessageSpan, &'a [u8])>,
+    ) -> FeedBatch {
+        let mut out = Vec::with_capacity(BUFCAP);
+        let mut spans = Vec::with_capacity(messages.len());
+        match format {
+            FeedFormat::Json => out.push(b'['),
+            FeedFormat::MessagePack => {
+                let _ = rmp::encode::write_array_len(&mut out, (messages.len() * 2) as u32);
+            }
+        }
+        for (idx, (span, message)) in messages.enumerate() {
+            if idx > 0 && format == FeedFormat::Json {
+                out.push(b',');
+            }
+            let start = out.len();
+            out.extend_from_slice(message);
+            let (json, msgpack) = match format {
+                FeedFormat::Json => (start..out.len(), 0..0),
+                FeedFormat::MessagePack => (0..0, start..out.len()),
+            };
+            spans.push(MessageSpan {
+                json,
+                msgpack,
+                ..*span
+            });
+        }
+        if format == FeedFormat::Json {
+            out.push(b']');
+        }
+
+        FeedBatch {
+            bytes: out.into(),
+            format,
+            chain,
+            spans: spans.into(),
+        }
+    }
+
+    /// Where the message is in the bytes of this batch.
+    fn range(&self, span: &MessageSpan) -> Range<usize> {
+        match self.format {
+            FeedFormat::Json => span.json.clone(),
+            FeedFormat::MessagePack => span.msgpack.clone(),
+        }
+    }
+}
+
+/// What to do with a message when rewriting a [`FeedBatch`].
+enum Rewrite {
+    Keep,
+    Drop,
+    /// Send this message (action and payload, serialized like the rest) in its place.
+    Replace(Vec<u8>),
+}
+
+/// If a later message with the same key makes this one redundant, return that key.
+fn supersede_key(
+    chain: Option<BlockHash>,
+    span: &MessageSpan,
+) -> Option<(Option<BlockHash>, u8, Option<FeedNodeId>)> {
+    let node_id = match span.subject {
+        MessageSubject::Node(id) => Some(id),
+        MessageSubject::Chain => None,
+        _ => return None,
+    };
+    match (span.action, node_id) {
+        (BestBlock::ACTION, None)
+        | (BestFinalized::ACTION, None)
+        | (TimeSync::ACTION, None)
+        | (ChainStatsUpdate::ACTION, None)
+            if chain.is_some() =>
+        {
+            Some((chain, span.action, None))
+        }
+        (ImportedBlock::ACTION, Some(_))
+        | (FinalizedBlock::ACTION, Some(_))
+        | (NodeStatsUpdate::ACTION, Some(_)) => Some((chain, span.action, node_id)),
+        _ => None,
+    }
+}
+
+/// If this message is a delta to a node's series that can be merged with the others
+/// for the same key, return that key.
+fn delta_key(
+    chain: Option<BlockHash>,
+    span: &MessageSpan,
+) -> Option<(Option<BlockHash>, u8, FeedNodeId)> {
+    match (span.action, span.subject) {
+        (HardwareDelta::ACTION, MessageSubject::Node(id))
+        | (NodeIODelta::ACTION, MessageSubject::Node(id)) => Some((chain, span.action, id)),
+        _ => None,
+    }
+}
+
+/// The number of samples to drop from the start of a series, followed by the samples
+/// to append to it, as [`common::MeanList::delta`] describes them.
+type SeriesDelta<T> = (u8, Vec<T>);
+
+/// The most samples that a series has; dropping at least this many replaces it.
+const SERIES_LEN: usize = 20;
+
+/// Merge consecutive [`HardwareDelta`] or [`NodeIODelta`] messages (action and
+/// payload, oldest first) about one node into one message, which is followed by the
+/// sequence number of the first of them. Returns `None` if they can't be merged.
+fn merge_deltas<'a>(
+    format: FeedFormat,
+    mut messages: impl Iterator<Item = (u8, &'a [u8])>,
+) -> Option<Vec<u8>> {
+    let (action, first) = messages.next()?;
+    match action {
+        HardwareDelta::ACTION => {
+            type Payload = (
+                FeedNodeId,
+                u64,
+                SeriesDelta<f64>,
+                SeriesDelta<f64>,
+                SeriesDelta<f64>,
+            );
+            let (nid, first_seq, mut up, mut down, mut stamps): Payload =
+                decode_payload(format, first)?;
+            let mut seq = first_seq;
+            for (_, message) in messages {
+                let (_, next_seq, next_up, next_down, next_stamps): Payload =
+                    decode_payload(format, message)?;
+                if next_seq != seq + 1 {
+                    return None;
+                }
+                seq = next_seq;
+                up = merge_series(up, next_up);
+                down = merge_series(down, next_down);
+                stamps = merge_series(stamps, next_stamps);
+            }
+            Some(encode_message(
+                format,
+                action,
+                &(nid, seq, up, down, stamps, first_seq),
+            ))
+        }
+        NodeIODelta::ACTION => {
+            type Payload = (FeedNodeId, u64, SeriesDelta<f32>);
+            let (nid, first_seq, mut state_cache): Payload = decode_payload(format, first)?;
+            let mut seq = first_seq;
+            for (_, message) in messages {
+                let (_, next_seq, next_state_cache): Payload = decode_payload(format, message)?;
+                if next_seq != seq + 1 {
+                    return None;
+                }
+                seq = next_seq;
+                state_cache = merge_series(state_cache, next_state_cache);
+            }
+            Some(encode_message(
+                format,
+                action,
+                &(nid, seq, state_cache, first_seq),
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Combine two consecutive deltas to a series into one which, applied like any other
+/// (dropping samples first, and then keeping at most the last [`SERIES_LEN`]), leaves
+/// the series the same as applying each of them in turn would.
+fn merge_series<T>((drop, mut samples): SeriesDelta<T>, next: SeriesDelta<T>) -> SeriesDelta<T> {
+    let (next_drop, next_samples) = next;
+    if next_drop as usize >= SERIES_LEN {
+        return (next_drop, next_samples);
+    }
+    let drop = if drop as usize >= SERIES_LEN {
+        // The samples are the whole series, so we can drop from them directly:
+        samples.drain(..samples.len().min(next_drop as usize));
+        drop
+    } else {
+        // The series is only ever shifted once it's full, so once more samples have
+        // been dropped than it had, what's left is the last SERIES_LEN appended:
+        (drop + next_drop).min(SERIES_LEN as u8)
+    };
+    samples.extend(next_samples);
+    samples.drain(..samples.len().saturating_sub(SERIES_LEN));
+    (drop, samples)
+}
+
+/// Decode the payload of a message (action and payload) serialized in the format given.
+fn decode_payload<T: DeserializeOwned>(format: FeedFormat, mut message: &[u8]) -> Option<T> {
+    match format {
+        FeedFormat::Json => {
+            let comma = message.iter().position(|&b| b == b',')?;
+            serde_json::from_slice(&message[comma + 1..]).ok()
+        }
+        FeedFormat::MessagePack => {
+            rmp::decode::read_int::<u8, _>(&mut message).ok()?;
+            rmp_serde::from_slice(message).ok()
+        }
+    }
+}
+
+/// Serialize a message (action and payload) in the format given, as
+/// [`FeedMessageSerializer`] would.
+fn encode_message<S: Serialize>(format: FeedFormat, action: u8, payload: &S) -> Vec<u8> {
+    let mut out = Vec::with_capacity(BUFCAP);
+    match format {
+        FeedFormat::Json => {
+            let _ = to_writer(&mut out, &action);
+            out.push(b',');
+            let _ = to_writer(&mut out, payload);
+        }
+        FeedFormat::MessagePack => {
+            let _ = rmp::encode::write_uint(&mut out, action.into());
+            let _ = rmp_serde::encode::write_named(&mut out, payload);
+        }
+    }
+    out
+}
+
 macro_rules! actions {
-    ($($action:literal: $t:ty,)*) => {
+    ($($action:literal: $t:ty $(=> $subject:ident $(($field:tt))?)?,)*) => {
         $(
             impl FeedMessage for $t {
                 const ACTION: u8 = $action;
+                $(
+                    fn subject(&self) -> MessageSubject {
+                        MessageSubject::$subject $((self.$field))?
+                    }
+                )?
             }
         )*
     }
@@ -105,24 +690,32 @@ actions! {
      0: Version,
      1: BestBlock,
      2: BestFinalized,
-     3: AddedNode<'_>,
-     4: RemovedNode,
-     5: LocatedNode<'_>,
-     6: ImportedBlock<'_>,
-     7: FinalizedBlock,
-     8: NodeStatsUpdate<'_>,
-     9: Hardware<'_>,
+     3: AddedNode<'_> => NodeAdded(0),
+     4: RemovedNode => NodeRemoved(0),
+     5: LocatedNode<'_> => Node(0),
+     6: ImportedBlock<'_> => Node(0),
+     7: FinalizedBlock => Node(0),
+     8: NodeStatsUpdate<'_> => Node(0),
     10: TimeSync,
     11: AddedChain<'_>,
     12: RemovedChain,
     13: SubscribedTo,
     14: UnsubscribedFrom,
     15: Pong<'_>,
-    // Note; some now-unused messages were removed between IDs 15 and 20.
+    // Note; some now-unused messages were removed between IDs 15 and 20, and
+    // IDs 9 and 21 (full hardware and IO updates) were replaced by 23 and 24.
     // We maintain existing IDs for backward compatibility.
-    20: StaleNode,
-    21: NodeIOUpdate<'_>,
+    20: StaleNode => Node(0),
     22: ChainStatsUpdate<'_>,
+    23: HardwareDelta<'_> => Node(0),
+    24: NodeIODelta<'_> => Node(0),
+    25: ChainContext => Context,
+    26: CommandAck,
+    27: CommandError<'_>,
+    28: ChainSeq => Context,
+    29: Resumed,
+    30: ChainStatsDelta<'_>,
+    31: AuthoritiesUpdate<'_>,
 }
 
 #[derive(Serialize)]
@@ -151,11 +744,17 @@ pub struct FinalizedBlock(pub FeedNodeId, pub BlockNumber, pub BlockHash);
 #[derive(Serialize)]
 pub struct NodeStatsUpdate<'a>(pub FeedNodeId, pub &'a NodeStats);
 
-#[derive(Serialize)]
-pub struct NodeIOUpdate<'a>(pub FeedNodeId, pub &'a NodeIO);
+/// The samples added to each of a node's hardware series since the last update. The
+/// sequence number goes up by one with each update, so that feeds can spot gaps.
+pub struct HardwareDelta<'a>(
+    pub FeedNodeId,
+    pub u64,
+    pub &'a NodeHardware,
+    pub [MeanListChange; 3],
+);
 
-#[derive(Serialize)]
-pub struct Hardware<'a>(pub FeedNodeId, pub &'a NodeHardware);
+/// The samples added to a node's IO series since the last update.
+pub struct NodeIODelta<'a>(pub FeedNodeId, pub u64, pub &'a NodeIO, pub MeanListChange);
 
 #[derive(Serialize)]
 pub struct TimeSync(pub u64);
@@ -178,6 +777,33 @@ pub struct Pong<'a>(pub &'a str);
 #[derive(Serialize)]
 pub struct StaleNode(pub FeedNodeId);
 
+/// The messages that follow this one (in the same websocket message) are about the chain
+/// with this genesis hash.
+#[derive(Serialize)]
+pub struct ChainContext(pub BlockHash);
+
+/// The messages about a chain up to and including this one are numbered this. Feeds
+/// which reconnect can pick up from here rather than subscribing again.
+#[derive(Serialize)]
+pub struct ChainSeq(pub u64);
+
+/// The feed has resumed its subscription to the chain with this genesis hash. It'll be
+/// sent any messages about the chain that it missed next.
+#[derive(Serialize)]
+pub struct Resumed(pub BlockHash);
+
+/// The command sent by a feed with this request ID was carried out.
+#[derive(Serialize)]
+pub struct CommandAck(pub u64);
+
+/// The command sent by a feed with this request ID failed, for the reason given.
+#[derive(Serialize)]
+pub struct CommandError<'a>(pub u64, pub &'a str);
+
+/// The authorities of the chain which aren't reporting or have gone stale, and how
+/// many validators and full nodes are running each version.
+pub struct AuthoritiesUpdate<'a>(pub &'a AuthoritySummary);
+
 impl FeedMessageWrite for AddedNode<'_> {
     fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
         let AddedNode(nid, node, expose_node_details) = self;
@@ -211,6 +837,52 @@ impl FeedMessageWrite for AddedNode<'_> {
             node.block_details(),
             &node.location(),
             &node.startup_time(),
+            node.hardware_seq(),
+            node.io_seq(),
+        ));
+    }
+}
+
+impl FeedMessageWrite for HardwareDelta<'_> {
+    fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
+        let HardwareDelta(nid, seq, hardware, [upload, download, chart_stamps]) = self;
+        ser.write(&(
+            nid,
+            seq,
+            hardware.upload.delta(*upload),
+            hardware.download.delta(*download),
+            hardware.chart_stamps.delta(*chart_stamps),
+        ));
+    }
+}
+
+impl FeedMessageWrite for NodeIODelta<'_> {
+    fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
+        let NodeIODelta(nid, seq, io, used_state_cache_size) = self;
+        ser.write(&(
+            nid,
+            seq,
+            io.used_state_cache_size.delta(*used_state_cache_size),
+        ));
+    }
+}
+
+impl FeedMessageWrite for AuthoritiesUpdate<'_> {
+    fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
+        let AuthoritiesUpdate(summary) = self;
+        let versions: Vec<_> = summary
+            .versions
+            .iter()
+            .map(|v| (&v.version, v.validators, v.full_nodes))
+            .collect();
+        ser.write(&(
+            summary.set_id,
+            summary.set_size,
+            summary.reporting,
+            &summary.stale,
+            &summary.stopped,
+            &summary.missing,
+            versions,
         ));
     }
 }
@@ -225,20 +897,290 @@ pub struct Ranking<K> {
     pub unknown: u64,
 }
 
-#[derive(Serialize, PartialEq, Eq, Default)]
-pub struct ChainStats {
-    pub version: Ranking<String>,
-    pub target_os: Ranking<String>,
-    pub target_arch: Ranking<String>,
-    pub cpu: Ranking<String>,
-    pub memory: Ranking<(u32, Option<u32>)>,
-    pub core_count: Ranking<u32>,
-    pub linux_kernel: Ranking<String>,
-    pub linux_distro: Ranking<String>,
-    pub is_virtual_machine: Ranking<bool>,
-    pub cpu_hashrate_score: Ranking<(u32, Option<u32>)>,
-    pub memory_memcpy_score: Ranking<(u32, Option<u32>)>,
-    pub disk_sequential_write_score: Ranking<(u32, Option<u32>)>,
-    pub disk_random_write_score: Ranking<(u32, Option<u32>)>,
-    pub cpu_vendor: Ranking<String>,
+macro_rules! chain_stats {
+    ($($field:ident: $key:ty,)*) => {
+        /// Rankings of the nodes on a chain. Those for dimensions that
+        /// aren't being collected for the chain are `None`.
+        #[derive(Serialize, PartialEq, Eq, Default)]
+        pub struct ChainStats {
+            $(pub $field: Option<Ranking<$key>>,)*
+        }
+
+        /// Just the rankings in some [`ChainStats`] which have changed; the
+        /// others are left out when this is serialized.
+        #[derive(Serialize, Default)]
+        pub struct ChainStatsDelta<'a> {
+            $(
+                #[serde(skip_serializing_if = "Option::is_none")]
+                pub $field: Option<&'a Ranking<$key>>,
+            )*
+        }
+
+        impl ChainStatsDelta<'_> {
+            /// Have none of the rankings changed?
+            pub fn is_empty(&self) -> bool {
+                true $(&& self.$field.is_none())*
+            }
+        }
+    };
+}
+
+chain_stats! {
+    version: String,
+    implementation: String,
+    target_os: String,
+    target_arch: String,
+    cpu: String,
+    memory: (u32, Option<u32>),
+    core_count: u32,
+    linux_kernel: String,
+    linux_distro: String,
+    is_virtual_machine: bool,
+    cpu_hashrate_score: (u32, Option<u32>),
+    memory_memcpy_score: (u32, Option<u32>),
+    disk_sequential_write_score: (u32, Option<u32>),
+    disk_random_write_score: (u32, Option<u32>),
+    cpu_vendor: String,
+    validator: bool,
+    country: String,
+    startup_age: (u32, Option<u32>),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::MeanList;
+
+    #[test]
+    fn msgpack_and_json_carry_the_same_messages() {
+        let mut ser = FeedMessageSerializer::with_formats(FeedFormat::ALL);
+        ser.push(Version(35));
+        ser.push(AddedChain("Polkadot", BlockHash::repeat_byte(1), 10));
+        ser.push(Pong("ping"));
+        let bytes = ser.into_finalized().unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_slice(bytes.get(FeedFormat::Json).unwrap()).unwrap();
+        let msgpack: serde_json::Value =
+            rmp_serde::from_slice(bytes.get(FeedFormat::MessagePack).unwrap()).unwrap();
+        assert_eq!(msgpack, json);
+        assert_eq!(json[0], 0);
+        assert_eq!(json.as_array().unwrap().len(), 6);
+    }
+
+    #[test]
+    fn only_the_formats_asked_for_are_serialized() {
+        let mut ser = FeedMessageSerializer::with_formats([FeedFormat::MessagePack]);
+        ser.push(Version(35));
+        let bytes = ser.into_finalized().unwrap();
+        assert!(bytes.get(FeedFormat::Json).is_none());
+        assert!(bytes.get(FeedFormat::MessagePack).is_some());
+
+        let ser = FeedMessageSerializer::with_formats([FeedFormat::Json]);
+        assert!(ser.into_finalized().is_none());
+    }
+
+    #[test]
+    fn messages_can_be_filtered_by_node() {
+        let mut ser = FeedMessageSerializer::for_chain(FeedFormat::ALL, BlockHash::repeat_byte(1));
+        ser.push(StaleNode(1));
+        ser.push(BestBlock(10, 0, None));
+        ser.push(StaleNode(2));
+        ser.push(RemovedNode(3));
+        let bytes = ser.into_finalized().unwrap();
+
+        let keep = |subject| {
+            !matches!(
+                subject,
+                MessageSubject::Node(2) | MessageSubject::NodeRemoved(_)
+            )
+        };
+        let json: serde_json::Value =
+            serde_json::from_slice(bytes.filtered(FeedFormat::Json, keep).unwrap().bytes())
+                .unwrap();
+        let msgpack: serde_json::Value = rmp_serde::from_slice(
+            bytes
+                .filtered(FeedFormat::MessagePack, keep)
+                .unwrap()
+                .bytes(),
+        )
+        .unwrap();
+        assert_eq!(msgpack, json);
+        let actions: Vec<_> = json.as_array().unwrap().iter().step_by(2).collect();
+        assert_eq!(actions, [25, 20, 1]);
+
+        // Nothing is worth sending if only the chain context is left:
+        let mut ser = FeedMessageSerializer::for_chain(FeedFormat::ALL, BlockHash::repeat_byte(1));
+        ser.push(StaleNode(2));
+        let bytes = ser.into_finalized().unwrap();
+        assert!(bytes.filtered(FeedFormat::Json, keep).is_none());
+    }
+
+    #[test]
+    fn superseded_messages_are_dropped_from_a_backlog() {
+        let chain = BlockHash::repeat_byte(1);
+        let stats = NodeStats::default();
+        let batch = |msgs: &dyn Fn(&mut FeedMessageSerializer)| {
+            let mut ser = FeedMessageSerializer::for_chain(FeedFormat::ALL, chain);
+            msgs(&mut ser);
+            ser.push(ChainSeq(1));
+            ser.into_finalized().unwrap()
+        };
+
+        let first = batch(&|ser| {
+            ser.push(NodeStatsUpdate(1, &stats));
+            ser.push(NodeStatsUpdate(2, &stats));
+            ser.push(BestFinalized(10, BlockHash::zero()));
+        });
+        let second = batch(&|ser| {
+            ser.push(NodeStatsUpdate(1, &stats));
+            ser.push(StaleNode(1));
+        });
+        let third = batch(&|ser| {
+            ser.push(NodeStatsUpdate(2, &stats));
+        });
+
+        for format in FeedFormat::ALL {
+            let mut batches: Vec<_> = [&first, &second, &third]
+                .into_iter()
+                .map(|b| b.batch(format).unwrap())
+                .collect();
+            assert_eq!(FeedBatch::coalesce(&mut batches), 2);
+
+            let actions: Vec<Vec<u64>> = batches
+                .iter()
+                .map(|b| {
+                    let value: serde_json::Value = match format {
+                        FeedFormat::Json => serde_json::from_slice(b.bytes()).unwrap(),
+                        FeedFormat::MessagePack => rmp_serde::from_slice(b.bytes()).unwrap(),
+                    };
+                    let msgs = value.as_array().unwrap();
+                    msgs.iter()
+                        .step_by(2)
+                        .map(|a| a.as_u64().unwrap())
+                        .collect()
+                })
+                .collect();
+            assert_eq!(
+                actions,
+                [vec![25, 2, 28], vec![25, 8, 20, 28], vec![25, 8, 28]]
+            );
+        }
+
+        // Batches left with nothing worth sending are dropped altogether:
+        let mut batches = vec![
+            third.batch(FeedFormat::Json).unwrap(),
+            third.batch(FeedFormat::Json).unwrap(),
+        ];
+        assert_eq!(FeedBatch::coalesce(&mut batches), 1);
+        assert_eq!(batches.len(), 1);
+    }
+
+    #[test]
+    fn batches_can_be_joined_into_one_message() {
+        let batch = |format, chain: u8, number| {
+            let mut ser = FeedMessageSerializer::for_chain([format], BlockHash::repeat_byte(chain));
+            ser.push(BestFinalized(number, BlockHash::zero()));
+            ser.into_finalized().unwrap().batch(format).unwrap()
+        };
+
+        for format in FeedFormat::ALL {
+            let batches = vec![batch(format, 1, 10), batch(format, 2, 20)];
+            let joined = FeedBatch::join(batches).unwrap();
+            let value: serde_json::Value = match format {
+                FeedFormat::Json => serde_json::from_slice(joined.bytes()).unwrap(),
+                FeedFormat::MessagePack => rmp_serde::from_slice(joined.bytes()).unwrap(),
+            };
+            let msgs = value.as_array().unwrap();
+            let actions: Vec<_> = msgs.iter().step_by(2).collect();
+            assert_eq!(actions, [25, 2, 25, 2]);
+            assert_eq!(msgs[3][0], 10);
+            assert_eq!(msgs[7][0], 20);
+
+            assert_eq!(joined.chain, None);
+        }
+
+        assert!(FeedBatch::join(Vec::new()).is_none());
+    }
+
+    #[test]
+    fn merged_deltas_leave_a_series_as_each_delta_would() {
+        // Apply a delta the way that feeds do:
+        fn apply(series: &mut Vec<f64>, (drop, samples): SeriesDelta<f64>) {
+            series.drain(..series.len().min(drop as usize));
+            series.extend(samples);
+            series.drain(..series.len().saturating_sub(SERIES_LEN));
+        }
+
+        let mut list = MeanList::<f64>::default();
+        let mut deltas = Vec::new();
+        let mut slices = vec![Vec::new()];
+        for val in 0..2000 {
+            let change = list.push(val as f64);
+            if change.is_changed() {
+                let (drop, samples) = list.delta(change);
+                deltas.push((drop, samples.to_vec()));
+                slices.push(list.slice().to_vec());
+            }
+        }
+
+        // Merge every run of deltas of a few lengths, starting from every point:
+        for run in [2, 3, 7, 20, 45] {
+            for start in 0..deltas.len().saturating_sub(run) {
+                let mut series = slices[start].clone();
+                let merged = deltas[start..start + run]
+                    .iter()
+                    .cloned()
+                    .reduce(merge_series)
+                    .unwrap();
+                apply(&mut series, merged);
+                assert_eq!(series, slices[start + run], "run of {run} from {start}");
+            }
+        }
+    }
+
+    #[test]
+    fn deltas_are_merged_when_coalescing() {
+        let chain = BlockHash::repeat_byte(1);
+        let mut io = NodeIO::default();
+        for format in FeedFormat::ALL {
+            let mut batches = Vec::new();
+            for seq in 1..=3 {
+                let change = io.used_state_cache_size.push(seq as f32);
+                let mut ser = FeedMessageSerializer::for_chain([format], chain);
+                ser.push(NodeIODelta(1, seq, &io, change));
+                ser.push(NodeIODelta(2, seq, &io, change));
+                batches.push(ser.into_finalized().unwrap().batch(format).unwrap());
+            }
+
+            assert_eq!(FeedBatch::coalesce(&mut batches), 4);
+            assert_eq!(batches.len(), 1);
+            let value: serde_json::Value = match format {
+                FeedFormat::Json => serde_json::from_slice(batches[0].bytes()).unwrap(),
+                FeedFormat::MessagePack => rmp_serde::from_slice(batches[0].bytes()).unwrap(),
+            };
+            let msgs = value.as_array().unwrap();
+            assert_eq!(msgs.len(), 6);
+            // The latest sequence number, the merged samples, and the first sequence number:
+            for (node, msg) in [1, 2].into_iter().zip([&msgs[3], &msgs[5]]) {
+                assert_eq!(msgs[2], NodeIODelta::ACTION);
+                assert_eq!(*msg, serde_json::json!([node, 3, [0, [1.0, 2.0, 3.0]], 1]));
+            }
+        }
+
+        // Deltas that don't follow on from each other are left alone:
+        let mut batches: Vec<_> = [1, 3]
+            .into_iter()
+            .map(|seq| {
+                let mut ser = FeedMessageSerializer::for_chain([FeedFormat::Json], chain);
+                ser.push(NodeIODelta(1, seq, &io, MeanListChange::Appended));
+                ser.into_finalized()
+                    .unwrap()
+                    .batch(FeedFormat::Json)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(FeedBatch::coalesce(&mut batches), 0);
+        assert_eq!(batches.len(), 2);
+    }
 }
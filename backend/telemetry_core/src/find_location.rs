@@ -46,6 +46,7 @@ where
             latitude: 52.516_6667,
             longitude: 13.4,
             city: "Berlin".into(),
+            country: Some("DE".into()),
         }),
     );
 
@@ -111,7 +112,12 @@ impl Locator {
             return cached_loc;
         }
 
-        let City { city, location, .. } = self.city.lookup(ip.into()).ok()?;
+        let City {
+            city,
+            location,
+            country,
+            ..
+        } = self.city.lookup(ip.into()).ok()?;
         let city = city
             .as_ref()?
             .names
@@ -121,11 +127,13 @@ impl Locator {
             .into_boxed_str();
         let latitude = location.as_ref()?.latitude? as f32;
         let longitude = location?.longitude? as f32;
+        let country = country.and_then(|country| country.iso_code).map(Into::into);
 
         let location = Arc::new(NodeLocation {
             city,
             latitude,
             longitude,
+            country,
         });
         self.cache.write().insert(ip, Arc::clone(&location));
 
@@ -15,6 +15,7 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 mod aggregator;
+mod config;
 mod endpoints;
 mod feed_message;
 mod find_location;
@@ -22,7 +23,9 @@ mod state;
 
 use hyper::Body;
 use primitive_types::H256;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
 use tokio::time::{Duration, Instant};
 
 use aggregator::{
@@ -30,12 +33,19 @@ use aggregator::{
     ToShardWebsocket,
 };
 use bincode::Options;
+use common::byte_size::ByteSize;
 use common::http_utils;
 use common::internal_messages;
+use common::logging::{self, LogFormat, ModuleLevel};
+use common::node_types::BlockHash;
 use common::ready_chunks_all::ReadyChunksAll;
-use futures::{SinkExt, StreamExt};
+use common::shutdown::{self, Shutdown, ShutdownListener};
+use common::tls::TlsAcceptor;
+use config::Config;
+use feed_message::{FeedBatch, FeedFormat};
+use futures::{FutureExt, SinkExt, StreamExt};
 use hyper::{Method, Response};
-use simple_logger::SimpleLogger;
+use serde::Serialize;
 use structopt::StructOpt;
 
 #[cfg(not(target_env = "msvc"))]
@@ -48,61 +58,215 @@ static GLOBAL: Jemalloc = Jemalloc;
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
 const NAME: &str = "Substrate Telemetry Backend Core";
+/// The most messages we'll try to send on to a feed once the server is shutting down.
+const SHUTDOWN_DRAIN_LIMIT: usize = 10_000;
 const ABOUT: &str = "This is the Telemetry Backend Core that receives telemetry messages \
                      from Substrate/Polkadot nodes and provides the data to a subsribed feed";
 
-#[derive(StructOpt, Debug)]
+/// Command line flags, each of which can also be given in a config file (see '--config').
+/// These, and the corresponding environment variables, take precedence over the config file.
+#[derive(StructOpt, Serialize, Debug)]
 #[structopt(name = NAME, version = VERSION, author = AUTHORS, about = ABOUT)]
 struct Opts {
+    /// A TOML config file to load settings from. Keys are the names of the flags here with
+    /// any '-' replaced by '_', eg 'feed_timeout = 20' or 'denylist = ["Some Chain"]'.
+    #[structopt(long, env = "TELEMETRY_CORE_CONFIG")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
     /// This is the socket address that Telemetry is listening to. This is restricted to
-    /// localhost (127.0.0.1) by default and should be fine for most use cases. If
+    /// localhost (127.0.0.1:8000) by default and should be fine for most use cases. If
     /// you are using Telemetry in a container, you likely want to set this to '0.0.0.0:8000'
-    #[structopt(short = "l", long = "listen", default_value = "127.0.0.1:8000")]
-    socket: std::net::SocketAddr,
+    #[structopt(short = "l", long, env = "TELEMETRY_CORE_LISTEN")]
+    listen: Option<std::net::SocketAddr>,
     /// The desired log level; one of 'error', 'warn', 'info', 'debug' or 'trace', where
-    /// 'error' only logs errors and 'trace' logs everything.
-    #[structopt(long = "log", default_value = "info")]
-    log_level: log::LevelFilter,
+    /// 'error' only logs errors and 'trace' logs everything. Defaults to 'info'.
+    #[structopt(long, env = "TELEMETRY_CORE_LOG")]
+    log: Option<log::LevelFilter>,
+    /// The format to write logs out in; one of 'text' (the default) or 'json'. JSON logs are
+    /// written as one object per line, with fields like 'conn_id', 'ip' and 'genesis_hash'
+    /// where relevant.
+    #[structopt(long, env = "TELEMETRY_CORE_LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+    /// Override the log level for a given module, eg 'soketto=warn' or
+    /// 'telemetry_core::aggregator=debug'. Can be provided multiple times, or as a comma
+    /// separated list in the environment variable.
+    #[structopt(
+        long,
+        required = false,
+        env = "TELEMETRY_CORE_LOG_MODULE",
+        use_delimiter = true
+    )]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    log_module: Vec<ModuleLevel>,
     /// Space delimited list of the names of chains that are not allowed to connect to
-    /// telemetry. Case sensitive.
-    #[structopt(long, required = false)]
+    /// telemetry (comma delimited in the environment variable). Case sensitive.
+    #[structopt(
+        long,
+        required = false,
+        env = "TELEMETRY_CORE_DENYLIST",
+        use_delimiter = true
+    )]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
     denylist: Vec<String>,
     /// If it takes longer than this number of seconds to send the current batch of messages
-    /// to a feed, the feed connection will be closed.
-    #[structopt(long, default_value = "10")]
-    feed_timeout: u64,
+    /// to a feed (after merging any that are redundant), the feed connection will be closed.
+    /// Defaults to 10.
+    #[structopt(long, env = "TELEMETRY_CORE_FEED_TIMEOUT")]
+    feed_timeout: Option<u64>,
+    /// How long to wait, in milliseconds, after sending a batch of messages to a feed
+    /// before sending the next one, so that messages are sent in fewer, larger batches.
+    /// Defaults to 75.
+    #[structopt(long, env = "TELEMETRY_CORE_FEED_DEBOUNCE_MS")]
+    feed_debounce_ms: Option<u64>,
+    /// Compress messages sent to feeds which support the permessage-deflate websocket
+    /// extension, at this zlib compression level from 1 (fastest) to 9 (smallest). 0 turns
+    /// compression off. Defaults to 1.
+    #[structopt(long, env = "TELEMETRY_CORE_FEED_COMPRESSION_LEVEL")]
+    feed_compression_level: Option<u32>,
+    /// Messages to feeds that are smaller than this are sent uncompressed, eg '512' or '4k'.
+    /// Defaults to '1k'.
+    #[structopt(long, env = "TELEMETRY_CORE_FEED_COMPRESSION_THRESHOLD")]
+    feed_compression_threshold: Option<ByteSize>,
+    /// How many of the most recent batches of messages about each chain to keep, so that
+    /// feeds which reconnect can be sent just the ones they missed. 0 turns this off.
+    /// Defaults to 256.
+    #[structopt(long, env = "TELEMETRY_CORE_FEED_REPLAY_LEN")]
+    feed_replay_len: Option<usize>,
+    /// If this many batches of messages are waiting to be sent to a feed, messages which
+    /// later ones in the queue supersede (eg node stats) are dropped rather than sent, and
+    /// each node's hardware and IO samples are merged into one update. 0 turns this off.
+    /// Defaults to 16.
+    #[structopt(long, env = "TELEMETRY_CORE_FEED_COALESCE_BACKLOG")]
+    feed_coalesce_backlog: Option<usize>,
+    /// Feeds which have this many batches of messages waiting to be sent to them are
+    /// disconnected. Defaults to 10000.
+    #[structopt(long, env = "TELEMETRY_CORE_FEED_MAX_BACKLOG")]
+    feed_max_backlog: Option<usize>,
     /// Number of worker threads to spawn. If "0" is given, use the number of CPUs available
     /// on the machine. If no value is given, use an internal default that we have deemed sane.
-    #[structopt(long)]
+    #[structopt(long, env = "TELEMETRY_CORE_WORKER_THREADS")]
     worker_threads: Option<usize>,
-    /// Each aggregator keeps track of the entire node state. Feed subscriptions are split across
-    /// aggregators.
-    #[structopt(long)]
+    /// The number of workers which feed subscriptions are split across. The node state is kept
+    /// and messages to feeds are serialized just once, and each worker sends these messages on
+    /// to its feeds. If "0" is given, use the number of CPUs available on the machine.
+    #[structopt(long, env = "TELEMETRY_CORE_FEED_WORKERS")]
+    feed_workers: Option<usize>,
+    /// Deprecated: the old name for `--feed-workers`, which takes precedence if both are given.
+    #[structopt(long, env = "TELEMETRY_CORE_NUM_AGGREGATORS")]
     num_aggregators: Option<usize>,
+    /// Partition chains by genesis hash across this many aggregator loops, each keeping track
+    /// of the state of its own chains, so that a very busy chain doesn't hold up the others.
+    /// If "0" is given, use the number of CPUs available on the machine. Defaults to 1.
+    #[structopt(long, env = "TELEMETRY_CORE_CHAIN_PARTITIONS")]
+    chain_partitions: Option<usize>,
     /// How big can the message queue for each aggregator grow before we start dropping non-essential
-    /// messages in an attempt to let it reduce?
-    #[structopt(long)]
+    /// messages in an attempt to let it reduce? Samples such as bandwidth are dropped first, and
+    /// updates carrying block details once the queue is twice this long. New blocks and finality
+    /// are never dropped. Defaults to 10000.
+    #[structopt(long, env = "TELEMETRY_CORE_AGGREGATOR_QUEUE_LEN")]
     aggregator_queue_len: Option<usize>,
-    /// How many nodes from third party chains are allowed to connect before we prevent connections from them.
-    #[structopt(long, default_value = "1000")]
-    max_third_party_nodes: usize,
-    /// Flag to expose the node's details (IP address, SysInfo, HwBench) of all connected
-    /// nodes to the feed subscribers.
-    #[structopt(long)]
-    pub expose_node_details: bool,
+    /// How many nodes from third party chains are allowed to connect before we prevent connections
+    /// from them. Defaults to 1000.
+    #[structopt(long, env = "TELEMETRY_CORE_MAX_THIRD_PARTY_NODES")]
+    max_third_party_nodes: Option<usize>,
+    /// The genesis hash of a "first party" chain, which any number of nodes can connect to.
+    /// Can be provided multiple times, or as a comma separated list in the environment
+    /// variable. Defaults to the Polkadot and Kusama genesis hashes.
+    #[structopt(
+        long,
+        required = false,
+        env = "TELEMETRY_CORE_FIRST_PARTY_NETWORK",
+        use_delimiter = true
+    )]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    first_party_network: Vec<BlockHash>,
+    /// Whether to expose the node's details (IP address, SysInfo, HwBench) of all connected
+    /// nodes to the feed subscribers; 'true' or 'false'. Defaults to false.
+    #[structopt(long, env = "TELEMETRY_CORE_EXPOSE_NODE_DETAILS")]
+    expose_node_details: Option<bool>,
+    /// If a chain hasn't seen a new best block for this many seconds, nodes which haven't
+    /// either are marked as stale and ignored when working out the best block. Defaults to 120.
+    #[structopt(long, env = "TELEMETRY_CORE_STALE_TIMEOUT")]
+    stale_timeout: Option<u64>,
+    /// The minimum time, in seconds, between regenerating the stats for a chain. Defaults to 5.
+    #[structopt(long, env = "TELEMETRY_CORE_STATS_UPDATE_INTERVAL")]
+    stats_update_interval: Option<u64>,
+    /// How many of the most recent block heights of each chain to keep the block times for,
+    /// for the '/block_history' endpoint. Defaults to 30.
+    #[structopt(long, env = "TELEMETRY_CORE_MAX_BLOCK_HEIGHT_STORED")]
+    max_block_height_stored: Option<usize>,
+    /// If the time between a node's blocks is no more than this many milliseconds, updates
+    /// about its blocks are only sent to feeds once a second. Defaults to 100.
+    #[structopt(long, env = "TELEMETRY_CORE_THROTTLE_THRESHOLD_MS")]
+    throttle_threshold_ms: Option<u64>,
+    /// How often, in seconds, to refresh the '/metrics', '/overview', '/block_history' and
+    /// '/node_list' endpoints. Defaults to 10.
+    #[structopt(long, env = "TELEMETRY_CORE_METRICS_INTERVAL")]
+    metrics_interval: Option<u64>,
+    /// Run as a standby for another core, given the address of that core (eg
+    /// 'http://10.0.0.1:8000'). The history of each chain is periodically fetched from its
+    /// '/state_snapshot' endpoint and used to seed chains here when shards fail over to this
+    /// core, so that best blocks and average block times carry on where they left off.
+    #[structopt(long, env = "TELEMETRY_CORE_STANDBY_OF")]
+    standby_of: Option<String>,
+    /// How often, in seconds, to fetch chain history from the core given by '--standby-of'.
+    /// Defaults to 10.
+    #[structopt(long, env = "TELEMETRY_CORE_STANDBY_INTERVAL")]
+    standby_interval: Option<u64>,
+    /// Periodically write a snapshot of every chain and node (including best blocks, block
+    /// times and node hardware history) to this file, as well as on shutdown. The snapshot can
+    /// be restored with '--restore-from' when the core restarts.
+    #[structopt(long, env = "TELEMETRY_CORE_SNAPSHOT_PATH")]
+    snapshot_path: Option<PathBuf>,
+    /// How often, in seconds, to write a snapshot to '--snapshot-path'. Defaults to 60.
+    #[structopt(long, env = "TELEMETRY_CORE_SNAPSHOT_INTERVAL")]
+    snapshot_interval: Option<u64>,
+    /// Restore chains and nodes from a snapshot written to '--snapshot-path'. If the file can't
+    /// be read, we log a warning and start afresh.
+    #[structopt(long, env = "TELEMETRY_CORE_RESTORE_FROM")]
+    restore_from: Option<PathBuf>,
+    /// Nodes restored with '--restore-from' are kept until their shard adds them again. Any
+    /// that haven't been added again after this many seconds are removed. Defaults to 120.
+    #[structopt(long, env = "TELEMETRY_CORE_RESTORE_TIMEOUT")]
+    restore_timeout: Option<u64>,
+    /// On SIGTERM or SIGINT, we stop accepting connections, write a snapshot (if
+    /// '--snapshot-path' is given), and give feed and shard connections this many seconds
+    /// to flush any pending messages and close before exiting anyway. Defaults to 10.
+    #[structopt(long, env = "TELEMETRY_CORE_SHUTDOWN_DEADLINE")]
+    shutdown_deadline: Option<u64>,
+    /// Serve '/feed', '/shard_submit' and every other endpoint over TLS, using the PEM encoded
+    /// certificate chain in this file. Requires '--tls-key'.
+    #[structopt(long, env = "TELEMETRY_CORE_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+    /// The PEM encoded private key for '--tls-cert'.
+    #[structopt(long, env = "TELEMETRY_CORE_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+    /// Require shards connecting to '/shard_submit' to present a client certificate signed by
+    /// one of the CAs in this PEM file. Other endpoints don't ask for a client certificate.
+    #[structopt(long, env = "TELEMETRY_CORE_TLS_CLIENT_CA")]
+    tls_client_ca: Option<PathBuf>,
+    /// How often, in seconds, to check '--tls-cert' and '--tls-key' for changes. Changed
+    /// certificates are used for new connections without needing a restart. Defaults to 60.
+    #[structopt(long, env = "TELEMETRY_CORE_TLS_RELOAD_INTERVAL")]
+    tls_reload_interval: Option<u64>,
 }
 
 fn main() {
     let opts = Opts::from_args();
+    let config = match Config::load(opts.config.as_deref(), &opts) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {e:#}");
+            std::process::exit(1);
+        }
+    };
 
-    SimpleLogger::new()
-        .with_level(opts.log_level)
-        .init()
+    logging::init(config.log, config.log_format, &config.log_module)
         .expect("Must be able to start a logger");
 
     log::info!("Starting Telemetry Core version: {}", VERSION);
 
-    let worker_threads = match opts.worker_threads {
+    let worker_threads = match config.worker_threads {
         Some(0) => num_cpus::get(),
         Some(n) => n,
         // By default, use a max of 8 worker threads, as perf
@@ -110,14 +274,23 @@ fn main() {
         None => usize::min(num_cpus::get(), 8),
     };
 
-    let num_aggregators = match opts.num_aggregators {
+    if config.num_aggregators.is_some() {
+        log::warn!("num_aggregators (--num-aggregators, TELEMETRY_CORE_NUM_AGGREGATORS) is deprecated; use feed_workers (--feed-workers, TELEMETRY_CORE_FEED_WORKERS) instead");
+    }
+    let num_feed_workers = match config.feed_workers.or(config.num_aggregators) {
         Some(0) => num_cpus::get(),
         Some(n) => n,
-        // For now, we just have 1 aggregator loop by default,
+        // For now, we just have 1 feed worker by default,
         // but we may want to be smarter here eventually.
         None => 1,
     };
 
+    let num_chain_partitions = match config.chain_partitions {
+        Some(0) => num_cpus::get(),
+        Some(n) => n,
+        None => 1,
+    };
+
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .worker_threads(worker_threads)
@@ -125,175 +298,351 @@ fn main() {
         .build()
         .unwrap()
         .block_on(async {
-            if let Err(e) = start_server(num_aggregators, opts).await {
+            if let Err(e) = start_server(num_chain_partitions, num_feed_workers, config).await {
                 log::error!("Error starting server: {}", e);
             }
         });
 }
 
 /// Declare our routes and start the server.
-async fn start_server(num_aggregators: usize, opts: Opts) -> anyhow::Result<()> {
-    let aggregator_queue_len = opts.aggregator_queue_len.unwrap_or(10_000);
+async fn start_server(
+    num_chain_partitions: usize,
+    num_feed_workers: usize,
+    config: Config,
+) -> anyhow::Result<()> {
+    // Serve the effective config as it is now, before we pick it apart:
+    let config_json = Arc::new(serde_json::to_string_pretty(&config)?);
+    let tls = config.tls_opts().map(TlsAcceptor::new).transpose()?;
+    let require_shard_client_cert = config.tls_client_ca.is_some();
+    let feed_deflate = config.feed_deflate_opts();
     let aggregator = AggregatorSet::spawn(
-        num_aggregators,
+        num_chain_partitions,
         AggregatorOpts {
-            max_queue_len: aggregator_queue_len,
-            denylist: opts.denylist,
-            max_third_party_nodes: opts.max_third_party_nodes,
-            expose_node_details: opts.expose_node_details,
+            max_queue_len: config.aggregator_queue_len,
+            chain_opts: config.chain_opts(),
+            denylist: config.denylist,
+            max_third_party_nodes: config.max_third_party_nodes,
+            first_party_networks: config.first_party_network,
+            expose_node_details: config.expose_node_details,
+            metrics_interval: Duration::from_secs(config.metrics_interval),
+            feed_replay_len: config.feed_replay_len,
+            feed_max_backlog: config.feed_max_backlog,
+            feed_workers: num_feed_workers,
         },
     )
     .await?;
-    let socket_addr = opts.socket;
-    let feed_timeout = opts.feed_timeout;
-
-    let server = http_utils::start_server(socket_addr, move |addr, req| {
-        let aggregator = aggregator.clone();
-        async move {
-            let default_response = || {
-                Ok(Response::builder()
-                    .status(404)
-                    .body("Not found".into())
-                    .unwrap())
-            };
-
-            let error_response = move |message: &str| {
-                let message = std::format!("\"error\": \"{}\"", message);
-                Ok(Response::builder()
-                    .status(404)
-                    .body(Body::from(message))
-                    .unwrap())
-            };
-
-            match (req.method(), req.uri().path().trim_end_matches('/')) {
-                // Check that the server is up and running:
-                (&Method::GET, "/health") => Ok(Response::new("OK".into())),
-                // Subscribe to feed messages:
-                (&Method::GET, "/feed") => {
-                    log::info!("Opening /feed connection from {:?}", addr);
-                    Ok(http_utils::upgrade_to_websocket(
-                        req,
-                        move |ws_send, ws_recv| async move {
-                            let (feed_id, tx_to_aggregator) = aggregator.subscribe_feed();
-                            let (mut tx_to_aggregator, mut ws_send) =
-                                handle_feed_websocket_connection(
-                                    ws_send,
-                                    ws_recv,
-                                    tx_to_aggregator,
-                                    feed_timeout,
-                                    feed_id,
-                                )
-                                .await;
-                            log::info!("Closing /feed connection from {:?}", addr);
-                            // Tell the aggregator that this connection has closed, so it can tidy up.
-                            let _ = tx_to_aggregator.send(FromFeedWebsocket::Disconnected).await;
-                            let _ = ws_send.close().await;
-                        },
-                    ))
-                }
-                // Subscribe to shard messages:
-                (&Method::GET, "/shard_submit") => {
-                    Ok(http_utils::upgrade_to_websocket(
-                        req,
-                        move |ws_send, ws_recv| async move {
-                            log::info!("Opening /shard_submit connection from {:?}", addr);
-                            let tx_to_aggregator = aggregator.subscribe_shard();
-                            let (mut tx_to_aggregator, mut ws_send) =
-                                handle_shard_websocket_connection(
-                                    ws_send,
-                                    ws_recv,
-                                    tx_to_aggregator,
-                                )
-                                .await;
-                            log::info!("Closing /shard_submit connection from {:?}", addr);
-                            // Tell the aggregator that this connection has closed, so it can tidy up.
-                            let _ = tx_to_aggregator
-                                .send(FromShardWebsocket::Disconnected)
-                                .await;
-                            let _ = ws_send.close().await;
-                        },
-                    ))
-                }
-                // Return metrics in a prometheus-friendly text based format:
-                (&Method::GET, "/metrics") => Ok(return_prometheus_metrics(aggregator).await),
-                (&Method::GET, uri) => {
-                    let uri_split = uri.split('/');
-                    if uri.starts_with("/overview/") {
-                        let Some(genesis_hash) = uri_split.last() else {
-                            return error_response("Failed split string");
-                        };
-
-                        let Ok(genesis_hash) = genesis_hash.parse::<H256>() else {
-                            return error_response("Cannot convert given block hash to H256");
-                        };
-                        let overview = match aggregator.overview_endpoint(genesis_hash) {
-                            Ok(o) => o,
-                            Err(err) => return error_response(err),
-                        };
-
-                        let Ok(overview) = serde_json::to_string_pretty(&overview) else {
-                            return error_response("Failed to do json");
-                        };
-
-                        Ok(Response::builder().body(overview.into()).unwrap())
-                    } else if uri.starts_with("/block_history/") {
-                        let Some(genesis_hash) = uri_split.last() else {
-                            return error_response("Failed split string");
-                        };
-
-                        let Ok(genesis_hash) = genesis_hash.parse::<H256>() else {
-                            return error_response("Cannot convert given block hash to H256");
-                        };
-                        let overview = match aggregator.block_history_endpoint(genesis_hash) {
-                            Ok(o) => o,
-                            Err(err) => return error_response(err),
-                        };
-
-                        let Ok(overview) = serde_json::to_string_pretty(&overview) else {
-                            return error_response("Failed to do json");
-                        };
+    if let Some(path) = config.restore_from {
+        let restore_timeout = Duration::from_secs(config.restore_timeout);
+        match aggregator
+            .restore_state_snapshot(&path, restore_timeout)
+            .await
+        {
+            Ok(num_nodes) => log::info!(
+                "Restored {num_nodes} nodes from {}; they will be removed in {}s unless added again",
+                path.display(),
+                config.restore_timeout
+            ),
+            Err(e) => log::warn!(
+                "Cannot restore state from {} (starting afresh): {e}",
+                path.display()
+            ),
+        }
+    }
+    if let Some(path) = &config.snapshot_path {
+        aggregator.spawn_snapshot_loop(path.clone(), Duration::from_secs(config.snapshot_interval));
+    }
+    if let Some(primary) = config.standby_of {
+        let snapshot_url = format!("{}/state_snapshot", primary.trim_end_matches('/'));
+        log::info!("Standing by for the core at {primary}");
+        aggregator.spawn_standby_loop(snapshot_url, Duration::from_secs(config.standby_interval));
+    }
+    let socket_addr = config.listen;
+    let feed_timeout = config.feed_timeout;
+    let feed_debounce = Duration::from_millis(config.feed_debounce_ms);
+    let feed_coalesce_backlog = config.feed_coalesce_backlog;
+    let snapshot_path = config.snapshot_path;
+    let shutdown_deadline = Duration::from_secs(config.shutdown_deadline);
+    let aggregator_for_shutdown = aggregator.clone();
+    let shutdown = Shutdown::new();
+    let shutdown_listener = shutdown.listener();
+
+    let server = http_utils::start_server(
+        socket_addr,
+        tls,
+        shutdown.started(),
+        move |addr, req| {
+            let aggregator = aggregator.clone();
+            let shutdown_listener = shutdown_listener.clone();
+            let config_json = Arc::clone(&config_json);
+            async move {
+                let default_response = || {
+                    Ok(Response::builder()
+                        .status(404)
+                        .body("Not found".into())
+                        .unwrap())
+                };
 
-                        Ok(Response::builder().body(overview.into()).unwrap())
-                    } else if uri.starts_with("/node_list/") {
-                        let Some(genesis_hash) = uri_split.last() else {
-                            return error_response("Failed split string");
-                        };
+                let error_response = move |message: &str| {
+                    let message = std::format!("\"error\": \"{}\"", message);
+                    Ok(Response::builder()
+                        .status(404)
+                        .body(Body::from(message))
+                        .unwrap())
+                };
 
-                        let Ok(genesis_hash) = genesis_hash.parse::<H256>() else {
-                            return error_response("Cannot convert given block hash to H256");
+                match (req.method(), req.uri().path().trim_end_matches('/')) {
+                    // Check that the server is up and running:
+                    (&Method::GET, "/health") => Ok(Response::new("OK".into())),
+                    // Subscribe to feed messages:
+                    (&Method::GET, "/feed") => {
+                        let (format, subprotocol) = match negotiate_feed_format(&req) {
+                            Ok(negotiated) => negotiated,
+                            Err(e) => {
+                                return Ok(Response::builder().status(400).body(e.into()).unwrap())
+                            }
                         };
-                        let overview = match aggregator.node_list_endpoint(genesis_hash) {
-                            Ok(o) => o,
-                            Err(err) => return error_response(err),
+                        log::info!(ip:% = addr.ip(); "Opening /feed connection from {:?} ({})", addr, format.name());
+                        let mut res = http_utils::upgrade_to_websocket_with_deflate(
+                            req,
+                            feed_deflate,
+                            move |ws_send, ws_recv| async move {
+                                let (feed_id, tx_to_aggregator) = aggregator.subscribe_feed();
+                                let (mut tx_to_aggregator, mut ws_send) =
+                                    handle_feed_websocket_connection(
+                                        ws_send,
+                                        ws_recv,
+                                        tx_to_aggregator,
+                                        FeedConnectionOpts {
+                                            timeout: feed_timeout,
+                                            debounce: feed_debounce,
+                                            coalesce_backlog: feed_coalesce_backlog,
+                                            format,
+                                        },
+                                        feed_id,
+                                        shutdown_listener,
+                                    )
+                                    .await;
+                                log::info!(conn_id = feed_id, ip:% = addr.ip(); "Closing /feed connection from {:?}", addr);
+                                // Tell the aggregator that this connection has closed, so it can tidy up.
+                                let _ =
+                                    tx_to_aggregator.send(FromFeedWebsocket::Disconnected).await;
+                                let _ = ws_send.close().await;
+                            },
+                        );
+                        // Let the client know which of the subprotocols it offered we picked:
+                        if let Some(subprotocol) = subprotocol {
+                            if res.status() == hyper::StatusCode::SWITCHING_PROTOCOLS {
+                                res.headers_mut().insert(
+                                    "Sec-WebSocket-Protocol",
+                                    hyper::header::HeaderValue::from_static(subprotocol),
+                                );
+                            }
+                        }
+                        Ok(res)
+                    }
+                    // Subscribe to shard messages:
+                    (&Method::GET, "/shard_submit") => {
+                        if require_shard_client_cert && !http_utils::has_client_certificate(&req) {
+                            log::warn!(ip:% = addr.ip(); "Rejecting /shard_submit connection from {:?}: no client certificate", addr);
+                            return Ok(Response::builder()
+                                .status(403)
+                                .body("A client certificate is required".into())
+                                .unwrap());
+                        }
+                        Ok(http_utils::upgrade_to_websocket(
+                            req,
+                            move |ws_send, ws_recv| async move {
+                                let (shard_id, tx_to_aggregator) = aggregator.subscribe_shard();
+                                log::info!(shard = shard_id, ip:% = addr.ip(); "Opening /shard_submit connection from {:?}", addr);
+                                let (mut tx_to_aggregator, mut ws_send) =
+                                    handle_shard_websocket_connection(
+                                        ws_send,
+                                        ws_recv,
+                                        tx_to_aggregator,
+                                        shutdown_listener,
+                                    )
+                                    .await;
+                                log::info!(shard = shard_id, ip:% = addr.ip(); "Closing /shard_submit connection from {:?}", addr);
+                                // Tell the aggregator that this connection has closed, so it can tidy up.
+                                let _ = tx_to_aggregator
+                                    .send(FromShardWebsocket::Disconnected)
+                                    .await;
+                                let _ = ws_send.close().await;
+                            },
+                        ))
+                    }
+                    // Return the configuration that we're running with:
+                    (&Method::GET, "/config") => Ok(Response::builder()
+                        .header("Content-Type", "application/json")
+                        .body(config_json.to_string().into())
+                        .unwrap()),
+                    // Return metrics in a prometheus-friendly text based format:
+                    (&Method::GET, "/metrics") => Ok(return_prometheus_metrics(aggregator).await),
+                    // Return the history of each chain, so that a standby core can take over from us:
+                    (&Method::GET, "/state_snapshot") => {
+                        let snapshots = match aggregator.chain_snapshots().await {
+                            Ok(s) => s,
+                            Err(e) => return error_response(&e.to_string()),
                         };
 
-                        let Ok(overview) = serde_json::to_string_pretty(&overview) else {
+                        let Ok(snapshots) = serde_json::to_string(&snapshots) else {
                             return error_response("Failed to do json");
                         };
 
-                        Ok(Response::builder().body(overview.into()).unwrap())
-                    } else {
                         Ok(Response::builder()
-                            .status(404)
-                            .body("Not found 2".into())
+                            .header("Content-Type", "application/json")
+                            .body(snapshots.into())
                             .unwrap())
                     }
+                    (&Method::GET, uri) => {
+                        let uri_split = uri.split('/');
+                        if uri.starts_with("/overview/") {
+                            let Some(genesis_hash) = uri_split.last() else {
+                                return error_response("Failed split string");
+                            };
+
+                            let Ok(genesis_hash) = genesis_hash.parse::<H256>() else {
+                                return error_response("Cannot convert given block hash to H256");
+                            };
+                            let overview = match aggregator.overview_endpoint(genesis_hash) {
+                                Ok(o) => o,
+                                Err(err) => return error_response(err),
+                            };
+
+                            let Ok(overview) = serde_json::to_string_pretty(&overview) else {
+                                return error_response("Failed to do json");
+                            };
+
+                            Ok(Response::builder().body(overview.into()).unwrap())
+                        } else if uri.starts_with("/block_history/") {
+                            let Some(genesis_hash) = uri_split.last() else {
+                                return error_response("Failed split string");
+                            };
+
+                            let Ok(genesis_hash) = genesis_hash.parse::<H256>() else {
+                                return error_response("Cannot convert given block hash to H256");
+                            };
+                            let overview = match aggregator.block_history_endpoint(genesis_hash) {
+                                Ok(o) => o,
+                                Err(err) => return error_response(err),
+                            };
+
+                            let Ok(overview) = serde_json::to_string_pretty(&overview) else {
+                                return error_response("Failed to do json");
+                            };
+
+                            Ok(Response::builder().body(overview.into()).unwrap())
+                        } else if uri.starts_with("/node_list/") {
+                            let Some(genesis_hash) = uri_split.last() else {
+                                return error_response("Failed split string");
+                            };
+
+                            let Ok(genesis_hash) = genesis_hash.parse::<H256>() else {
+                                return error_response("Cannot convert given block hash to H256");
+                            };
+                            let overview = match aggregator.node_list_endpoint(genesis_hash) {
+                                Ok(o) => o,
+                                Err(err) => return error_response(err),
+                            };
+
+                            let Ok(overview) = serde_json::to_string_pretty(&overview) else {
+                                return error_response("Failed to do json");
+                            };
+
+                            Ok(Response::builder().body(overview.into()).unwrap())
+                        } else if let Some(genesis_hash) =
+                            genesis_hash_from_path(uri, "/hardware_compliance/")
+                        {
+                            let genesis_hash = match genesis_hash {
+                                Ok(hash) => hash,
+                                Err(err) => return error_response(err),
+                            };
+                            let report = match aggregator.hardware_compliance_endpoint(genesis_hash)
+                            {
+                                Ok(r) => r,
+                                Err(err) => return error_response(err),
+                            };
+
+                            let Ok(report) = serde_json::to_string_pretty(&report) else {
+                                return error_response("Failed to do json");
+                            };
+
+                            Ok(Response::builder().body(report.into()).unwrap())
+                        } else if let Some(genesis_hash) =
+                            genesis_hash_from_path(uri, "/authorities/")
+                        {
+                            let genesis_hash = match genesis_hash {
+                                Ok(hash) => hash,
+                                Err(err) => return error_response(err),
+                            };
+                            let authorities = match aggregator.authorities_endpoint(genesis_hash) {
+                                Ok(a) => a,
+                                Err(err) => return error_response(err),
+                            };
+
+                            let Ok(authorities) = serde_json::to_string_pretty(&authorities) else {
+                                return error_response("Failed to do json");
+                            };
+
+                            Ok(Response::builder().body(authorities.into()).unwrap())
+                        } else {
+                            Ok(Response::builder()
+                                .status(404)
+                                .body("Not found 2".into())
+                                .unwrap())
+                        }
+                    }
+                    // 404 for anything else:
+                    _ => default_response(),
                 }
-                // 404 for anything else:
-                _ => default_response(),
             }
+        },
+    );
+
+    let mut server = tokio::spawn(server);
+    tokio::select! {
+        res = &mut server => return res?,
+        _ = shutdown::signal() => {}
+    }
+
+    // Write the snapshot before closing any connections, so that it still contains
+    // the nodes from every shard that's connected to us.
+    log::info!("Shutting down");
+    if let Some(path) = snapshot_path {
+        match aggregator_for_shutdown.write_state_snapshot(&path).await {
+            Ok(()) => log::info!("Wrote state snapshot to {}", path.display()),
+            Err(e) => log::error!("Failed to write state snapshot to {}: {e}", path.display()),
         }
-    });
+    }
 
-    server.await?;
+    // Stop accepting connections, and give the open ones a chance to close cleanly:
+    if !shutdown.shutdown(shutdown_deadline).await {
+        log::warn!(
+            "Some connections didn't close within {}s; exiting anyway",
+            shutdown_deadline.as_secs()
+        );
+    }
+    let _ = tokio::time::timeout(Duration::from_secs(1), server).await;
     Ok(())
 }
 
 /// This handles messages coming to/from a shard connection
+/// The genesis hash at the end of a path which starts with `prefix` (eg `/authorities/`),
+/// or `None` if the path doesn't start with it.
+fn genesis_hash_from_path(path: &str, prefix: &str) -> Option<Result<H256, &'static str>> {
+    let genesis_hash = path.strip_prefix(prefix)?.rsplit('/').next()?;
+    Some(
+        genesis_hash
+            .parse::<H256>()
+            .map_err(|_| "Cannot convert given block hash to H256"),
+    )
+}
+
 async fn handle_shard_websocket_connection<S>(
     mut ws_send: http_utils::WsSender,
     mut ws_recv: http_utils::WsReceiver,
     mut tx_to_aggregator: S,
+    shutdown: ShutdownListener,
 ) -> (S, http_utils::WsSender)
 where
     S: futures::Sink<FromShardWebsocket, Error = anyhow::Error> + Unpin + Send + 'static,
@@ -381,6 +730,10 @@ where
             let msg = tokio::select! {
                 msg = rx_from_aggregator.recv_async() => msg,
                 _ = &mut send_closer_rx => { break }
+                _ = shutdown.recv() => {
+                    log::info!("Closing shard connection: the server is shutting down");
+                    break
+                }
             };
 
             let msg = match msg {
@@ -419,18 +772,65 @@ where
     (tx_to_aggregator, ws_send)
 }
 
+/// Work out which format a feed wants messages in. A `format` query parameter (eg
+/// `/feed?format=msgpack`) takes precedence, and otherwise we pick the first of the
+/// websocket subprotocols offered that we recognise, defaulting to JSON. If the format
+/// was picked from a subprotocol, that subprotocol is also returned so that we can
+/// confirm it in our response.
+fn negotiate_feed_format(
+    req: &hyper::Request<Body>,
+) -> Result<(FeedFormat, Option<&'static str>), String> {
+    let query_format = req
+        .uri()
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .find_map(|param| param.strip_prefix("format="));
+    if let Some(name) = query_format {
+        return FeedFormat::from_name(name)
+            .map(|format| (format, None))
+            .ok_or_else(|| format!("Unknown feed format '{name}'"));
+    }
+
+    let subprotocol_format = req
+        .headers()
+        .get_all("Sec-WebSocket-Protocol")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|protocol| FeedFormat::from_subprotocol(protocol.trim()));
+    Ok(match subprotocol_format {
+        Some(format) => (format, Some(format.subprotocol())),
+        None => (FeedFormat::Json, None),
+    })
+}
+
+/// How to handle a single feed connection.
+struct FeedConnectionOpts {
+    /// Seconds to wait for a batch of messages to be sent before giving up on the feed.
+    timeout: u64,
+    /// How long to wait after sending a batch of messages before sending the next.
+    debounce: Duration,
+    /// Merge the batches of messages waiting to be sent once there are this many.
+    coalesce_backlog: usize,
+    /// The format the feed wants messages in.
+    format: FeedFormat,
+}
+
 /// This handles messages coming from a feed connection
 async fn handle_feed_websocket_connection<S>(
     mut ws_send: http_utils::WsSender,
     mut ws_recv: http_utils::WsReceiver,
     mut tx_to_aggregator: S,
-    feed_timeout: u64,
-    _feed_id: u64, // <- can be useful for debugging purposes.
+    opts: FeedConnectionOpts,
+    feed_id: u64,
+    shutdown: ShutdownListener,
 ) -> (S, http_utils::WsSender)
 where
     S: futures::Sink<FromFeedWebsocket, Error = anyhow::Error> + Unpin + Send + 'static,
 {
-    // unbounded channel so that slow feeds don't block aggregator progress:
+    // unbounded channel so that slow feeds don't block aggregator progress (the aggregator
+    // gives up on feeds whose queue grows too long instead):
     let (tx_to_feed_conn, rx_from_aggregator) = flume::unbounded();
 
     // `Receiver::into_stream()` is currently problematic at the time of writing
@@ -443,6 +843,7 @@ where
     // Tell the aggregator about this new connection, and give it a way to send messages to us:
     let init_msg = FromFeedWebsocket::Initialize {
         channel: tx_to_feed_conn,
+        format: opts.format,
     };
     if let Err(e) = tx_to_aggregator.send(init_msg).await {
         log::error!("Error sending message to aggregator: {e}");
@@ -484,7 +885,7 @@ where
             let cmd = match FromFeedWebsocket::from_str(&text) {
                 Ok(cmd) => cmd,
                 Err(e) => {
-                    log::warn!("Ignoring invalid command '{text}' from the frontend: {e}");
+                    log::warn!(conn_id = feed_id; "Ignoring invalid command '{text}' from the frontend: {e}");
                     continue;
                 }
             };
@@ -500,12 +901,33 @@ where
 
     // Send messages to the feed:
     let send_handle = tokio::spawn(async move {
+        let mut shutting_down = false;
+        let mut drained = 0;
+        let mut update_interval = opts.debounce;
         'outer: loop {
-            let debounce = tokio::time::sleep_until(Instant::now() + Duration::from_millis(75));
+            let debounce = tokio::time::sleep_until(Instant::now() + update_interval);
 
-            let msgs = tokio::select! {
-                msgs = rx_from_aggregator_chunks.next() => msgs,
-                _ = &mut send_closer_rx => { break }
+            let msgs = if shutting_down {
+                // Keep sending anything that was queued up while we sent the last lot, until
+                // there's nothing left or we've sent as much as we're willing to:
+                if drained >= SHUTDOWN_DRAIN_LIMIT {
+                    break;
+                }
+                drain_ready(
+                    &mut rx_from_aggregator_chunks,
+                    SHUTDOWN_DRAIN_LIMIT - drained,
+                )
+            } else {
+                tokio::select! {
+                    msgs = rx_from_aggregator_chunks.next() => msgs,
+                    _ = &mut send_closer_rx => { break }
+                    _ = shutdown.recv() => {
+                        // Send anything that's already queued up for the feed before closing:
+                        log::info!(conn_id = feed_id; "Closing feed connection: the server is shutting down");
+                        shutting_down = true;
+                        drain_ready(&mut rx_from_aggregator_chunks, SHUTDOWN_DRAIN_LIMIT)
+                    }
+                }
             };
 
             // End the loop when connection from aggregator ends:
@@ -514,28 +936,55 @@ where
                 None => break,
             };
 
-            // There is only one message type at the mo; bytes to send
-            // to the websocket. collect them all up to dispatch in one shot.
-            let all_msg_bytes = msgs.into_iter().map(|msg| match msg {
-                ToFeedWebsocket::Bytes(bytes) => bytes,
-            });
+            if shutting_down {
+                drained += msgs.len();
+            }
 
-            // If the feed is too slow to receive the current batch of messages, we'll drop it.
-            let message_send_deadline = Instant::now() + Duration::from_secs(feed_timeout);
+            // Collect up the batches to dispatch in one shot. The feed can ask for updates
+            // less often than usual, but not more often.
+            let mut batches = Vec::with_capacity(msgs.len());
+            for msg in msgs {
+                match msg {
+                    ToFeedWebsocket::Messages(batch) => batches.push(batch),
+                    ToFeedWebsocket::SetUpdateRate(interval) => {
+                        update_interval = interval.max(opts.debounce);
+                    }
+                }
+            }
+
+            // Feeds which asked for updates less often than usual get one merged update
+            // rather than every batch, as do feeds which are falling behind: messages that
+            // later ones supersede aren't sent, each node's samples are merged, and what's
+            // left is sent in one websocket message.
+            let backlog = batches.len();
+            let falling_behind = opts.coalesce_backlog > 0 && backlog >= opts.coalesce_backlog;
+            if falling_behind || update_interval > opts.debounce {
+                let dropped = FeedBatch::coalesce(&mut batches);
+                if falling_behind {
+                    log::debug!(conn_id = feed_id; "Feed is {backlog} batches behind; dropped {dropped} superseded messages");
+                }
+                batches = FeedBatch::join(batches).into_iter().collect();
+            }
 
-            for bytes in all_msg_bytes {
-                match tokio::time::timeout_at(message_send_deadline, ws_send.send_binary(&bytes))
-                    .await
+            // If the feed is too slow to receive the current batch of messages, we'll drop it.
+            let message_send_deadline = Instant::now() + Duration::from_secs(opts.timeout);
+
+            for batch in batches {
+                match tokio::time::timeout_at(
+                    message_send_deadline,
+                    ws_send.send_binary(batch.bytes()),
+                )
+                .await
                 {
                     Err(_) => {
-                        log::debug!("Closing feed websocket that was too slow to keep up (too slow to send messages)");
+                        log::debug!(conn_id = feed_id; "Closing feed websocket that was too slow to keep up (too slow to send messages)");
                         break 'outer;
                     }
                     Ok(Err(soketto::connection::Error::Closed)) => {
                         break 'outer;
                     }
                     Ok(Err(e)) => {
-                        log::debug!("Closing feed websocket due to error sending data: {}", e);
+                        log::debug!(conn_id = feed_id; "Closing feed websocket due to error sending data: {}", e);
                         break 'outer;
                     }
                     Ok(_) => {}
@@ -544,19 +993,22 @@ where
 
             match tokio::time::timeout_at(message_send_deadline, ws_send.flush()).await {
                 Err(_) => {
-                    log::debug!("Closing feed websocket that was too slow to keep up (too slow to flush messages)");
+                    log::debug!(conn_id = feed_id; "Closing feed websocket that was too slow to keep up (too slow to flush messages)");
                     break;
                 }
                 Ok(Err(soketto::connection::Error::Closed)) => {
                     break;
                 }
                 Ok(Err(e)) => {
-                    log::debug!("Closing feed websocket due to error flushing data: {}", e);
+                    log::debug!(conn_id = feed_id; "Closing feed websocket due to error flushing data: {}", e);
                     break;
                 }
                 Ok(_) => {}
             }
 
+            if shutting_down {
+                continue;
+            }
             debounce.await;
         }
 
@@ -573,12 +1025,28 @@ where
     (tx_to_aggregator, ws_send)
 }
 
+/// Take everything that's ready to be sent to a feed without waiting, stopping once we
+/// have at least `limit` messages. Returns `None` if nothing is ready.
+fn drain_ready<T, St>(stream: &mut St, limit: usize) -> Option<Vec<T>>
+where
+    St: futures::Stream<Item = Vec<T>> + Unpin,
+{
+    let mut msgs = Vec::new();
+    while msgs.len() < limit {
+        match stream.next().now_or_never() {
+            Some(Some(mut more)) => msgs.append(&mut more),
+            _ => break,
+        }
+    }
+    (!msgs.is_empty()).then_some(msgs)
+}
+
 async fn return_prometheus_metrics(aggregator: AggregatorSet) -> Response<hyper::Body> {
-    let metrics = aggregator.latest_metrics();
+    let m = aggregator.latest_metrics();
 
     // Instead of using the rust prometheus library (which is optimised around global variables updated across a codebase),
     // we just split out the text format that prometheus expects ourselves, and use the latest metrics that we've
-    // captured so far from the aggregators. See:
+    // captured so far from the aggregator. See:
     //
     // https://github.com/prometheus/docs/blob/master/content/docs/instrumenting/exposition_formats.md#text-format-details
     //
@@ -588,51 +1056,65 @@ async fn return_prometheus_metrics(aggregator: AggregatorSet) -> Response<hyper:
     // Note: '{{' and '}}' are just escaped versions of '{' and '}' in Rust fmt strings.
     use std::fmt::Write;
     let mut s = String::new();
-    for (idx, m) in metrics.iter().enumerate() {
-        let _ = write!(
-            &mut s,
-            "telemetry_core_connected_feeds{{aggregator=\"{}\"}} {} {}\n",
-            idx, m.connected_feeds, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_connected_nodes{{aggregator=\"{}\"}} {} {}\n",
-            idx, m.connected_nodes, m.timestamp_unix_ms
-        );
-        let _ = write!(
+    for (feed_id, len) in &m.feed_queue_lens {
+        let _ = writeln!(
             &mut s,
-            "telemetry_core_connected_shards{{aggregator=\"{}\"}} {} {}\n",
-            idx, m.connected_shards, m.timestamp_unix_ms
+            "telemetry_core_feed_queue_len{{feed=\"{}\"}} {} {}",
+            feed_id, len, m.timestamp_unix_ms
         );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_chains_subscribed_to{{aggregator=\"{}\"}} {} {}\n",
-            idx, m.chains_subscribed_to, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_subscribed_feeds{{aggregator=\"{}\"}} {} {}\n",
-            idx, m.subscribed_feeds, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_total_messages_to_feeds{{aggregator=\"{}\"}} {} {}\n",
-            idx, m.total_messages_to_feeds, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_current_messages_to_aggregator{{aggregator=\"{}\"}} {} {}\n\n",
-            idx, m.current_messages_to_aggregator, m.timestamp_unix_ms
-        );
-        let _ = write!(
-            &mut s,
-            "telemetry_core_total_messages_to_aggregator{{aggregator=\"{}\"}} {} {}\n\n",
-            idx, m.total_messages_to_aggregator, m.timestamp_unix_ms
-        );
-        let _ = write!(
+    }
+    let _ = writeln!(
+        &mut s,
+        "telemetry_core_connected_feeds {} {}",
+        m.connected_feeds, m.timestamp_unix_ms
+    );
+    let _ = writeln!(
+        &mut s,
+        "telemetry_core_connected_nodes {} {}",
+        m.connected_nodes, m.timestamp_unix_ms
+    );
+    let _ = writeln!(
+        &mut s,
+        "telemetry_core_connected_shards {} {}",
+        m.connected_shards, m.timestamp_unix_ms
+    );
+    let _ = writeln!(
+        &mut s,
+        "telemetry_core_chains_subscribed_to {} {}",
+        m.chains_subscribed_to, m.timestamp_unix_ms
+    );
+    let _ = writeln!(
+        &mut s,
+        "telemetry_core_subscribed_feeds {} {}",
+        m.subscribed_feeds, m.timestamp_unix_ms
+    );
+    let _ = writeln!(
+        &mut s,
+        "telemetry_core_total_messages_to_feeds {} {}",
+        m.total_messages_to_feeds, m.timestamp_unix_ms
+    );
+    let _ = writeln!(
+        &mut s,
+        "telemetry_core_current_messages_to_aggregator {} {}\n",
+        m.current_messages_to_aggregator, m.timestamp_unix_ms
+    );
+    let _ = writeln!(
+        &mut s,
+        "telemetry_core_total_messages_to_aggregator {} {}\n",
+        m.total_messages_to_aggregator, m.timestamp_unix_ms
+    );
+    let _ = writeln!(
+        &mut s,
+        "telemetry_core_dropped_messages_to_aggregator {} {}\n",
+        m.dropped_messages_to_aggregator, m.timestamp_unix_ms
+    );
+    for (priority, n) in &m.dropped_messages_by_priority {
+        let _ = writeln!(
             &mut s,
-            "telemetry_core_dropped_messages_to_aggregator{{aggregator=\"{}\"}} {} {}\n\n",
-            idx, m.dropped_messages_to_aggregator, m.timestamp_unix_ms
+            "telemetry_core_dropped_messages_to_aggregator_by_priority{{priority=\"{}\"}} {} {}",
+            priority.name(),
+            n,
+            m.timestamp_unix_ms
         );
     }
 
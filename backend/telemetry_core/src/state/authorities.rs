@@ -0,0 +1,411 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use common::node_message::AfgAuthoritySet;
+use common::node_types::Timestamp;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use super::chain::ChainNodeId;
+use super::counter::{Counter, CounterValue};
+use super::node::Node;
+
+/// Authority sets reported with more authorities than this are ignored.
+const MAX_AUTHORITY_SET_LEN: usize = 10_000;
+
+/// The authority set of a chain, as last reported by one of its nodes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuthoritySet {
+    pub id: Option<u64>,
+    pub authorities: BTreeSet<Box<str>>,
+}
+
+/// How an authority is doing, going by the nodes which report as it.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthorityStatus {
+    /// At least one of the nodes reporting as the authority is keeping up.
+    Reporting,
+    /// Every node reporting as the authority has gone stale.
+    Stale,
+    /// Nodes have reported as the authority, but none do any more.
+    Stopped,
+    /// The authority is in the set, but no node has reported as it.
+    Missing,
+}
+
+/// An authority of a chain, and the nodes which report as it.
+#[derive(Debug, Clone)]
+pub struct AuthorityState<'a> {
+    pub authority_id: &'a str,
+    /// Whether the authority is in the reported set, if we know of one.
+    pub in_set: Option<bool>,
+    pub status: AuthorityStatus,
+    /// When a node last reported as the authority, if none do any more.
+    pub last_seen: Option<Timestamp>,
+    pub nodes: Vec<ChainNodeId>,
+}
+
+/// How many validators and full nodes are running a version.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct VersionCount {
+    pub version: String,
+    pub validators: u64,
+    pub full_nodes: u64,
+}
+
+/// The authorities of a chain which need looking at, as sent to feeds.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AuthoritySummary {
+    pub set_id: Option<u64>,
+    pub set_size: Option<usize>,
+    /// How many authorities have a node which is keeping up.
+    pub reporting: usize,
+    pub stale: Vec<Box<str>>,
+    pub stopped: Vec<(Box<str>, Timestamp)>,
+    pub missing: Vec<Box<str>>,
+    pub versions: Vec<VersionCount>,
+}
+
+/// Keeps track of the authority set of a chain, which nodes report as which
+/// authorities, and how many validators and full nodes run each version.
+#[derive(Default)]
+pub struct AuthorityTracker {
+    /// The most recent authority set that a node told us about.
+    set: Option<AuthoritySet>,
+    /// The nodes which report as each authority.
+    nodes: HashMap<Box<str>, HashSet<ChainNodeId>>,
+    /// When authorities in the set which no node reports as any more were last seen.
+    last_seen: HashMap<Box<str>, Timestamp>,
+    validator_versions: Counter<String>,
+    full_node_versions: Counter<String>,
+}
+
+impl AuthorityTracker {
+    /// Adds or removes a node. Call this with [`CounterValue::Decrement`] before
+    /// changing anything about a node which affects whether it's a validator, and
+    /// with [`CounterValue::Increment`] afterwards.
+    pub fn add_or_remove_node(
+        &mut self,
+        nid: ChainNodeId,
+        node: &Node,
+        op: CounterValue,
+        now: Timestamp,
+    ) {
+        let version = Some(&*node.details().version);
+        if node.is_validator() {
+            self.validator_versions.modify(version, op);
+        } else {
+            self.full_node_versions.modify(version, op);
+        }
+
+        let Some(authority_id) = &node.details().validator else {
+            return;
+        };
+        match op {
+            CounterValue::Increment => {
+                self.last_seen.remove(authority_id);
+                self.nodes
+                    .entry(authority_id.clone())
+                    .or_default()
+                    .insert(nid);
+            }
+            CounterValue::Decrement => {
+                let Some(nodes) = self.nodes.get_mut(authority_id) else {
+                    return;
+                };
+                nodes.remove(&nid);
+                if nodes.is_empty() {
+                    self.nodes.remove(authority_id);
+                    if self.in_set(authority_id) {
+                        self.last_seen.insert(authority_id.clone(), now);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Take note of the authority set that a node has reported, if it's at least as
+    /// recent as the one we know of. Nodes which are still syncing keep reporting older
+    /// sets, which are ignored. Sets larger than [`MAX_AUTHORITY_SET_LEN`] are ignored too.
+    pub fn update_set(&mut self, msg: &AfgAuthoritySet) {
+        let Some(authorities) = &msg.authorities else {
+            return;
+        };
+        if authorities.len() > MAX_AUTHORITY_SET_LEN {
+            return;
+        }
+        if let Some(set) = &self.set {
+            if set.id > msg.authority_set_id {
+                return;
+            }
+        }
+
+        self.restore_set(Some(AuthoritySet {
+            id: msg.authority_set_id,
+            authorities: authorities.iter().cloned().collect(),
+        }));
+    }
+
+    /// The authority set we know of, if any.
+    pub fn set(&self) -> Option<&AuthoritySet> {
+        self.set.as_ref()
+    }
+
+    /// Replace the authority set, ie with one restored from a snapshot.
+    pub fn restore_set(&mut self, set: Option<AuthoritySet>) {
+        // We only remember when authorities in the set were last seen:
+        match &set {
+            Some(set) => self.last_seen.retain(|id, _| set.authorities.contains(id)),
+            None => self.last_seen.clear(),
+        }
+        self.set = set;
+    }
+
+    fn in_set(&self, authority_id: &str) -> bool {
+        self.set
+            .as_ref()
+            .is_some_and(|set| set.authorities.contains(authority_id))
+    }
+
+    /// Every authority in the set, and any others that nodes report as, ordered by ID.
+    pub fn authorities(&self, is_stale: impl Fn(ChainNodeId) -> bool) -> Vec<AuthorityState<'_>> {
+        let set_ids = self.set.iter().flat_map(|set| set.authorities.iter());
+        let ids: BTreeSet<&Box<str>> = set_ids.chain(self.nodes.keys()).collect();
+
+        ids.into_iter()
+            .map(|authority_id| {
+                let nodes: Vec<ChainNodeId> = self
+                    .nodes
+                    .get(authority_id)
+                    .map(|nodes| nodes.iter().copied().collect())
+                    .unwrap_or_default();
+                let last_seen = self.last_seen.get(authority_id).copied();
+                let status = if nodes.is_empty() {
+                    match last_seen {
+                        Some(_) => AuthorityStatus::Stopped,
+                        None => AuthorityStatus::Missing,
+                    }
+                } else if nodes.iter().all(|&nid| is_stale(nid)) {
+                    AuthorityStatus::Stale
+                } else {
+                    AuthorityStatus::Reporting
+                };
+
+                AuthorityState {
+                    authority_id,
+                    in_set: self.set.as_ref().map(|_| self.in_set(authority_id)),
+                    status,
+                    last_seen,
+                    nodes,
+                }
+            })
+            .collect()
+    }
+
+    /// How many validators and full nodes are running each version, most common first.
+    pub fn versions(&self) -> Vec<VersionCount> {
+        let mut versions: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+        let validators = self.validator_versions.generate_ranking_top(usize::MAX);
+        for (version, count) in validators.list {
+            versions.entry(version).or_default().0 = count;
+        }
+        let full_nodes = self.full_node_versions.generate_ranking_top(usize::MAX);
+        for (version, count) in full_nodes.list {
+            versions.entry(version).or_default().1 = count;
+        }
+
+        let mut versions: Vec<VersionCount> = versions
+            .into_iter()
+            .map(|(version, (validators, full_nodes))| VersionCount {
+                version,
+                validators,
+                full_nodes,
+            })
+            .collect();
+        versions.sort_by_key(|v| std::cmp::Reverse(v.validators + v.full_nodes));
+        versions
+    }
+
+    /// Summarise the authorities which need looking at.
+    pub fn summary(&self, is_stale: impl Fn(ChainNodeId) -> bool) -> AuthoritySummary {
+        let mut summary = AuthoritySummary {
+            set_id: self.set.as_ref().and_then(|set| set.id),
+            set_size: self.set.as_ref().map(|set| set.authorities.len()),
+            versions: self.versions(),
+            ..Default::default()
+        };
+        for authority in self.authorities(is_stale) {
+            let id = authority.authority_id.into();
+            match authority.status {
+                AuthorityStatus::Reporting => summary.reporting += 1,
+                AuthorityStatus::Stale => summary.stale.push(id),
+                AuthorityStatus::Stopped => summary
+                    .stopped
+                    .push((id, authority.last_seen.unwrap_or_default())),
+                AuthorityStatus::Missing => summary.missing.push(id),
+            }
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::test_utils::{self, node_details};
+    use common::node_types::NodeDetails;
+
+    fn node(version: &str, validator: Option<&str>) -> Node {
+        test_utils::node(NodeDetails {
+            version: version.into(),
+            validator: validator.map(Into::into),
+            ..node_details()
+        })
+    }
+
+    fn authority_set(id: u64, authorities: &[&str]) -> AfgAuthoritySet {
+        AfgAuthoritySet {
+            authority_id: authorities[0].into(),
+            authority_set_id: Some(id),
+            authorities: Some(authorities.iter().map(|&a| a.into()).collect()),
+        }
+    }
+
+    #[test]
+    fn authorities_without_a_reporting_node_are_found() {
+        let mut tracker = AuthorityTracker::default();
+        tracker.update_set(&authority_set(2, &["A", "B", "C"]));
+
+        let a = node("1.0", Some("A"));
+        let b = node("1.0", Some("B"));
+        tracker.add_or_remove_node(ChainNodeId::new(0), &a, CounterValue::Increment, 0);
+        tracker.add_or_remove_node(ChainNodeId::new(1), &b, CounterValue::Increment, 0);
+        tracker.add_or_remove_node(
+            ChainNodeId::new(2),
+            &node("1.1", None),
+            CounterValue::Increment,
+            0,
+        );
+
+        // B's node has gone stale, and no node reports as C:
+        let is_stale = |nid| nid == ChainNodeId::new(1);
+        let summary = tracker.summary(is_stale);
+        assert_eq!(summary.set_id, Some(2));
+        assert_eq!(summary.set_size, Some(3));
+        assert_eq!(summary.reporting, 1);
+        assert_eq!(summary.stale, vec!["B".into()]);
+        assert!(summary.stopped.is_empty());
+        assert_eq!(summary.missing, vec!["C".into()]);
+        assert_eq!(
+            summary.versions,
+            vec![
+                VersionCount {
+                    version: "1.0".into(),
+                    validators: 2,
+                    full_nodes: 0
+                },
+                VersionCount {
+                    version: "1.1".into(),
+                    validators: 0,
+                    full_nodes: 1
+                },
+            ]
+        );
+
+        // Once B's node disconnects, we remember when it was last seen:
+        tracker.add_or_remove_node(ChainNodeId::new(1), &b, CounterValue::Decrement, 1234);
+        let summary = tracker.summary(is_stale);
+        assert!(summary.stale.is_empty());
+        assert_eq!(summary.stopped, vec![("B".into(), 1234)]);
+
+        // Older sets are ignored, and newer ones forget about authorities that left:
+        tracker.update_set(&authority_set(1, &["A"]));
+        assert_eq!(tracker.set().unwrap().id, Some(2));
+        tracker.update_set(&authority_set(3, &["A", "C", "D"]));
+        let summary = tracker.summary(is_stale);
+        assert_eq!(summary.reporting, 1);
+        assert!(summary.stopped.is_empty());
+        assert_eq!(summary.missing, vec!["C".into(), "D".into()]);
+    }
+
+    #[test]
+    fn older_sets_from_lagging_nodes_are_ignored() {
+        let mut tracker = AuthorityTracker::default();
+        let a = node("1.0", Some("A"));
+        let b = node("1.0", Some("B"));
+        tracker.update_set(&authority_set(2, &["A", "B"]));
+        tracker.add_or_remove_node(ChainNodeId::new(0), &a, CounterValue::Increment, 0);
+        tracker.add_or_remove_node(ChainNodeId::new(1), &b, CounterValue::Increment, 0);
+        tracker.add_or_remove_node(ChainNodeId::new(1), &b, CounterValue::Decrement, 1234);
+
+        // Reports of the old and new sets are interleaved as nodes catch up:
+        for (id, authorities) in [
+            (3, &["B", "C"][..]),
+            (2, &["A", "B"]),
+            (3, &["B", "C"]),
+            (1, &["A"]),
+            (2, &["A", "B"]),
+        ] {
+            tracker.update_set(&authority_set(id, authorities));
+            let set = tracker.set().unwrap();
+            assert_eq!(set.id, Some(3));
+            assert_eq!(set.authorities, ["B".into(), "C".into()].into());
+        }
+
+        // B is still remembered as having stopped, rather than forgotten and then missing:
+        let summary = tracker.summary(|_| false);
+        assert_eq!(summary.stopped, vec![("B".into(), 1234)]);
+        assert_eq!(summary.missing, vec!["C".into()]);
+    }
+
+    #[test]
+    fn oversized_sets_are_ignored() {
+        let mut tracker = AuthorityTracker::default();
+        tracker.update_set(&authority_set(2, &["A", "B"]));
+
+        let names: Vec<String> = (0..=MAX_AUTHORITY_SET_LEN).map(|n| n.to_string()).collect();
+        let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+        tracker.update_set(&authority_set(3, &names));
+        assert_eq!(tracker.set().unwrap().id, Some(2));
+    }
+
+    #[test]
+    fn validators_outside_of_the_set_are_listed() {
+        let mut tracker = AuthorityTracker::default();
+        let a = node("1.0", Some("A"));
+        tracker.add_or_remove_node(ChainNodeId::new(0), &a, CounterValue::Increment, 0);
+
+        // Without a set, we can't tell whether the authority is in it:
+        let authorities = tracker.authorities(|_| false);
+        assert_eq!(authorities.len(), 1);
+        assert_eq!(authorities[0].in_set, None);
+        assert_eq!(authorities[0].status, AuthorityStatus::Reporting);
+
+        // Authorities which aren't in the set aren't remembered once they stop reporting:
+        tracker.update_set(&authority_set(1, &["B"]));
+        let authorities = tracker.authorities(|_| false);
+        assert_eq!(authorities[0].authority_id, "A");
+        assert_eq!(authorities[0].in_set, Some(false));
+        assert_eq!(authorities[1].authority_id, "B");
+        assert_eq!(authorities[1].status, AuthorityStatus::Missing);
+
+        tracker.add_or_remove_node(ChainNodeId::new(0), &a, CounterValue::Decrement, 10);
+        let authorities = tracker.authorities(|_| false);
+        assert_eq!(authorities.len(), 1);
+        assert_eq!(authorities[0].authority_id, "B");
+    }
+}
@@ -1,3 +1,4 @@
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 
 use common::{
@@ -7,16 +8,15 @@ use common::{
 
 use super::node::UniqueNodeIdentity;
 
-const MAX_BLOCK_HEIGHT_STORED: usize = 30;
-
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct BlockIntervalDetails {
     pub proposal: Option<IntervalFromNode>,
     pub import: Option<IntervalFromNode>,
     pub sync: Option<IntervalFromNode>,
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<StoredBlockEntry>", into = "Vec<StoredBlockEntry>")]
 pub struct StoredBlocks(
     pub  BTreeMap<
         BlockNumber,
@@ -42,10 +42,85 @@ impl StoredBlocks {
         let blocks = self.0.entry(block.height).or_default();
         let block = blocks.entry(block.hash).or_default();
         block.insert(identity, data);
+    }
 
-        // Trim Old Data if needed.
-        while self.0.len() > MAX_BLOCK_HEIGHT_STORED {
+    /// Forget about the oldest blocks until only `max_heights` block heights are stored.
+    pub fn trim(&mut self, max_heights: usize) {
+        while self.0.len() > max_heights {
             self.0.pop_first();
         }
     }
 }
+
+/// Stored blocks are serialized as a flat list of these, since the nested maps
+/// are keyed by things that can't be used as keys in formats like JSON.
+#[derive(Serialize, Deserialize)]
+struct StoredBlockEntry {
+    block: Block,
+    node: UniqueNodeIdentity,
+    #[serde(flatten)]
+    details: BlockIntervalDetails,
+}
+
+impl From<StoredBlocks> for Vec<StoredBlockEntry> {
+    fn from(stored_blocks: StoredBlocks) -> Self {
+        let mut entries = Vec::new();
+        for (height, blocks) in stored_blocks.0 {
+            for (hash, nodes) in blocks {
+                for (node, details) in nodes {
+                    entries.push(StoredBlockEntry {
+                        block: Block { hash, height },
+                        node,
+                        details,
+                    });
+                }
+            }
+        }
+        entries
+    }
+}
+
+impl From<Vec<StoredBlockEntry>> for StoredBlocks {
+    fn from(entries: Vec<StoredBlockEntry>) -> Self {
+        let mut stored_blocks = StoredBlocks::default();
+        for entry in entries {
+            stored_blocks.new_entry(
+                entry.node,
+                entry.block,
+                entry.details.proposal,
+                entry.details.import,
+                entry.details.sync,
+            );
+        }
+        stored_blocks
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stored_blocks_can_be_serialized_and_deserialized() {
+        let mut stored_blocks = StoredBlocks::default();
+        for (height, name) in [(1, "Alice"), (1, "Bob"), (2, "Alice")] {
+            let identity = UniqueNodeIdentity {
+                node_name: name.into(),
+                network_id: "network".into(),
+            };
+            let block = Block {
+                hash: BlockHash::from_low_u64_be(height),
+                height,
+            };
+            stored_blocks.new_entry(identity, block, None, None, None);
+        }
+
+        let json = serde_json::to_string(&stored_blocks).unwrap();
+        let restored: StoredBlocks = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(restored.0.len(), 2);
+        let nodes_at_1 = &restored.0[&1][&BlockHash::from_low_u64_be(1)];
+        assert_eq!(nodes_at_1.len(), 2);
+        assert_eq!(serde_json::to_string(&restored).unwrap(), json);
+    }
+}
@@ -16,22 +16,26 @@
 
 use common::node_message::Payload;
 use common::node_types::BlockHash;
-use common::node_types::{Block, Timestamp};
+use common::node_types::{Block, NodeDetails, Timestamp};
 use common::{id_type, time, DenseMap, MostSeen, NumStats};
-use once_cell::sync::Lazy;
-use std::collections::HashSet;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::hash::Hash;
-use std::str::FromStr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use crate::feed_message::{self, ChainStats, FeedMessageSerializer};
 use crate::find_location;
 
+use super::authorities::{
+    AuthoritySet, AuthorityState, AuthoritySummary, AuthorityTracker, VersionCount,
+};
 use super::blocks::StoredBlocks;
-use super::chain_stats::ChainStatsCollator;
+use super::chain_stats::{ChainStatsCollator, StatsOpts};
 use super::counter::CounterValue;
-use super::node::Node;
-use crate::endpoints::{BlockHistory, ChainOverview, NodeList};
+use super::hardware_compliance::HardwareRequirements;
+use super::node::{Node, NodeSnapshot};
+use crate::endpoints::{Authorities, BlockHistory, ChainOverview, HardwareCompliance, NodeList};
 
 id_type! {
     /// A Node ID that is unique to the chain it's in.
@@ -40,9 +44,6 @@ id_type! {
 
 pub type Label = Box<str>;
 
-const STALE_TIMEOUT: u64 = 2 * 60 * 1000; // 2 minutes
-const STATS_UPDATE_INTERVAL: Duration = Duration::from_secs(5);
-
 pub struct Chain {
     /// Labels that nodes use for this chain. We keep track of
     /// the most commonly used label as nodes are added/removed.
@@ -69,8 +70,54 @@ pub struct Chain {
     stats: ChainStats,
     /// Timestamp of when the stats were last regenerated.
     stats_last_regenerated: Instant,
+    /// The authority set, and which nodes report as which authorities.
+    authorities: AuthorityTracker,
+    /// The authority summary last sent to feeds.
+    authority_summary: AuthoritySummary,
     /// chain block history
     stored_blocks: StoredBlocks,
+    /// Settings for this chain.
+    opts: ChainOpts,
+}
+
+/// Settings which affect how every chain is tracked.
+#[derive(Debug, Clone)]
+pub struct ChainOpts {
+    /// If a chain hasn't seen a new best block in this long, we look for stale nodes.
+    pub stale_timeout: Duration,
+    /// The minimum time between regenerating the stats for a chain.
+    pub stats_update_interval: Duration,
+    /// How many block heights to keep the block times for, for the block history endpoint.
+    pub max_block_height_stored: usize,
+    /// Updates about a node's blocks are throttled if its block time is no more than this.
+    pub throttle_threshold: Duration,
+    /// Which stats to collect for chains, unless overridden in `chain_stats`.
+    pub stats: StatsOpts,
+    /// Which stats to collect for specific chains.
+    pub chain_stats: Arc<HashMap<BlockHash, StatsOpts>>,
+    /// The minimum hardware that validators on specific chains are expected to have.
+    pub hardware_requirements: Arc<HashMap<BlockHash, HardwareRequirements>>,
+}
+
+impl Default for ChainOpts {
+    fn default() -> Self {
+        ChainOpts {
+            stale_timeout: Duration::from_secs(2 * 60),
+            stats_update_interval: Duration::from_secs(5),
+            max_block_height_stored: 30,
+            throttle_threshold: Duration::from_millis(100),
+            stats: StatsOpts::default(),
+            chain_stats: Arc::new(HashMap::new()),
+            hardware_requirements: Arc::new(HashMap::new()),
+        }
+    }
+}
+
+impl ChainOpts {
+    /// Which stats to collect for the chain with the given genesis hash.
+    pub fn stats_for(&self, genesis_hash: &BlockHash) -> &StatsOpts {
+        self.chain_stats.get(genesis_hash).unwrap_or(&self.stats)
+    }
 }
 
 pub enum AddNodeResult {
@@ -85,8 +132,26 @@ pub struct RemoveNodeResult {
     pub chain_renamed: bool,
 }
 
-/// Genesis hashes of chains we consider "first party". These chains allow up to 5k nodes to connect.
-static FIRST_PARTY_NETWORKS: Lazy<HashSet<BlockHash>> = Lazy::new(|| {
+/// The history of a chain which can be handed over to another core, so that
+/// it can carry on where we left off rather than starting the chain afresh.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChainSnapshot {
+    pub genesis_hash: BlockHash,
+    pub best: Block,
+    pub finalized: Block,
+    /// Recent block times, oldest first.
+    pub block_times: Vec<u64>,
+    /// Recent blocks and how long each node took to propose, import or sync them.
+    #[serde(default)]
+    pub stored_blocks: StoredBlocks,
+    /// The authority set last reported by the chain's nodes.
+    #[serde(default)]
+    pub authority_set: Option<AuthoritySet>,
+}
+
+/// Genesis hashes of the chains we consider "first party" by default. There's no limit
+/// to the number of nodes that can connect from these chains.
+pub fn default_first_party_networks() -> Vec<BlockHash> {
     let genesis_hash_strs = &[
         "0xb91746b45e0346cc2f815a520b9c6cb4d5c0902af848db0a80f85932d2e8276a", // Mainnet
         "0xd3d2f3a3495dc597434a99d7d449ebad6616db45e4e4f178f31cc6fa14378b70", // Turing
@@ -94,19 +159,13 @@ static FIRST_PARTY_NETWORKS: Lazy<HashSet<BlockHash>> = Lazy::new(|| {
 
     genesis_hash_strs
         .iter()
-        .map(|h| BlockHash::from_str(h).expect("hardcoded hash str should be valid"))
+        .map(|h| h.parse().expect("hardcoded hash str should be valid"))
         .collect()
-});
-
-/// When we construct a chain, we want to check to see whether or not it's a "first party"
-/// network first, and assign a `max_nodes` accordingly. This helps us do that.
-pub fn is_first_party_network(genesis_hash: &BlockHash) -> bool {
-    FIRST_PARTY_NETWORKS.contains(genesis_hash)
 }
 
 impl Chain {
     /// Create a new chain with an initial label.
-    pub fn new(genesis_hash: BlockHash, max_nodes: usize) -> Self {
+    pub fn new(genesis_hash: BlockHash, max_nodes: usize, opts: ChainOpts) -> Self {
         Chain {
             labels: MostSeen::default(),
             nodes: DenseMap::new(),
@@ -117,13 +176,51 @@ impl Chain {
             timestamp: None,
             genesis_hash,
             max_nodes,
-            stats_collator: Default::default(),
+            stats_collator: ChainStatsCollator::new(opts.stats_for(&genesis_hash).clone()),
             stats: Default::default(),
             stats_last_regenerated: Instant::now(),
+            authorities: AuthorityTracker::default(),
+            authority_summary: AuthoritySummary::default(),
             stored_blocks: StoredBlocks::default(),
+            opts,
         }
     }
 
+    /// Take a snapshot of the history of this chain.
+    pub fn snapshot(&self) -> ChainSnapshot {
+        ChainSnapshot {
+            genesis_hash: self.genesis_hash,
+            best: self.best,
+            finalized: self.finalized,
+            block_times: self.block_times.iter().copied().collect(),
+            stored_blocks: self.stored_blocks.clone(),
+            authority_set: self.authorities.set().cloned(),
+        }
+    }
+
+    /// Take a snapshot of each of the nodes in this chain.
+    pub fn node_snapshots(&self) -> impl Iterator<Item = NodeSnapshot> + '_ {
+        self.nodes
+            .iter()
+            .map(move |(_, node)| node.snapshot(self.genesis_hash))
+    }
+
+    /// Carry on from the history in the snapshot given. The time that the best block
+    /// arrived isn't restored, so that the gap between the snapshot being taken and
+    /// the next best block arriving isn't counted as a block time.
+    pub fn restore(&mut self, snapshot: ChainSnapshot) {
+        self.best = snapshot.best;
+        self.finalized = snapshot.finalized;
+        self.block_times.reset();
+        for block_time in &snapshot.block_times {
+            self.block_times.push(*block_time);
+        }
+        self.average_block_time =
+            (!snapshot.block_times.is_empty()).then(|| self.block_times.average());
+        self.stored_blocks = snapshot.stored_blocks;
+        self.authorities.restore_set(snapshot.authority_set);
+    }
+
     /// Is the chain the node belongs to overquota?
     pub fn is_overquota(&self) -> bool {
         self.nodes.len() >= self.max_nodes
@@ -135,13 +232,16 @@ impl Chain {
             return AddNodeResult::Overquota;
         }
 
-        let details = node.details();
         self.stats_collator
-            .add_or_remove_node(details, None, CounterValue::Increment);
+            .add_or_remove_node(&node, CounterValue::Increment);
 
-        let node_chain_label = &details.chain;
+        let node_chain_label = &node.details().chain;
         let label_result = self.labels.insert(node_chain_label);
-        let node_id = self.nodes.add(node);
+        let authorities = &mut self.authorities;
+        let node_id = self.nodes.add_with(|node_id| {
+            authorities.add_or_remove_node(node_id, &node, CounterValue::Increment, time::now());
+            node
+        });
 
         AddNodeResult::Added {
             id: node_id,
@@ -160,9 +260,10 @@ impl Chain {
             }
         };
 
-        let details = node.details();
         self.stats_collator
-            .add_or_remove_node(details, node.hwbench(), CounterValue::Decrement);
+            .add_or_remove_node(&node, CounterValue::Decrement);
+        self.authorities
+            .add_or_remove_node(node_id, &node, CounterValue::Decrement, time::now());
 
         let node_chain_label = &node.details().chain;
         let label_result = self.labels.remove(node_chain_label);
@@ -172,6 +273,34 @@ impl Chain {
         }
     }
 
+    /// Hand fresh details to a node that's already in this chain (ie one that was restored
+    /// from a snapshot and is being added again). Returns whether the chain was renamed as
+    /// a result, or `None` if the node doesn't exist.
+    pub fn adopt_node(&mut self, node_id: ChainNodeId, details: NodeDetails) -> Option<bool> {
+        let node = self.nodes.get_mut(node_id)?;
+
+        let old_label = node.details().chain.clone();
+        let now = time::now();
+        self.stats_collator
+            .add_or_remove_node(node, CounterValue::Decrement);
+        self.authorities
+            .add_or_remove_node(node_id, node, CounterValue::Decrement, now);
+
+        node.adopt_details(details);
+
+        self.stats_collator
+            .add_or_remove_node(node, CounterValue::Increment);
+        self.authorities
+            .add_or_remove_node(node_id, node, CounterValue::Increment, now);
+
+        if node.details().chain == old_label {
+            return Some(false);
+        }
+        let removed = self.labels.remove(&old_label).has_changed();
+        let inserted = self.labels.insert(&node.details().chain).has_changed();
+        Some(removed || inserted)
+    }
+
     /// Attempt to update the best block seen in this chain.
     pub fn update_node(
         &mut self,
@@ -188,20 +317,43 @@ impl Chain {
             match payload {
                 Payload::SystemInterval(ref interval) => {
                     // Send a feed message if any of the relevant node details change:
-                    if node.update_hardware(interval) {
-                        feed.push(feed_message::Hardware(nid.into(), node.hardware()));
+                    // Feeds are sent the full hardware and IO series when they subscribe, and
+                    // after that just the changes to them:
+                    if let Some(changes) = node.update_hardware(interval) {
+                        feed.push(feed_message::HardwareDelta(
+                            nid.into(),
+                            node.hardware_seq(),
+                            node.hardware(),
+                            changes,
+                        ));
                     }
                     if let Some(stats) = node.update_stats(interval) {
                         feed.push(feed_message::NodeStatsUpdate(nid.into(), stats));
                     }
-                    if let Some(io) = node.update_io(interval) {
-                        feed.push(feed_message::NodeIOUpdate(nid.into(), io));
+                    if let Some(change) = node.update_io(interval) {
+                        feed.push(feed_message::NodeIODelta(
+                            nid.into(),
+                            node.io_seq(),
+                            node.io(),
+                            change,
+                        ));
                     }
                 }
                 Payload::AfgAuthoritySet(authority) => {
                     // If our node validator address (and thus details) change, send an
                     // updated "add node" feed message:
-                    if node.set_validator_address(authority.authority_id.clone()) {
+                    let now = time::now();
+                    self.stats_collator
+                        .update_validator(node, CounterValue::Decrement);
+                    self.authorities
+                        .add_or_remove_node(nid, node, CounterValue::Decrement, now);
+                    let changed = node.set_validator_address(authority.authority_id.clone());
+                    self.stats_collator
+                        .update_validator(node, CounterValue::Increment);
+                    self.authorities
+                        .add_or_remove_node(nid, node, CounterValue::Increment, now);
+                    self.authorities.update_set(&authority);
+                    if changed {
                         feed.push(feed_message::AddedNode(
                             nid.into(),
                             &node,
@@ -255,8 +407,26 @@ impl Chain {
 
                         self.stored_blocks
                             .new_entry(identity, block, proposal, import, sync);
-
+                        self.stored_blocks.trim(self.opts.max_block_height_stored);
+
+                        let now = time::now();
+                        self.stats_collator
+                            .update_validator(node, CounterValue::Decrement);
+                        self.authorities.add_or_remove_node(
+                            nid,
+                            node,
+                            CounterValue::Decrement,
+                            now,
+                        );
                         node.set_is_authority(Some(prop.is_authority));
+                        self.stats_collator
+                            .update_validator(node, CounterValue::Increment);
+                        self.authorities.add_or_remove_node(
+                            nid,
+                            node,
+                            CounterValue::Increment,
+                            now,
+                        );
                     }
                 }
                 _ => {}
@@ -299,6 +469,7 @@ impl Chain {
             if block.height > self.best.height {
                 self.best = *block;
                 log::debug!(
+                    genesis_hash:? = self.genesis_hash, height = self.best.height;
                     "[{}] [nodes={}] new best block={}/{:?}",
                     self.labels.best(),
                     nodes_len,
@@ -322,7 +493,8 @@ impl Chain {
                 }
             }
 
-            if let Some(details) = node.update_details(now, propagation_time) {
+            let throttle_threshold = self.opts.throttle_threshold.as_millis() as u64;
+            if let Some(details) = node.update_details(now, propagation_time, throttle_threshold) {
                 feed.push(feed_message::ImportedBlock(nid.into(), details));
             }
         }
@@ -331,7 +503,7 @@ impl Chain {
     /// Check if the chain is stale (has not received a new best block in a while).
     /// If so, find a new best block, ignoring any stale nodes and marking them as such.
     fn update_stale_nodes(&mut self, now: u64, feed: &mut FeedMessageSerializer) {
-        let threshold = now - STALE_TIMEOUT;
+        let threshold = now.saturating_sub(self.opts.stale_timeout.as_millis() as u64);
         let timestamp = match self.timestamp {
             Some(ts) => ts,
             None => return,
@@ -379,19 +551,38 @@ impl Chain {
         }
     }
 
+    /// Regenerate the chain stats straight away, rather than waiting for the next block.
+    pub fn regenerate_stats(&mut self) {
+        self.stats_last_regenerated = Instant::now();
+        self.stats_collator.update(&mut self.stats, time::now());
+    }
+
     fn regenerate_stats_if_necessary(&mut self, feed: &mut FeedMessageSerializer) {
         let now = Instant::now();
         let elapsed = now - self.stats_last_regenerated;
-        if elapsed < STATS_UPDATE_INTERVAL {
+        if elapsed < self.opts.stats_update_interval {
             return;
         }
 
         self.stats_last_regenerated = now;
-        let new_stats = self.stats_collator.generate();
-        if new_stats != self.stats {
-            self.stats = new_stats;
-            feed.push(feed_message::ChainStatsUpdate(&self.stats));
+        let delta = self.stats_collator.update(&mut self.stats, time::now());
+        if !delta.is_empty() {
+            feed.push(delta);
         }
+
+        let authority_summary = self.authority_summary();
+        if authority_summary != self.authority_summary {
+            self.authority_summary = authority_summary;
+            feed.push(feed_message::AuthoritiesUpdate(&self.authority_summary));
+        }
+    }
+
+    /// Is the node stale, or has it fallen behind for longer than the stale timeout?
+    fn is_node_stale(&self, nid: ChainNodeId, now: Timestamp) -> bool {
+        let threshold = now.saturating_sub(self.opts.stale_timeout.as_millis() as u64);
+        self.nodes
+            .get(nid)
+            .is_none_or(|node| node.is_stale_since(threshold))
     }
 
     pub fn update_node_location(
@@ -400,7 +591,11 @@ impl Chain {
         location: find_location::Location,
     ) -> bool {
         if let Some(node) = self.nodes.get_mut(node_id) {
+            self.stats_collator
+                .update_country(node, CounterValue::Decrement);
             node.update_location(location);
+            self.stats_collator
+                .update_country(node, CounterValue::Increment);
             true
         } else {
             false
@@ -437,6 +632,23 @@ impl Chain {
     pub fn stats(&self) -> &ChainStats {
         &self.stats
     }
+    /// Every authority we know of, and how it's doing.
+    pub fn authorities(&self) -> Vec<AuthorityState<'_>> {
+        let now = time::now();
+        self.authorities
+            .authorities(|nid| self.is_node_stale(nid, now))
+    }
+    /// The authorities which need looking at, as of now.
+    pub fn authority_summary(&self) -> AuthoritySummary {
+        let now = time::now();
+        self.authorities.summary(|nid| self.is_node_stale(nid, now))
+    }
+    pub fn authority_set(&self) -> Option<&AuthoritySet> {
+        self.authorities.set()
+    }
+    pub fn versions(&self) -> Vec<VersionCount> {
+        self.authorities.versions()
+    }
     pub fn max_nodes(&self) -> usize {
         self.max_nodes
     }
@@ -469,4 +681,17 @@ impl Chain {
     pub fn node_list_endpoint(&self) -> NodeList {
         self.into()
     }
+    pub fn authorities_endpoint(&self) -> Authorities {
+        self.into()
+    }
+    /// How the validators on this chain measure up to its hardware requirements,
+    /// if it has any.
+    pub fn hardware_compliance_endpoint(&self) -> Option<HardwareCompliance> {
+        let requirements = self.hardware_requirements()?;
+        Some(HardwareCompliance::new(requirements, self))
+    }
+    /// The minimum hardware that validators on this chain are expected to have, if any.
+    pub fn hardware_requirements(&self) -> Option<&HardwareRequirements> {
+        self.opts.hardware_requirements.get(&self.genesis_hash)
+    }
 }
@@ -15,29 +15,147 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use super::counter::{Counter, CounterValue};
-use crate::feed_message::ChainStats;
-
-// These are the benchmark scores generated on our reference hardware.
-const REFERENCE_CPU_SCORE: u64 = 1028;
-const REFERENCE_MEMORY_SCORE: u64 = 14899;
-const REFERENCE_DISK_SEQUENTIAL_WRITE_SCORE: u64 = 485;
-const REFERENCE_DISK_RANDOM_WRITE_SCORE: u64 = 222;
-
-macro_rules! buckets {
-    (@try $value:expr, $bucket_min:expr, $bucket_max:expr,) => {
-        if $value < $bucket_max {
-            return ($bucket_min, Some($bucket_max));
+use super::node::Node;
+use crate::feed_message::{ChainStats, ChainStatsDelta, Ranking};
+use common::node_types::{NodeHwBench, Timestamp};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+/// The things that nodes on a chain can be ranked by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dimension {
+    Version,
+    Implementation,
+    TargetOs,
+    TargetArch,
+    Cpu,
+    Memory,
+    CoreCount,
+    LinuxKernel,
+    LinuxDistro,
+    IsVirtualMachine,
+    CpuHashrateScore,
+    MemoryMemcpyScore,
+    DiskSequentialWriteScore,
+    DiskRandomWriteScore,
+    CpuVendor,
+    Validator,
+    Country,
+    StartupAge,
+}
+
+impl Dimension {
+    pub const ALL: [Dimension; 18] = [
+        Dimension::Version,
+        Dimension::Implementation,
+        Dimension::TargetOs,
+        Dimension::TargetArch,
+        Dimension::Cpu,
+        Dimension::Memory,
+        Dimension::CoreCount,
+        Dimension::LinuxKernel,
+        Dimension::LinuxDistro,
+        Dimension::IsVirtualMachine,
+        Dimension::CpuHashrateScore,
+        Dimension::MemoryMemcpyScore,
+        Dimension::DiskSequentialWriteScore,
+        Dimension::DiskRandomWriteScore,
+        Dimension::CpuVendor,
+        Dimension::Validator,
+        Dimension::Country,
+        Dimension::StartupAge,
+    ];
+}
+
+/// Which stats to collect about the nodes on a chain, and how to bucket them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsOpts {
+    /// The dimensions to rank nodes by; the rest are left out of the stats.
+    pub dimensions: Vec<Dimension>,
+    /// Benchmark scores of the reference hardware. Hardware benchmark results are
+    /// bucketed by how they compare (in percent) to these.
+    pub reference_cpu_score: u64,
+    pub reference_memory_score: u64,
+    pub reference_disk_sequential_write_score: u64,
+    pub reference_disk_random_write_score: u64,
+    /// Boundaries of the buckets that benchmark scores fall into, in percent of the
+    /// reference score.
+    pub score_buckets: Vec<u32>,
+    /// Boundaries of the buckets that the memory of nodes falls into, in GB.
+    pub memory_buckets: Vec<u32>,
+    /// Boundaries of the buckets that the time since nodes started falls into, in hours.
+    pub startup_age_buckets: Vec<u32>,
+}
+
+impl Default for StatsOpts {
+    fn default() -> Self {
+        // The reference scores are those generated on our reference hardware.
+        StatsOpts {
+            dimensions: Dimension::ALL.to_vec(),
+            reference_cpu_score: 1028,
+            reference_memory_score: 14899,
+            reference_disk_sequential_write_score: 485,
+            reference_disk_random_write_score: 222,
+            score_buckets: vec![0, 10, 30, 50, 70, 90, 110, 130, 150, 200, 300, 400, 500],
+            memory_buckets: vec![1, 2, 4, 6, 8, 10, 16, 24, 32, 48, 56, 64, 128],
+            startup_age_buckets: vec![0, 1, 6, 24, 24 * 7, 24 * 30],
+        }
+    }
+}
+
+impl StatsOpts {
+    /// Check that these settings make sense, returning a description of the problem if not.
+    pub fn validate(&self) -> Result<(), String> {
+        let reference_scores = [
+            ("reference_cpu_score", self.reference_cpu_score),
+            ("reference_memory_score", self.reference_memory_score),
+            (
+                "reference_disk_sequential_write_score",
+                self.reference_disk_sequential_write_score,
+            ),
+            (
+                "reference_disk_random_write_score",
+                self.reference_disk_random_write_score,
+            ),
+        ];
+        for (name, score) in reference_scores {
+            if score == 0 {
+                return Err(format!("'{name}' must be greater than 0"));
+            }
+        }
+
+        let buckets = [
+            ("score_buckets", &self.score_buckets),
+            ("memory_buckets", &self.memory_buckets),
+            ("startup_age_buckets", &self.startup_age_buckets),
+        ];
+        for (name, boundaries) in buckets {
+            if boundaries.is_empty() || boundaries.windows(2).any(|w| w[0] >= w[1]) {
+                return Err(format!(
+                    "'{name}' must be a non-empty list of increasing numbers"
+                ));
+            }
         }
-    };
 
-    ($value:expr, $bucket_min:expr, $bucket_max:expr, $($remaining:expr,)*) => {
-        buckets! { @try $value, $bucket_min, $bucket_max, }
-        buckets! { $value, $bucket_max, $($remaining,)* }
-    };
+        Ok(())
+    }
+}
 
-    ($value:expr, $bucket_last:expr,) => {
-        ($bucket_last, None)
+/// Find the bucket that a value falls into, given the boundaries between buckets.
+///
+/// The value returned is the range within which the value falls, with the lower bound
+/// being inclusive and the upper bound being exclusive. Values below the second boundary
+/// fall into the first bucket, and values at or above the last boundary fall into an
+/// unbounded bucket.
+fn bucket(value: u64, boundaries: &[u32]) -> (u32, Option<u32>) {
+    for pair in boundaries.windows(2) {
+        if value < pair[1] as u64 {
+            return (pair[0], Some(pair[1]));
+        }
     }
+    (boundaries.last().copied().unwrap_or(0), None)
 }
 
 /// Translates a given raw benchmark score into a relative measure
@@ -47,57 +165,28 @@ macro_rules! buckets {
 /// falls into. For example, a value of `(90, Some(110))` means that the score
 /// is between 90% and 110% of the reference score, with the lower bound being
 /// inclusive and the upper bound being exclusive.
-fn bucket_score(score: u64, reference_score: u64) -> (u32, Option<u32>) {
-    let relative_score = ((score as f64 / reference_score as f64) * 100.0) as u32;
-
-    buckets! {
-        relative_score,
-        0,
-        10,
-        30,
-        50,
-        70,
-        90,
-        110,
-        130,
-        150,
-        200,
-        300,
-        400,
-        500,
-    }
+fn bucket_score(score: u64, reference_score: u64, boundaries: &[u32]) -> (u32, Option<u32>) {
+    let relative_score = ((score as f64 / reference_score as f64) * 100.0) as u64;
+    bucket(relative_score, boundaries)
 }
 
 #[test]
 fn test_bucket_score() {
-    assert_eq!(bucket_score(0, 100), (0, Some(10)));
-    assert_eq!(bucket_score(9, 100), (0, Some(10)));
-    assert_eq!(bucket_score(10, 100), (10, Some(30)));
-    assert_eq!(bucket_score(29, 100), (10, Some(30)));
-    assert_eq!(bucket_score(30, 100), (30, Some(50)));
-    assert_eq!(bucket_score(100, 100), (90, Some(110)));
-    assert_eq!(bucket_score(500, 100), (500, None));
+    let boundaries = StatsOpts::default().score_buckets;
+    assert_eq!(bucket_score(0, 100, &boundaries), (0, Some(10)));
+    assert_eq!(bucket_score(9, 100, &boundaries), (0, Some(10)));
+    assert_eq!(bucket_score(10, 100, &boundaries), (10, Some(30)));
+    assert_eq!(bucket_score(29, 100, &boundaries), (10, Some(30)));
+    assert_eq!(bucket_score(30, 100, &boundaries), (30, Some(50)));
+    assert_eq!(bucket_score(100, 100, &boundaries), (90, Some(110)));
+    assert_eq!(bucket_score(500, 100, &boundaries), (500, None));
+
+    assert_eq!(bucket_score(100, 100, &[0, 50, 150]), (50, Some(150)));
+    assert_eq!(bucket_score(100, 100, &[0]), (0, None));
 }
 
-fn bucket_memory(memory: u64) -> (u32, Option<u32>) {
-    let memory = memory / (1024 * 1024) / 1000;
-
-    buckets! {
-        memory,
-        1,
-        2,
-        4,
-        6,
-        8,
-        10,
-        16,
-        24,
-        32,
-        48,
-        56,
-        64,
-        128,
-    }
+fn bucket_memory(memory: u64, boundaries: &[u32]) -> (u32, Option<u32>) {
+    bucket(memory / (1024 * 1024) / 1000, boundaries)
 }
 
 fn kernel_version_number(version: &Box<str>) -> &str {
@@ -134,9 +223,53 @@ fn cpu_vendor(cpu: &Box<str>) -> &str {
     }
 }
 
+/// Counts how many nodes started at each time, so that they can be bucketed by how long
+/// they've been running whenever the ranking is generated.
 #[derive(Default)]
+struct StartupTimes {
+    times: BTreeMap<Timestamp, u64>,
+    empty: u64,
+}
+
+impl StartupTimes {
+    fn modify(&mut self, time: Option<Timestamp>, op: CounterValue) {
+        let count = match time {
+            Some(time) => self.times.entry(time).or_default(),
+            None => &mut self.empty,
+        };
+        match op {
+            CounterValue::Increment => *count += 1,
+            CounterValue::Decrement => *count -= 1,
+        }
+        if let Some(time) = time {
+            if self.times.get(&time) == Some(&0) {
+                self.times.remove(&time);
+            }
+        }
+    }
+
+    fn generate_ranking(&self, now: Timestamp, boundaries: &[u32]) -> Ranking<(u32, Option<u32>)> {
+        const HOUR: u64 = 60 * 60 * 1000;
+
+        let mut counts: BTreeMap<(u32, Option<u32>), u64> = BTreeMap::new();
+        for (&time, &count) in &self.times {
+            let age_in_hours = now.saturating_sub(time) / HOUR;
+            *counts.entry(bucket(age_in_hours, boundaries)).or_default() += count;
+        }
+
+        Ranking {
+            list: counts.into_iter().collect(),
+            other: 0,
+            unknown: self.empty,
+        }
+    }
+}
+
 pub struct ChainStatsCollator {
+    opts: StatsOpts,
+    dimensions: HashSet<Dimension>,
     version: Counter<String>,
+    implementation: Counter<String>,
     target_os: Counter<String>,
     target_arch: Counter<String>,
     cpu: Counter<String>,
@@ -150,118 +283,378 @@ pub struct ChainStatsCollator {
     disk_sequential_write_score: Counter<(u32, Option<u32>)>,
     disk_random_write_score: Counter<(u32, Option<u32>)>,
     cpu_vendor: Counter<String>,
+    validator: Counter<bool>,
+    country: Counter<String>,
+    startup_age: StartupTimes,
 }
 
 impl ChainStatsCollator {
-    pub fn add_or_remove_node(
-        &mut self,
-        details: &common::node_types::NodeDetails,
-        hwbench: Option<&common::node_types::NodeHwBench>,
-        op: CounterValue,
-    ) {
-        self.version.modify(Some(&*details.version), op);
+    pub fn new(opts: StatsOpts) -> Self {
+        ChainStatsCollator {
+            dimensions: opts.dimensions.iter().copied().collect(),
+            opts,
+            version: Default::default(),
+            implementation: Default::default(),
+            target_os: Default::default(),
+            target_arch: Default::default(),
+            cpu: Default::default(),
+            memory: Default::default(),
+            core_count: Default::default(),
+            linux_kernel: Default::default(),
+            linux_distro: Default::default(),
+            is_virtual_machine: Default::default(),
+            cpu_hashrate_score: Default::default(),
+            memory_memcpy_score: Default::default(),
+            disk_sequential_write_score: Default::default(),
+            disk_random_write_score: Default::default(),
+            cpu_vendor: Default::default(),
+            validator: Default::default(),
+            country: Default::default(),
+            startup_age: Default::default(),
+        }
+    }
 
-        self.target_os
-            .modify(details.target_os.as_ref().map(|value| &**value), op);
+    fn has(&self, dimension: Dimension) -> bool {
+        self.dimensions.contains(&dimension)
+    }
+
+    pub fn add_or_remove_node(&mut self, node: &Node, op: CounterValue) {
+        let details = node.details();
+
+        if self.has(Dimension::Version) {
+            self.version.modify(Some(&*details.version), op);
+        }
 
-        self.target_arch
-            .modify(details.target_arch.as_ref().map(|value| &**value), op);
+        if self.has(Dimension::Implementation) {
+            self.implementation
+                .modify(Some(&*details.implementation), op);
+        }
+
+        if self.has(Dimension::TargetOs) {
+            self.target_os
+                .modify(details.target_os.as_ref().map(|value| &**value), op);
+        }
+
+        if self.has(Dimension::TargetArch) {
+            self.target_arch
+                .modify(details.target_arch.as_ref().map(|value| &**value), op);
+        }
 
         let sysinfo = details.sysinfo.as_ref();
-        self.cpu.modify(
-            sysinfo
-                .and_then(|sysinfo| sysinfo.cpu.as_ref())
-                .map(|value| &**value),
-            op,
-        );
-
-        let memory = sysinfo.and_then(|sysinfo| sysinfo.memory.map(bucket_memory));
-        self.memory.modify(memory.as_ref(), op);
-
-        self.core_count
-            .modify(sysinfo.and_then(|sysinfo| sysinfo.core_count.as_ref()), op);
-
-        self.linux_kernel.modify(
-            sysinfo
-                .and_then(|sysinfo| sysinfo.linux_kernel.as_ref())
-                .map(kernel_version_number),
-            op,
-        );
-
-        self.linux_distro.modify(
-            sysinfo
-                .and_then(|sysinfo| sysinfo.linux_distro.as_ref())
-                .map(|value| &**value),
-            op,
-        );
-
-        self.is_virtual_machine.modify(
-            sysinfo.and_then(|sysinfo| sysinfo.is_virtual_machine.as_ref()),
-            op,
-        );
-
-        self.cpu_vendor.modify(
-            sysinfo.and_then(|sysinfo| sysinfo.cpu.as_ref().map(cpu_vendor)),
-            op,
-        );
-
-        self.update_hwbench(hwbench, op);
+        if self.has(Dimension::Cpu) {
+            self.cpu.modify(
+                sysinfo
+                    .and_then(|sysinfo| sysinfo.cpu.as_ref())
+                    .map(|value| &**value),
+                op,
+            );
+        }
+
+        if self.has(Dimension::Memory) {
+            let memory = sysinfo.and_then(|sysinfo| {
+                sysinfo
+                    .memory
+                    .map(|memory| bucket_memory(memory, &self.opts.memory_buckets))
+            });
+            self.memory.modify(memory.as_ref(), op);
+        }
+
+        if self.has(Dimension::CoreCount) {
+            self.core_count
+                .modify(sysinfo.and_then(|sysinfo| sysinfo.core_count.as_ref()), op);
+        }
+
+        if self.has(Dimension::LinuxKernel) {
+            self.linux_kernel.modify(
+                sysinfo
+                    .and_then(|sysinfo| sysinfo.linux_kernel.as_ref())
+                    .map(kernel_version_number),
+                op,
+            );
+        }
+
+        if self.has(Dimension::LinuxDistro) {
+            self.linux_distro.modify(
+                sysinfo
+                    .and_then(|sysinfo| sysinfo.linux_distro.as_ref())
+                    .map(|value| &**value),
+                op,
+            );
+        }
+
+        if self.has(Dimension::IsVirtualMachine) {
+            self.is_virtual_machine.modify(
+                sysinfo.and_then(|sysinfo| sysinfo.is_virtual_machine.as_ref()),
+                op,
+            );
+        }
+
+        if self.has(Dimension::CpuVendor) {
+            self.cpu_vendor.modify(
+                sysinfo.and_then(|sysinfo| sysinfo.cpu.as_ref().map(cpu_vendor)),
+                op,
+            );
+        }
+
+        if self.has(Dimension::StartupAge) {
+            self.startup_age.modify(node.startup_time(), op);
+        }
+
+        self.update_validator(node, op);
+        self.update_country(node, op);
+        self.update_hwbench(node.hwbench(), op);
+    }
+
+    /// Count the node as a validator or not. Call this before and after anything
+    /// which might change that.
+    pub fn update_validator(&mut self, node: &Node, op: CounterValue) {
+        if self.has(Dimension::Validator) {
+            self.validator.modify(Some(&node.is_validator()), op);
+        }
     }
 
-    pub fn update_hwbench(
-        &mut self,
-        hwbench: Option<&common::node_types::NodeHwBench>,
-        op: CounterValue,
-    ) {
-        self.cpu_hashrate_score.modify(
-            hwbench
-                .map(|hwbench| bucket_score(hwbench.cpu_hashrate_score, REFERENCE_CPU_SCORE))
-                .as_ref(),
-            op,
-        );
-
-        self.memory_memcpy_score.modify(
-            hwbench
-                .map(|hwbench| bucket_score(hwbench.memory_memcpy_score, REFERENCE_MEMORY_SCORE))
-                .as_ref(),
-            op,
-        );
-
-        self.disk_sequential_write_score.modify(
-            hwbench
-                .and_then(|hwbench| hwbench.disk_sequential_write_score)
-                .map(|score| bucket_score(score, REFERENCE_DISK_SEQUENTIAL_WRITE_SCORE))
-                .as_ref(),
-            op,
-        );
-
-        self.disk_random_write_score.modify(
-            hwbench
-                .and_then(|hwbench| hwbench.disk_random_write_score)
-                .map(|score| bucket_score(score, REFERENCE_DISK_RANDOM_WRITE_SCORE))
-                .as_ref(),
-            op,
-        );
+    /// Count the country that the node is in. Call this before and after the
+    /// location of the node changes.
+    pub fn update_country(&mut self, node: &Node, op: CounterValue) {
+        if self.has(Dimension::Country) {
+            self.country.modify(
+                node.location()
+                    .and_then(|location| location.country.as_deref()),
+                op,
+            );
+        }
+    }
+
+    pub fn update_hwbench(&mut self, hwbench: Option<&NodeHwBench>, op: CounterValue) {
+        let opts = &self.opts;
+
+        if self.has(Dimension::CpuHashrateScore) {
+            self.cpu_hashrate_score.modify(
+                hwbench
+                    .map(|hwbench| {
+                        bucket_score(
+                            hwbench.cpu_hashrate_score,
+                            opts.reference_cpu_score,
+                            &opts.score_buckets,
+                        )
+                    })
+                    .as_ref(),
+                op,
+            );
+        }
+
+        if self.has(Dimension::MemoryMemcpyScore) {
+            self.memory_memcpy_score.modify(
+                hwbench
+                    .map(|hwbench| {
+                        bucket_score(
+                            hwbench.memory_memcpy_score,
+                            opts.reference_memory_score,
+                            &opts.score_buckets,
+                        )
+                    })
+                    .as_ref(),
+                op,
+            );
+        }
+
+        if self.has(Dimension::DiskSequentialWriteScore) {
+            self.disk_sequential_write_score.modify(
+                hwbench
+                    .and_then(|hwbench| hwbench.disk_sequential_write_score)
+                    .map(|score| {
+                        bucket_score(
+                            score,
+                            opts.reference_disk_sequential_write_score,
+                            &opts.score_buckets,
+                        )
+                    })
+                    .as_ref(),
+                op,
+            );
+        }
+
+        if self.has(Dimension::DiskRandomWriteScore) {
+            self.disk_random_write_score.modify(
+                hwbench
+                    .and_then(|hwbench| hwbench.disk_random_write_score)
+                    .map(|score| {
+                        bucket_score(
+                            score,
+                            opts.reference_disk_random_write_score,
+                            &opts.score_buckets,
+                        )
+                    })
+                    .as_ref(),
+                op,
+            );
+        }
     }
 
-    pub fn generate(&self) -> ChainStats {
-        ChainStats {
-            version: self.version.generate_ranking_top(10),
-            target_os: self.target_os.generate_ranking_top(10),
-            target_arch: self.target_arch.generate_ranking_top(10),
-            cpu: self.cpu.generate_ranking_top(10),
-            memory: self.memory.generate_ranking_ordered(),
-            core_count: self.core_count.generate_ranking_top(10),
-            linux_kernel: self.linux_kernel.generate_ranking_top(10),
-            linux_distro: self.linux_distro.generate_ranking_top(10),
-            is_virtual_machine: self.is_virtual_machine.generate_ranking_ordered(),
-            cpu_hashrate_score: self.cpu_hashrate_score.generate_ranking_top(10),
-            memory_memcpy_score: self.memory_memcpy_score.generate_ranking_ordered(),
-            disk_sequential_write_score: self
-                .disk_sequential_write_score
-                .generate_ranking_ordered(),
-            disk_random_write_score: self.disk_random_write_score.generate_ranking_ordered(),
-            cpu_vendor: self.cpu_vendor.generate_ranking_top(10),
+    /// Regenerate the rankings of any counters which have been modified since the
+    /// last update, returning the rankings in `stats` which have changed as a result.
+    /// Rankings for dimensions which aren't being collected are left as `None`.
+    pub fn update<'a>(&mut self, stats: &'a mut ChainStats, now: Timestamp) -> ChainStatsDelta<'a> {
+        let mut delta = ChainStatsDelta::default();
+
+        macro_rules! update_rankings {
+            ($($field:ident => $generate:ident($($arg:expr)?),)*) => {
+                $(
+                    if self.$field.take_dirty() {
+                        let ranking = self.$field.$generate($($arg)?);
+                        if stats.$field.as_ref() != Some(&ranking) {
+                            delta.$field = Some(&*stats.$field.insert(ranking));
+                        }
+                    }
+                )*
+            };
         }
+
+        update_rankings! {
+            version => generate_ranking_top(10),
+            implementation => generate_ranking_top(10),
+            target_os => generate_ranking_top(10),
+            target_arch => generate_ranking_top(10),
+            cpu => generate_ranking_top(10),
+            memory => generate_ranking_ordered(),
+            core_count => generate_ranking_top(10),
+            linux_kernel => generate_ranking_top(10),
+            linux_distro => generate_ranking_top(10),
+            is_virtual_machine => generate_ranking_ordered(),
+            cpu_hashrate_score => generate_ranking_top(10),
+            memory_memcpy_score => generate_ranking_ordered(),
+            disk_sequential_write_score => generate_ranking_ordered(),
+            disk_random_write_score => generate_ranking_ordered(),
+            cpu_vendor => generate_ranking_top(10),
+            validator => generate_ranking_ordered(),
+            country => generate_ranking_top(10),
+        }
+
+        // Nodes get older without anything else changing, so we check this every time
+        // (including once they've all gone, so that we don't hold on to a stale ranking):
+        if self.has(Dimension::StartupAge) {
+            let ranking = self
+                .startup_age
+                .generate_ranking(now, &self.opts.startup_age_buckets);
+            if stats.startup_age.as_ref() != Some(&ranking) {
+                delta.startup_age = Some(&*stats.startup_age.insert(ranking));
+            }
+        }
+
+        delta
     }
 }
+
+#[cfg(test)]
+fn node(version: &str, startup_time: Option<&str>) -> Node {
+    use super::test_utils::{self, node_details};
+    use common::node_types::NodeDetails;
+
+    test_utils::node(NodeDetails {
+        version: version.into(),
+        startup_time: startup_time.map(Into::into),
+        ..node_details()
+    })
+}
+
+#[test]
+fn test_update_only_returns_changed_rankings() {
+    let mut collator = ChainStatsCollator::new(StatsOpts::default());
+    let mut stats = ChainStats::default();
+
+    // Every ranking that a node contributes to has changed to begin with:
+    collator.add_or_remove_node(&node("0.1", None), CounterValue::Increment);
+    let delta = collator.update(&mut stats, 0);
+    assert!(delta.version.is_some());
+    assert!(delta.target_os.is_some());
+    assert!(delta.cpu.is_some());
+
+    // Nothing has changed since then:
+    assert!(collator.update(&mut stats, 0).is_empty());
+
+    // Swapping a node for an identical one doesn't change anything either:
+    collator.add_or_remove_node(&node("0.1", None), CounterValue::Decrement);
+    collator.add_or_remove_node(&node("0.1", None), CounterValue::Increment);
+    assert!(collator.update(&mut stats, 0).is_empty());
+
+    // But upgrading the node only changes the version ranking:
+    collator.add_or_remove_node(&node("0.1", None), CounterValue::Decrement);
+    collator.add_or_remove_node(&node("0.2", None), CounterValue::Increment);
+    let delta = collator.update(&mut stats, 0);
+    let json = serde_json::to_value(&delta).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "version": { "list": [["0.2", 1]], "other": 0, "unknown": 0 }
+        })
+    );
+    assert_eq!(stats.version.unwrap().list, vec![("0.2".to_owned(), 1)]);
+}
+
+#[test]
+fn test_dimensions_can_be_configured() {
+    const HOUR: u64 = 60 * 60 * 1000;
+
+    let mut collator = ChainStatsCollator::new(StatsOpts {
+        dimensions: vec![Dimension::Implementation, Dimension::StartupAge],
+        startup_age_buckets: vec![0, 2, 10],
+        ..Default::default()
+    });
+    let mut stats = ChainStats::default();
+
+    collator.add_or_remove_node(&node("0.1", Some("0")), CounterValue::Increment);
+    collator.add_or_remove_node(&node("0.1", None), CounterValue::Increment);
+
+    // Only the dimensions asked for are collected:
+    let delta = collator.update(&mut stats, HOUR);
+    let json = serde_json::to_value(&delta).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "implementation": { "list": [["Bar", 2]], "other": 0, "unknown": 0 },
+            "startup_age": { "list": [[[0, 2], 1]], "other": 0, "unknown": 1 },
+        })
+    );
+    assert!(stats.version.is_none());
+
+    // Nodes move into other buckets as they get older:
+    let delta = collator.update(&mut stats, 5 * HOUR);
+    let json = serde_json::to_value(&delta).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "startup_age": { "list": [[[2, 10], 1]], "other": 0, "unknown": 1 },
+        })
+    );
+}
+
+#[test]
+fn test_startup_age_is_cleared_when_all_nodes_leave() {
+    const HOUR: u64 = 60 * 60 * 1000;
+
+    let mut collator = ChainStatsCollator::new(StatsOpts {
+        dimensions: vec![Dimension::StartupAge],
+        startup_age_buckets: vec![0, 2, 10],
+        ..Default::default()
+    });
+    let mut stats = ChainStats::default();
+
+    collator.add_or_remove_node(&node("0.1", Some("0")), CounterValue::Increment);
+    collator.add_or_remove_node(&node("0.1", None), CounterValue::Increment);
+    collator.update(&mut stats, HOUR);
+
+    // Once every node has gone, the ranking is emptied rather than left as it was:
+    collator.add_or_remove_node(&node("0.1", Some("0")), CounterValue::Decrement);
+    collator.add_or_remove_node(&node("0.1", None), CounterValue::Decrement);
+    let delta = collator.update(&mut stats, 5 * HOUR);
+    let json = serde_json::to_value(&delta).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "startup_age": { "list": [], "other": 0, "unknown": 0 },
+        })
+    );
+
+    // And there's nothing more to say about it after that:
+    assert!(collator.update(&mut stats, 10 * HOUR).is_empty());
+}
@@ -27,6 +27,9 @@ pub struct Counter<K> {
 
     /// The number of occurrences where the key is `None`.
     empty: u64,
+
+    /// Has this been modified since we last checked?
+    dirty: bool,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
@@ -46,6 +49,7 @@ where
         K: std::borrow::Borrow<Q>,
         Q: std::borrow::ToOwned<Owned = K>,
     {
+        self.dirty = true;
         if let Some(key) = key {
             if let Some(entry) = self.map.get_mut(key) {
                 match op {
@@ -76,6 +80,12 @@ where
         }
     }
 
+    /// Returns whether this has been modified since the last call,
+    /// and resets that ready for the next.
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.dirty)
+    }
+
     /// Generates a top-N table of the most common keys.
     pub fn generate_ranking_top(&self, max_count: usize) -> Ranking<K>
     where
@@ -0,0 +1,193 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use super::node::Node;
+use serde::{Deserialize, Serialize};
+
+/// The minimum hardware that validators on a chain are expected to have. Any
+/// requirements which are left out aren't checked.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HardwareRequirements {
+    /// The minimum CPU hashrate benchmark score, in MB/s.
+    pub cpu_hashrate_score: Option<u64>,
+    /// The minimum memory bandwidth benchmark score, in MB/s.
+    pub memory_memcpy_score: Option<u64>,
+    /// The minimum sequential disk write benchmark score, in MB/s.
+    pub disk_sequential_write_score: Option<u64>,
+    /// The minimum random disk write benchmark score, in MB/s.
+    pub disk_random_write_score: Option<u64>,
+    /// The minimum amount of memory, in GB.
+    pub memory: Option<u64>,
+    /// The minimum number of physical CPU cores.
+    pub core_count: Option<u32>,
+}
+
+/// Whether a node meets a single requirement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compliance {
+    Pass,
+    Fail,
+    /// The node hasn't told us enough to know either way.
+    Unknown,
+}
+
+/// How a node measures up to each of the hardware requirements of its chain.
+/// Requirements which aren't set for the chain are left out.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NodeCompliance {
+    /// Does the node pass every requirement?
+    pub compliant: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_hashrate_score: Option<Compliance>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_memcpy_score: Option<Compliance>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk_sequential_write_score: Option<Compliance>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk_random_write_score: Option<Compliance>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<Compliance>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub core_count: Option<Compliance>,
+}
+
+impl HardwareRequirements {
+    /// Check a node against each of these requirements.
+    pub fn check(&self, node: &Node) -> NodeCompliance {
+        let hwbench = node.hwbench();
+        let sysinfo = node.details().sysinfo.as_ref();
+
+        let mut compliance = NodeCompliance {
+            compliant: false,
+            cpu_hashrate_score: check(
+                self.cpu_hashrate_score,
+                hwbench.map(|hwbench| hwbench.cpu_hashrate_score),
+            ),
+            memory_memcpy_score: check(
+                self.memory_memcpy_score,
+                hwbench.map(|hwbench| hwbench.memory_memcpy_score),
+            ),
+            disk_sequential_write_score: check(
+                self.disk_sequential_write_score,
+                hwbench.and_then(|hwbench| hwbench.disk_sequential_write_score),
+            ),
+            disk_random_write_score: check(
+                self.disk_random_write_score,
+                hwbench.and_then(|hwbench| hwbench.disk_random_write_score),
+            ),
+            // Memory is converted to GB the same way as for the chain stats:
+            memory: check(
+                self.memory,
+                sysinfo
+                    .and_then(|sysinfo| sysinfo.memory)
+                    .map(|memory| memory / (1024 * 1024) / 1000),
+            ),
+            core_count: check(
+                self.core_count,
+                sysinfo.and_then(|sysinfo| sysinfo.core_count),
+            ),
+        };
+
+        compliance.compliant = [
+            compliance.cpu_hashrate_score,
+            compliance.memory_memcpy_score,
+            compliance.disk_sequential_write_score,
+            compliance.disk_random_write_score,
+            compliance.memory,
+            compliance.core_count,
+        ]
+        .into_iter()
+        .flatten()
+        .all(|result| result == Compliance::Pass);
+
+        compliance
+    }
+}
+
+fn check<T: PartialOrd>(required: Option<T>, actual: Option<T>) -> Option<Compliance> {
+    let required = required?;
+    let compliance = match actual {
+        Some(actual) if actual >= required => Compliance::Pass,
+        Some(_) => Compliance::Fail,
+        None => Compliance::Unknown,
+    };
+    Some(compliance)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::test_utils::{self, node_details};
+    use common::node_types::{NodeDetails, NodeHwBench, NodeSysInfo};
+
+    fn node(memory: Option<u64>, core_count: Option<u32>) -> Node {
+        test_utils::node(NodeDetails {
+            validator: Some("5Validator".into()),
+            sysinfo: Some(NodeSysInfo {
+                cpu: None,
+                memory,
+                core_count,
+                linux_kernel: None,
+                linux_distro: None,
+                is_virtual_machine: None,
+            }),
+            ..node_details()
+        })
+    }
+
+    #[test]
+    fn nodes_are_checked_against_each_requirement() {
+        let requirements = HardwareRequirements {
+            cpu_hashrate_score: Some(1000),
+            memory: Some(32),
+            core_count: Some(8),
+            ..Default::default()
+        };
+
+        // 64GB of memory but too few cores, and no benchmark results yet:
+        let mut node = node(Some(64 * 1024 * 1024 * 1000), Some(4));
+        let compliance = requirements.check(&node);
+        assert!(!compliance.compliant);
+        assert_eq!(compliance.memory, Some(Compliance::Pass));
+        assert_eq!(compliance.core_count, Some(Compliance::Fail));
+        assert_eq!(compliance.cpu_hashrate_score, Some(Compliance::Unknown));
+        assert_eq!(compliance.disk_random_write_score, None);
+
+        node.update_hwbench(NodeHwBench {
+            cpu_hashrate_score: 1200,
+            memory_memcpy_score: 0,
+            disk_sequential_write_score: None,
+            disk_random_write_score: None,
+        });
+        let compliance = requirements.check(&node);
+        assert_eq!(compliance.cpu_hashrate_score, Some(Compliance::Pass));
+        assert!(!compliance.compliant);
+
+        // Only the requirements which are set are checked:
+        let requirements = HardwareRequirements {
+            cpu_hashrate_score: Some(1000),
+            ..Default::default()
+        };
+        let compliance = requirements.check(&node);
+        assert!(compliance.compliant);
+        assert_eq!(
+            serde_json::to_value(&compliance).unwrap(),
+            serde_json::json!({ "compliant": true, "cpu_hashrate_score": "pass" })
+        );
+    }
+}
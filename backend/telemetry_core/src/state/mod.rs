@@ -14,14 +14,22 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+mod authorities;
 mod chain;
 mod chain_stats;
 mod counter;
+mod hardware_compliance;
 mod node;
 mod state;
 
+#[cfg(test)]
+pub mod test_utils;
+
 pub mod blocks;
 
-pub use chain::Chain;
+pub use authorities::{AuthorityStatus, AuthoritySummary, VersionCount};
+pub use chain::{default_first_party_networks, Chain, ChainOpts, ChainSnapshot};
+pub use chain_stats::StatsOpts;
+pub use hardware_compliance::{HardwareRequirements, NodeCompliance};
 pub use node::{Node, UniqueNodeIdentity};
 pub use state::*;
@@ -14,18 +14,17 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
 use crate::find_location;
 use common::node_message::SystemInterval;
 use common::node_types::{
-    Block, BlockDetails, NodeDetails, NodeHardware, NodeHwBench, NodeIO, NodeLocation, NodeStats,
-    Timestamp,
+    Block, BlockDetails, BlockHash, NodeDetails, NodeHardware, NodeHwBench, NodeIO, NodeLocation,
+    NodeStats, Timestamp,
 };
-use common::time;
+use common::{time, MeanList, MeanListChange};
 
-/// Minimum time between block below broadcasting updates to the browser gets throttled, in ms.
-const THROTTLE_THRESHOLD: u64 = 100;
 /// Minimum time of intervals for block updates sent to the browser when throttled, in ms.
 const THROTTLE_INTERVAL: u64 = 1000;
 
@@ -44,6 +43,10 @@ pub struct Node {
     throttle: u64,
     /// Hardware stats over time
     hardware: NodeHardware,
+    /// Incremented each time the hardware stats change
+    hardware_seq: u64,
+    /// Incremented each time the IO stats change
+    io_seq: u64,
     /// Physical location details
     location: find_location::Location,
     /// Flag marking if the node is stale (not syncing or producing blocks)
@@ -58,6 +61,22 @@ pub struct Node {
     is_authority: Option<bool>,
 }
 
+/// The details and history of a node, so that it can be restored after a restart.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NodeSnapshot {
+    pub genesis_hash: BlockHash,
+    pub details: NodeDetails,
+    pub startup_time: Option<Timestamp>,
+    pub best: Block,
+    pub finalized: Block,
+    pub hwbench: Option<NodeHwBench>,
+    pub location: Option<NodeLocation>,
+    pub upload: MeanList<f64>,
+    pub download: MeanList<f64>,
+    pub chart_stamps: MeanList<f64>,
+    pub used_state_cache_size: MeanList<f32>,
+}
+
 impl Node {
     pub fn new(mut details: NodeDetails) -> Self {
         let startup_time = details
@@ -65,10 +84,7 @@ impl Node {
             .take()
             .and_then(|time| time.parse().ok());
 
-        let identity = UniqueNodeIdentity {
-            node_name: details.name.clone().into(),
-            network_id: details.network_id.to_string().into(),
-        };
+        let identity = UniqueNodeIdentity::from_details(&details);
 
         Node {
             details,
@@ -78,6 +94,8 @@ impl Node {
             finalized: Block::zero(),
             throttle: 0,
             hardware: NodeHardware::default(),
+            hardware_seq: 0,
+            io_seq: 0,
             location: None,
             stale: false,
             startup_time,
@@ -87,6 +105,56 @@ impl Node {
         }
     }
 
+    /// Create a node from a snapshot. Until it reports a new block, the node is considered
+    /// stale, since we don't know whether it's still around.
+    pub fn from_snapshot(snapshot: NodeSnapshot) -> Self {
+        let mut node = Node::new(snapshot.details);
+        node.startup_time = snapshot.startup_time;
+        node.best.block = snapshot.best;
+        node.finalized = snapshot.finalized;
+        node.hwbench = snapshot.hwbench;
+        node.location = snapshot.location.map(Arc::new);
+        node.hardware = NodeHardware {
+            upload: snapshot.upload,
+            download: snapshot.download,
+            chart_stamps: snapshot.chart_stamps,
+        };
+        node.io = NodeIO {
+            used_state_cache_size: snapshot.used_state_cache_size,
+        };
+        node.stale = true;
+        node
+    }
+
+    /// Take a snapshot of this node, so that it can be restored later.
+    pub fn snapshot(&self, genesis_hash: BlockHash) -> NodeSnapshot {
+        NodeSnapshot {
+            genesis_hash,
+            details: self.details.clone(),
+            startup_time: self.startup_time,
+            best: self.best.block,
+            finalized: self.finalized,
+            hwbench: self.hwbench.clone(),
+            location: self.location.as_deref().cloned(),
+            upload: self.hardware.upload.clone(),
+            download: self.hardware.download.clone(),
+            chart_stamps: self.hardware.chart_stamps.clone(),
+            used_state_cache_size: self.io.used_state_cache_size.clone(),
+        }
+    }
+
+    /// Take on fresh details for a node that was restored from a snapshot and has now
+    /// been added again, keeping hold of the history that we have for it.
+    pub fn adopt_details(&mut self, details: NodeDetails) {
+        let Node {
+            details,
+            startup_time,
+            ..
+        } = Node::new(details);
+        self.details = details;
+        self.startup_time = startup_time.or(self.startup_time);
+    }
+
     pub fn identity(&self) -> UniqueNodeIdentity {
         self.identity.clone()
     }
@@ -119,6 +187,14 @@ impl Node {
         &self.hardware
     }
 
+    pub fn hardware_seq(&self) -> u64 {
+        self.hardware_seq
+    }
+
+    pub fn io_seq(&self) -> u64 {
+        self.io_seq
+    }
+
     pub fn location(&self) -> Option<&NodeLocation> {
         self.location.as_deref()
     }
@@ -150,17 +226,20 @@ impl Node {
         }
     }
 
+    /// Update the details of the node's best block. If the time between blocks is no more than
+    /// `throttle_threshold` (in ms), we then throttle how often these details are returned.
     pub fn update_details(
         &mut self,
         timestamp: u64,
         propagation_time: Option<u64>,
+        throttle_threshold: u64,
     ) -> Option<&BlockDetails> {
         self.best.block_time = timestamp - self.best.block_timestamp;
         self.best.block_timestamp = timestamp;
         self.best.propagation_time = propagation_time;
 
         if self.throttle < timestamp {
-            if self.best.block_time <= THROTTLE_THRESHOLD {
+            if self.best.block_time <= throttle_threshold {
                 self.throttle = timestamp + THROTTLE_INTERVAL;
             }
 
@@ -170,18 +249,25 @@ impl Node {
         }
     }
 
-    pub fn update_hardware(&mut self, interval: &SystemInterval) -> bool {
-        let mut changed = false;
+    /// Update the hardware stats. If any of them changed, this returns how the upload,
+    /// download and chart stamp series (in that order) each changed.
+    pub fn update_hardware(&mut self, interval: &SystemInterval) -> Option<[MeanListChange; 3]> {
+        let mut changes = [MeanListChange::Unchanged; 3];
 
         if let Some(upload) = interval.bandwidth_upload {
-            changed |= self.hardware.upload.push(upload);
+            changes[0] = self.hardware.upload.push(upload);
         }
         if let Some(download) = interval.bandwidth_download {
-            changed |= self.hardware.download.push(download);
+            changes[1] = self.hardware.download.push(download);
         }
-        self.hardware.chart_stamps.push(time::now() as f64);
+        changes[2] = self.hardware.chart_stamps.push(time::now() as f64);
 
-        changed
+        if changes.iter().any(|c| c.is_changed()) {
+            self.hardware_seq += 1;
+            Some(changes)
+        } else {
+            None
+        }
     }
 
     pub fn update_stats(&mut self, interval: &SystemInterval) -> Option<&NodeStats> {
@@ -207,15 +293,16 @@ impl Node {
         }
     }
 
-    pub fn update_io(&mut self, interval: &SystemInterval) -> Option<&NodeIO> {
-        let mut changed = false;
-
-        if let Some(size) = interval.used_state_cache_size {
-            changed |= self.io.used_state_cache_size.push(size);
-        }
+    /// Update the IO stats, returning how the state cache size series changed if it did.
+    pub fn update_io(&mut self, interval: &SystemInterval) -> Option<MeanListChange> {
+        let change = self
+            .io
+            .used_state_cache_size
+            .push(interval.used_state_cache_size?);
 
-        if changed {
-            Some(&self.io)
+        if change.is_changed() {
+            self.io_seq += 1;
+            Some(change)
         } else {
             None
         }
@@ -242,6 +329,13 @@ impl Node {
         self.stale
     }
 
+    /// Has the node been marked as stale, or not imported a block since `threshold`?
+    /// Nodes which haven't imported any blocks yet aren't considered stale.
+    pub fn is_stale_since(&self, threshold: u64) -> bool {
+        let timestamp = self.best.block_timestamp;
+        self.stale || (timestamp != 0 && timestamp < threshold)
+    }
+
     pub fn set_validator_address(&mut self, addr: Box<str>) -> bool {
         if self.details.validator.as_ref() == Some(&addr) {
             false
@@ -262,10 +356,25 @@ impl Node {
     pub fn is_authority(&self) -> Option<bool> {
         self.is_authority
     }
+
+    /// Is the node a validator, going by either its validator address or whether it
+    /// says that it's an authority?
+    pub fn is_validator(&self) -> bool {
+        self.details.validator.is_some() || self.is_authority == Some(true)
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct UniqueNodeIdentity {
     pub node_name: Arc<str>,
     pub network_id: Arc<str>,
 }
+
+impl UniqueNodeIdentity {
+    pub fn from_details(details: &NodeDetails) -> Self {
+        UniqueNodeIdentity {
+            node_name: details.name.clone().into(),
+            network_id: details.network_id.to_string().into(),
+        }
+    }
+}
@@ -14,17 +14,20 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use super::node::Node;
-use crate::endpoints::{BlockHistory, ChainOverview, NodeList};
+use super::authorities::AuthoritySummary;
+use super::node::{Node, NodeSnapshot, UniqueNodeIdentity};
+use crate::endpoints::{Authorities, BlockHistory, ChainOverview, HardwareCompliance, NodeList};
 use crate::feed_message::{ChainStats, FeedMessageSerializer};
 use crate::find_location;
+use bimap::BiMap;
 use common::node_message::Payload;
 use common::node_types::{Block, BlockHash, NodeDetails, Timestamp};
 use common::{id_type, DenseMap};
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::iter::IntoIterator;
 
-use super::chain::{self, Chain, ChainNodeId};
+use super::chain::{self, Chain, ChainNodeId, ChainOpts, ChainSnapshot};
 
 id_type! {
     /// A globally unique Chain ID.
@@ -55,6 +58,26 @@ pub struct State {
     /// How many nodes from third party chains are allowed to connect
     /// before we prevent connections from them.
     max_third_party_nodes: usize,
+
+    /// Chains which any number of nodes are allowed to connect to.
+    first_party_networks: HashSet<BlockHash>,
+
+    /// Settings for each chain we create.
+    chain_opts: ChainOpts,
+
+    /// History handed to us (eg from another core) to seed chains with
+    /// when they are first created.
+    chain_seeds: HashMap<BlockHash, ChainSnapshot>,
+
+    /// Nodes restored from a snapshot that haven't yet been added again by a shard.
+    pending_nodes: BiMap<NodeId, (BlockHash, UniqueNodeIdentity)>,
+}
+
+/// Everything needed to carry on where we left off after a restart.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StateSnapshot {
+    pub chains: Vec<ChainSnapshot>,
+    pub nodes: Vec<NodeSnapshot>,
 }
 
 /// Adding a node to a chain leads to this result.
@@ -90,6 +113,9 @@ pub struct NodeAddedToChain<'a> {
     pub chain_node_count: usize,
     /// Has the chain label been updated?
     pub has_chain_label_changed: bool,
+    /// Did the node take over an entry restored from a snapshot? If so, feeds will
+    /// have been told about it when it was restored.
+    pub adopted: bool,
 }
 
 /// if removing a node is successful, we get this information back.
@@ -107,15 +133,119 @@ pub struct RemovedNode {
 }
 
 impl State {
-    pub fn new<T: IntoIterator<Item = String>>(denylist: T, max_third_party_nodes: usize) -> State {
+    pub fn new<T, F>(
+        denylist: T,
+        max_third_party_nodes: usize,
+        first_party_networks: F,
+        chain_opts: ChainOpts,
+    ) -> State
+    where
+        T: IntoIterator<Item = String>,
+        F: IntoIterator<Item = BlockHash>,
+    {
         State {
             chains: DenseMap::new(),
             chains_by_genesis_hash: HashMap::new(),
             denylist: denylist.into_iter().collect(),
             max_third_party_nodes,
+            first_party_networks: first_party_networks.into_iter().collect(),
+            chain_opts,
+            chain_seeds: HashMap::new(),
+            pending_nodes: BiMap::new(),
         }
     }
 
+    /// Take a snapshot of every chain and node.
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            chains: self.chain_snapshots(),
+            nodes: self
+                .chains
+                .iter()
+                .flat_map(|(_, chain)| chain.node_snapshots())
+                .collect(),
+        }
+    }
+
+    /// Restore chains and nodes from a snapshot, returning the IDs of the nodes restored.
+    /// Restored nodes are pending until a shard adds them again, at which point they carry
+    /// on with the history we have for them. The history of chains without any nodes is
+    /// kept to seed them with if nodes from them show up.
+    pub fn restore(&mut self, snapshot: StateSnapshot) -> Vec<NodeId> {
+        self.set_chain_seeds(snapshot.chains);
+
+        let mut chain_ids = HashSet::new();
+        let mut node_ids = Vec::new();
+        for node_snapshot in snapshot.nodes {
+            if self.denylist.contains(&*node_snapshot.details.chain) {
+                continue;
+            }
+
+            let genesis_hash = node_snapshot.genesis_hash;
+            let pending_key = (
+                genesis_hash,
+                UniqueNodeIdentity::from_details(&node_snapshot.details),
+            );
+            if self.pending_nodes.contains_right(&pending_key) {
+                continue;
+            }
+
+            let chain_id = self.get_or_create_chain(genesis_hash);
+            chain_ids.insert(chain_id);
+            let chain = self
+                .chains
+                .get_mut(chain_id)
+                .expect("chain was just created");
+            if let chain::AddNodeResult::Added { id, .. } =
+                chain.add_node(Node::from_snapshot(node_snapshot))
+            {
+                self.pending_nodes.insert(NodeId(chain_id, id), pending_key);
+                node_ids.push(NodeId(chain_id, id));
+            }
+        }
+
+        for chain_id in chain_ids {
+            let Some(chain) = self.chains.get_mut(chain_id) else {
+                continue;
+            };
+            if chain.node_count() == 0 {
+                let genesis_hash = chain.genesis_hash();
+                self.chains_by_genesis_hash.remove(&genesis_hash);
+                self.chains.remove(chain_id);
+            } else {
+                chain.regenerate_stats();
+            }
+        }
+
+        node_ids
+    }
+
+    /// Take the IDs of any restored nodes which haven't been added again by a shard yet.
+    /// They are no longer considered pending, and so are expected to be removed.
+    pub fn take_pending_nodes(&mut self) -> Vec<NodeId> {
+        std::mem::take(&mut self.pending_nodes)
+            .into_iter()
+            .map(|(node_id, _)| node_id)
+            .collect()
+    }
+
+    /// Take a snapshot of the history of every chain.
+    pub fn chain_snapshots(&self) -> Vec<ChainSnapshot> {
+        self.chains
+            .iter()
+            .map(|(_, chain)| chain.snapshot())
+            .collect()
+    }
+
+    /// Replace the history that chains are seeded with when they are created. Chains
+    /// that already exist are left alone.
+    pub fn set_chain_seeds<T: IntoIterator<Item = ChainSnapshot>>(&mut self, seeds: T) {
+        self.chain_seeds = seeds
+            .into_iter()
+            .map(|seed| (seed.genesis_hash, seed))
+            .collect();
+    }
+
     pub fn iter_chains(&self) -> impl Iterator<Item = StateChain<'_>> {
         self.chains
             .iter()
@@ -146,48 +276,72 @@ impl State {
         // If we create a chain here, we are expecting that it will allow at
         // least this node to be added, because we don't currently try and clean it up
         // if the add fails.
-        let chain_id = match self.chains_by_genesis_hash.get(&genesis_hash) {
-            Some(id) => *id,
-            None => {
-                let max_nodes = match chain::is_first_party_network(&genesis_hash) {
-                    true => usize::MAX,
-                    false => self.max_third_party_nodes,
-                };
-                let chain_id = self.chains.add(Chain::new(genesis_hash, max_nodes));
-                self.chains_by_genesis_hash.insert(genesis_hash, chain_id);
-                chain_id
-            }
-        };
+        let chain_id = self.get_or_create_chain(genesis_hash);
 
         // Get the chain.
         let chain = self.chains.get_mut(chain_id).expect(
             "should be known to exist after the above (unless chains_by_genesis_hash out of sync)",
         );
 
-        let node = Node::new(node_details);
         let old_chain_label = chain.label().into();
 
-        match chain.add_node(node) {
-            chain::AddNodeResult::Overquota => AddNodeResult::ChainOverQuota,
-            chain::AddNodeResult::Added { id, chain_renamed } => {
-                let chain = &*chain;
-
-                AddNodeResult::NodeAddedToChain(NodeAddedToChain {
-                    id: NodeId(chain_id, id),
-                    node: chain.get_node(id).expect("node added above"),
-                    old_chain_label,
-                    new_chain_label: chain.label(),
-                    chain_node_count: chain.node_count(),
-                    has_chain_label_changed: chain_renamed,
-                })
+        // If this node was restored from a snapshot, it takes over the existing entry
+        // (and history) rather than being added afresh:
+        let pending_key = (
+            genesis_hash,
+            UniqueNodeIdentity::from_details(&node_details),
+        );
+        let (id, chain_renamed, adopted) = match self.pending_nodes.remove_by_right(&pending_key) {
+            Some((NodeId(_, id), _)) => {
+                let chain_renamed = chain
+                    .adopt_node(id, node_details)
+                    .expect("pending nodes are always in their chain");
+                (id, chain_renamed, true)
             }
+            None => match chain.add_node(Node::new(node_details)) {
+                chain::AddNodeResult::Overquota => return AddNodeResult::ChainOverQuota,
+                chain::AddNodeResult::Added { id, chain_renamed } => (id, chain_renamed, false),
+            },
+        };
+
+        let chain = &*chain;
+        AddNodeResult::NodeAddedToChain(NodeAddedToChain {
+            id: NodeId(chain_id, id),
+            node: chain.get_node(id).expect("node added above"),
+            old_chain_label,
+            new_chain_label: chain.label(),
+            chain_node_count: chain.node_count(),
+            has_chain_label_changed: chain_renamed,
+            adopted,
+        })
+    }
+
+    /// Get the ID of the chain with the genesis hash given, creating it if it doesn't exist.
+    fn get_or_create_chain(&mut self, genesis_hash: BlockHash) -> ChainId {
+        if let Some(&chain_id) = self.chains_by_genesis_hash.get(&genesis_hash) {
+            return chain_id;
+        }
+
+        // "First party" chains allow any number of nodes to connect:
+        let max_nodes = match self.first_party_networks.contains(&genesis_hash) {
+            true => usize::MAX,
+            false => self.max_third_party_nodes,
+        };
+        let mut chain = Chain::new(genesis_hash, max_nodes, self.chain_opts.clone());
+        if let Some(seed) = self.chain_seeds.remove(&genesis_hash) {
+            chain.restore(seed);
         }
+        let chain_id = self.chains.add(chain);
+        self.chains_by_genesis_hash.insert(genesis_hash, chain_id);
+        chain_id
     }
 
     /// Remove a node
     pub fn remove_node(&mut self, NodeId(chain_id, chain_node_id): NodeId) -> Option<RemovedNode> {
         let chain = self.chains.get_mut(chain_id)?;
         let old_chain_label = chain.label().into();
+        self.pending_nodes
+            .remove_by_left(&NodeId(chain_id, chain_node_id));
 
         // Actually remove the node
         let remove_result = chain.remove_node(chain_node_id);
@@ -279,9 +433,15 @@ impl<'a> StateChain<'a> {
     pub fn nodes_slice(&self) -> &[Option<Node>] {
         self.chain.nodes_slice()
     }
+    pub fn get_node(&self, id: ChainNodeId) -> Option<&'a Node> {
+        self.chain.get_node(id)
+    }
     pub fn stats(&self) -> &ChainStats {
         self.chain.stats()
     }
+    pub fn authority_summary(&self) -> AuthoritySummary {
+        self.chain.authority_summary()
+    }
     pub fn overview_endpoint(&self) -> ChainOverview {
         self.chain.overview_endpoint()
     }
@@ -291,10 +451,17 @@ impl<'a> StateChain<'a> {
     pub fn node_list_endpoint(&self) -> NodeList {
         self.chain.node_list_endpoint()
     }
+    pub fn hardware_compliance_endpoint(&self) -> Option<HardwareCompliance> {
+        self.chain.hardware_compliance_endpoint()
+    }
+    pub fn authorities_endpoint(&self) -> Authorities {
+        self.chain.authorities_endpoint()
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use super::super::authorities::AuthoritySet;
     use super::*;
     use common::node_types::NetworkId;
 
@@ -315,9 +482,18 @@ mod test {
         }
     }
 
+    fn new_state() -> State {
+        State::new(
+            None,
+            1000,
+            chain::default_first_party_networks(),
+            ChainOpts::default(),
+        )
+    }
+
     #[test]
     fn adding_a_node_returns_expected_response() {
-        let mut state = State::new(None, 1000);
+        let mut state = new_state();
 
         let chain1_genesis = BlockHash::from_low_u64_be(1);
 
@@ -352,7 +528,7 @@ mod test {
 
     #[test]
     fn adding_and_removing_nodes_updates_chain_label_mapping() {
-        let mut state = State::new(None, 1000);
+        let mut state = new_state();
 
         let chain1_genesis = BlockHash::from_low_u64_be(1);
         let node_id0 = state
@@ -412,7 +588,7 @@ mod test {
 
     #[test]
     fn chain_removed_when_last_node_is() {
-        let mut state = State::new(None, 1000);
+        let mut state = new_state();
 
         let chain1_genesis = BlockHash::from_low_u64_be(1);
         let node_id = state
@@ -427,4 +603,142 @@ mod test {
         assert!(state.get_chain_by_genesis_hash(&chain1_genesis).is_none());
         assert_eq!(state.iter_chains().count(), 0);
     }
+
+    #[test]
+    fn new_chains_are_seeded_from_snapshots() {
+        let mut state = new_state();
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let best = Block {
+            hash: BlockHash::from_low_u64_be(100),
+            height: 100,
+        };
+        let finalized = Block {
+            hash: BlockHash::from_low_u64_be(98),
+            height: 98,
+        };
+        state.set_chain_seeds(vec![ChainSnapshot {
+            genesis_hash: chain1_genesis,
+            best,
+            finalized,
+            block_times: vec![5000, 7000],
+            stored_blocks: Default::default(),
+            authority_set: None,
+        }]);
+
+        let node_id = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+
+        let chain = state
+            .get_chain_by_genesis_hash(&chain1_genesis)
+            .expect("Chain should exist");
+        assert_eq!(*chain.best_block(), best);
+        assert_eq!(*chain.finalized_block(), finalized);
+        assert_eq!(chain.average_block_time(), Some(6000));
+        assert_eq!(state.chain_snapshots()[0].block_times, vec![5000, 7000]);
+
+        // Seeds are only used once; a chain that comes back later starts afresh:
+        state.remove_node(node_id);
+        state.add_node(chain1_genesis, node("A", "Chain One"));
+
+        let chain = state
+            .get_chain_by_genesis_hash(&chain1_genesis)
+            .expect("Chain should exist");
+        assert_eq!(chain.best_block().height, 0);
+        assert_eq!(chain.average_block_time(), None);
+    }
+
+    #[test]
+    fn restored_nodes_are_pending_until_added_again() {
+        let mut state = new_state();
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let mut alice = node("Alice", "Chain One");
+        alice.network_id = NetworkId::from("alice").unwrap();
+        let mut bob = node("Bob", "Chain One");
+        bob.network_id = NetworkId::from("bob").unwrap();
+        state.add_node(chain1_genesis, alice.clone());
+        state.add_node(chain1_genesis, bob);
+
+        // Round trip the snapshot through JSON, as it would be when written to disk:
+        let snapshot = serde_json::to_vec(&state.snapshot()).unwrap();
+        let snapshot: StateSnapshot = serde_json::from_slice(&snapshot).unwrap();
+
+        let mut state = new_state();
+        let restored = state.restore(snapshot.clone());
+        assert_eq!(restored.len(), 2);
+        let chain = state
+            .get_chain_by_genesis_hash(&chain1_genesis)
+            .expect("Chain should exist");
+        assert_eq!(chain.node_count(), 2);
+        assert_eq!(chain.label(), "Chain One");
+        for &node_id in &restored {
+            assert!(chain.get_node(node_id.get_chain_node_id()).is_some());
+        }
+
+        // Restoring the same snapshot again doesn't restore the nodes a second time:
+        assert!(state.restore(snapshot).is_empty());
+
+        // Alice is added again, and takes over her restored entry rather than being added afresh:
+        let add_result = state.add_node(chain1_genesis, alice);
+        let alice_id = add_result.unwrap_id();
+        let AddNodeResult::NodeAddedToChain(details) = add_result else {
+            panic!("Node should have been added");
+        };
+        assert_eq!(details.chain_node_count, 2);
+        assert!(!details.has_chain_label_changed);
+        assert!(details.adopted);
+        assert!(restored.contains(&alice_id));
+
+        // Only Bob is still pending, and so he's the only one to be reaped:
+        let pending = state.take_pending_nodes();
+        assert_eq!(pending.len(), 1);
+        assert_ne!(pending[0], alice_id);
+        state.remove_node(pending[0]);
+        assert_eq!(
+            state
+                .get_chain_by_genesis_hash(&chain1_genesis)
+                .expect("Chain should exist")
+                .node_count(),
+            1
+        );
+        assert!(state.take_pending_nodes().is_empty());
+    }
+
+    #[test]
+    fn restored_chains_without_nodes_are_seeded() {
+        let mut state = new_state();
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let best = Block {
+            hash: BlockHash::from_low_u64_be(100),
+            height: 100,
+        };
+        state.restore(StateSnapshot {
+            chains: vec![ChainSnapshot {
+                genesis_hash: chain1_genesis,
+                best,
+                finalized: best,
+                block_times: vec![6000],
+                stored_blocks: Default::default(),
+                authority_set: Some(AuthoritySet {
+                    id: Some(4),
+                    authorities: ["5Alice".into()].into(),
+                }),
+            }],
+            nodes: vec![],
+        });
+        assert_eq!(state.iter_chains().count(), 0);
+
+        state.add_node(chain1_genesis, node("A", "Chain One"));
+        let chain = state
+            .get_chain_by_genesis_hash(&chain1_genesis)
+            .expect("Chain should exist");
+        assert_eq!(*chain.best_block(), best);
+        assert_eq!(chain.average_block_time(), Some(6000));
+        let summary = chain.authority_summary();
+        assert_eq!(summary.set_id, Some(4));
+        assert_eq!(summary.missing, vec!["5Alice".into()]);
+    }
 }
@@ -0,0 +1,44 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Fixtures shared by the tests of the state and aggregator modules.
+
+use super::Node;
+use common::node_types::{NetworkId, NodeDetails};
+
+/// The details of a node on the "Polkadot" chain, which tests can
+/// adjust using struct update syntax.
+pub fn node_details() -> NodeDetails {
+    NodeDetails {
+        chain: "Polkadot".into(),
+        name: "Node".into(),
+        implementation: "Bar".into(),
+        target_arch: Some("x86_64".into()),
+        target_os: Some("linux".into()),
+        target_env: None,
+        version: "0.1".into(),
+        validator: None,
+        network_id: NetworkId::from("12D3KooWEyop").unwrap(),
+        startup_time: None,
+        sysinfo: None,
+        ip: None,
+    }
+}
+
+/// A node with the given details.
+pub fn node(details: NodeDetails) -> Node {
+    Node::new(details)
+}
@@ -38,6 +38,7 @@ cargo test e2e -- --ignored
 
 use common::node_types::BlockHash;
 use common::ws_client::SentMessage;
+use futures::StreamExt;
 use serde_json::json;
 use std::{str::FromStr, time::Duration};
 use test_utils::{
@@ -69,7 +70,7 @@ async fn e2e_feed_sent_version_on_connect() {
     let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
     assert_eq!(
         feed_messages,
-        vec![FeedMessage::Version(32)],
+        vec![FeedMessage::Version(35)],
         "expecting version"
     );
 
@@ -77,6 +78,34 @@ async fn e2e_feed_sent_version_on_connect() {
     server.shutdown().await;
 }
 
+/// Feeds can ask for messages to be encoded as MessagePack rather than JSON.
+#[tokio::test]
+async fn e2e_feed_can_receive_msgpack() {
+    let server = start_server_debug().await;
+
+    let (mut raw_feed_tx, mut raw_feed_rx) = server
+        .get_core()
+        .connect_feed_raw_with_query("format=msgpack")
+        .await
+        .unwrap();
+
+    // The version and the pong are the same messages we'd get as JSON:
+    let mut bytes = Vec::new();
+    raw_feed_rx.receive_data(&mut bytes).await.unwrap();
+    let msg: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+    assert_eq!(msg, json!([0, 35]));
+
+    raw_feed_tx.send_text("ping:hello!").await.unwrap();
+    raw_feed_tx.flush().await.unwrap();
+    let mut bytes = Vec::new();
+    raw_feed_rx.receive_data(&mut bytes).await.unwrap();
+    let msg: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+    assert_eq!(msg, json!([15, "hello!"]));
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
 /// Another very simple test: pings from feeds should be responded to by pongs
 /// with the same message content.
 #[tokio::test]
@@ -127,7 +156,7 @@ async fn e2e_multiple_feeds_sent_version_on_connect() {
     for feed_messages in responses {
         assert_eq!(
             feed_messages.expect("should have messages"),
-            vec![FeedMessage::Version(32)],
+            vec![FeedMessage::Version(35)],
             "expecting version"
         );
     }
@@ -423,92 +452,97 @@ async fn e2e_feeds_told_about_chain_rename_and_stay_subscribed() {
     );
 }
 
-/// If we add a couple of shards and a node for each, all feeds should be
-/// told about both node chains. If one shard goes away, we should get a
-/// "removed chain" message only for the node connected to that shard.
+/// If the core goes away and comes back, shards should tell the new core about the nodes
+/// they have connected without those nodes needing to reconnect, and any updates that were
+/// buffered while the core was away should be passed on too.
 #[tokio::test]
-async fn e2e_feed_add_and_remove_shard() {
-    let mut server = start_server_debug().await;
-
-    let mut shards = vec![];
-    for id in 1..=2 {
-        // Add a shard:
-        let shard_id = server.add_shard().await.unwrap();
+async fn e2e_nodes_replayed_to_core_after_restart() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        ShardOpts {
+            core_buffer_len: Some(10),
+            ..Default::default()
+        },
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
 
-        // Connect a node to it:
-        let (mut node_tx, _node_rx) = server
-            .get_shard(shard_id)
-            .unwrap()
-            .connect_node()
-            .await
-            .expect("can connect to shard");
+    // Connect a node to the shard:
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
 
-        // Send a "system connected" message:
-        node_tx
-            .send_json_text(json!({
-                "id":id,
-                "ts":"2021-07-12T10:37:47.714666+01:00",
-                "payload": {
-                    "authority":true,
-                    "chain": format!("Local Testnet {}", id),
-                    "config":"",
-                    "genesis_hash": ghash(id),
-                    "implementation":"Substrate Node",
-                    "msg":"system.connected",
-                    "name":"Alice",
-                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
-                    "startup_time":"1625565542717",
-                    "version":"2.0.0-07a1af348-aarch64-macos"
-                },
-            }))
-            .unwrap();
+    // Send a "system connected" message:
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
 
-        // Keep what we need to keep connection alive and let us kill a shard:
-        shards.push((shard_id, node_tx));
-    }
+    // Take the core down, and send an update while it's away:
+    server.stop_core().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    node_tx.send_json_text(json!(
+        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":7},"ts":"2021-07-12T10:38:48.330433+01:00" }
+    )).unwrap();
 
-    // Connect a feed.
-    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    // Bring the core back, and give the shard a moment to reconnect to it:
+    server.restart_core().await.unwrap();
+    tokio::time::sleep(Duration::from_secs(2)).await;
 
-    // The feed should be told about both of the chains that we've sent info about:
+    // A new feed should see the node (and the update we sent) without it reconnecting:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
     let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
     assert!(feed_messages.contains(&FeedMessage::AddedChain {
-        name: "Local Testnet 1".to_owned(),
+        name: "Local Testnet".to_owned(),
         genesis_hash: ghash(1),
-        node_count: 1
-    }));
-    assert!(feed_messages.contains(&FeedMessage::AddedChain {
-        name: "Local Testnet 2".to_owned(),
-        genesis_hash: ghash(2),
-        node_count: 1
+        node_count: 1,
     }));
 
-    // Disconnect the first shard:
-    server.kill_shard(shards[0].0).await;
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        FeedMessage::AddedNode { node: NodeDetails { name, .. }, stats, .. } if name == "Alice" && stats.peers == 7,
+    );
 
-    // We should be told about the node connected to that shard disconnecting:
+    // The node is still connected, and the new core hears about it going away:
+    node_tx.close().await.unwrap();
     let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
     assert!(feed_messages.contains(&FeedMessage::RemovedChain {
         genesis_hash: ghash(1),
     }));
-    assert!(!feed_messages.contains(
-        // Spot the "!"; this chain was not removed.
-        &FeedMessage::RemovedChain {
-            genesis_hash: ghash(2),
-        }
-    ));
 
     // Tidy up:
     server.shutdown().await;
 }
 
-/// feeds can subscribe to one chain at a time. They should get the relevant
-/// messages for that chain and no other.
+/// Once a feed is subscribed, it's only sent the hardware and IO samples that change.
 #[tokio::test]
-async fn e2e_feed_can_subscribe_and_unsubscribe_from_chain() {
-    use FeedMessage::*;
-
-    // Start server, add shard, connect node:
+async fn e2e_feed_sent_hardware_and_io_deltas() {
     let mut server = start_server_debug().await;
     let shard_id = server.add_shard().await.unwrap();
     let (mut node_tx, _node_rx) = server
@@ -517,101 +551,1642 @@ async fn e2e_feed_can_subscribe_and_unsubscribe_from_chain() {
         .connect_node()
         .await
         .unwrap();
-
-    // Send a "system connected" message for a few nodes/chains:
-    for id in 1..=3 {
-        node_tx
-            .send_json_text(json!(
-                {
-                    "id":id,
-                    "ts":"2021-07-12T10:37:47.714666+01:00",
-                    "payload": {
-                        "authority":true,
-                        "chain":format!("Local Testnet {}", id),
-                        "config":"",
-                        "genesis_hash": ghash(id),
-                        "implementation":"Substrate Node",
-                        "msg":"system.connected",
-                        "name":format!("Alice {}", id),
-                        "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
-                        "startup_time":"1625565542717",
-                        "version":"2.0.0-07a1af348-aarch64-macos"
-                    },
-                }
-            ))
-            .unwrap();
-    }
-
-    // Connect a feed
     let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
 
-    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
-    assert_contains_matches!(feed_messages, AddedChain { name, genesis_hash, node_count: 1 } if name == "Local Testnet 1" && genesis_hash == ghash(1));
-
-    // Subscribe it to a chain
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
     feed_tx
         .send_command(
             "subscribe",
             "0x0000000000000000000000000000000000000000000000000000000000000001",
         )
         .unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    for (seq, bandwidth) in [(1, 100.0), (2, 200.0)] {
+        node_tx.send_json_text(json!(
+            {"id":1, "payload":{ "bandwidth_download":bandwidth,"bandwidth_upload":bandwidth,"msg":"system.interval","used_state_cache_size":bandwidth},"ts":"2021-07-12T10:38:48.330433+01:00" }
+        )).unwrap();
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        assert!(feed_messages.contains(&FeedMessage::HardwareDelta {
+            node_id: 0,
+            seq,
+            upload: (0, vec![bandwidth]),
+            download: (0, vec![bandwidth]),
+            first_seq: seq,
+        }));
+        assert!(feed_messages.contains(&FeedMessage::NodeIODelta {
+            node_id: 0,
+            seq,
+            used_state_cache_size: (0, vec![bandwidth]),
+            first_seq: seq,
+        }));
+    }
 
+    // A feed that subscribes now is told which updates the node is up to:
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
     let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
     assert_contains_matches!(
         feed_messages,
-        SubscribedTo { genesis_hash } if genesis_hash == ghash(1),
-        TimeSync {..},
-        BestBlock { block_number: 0, timestamp: 0, avg_block_time: None },
-        BestFinalized { block_number: 0, .. },
-        AddedNode { node_id: 0, node: NodeDetails { name, .. }, .. } if name == "Alice 1",
-        FinalizedBlock { node_id: 0, block_number: 0, .. }
+        FeedMessage::AddedNode {
+            hardware_seq: 2,
+            io_seq: 2,
+            ..
+        },
     );
 
-    // We receive updates relating to nodes on that chain:
-    node_tx.send_json_text(json!(
-        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:37:48.330433+01:00" }
-    )).unwrap();
-
-    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
-    assert_ne!(feed_messages.len(), 0);
-
-    // We don't receive anything for updates to nodes on other chains (wait a sec to ensure no messages are sent):
-    node_tx.send_json_text(json!(
-        {"id":2, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:37:48.330433+01:00" }
-    )).unwrap();
-
-    tokio::time::timeout(Duration::from_secs(1), feed_rx.recv_feed_messages())
-        .await
-        .expect_err("Timeout should elapse since no messages sent");
-
-    // We can change our subscription:
+    // A feed which missed some updates can ask for the whole series again:
     feed_tx
         .send_command(
-            "subscribe",
-            "0x0000000000000000000000000000000000000000000000000000000000000002",
+            "series",
+            "0x0000000000000000000000000000000000000000000000000000000000000001:0",
         )
         .unwrap();
     let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::HardwareDelta {
+        node_id: 0,
+        seq: 2,
+        upload: (20, vec![100.0, 200.0]),
+        download: (20, vec![100.0, 200.0]),
+        first_seq: 2,
+    }));
+    assert!(feed_messages.contains(&FeedMessage::NodeIODelta {
+        node_id: 0,
+        seq: 2,
+        used_state_cache_size: (20, vec![100.0, 200.0]),
+        first_seq: 2,
+    }));
 
-    // We are told about the subscription change and given similar on-subscribe messages to above.
-    assert_contains_matches!(
-        &feed_messages,
-        UnsubscribedFrom { genesis_hash } if *genesis_hash == ghash(1),
-        SubscribedTo { genesis_hash } if *genesis_hash == ghash(2),
-        TimeSync {..},
-        BestBlock {..},
-        BestFinalized {..},
-        AddedNode { node: NodeDetails { name, .. }, ..} if name == "Alice 2",
-        FinalizedBlock {..},
-    );
+    // Tidy up:
+    server.shutdown().await;
+}
 
-    // We didn't get messages from this earlier, but we will now we've subscribed:
-    node_tx.send_json_text(json!(
-        {"id":2, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:38:48.330433+01:00" }
-    )).unwrap();
+/// A core restored from a snapshot carries on with the chains and nodes it knew about, keeping
+/// restored nodes until their shard adds them again, and removing them if it doesn't.
+#[tokio::test]
+async fn e2e_core_state_restored_from_snapshot() {
+    let snapshot_path = std::env::temp_dir().join(format!(
+        "telemetry_core_e2e_snapshot_{}.json",
+        std::process::id()
+    ));
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            snapshot_path: Some(snapshot_path.clone()),
+            snapshot_interval: Some(1),
+            restore_from: Some(snapshot_path.clone()),
+            restore_timeout: Some(3),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
 
-    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
-    assert_ne!(feed_messages.len(), 0);
+    // Connect a node to the shard and import a block:
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:48.714666+01:00",
+            "payload": {
+                "best": ghash(123),
+                "height": 123,
+                "msg":"block.import",
+                "origin":"Own"
+            },
+        }))
+        .unwrap();
+
+    // Give the core time to write a snapshot, then take it down. The node goes
+    // away while the core is down, so the shard won't add it again:
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    server.stop_core().await.unwrap();
+    node_tx.close().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // The restarted core still knows about the chain and node:
+    server.restart_core().await.unwrap();
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::AddedChain {
+        name: "Local Testnet".to_owned(),
+        genesis_hash: ghash(1),
+        node_count: 1,
+    }));
+
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.iter().any(|m| matches!(
+        m,
+        FeedMessage::BestBlock {
+            block_number: 123,
+            ..
+        }
+    )));
+    assert_contains_matches!(
+        feed_messages,
+        FeedMessage::AddedNode { node: NodeDetails { name, .. }, .. } if name == "Alice",
+    );
+
+    // Nobody adds the node again, so it's removed after the restore timeout:
+    let feed_messages = tokio::time::timeout(Duration::from_secs(10), feed_rx.recv_feed_messages())
+        .await
+        .expect("node should be removed before the timeout")
+        .unwrap();
+    assert!(feed_messages.contains(&FeedMessage::RemovedChain {
+        genesis_hash: ghash(1),
+    }));
+
+    // Tidy up:
+    server.shutdown().await;
+    let _ = std::fs::remove_file(&snapshot_path);
+}
+
+/// When a shard adds a restored node again, feeds which already know about the node from
+/// its restoration aren't told about it a second time.
+#[tokio::test]
+async fn e2e_restored_node_is_announced_once() {
+    let snapshot_path = std::env::temp_dir().join(format!(
+        "telemetry_core_e2e_announce_snapshot_{}.json",
+        std::process::id()
+    ));
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            snapshot_path: Some(snapshot_path.clone()),
+            snapshot_interval: Some(1),
+            restore_from: Some(snapshot_path.clone()),
+            restore_timeout: Some(30),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+
+    // Give the core time to write a snapshot, then restart it. The node stays connected
+    // to the shard, which adds it again once it reconnects to the core:
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    server.stop_core().await.unwrap();
+    server.restart_core().await.unwrap();
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+
+    // Once the shard has reconnected, the node carries on in its restored entry:
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:48.714666+01:00",
+            "payload": {
+                "best": ghash(123),
+                "height": 123,
+                "msg":"block.import",
+                "origin":"Own"
+            },
+        }))
+        .unwrap();
+
+    let mut feed_messages = Vec::new();
+    loop {
+        let mut msgs = feed_rx
+            .recv_feed_messages_timeout(Duration::from_secs(2))
+            .await
+            .unwrap();
+        if msgs.is_empty() {
+            break;
+        }
+        feed_messages.append(&mut msgs);
+    }
+    assert!(feed_messages.iter().any(|m| matches!(
+        m,
+        FeedMessage::BestBlock {
+            block_number: 123,
+            ..
+        }
+    )));
+    let added = feed_messages
+        .iter()
+        .filter(|m| {
+            matches!(
+                m,
+                FeedMessage::AddedNode { node: NodeDetails { name, .. }, .. } if name == "Alice"
+            )
+        })
+        .count();
+    assert_eq!(added, 1);
+
+    // Tidy up:
+    server.shutdown().await;
+    let _ = std::fs::remove_file(&snapshot_path);
+}
+
+/// A standby core keeps hold of the history of each chain on the core it's standing by for,
+/// so that when shards fail over to it, chains carry on where they left off.
+#[tokio::test]
+async fn e2e_standby_core_takes_over_chain_history() {
+    let mut server = start_server_debug().await;
+    let standby_server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            standby_of: Some(server.get_core().host().to_owned()),
+            standby_interval: Some(1),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+
+    let standby_host = standby_server.get_core().host().to_owned();
+    let shard_id = server
+        .add_shard_with_standby_cores(&[&standby_host])
+        .await
+        .unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+
+    // Add a node and have it import a couple of blocks:
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+    for height in [10, 11] {
+        node_tx
+            .send_json_text(json!({
+                "id":1,
+                "ts":"2021-07-12T10:37:48.714666+01:00",
+                "payload": {
+                    "best": ghash(height),
+                    "height": height,
+                    "msg":"block.import",
+                    "origin":"Own"
+                },
+            }))
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+
+    // Let the standby fetch the chain history, then take the primary core away:
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    server.stop_core().await.unwrap();
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    // The standby now knows about the node, and the chain's history:
+    let (feed_tx, mut feed_rx) = standby_server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::AddedChain {
+        name: "Local Testnet".to_owned(),
+        genesis_hash: ghash(1),
+        node_count: 1,
+    }));
+
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        FeedMessage::BestBlock { block_number: 11, avg_block_time, .. } if avg_block_time.is_some(),
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+    standby_server.shutdown().await;
+}
+
+/// A shard can forward node messages to more than one core. Each core decides which nodes
+/// to mute on its own, so a node muted by one core should still be heard by the other.
+#[tokio::test]
+async fn e2e_shard_forwards_to_multiple_cores() {
+    // A second core which doesn't allow our node's chain:
+    let denying_server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            denylist: Some(vec!["Local Testnet".to_owned()]),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        ShardOpts {
+            extra_core_hosts: vec![denying_server.get_core().host().to_owned()],
+            ..Default::default()
+        },
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    // Give the shard a moment to connect to both cores:
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // The core that denies the chain doesn't know about it:
+    let (_denied_feed_tx, mut denied_feed_rx) =
+        denying_server.get_core().connect_feed().await.unwrap();
+    let feed_messages = denied_feed_rx.recv_feed_messages().await.unwrap();
+    assert!(!feed_messages
+        .iter()
+        .any(|m| matches!(m, FeedMessage::AddedChain { .. })));
+
+    // The other core does, and keeps hearing updates from the node:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::AddedChain {
+        name: "Local Testnet".to_owned(),
+        genesis_hash: ghash(1),
+        node_count: 1,
+    }));
+
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    node_tx.send_json_text(json!(
+        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":7},"ts":"2021-07-12T10:38:48.330433+01:00" }
+    )).unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        FeedMessage::NodeStatsUpdate { stats, .. } if stats.peers == 7,
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+    denying_server.shutdown().await;
+}
+
+/// If we add a couple of shards and a node for each, all feeds should be
+/// told about both node chains. If one shard goes away, we should get a
+/// "removed chain" message only for the node connected to that shard.
+#[tokio::test]
+async fn e2e_feed_add_and_remove_shard() {
+    let mut server = start_server_debug().await;
+
+    let mut shards = vec![];
+    for id in 1..=2 {
+        // Add a shard:
+        let shard_id = server.add_shard().await.unwrap();
+
+        // Connect a node to it:
+        let (mut node_tx, _node_rx) = server
+            .get_shard(shard_id)
+            .unwrap()
+            .connect_node()
+            .await
+            .expect("can connect to shard");
+
+        // Send a "system connected" message:
+        node_tx
+            .send_json_text(json!({
+                "id":id,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain": format!("Local Testnet {}", id),
+                    "config":"",
+                    "genesis_hash": ghash(id),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }))
+            .unwrap();
+
+        // Keep what we need to keep connection alive and let us kill a shard:
+        shards.push((shard_id, node_tx));
+    }
+
+    // Connect a feed.
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+
+    // The feed should be told about both of the chains that we've sent info about:
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::AddedChain {
+        name: "Local Testnet 1".to_owned(),
+        genesis_hash: ghash(1),
+        node_count: 1
+    }));
+    assert!(feed_messages.contains(&FeedMessage::AddedChain {
+        name: "Local Testnet 2".to_owned(),
+        genesis_hash: ghash(2),
+        node_count: 1
+    }));
+
+    // Disconnect the first shard:
+    server.kill_shard(shards[0].0).await;
+
+    // We should be told about the node connected to that shard disconnecting:
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::RemovedChain {
+        genesis_hash: ghash(1),
+    }));
+    assert!(!feed_messages.contains(
+        // Spot the "!"; this chain was not removed.
+        &FeedMessage::RemovedChain {
+            genesis_hash: ghash(2),
+        }
+    ));
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// A shard that's asked to shut down closes the connections from its nodes and removes
+/// them from the core before exiting.
+#[tokio::test]
+async fn e2e_shard_shuts_down_gracefully() {
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+
+    // Connect a node to the shard:
+    let (mut node_tx, mut node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+    node_tx
+        .send_json_text(json!({
+            "id":1,
+            "ts":"2021-07-12T10:37:47.714666+01:00",
+            "payload": {
+                "authority":true,
+                "chain":"Local Testnet",
+                "config":"",
+                "genesis_hash": ghash(1),
+                "implementation":"Substrate Node",
+                "msg":"system.connected",
+                "name":"Alice",
+                "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                "startup_time":"1625565542717",
+                "version":"2.0.0-07a1af348-aarch64-macos"
+            },
+        }))
+        .unwrap();
+
+    // Connect a feed and wait for it to hear about the node:
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::AddedChain {
+        name: "Local Testnet".to_owned(),
+        genesis_hash: ghash(1),
+        node_count: 1
+    }));
+
+    // Ask the shard to shut down; it should exit cleanly rather than being killed:
+    let status = server.terminate_shard(shard_id).await.unwrap();
+    assert!(status.success(), "shard exited with {status}");
+
+    // The node connection was closed by the shard:
+    let node_msg = tokio::time::timeout(Duration::from_secs(1), node_rx.next())
+        .await
+        .expect("node connection should be closed");
+    assert!(node_msg.is_none());
+
+    // And the core was told to remove the node:
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::RemovedChain {
+        genesis_hash: ghash(1),
+    }));
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// feeds can subscribe to and unsubscribe from chains. They should get the relevant
+/// messages for the chains they're subscribed to and no others.
+#[tokio::test]
+async fn e2e_feed_can_subscribe_and_unsubscribe_from_chain() {
+    use FeedMessage::*;
+
+    // Start server, add shard, connect node:
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    // Send a "system connected" message for a few nodes/chains:
+    for id in 1..=3 {
+        node_tx
+            .send_json_text(json!(
+                {
+                    "id":id,
+                    "ts":"2021-07-12T10:37:47.714666+01:00",
+                    "payload": {
+                        "authority":true,
+                        "chain":format!("Local Testnet {}", id),
+                        "config":"",
+                        "genesis_hash": ghash(id),
+                        "implementation":"Substrate Node",
+                        "msg":"system.connected",
+                        "name":format!("Alice {}", id),
+                        "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                        "startup_time":"1625565542717",
+                        "version":"2.0.0-07a1af348-aarch64-macos"
+                    },
+                }
+            ))
+            .unwrap();
+    }
+
+    // Connect a feed
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(feed_messages, AddedChain { name, genesis_hash, node_count: 1 } if name == "Local Testnet 1" && genesis_hash == ghash(1));
+
+    // Subscribe it to a chain
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        SubscribedTo { genesis_hash } if genesis_hash == ghash(1),
+        TimeSync {..},
+        BestBlock { block_number: 0, timestamp: 0, avg_block_time: None },
+        BestFinalized { block_number: 0, .. },
+        AddedNode { node_id: 0, node: NodeDetails { name, .. }, .. } if name == "Alice 1",
+        FinalizedBlock { node_id: 0, block_number: 0, .. }
+    );
+
+    // We receive updates relating to nodes on that chain:
+    node_tx.send_json_text(json!(
+        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:37:48.330433+01:00" }
+    )).unwrap();
+
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_ne!(feed_messages.len(), 0);
+
+    // We don't receive anything for updates to nodes on other chains (wait a sec to ensure no messages are sent):
+    node_tx.send_json_text(json!(
+        {"id":2, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:37:48.330433+01:00" }
+    )).unwrap();
+
+    tokio::time::timeout(Duration::from_secs(1), feed_rx.recv_feed_messages())
+        .await
+        .expect_err("Timeout should elapse since no messages sent");
+
+    // We can change our subscription:
+    feed_tx
+        .send_command(
+            "unsubscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000001",
+        )
+        .unwrap();
+    feed_tx
+        .send_command(
+            "subscribe",
+            "0x0000000000000000000000000000000000000000000000000000000000000002",
+        )
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+
+    // We are told about the subscription change and given similar on-subscribe messages to above.
+    assert_contains_matches!(
+        &feed_messages,
+        UnsubscribedFrom { genesis_hash } if *genesis_hash == ghash(1),
+        SubscribedTo { genesis_hash } if *genesis_hash == ghash(2),
+        TimeSync {..},
+        BestBlock {..},
+        BestFinalized {..},
+        AddedNode { node: NodeDetails { name, .. }, ..} if name == "Alice 2",
+        FinalizedBlock {..},
+    );
+
+    // We didn't get messages from this earlier, but we will now we've subscribed:
+    node_tx.send_json_text(json!(
+        {"id":2, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":1},"ts":"2021-07-12T10:38:48.330433+01:00" }
+    )).unwrap();
+
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_ne!(feed_messages.len(), 0);
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds split across several workers are each sent what's broadcast about the chains
+/// that they're subscribed to.
+#[tokio::test]
+async fn e2e_feeds_across_workers_are_sent_chain_updates() {
+    use FeedMessage::*;
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            feed_workers: Some(3),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": ghash(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }
+        ))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // Connect enough feeds that every worker has some, and subscribe them all to the chain:
+    let mut feeds = server.get_core().connect_multiple_feeds(6).await.unwrap();
+    for (feed_tx, feed_rx) in &mut feeds {
+        feed_rx.recv_feed_messages().await.unwrap();
+        feed_tx
+            .send_command(
+                "subscribe",
+                "0x0000000000000000000000000000000000000000000000000000000000000001",
+            )
+            .unwrap();
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        assert_contains_matches!(
+            feed_messages,
+            AddedNode { node_id: 0, node: NodeDetails { name, .. }, .. } if name == "Alice"
+        );
+    }
+
+    // An update about the node reaches every feed:
+    node_tx.send_json_text(json!(
+        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":7},"ts":"2021-07-12T10:37:48.330433+01:00" }
+    )).unwrap();
+    for (_, feed_rx) in &mut feeds {
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        assert_contains_matches!(
+            feed_messages,
+            NodeStatsUpdate { node_id: 0, stats } if stats.peers == 7
+        );
+    }
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Chains can be partitioned across several aggregator loops. Feeds hear about the chains
+/// of every loop, and can subscribe to each of them.
+#[tokio::test]
+async fn e2e_feeds_hear_about_chains_across_partitions() {
+    use FeedMessage::*;
+
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            chain_partitions: Some(3),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    // Add a node to each of a few chains, which end up in different partitions:
+    for id in 1..=3 {
+        node_tx
+            .send_json_text(json!(
+                {
+                    "id":id,
+                    "ts":"2021-07-12T10:37:47.714666+01:00",
+                    "payload": {
+                        "authority":true,
+                        "chain":format!("Local Testnet {}", id),
+                        "config":"",
+                        "genesis_hash": ghash(id),
+                        "implementation":"Substrate Node",
+                        "msg":"system.connected",
+                        "name":format!("Alice {}", id),
+                        "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                        "startup_time":"1625565542717",
+                        "version":"2.0.0-07a1af348-aarch64-macos"
+                    },
+                }
+            ))
+            .unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // A new feed is told the version first, and then about every chain:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_eq!(feed_messages[0], Version(35));
+    for id in 1..=3 {
+        assert!(feed_messages.iter().any(|msg| matches!(
+            msg,
+            AddedChain { genesis_hash, node_count: 1, .. } if *genesis_hash == ghash(id)
+        )));
+    }
+
+    // The feed can subscribe to each chain, and hears about updates to its nodes:
+    for id in 1..=3 {
+        feed_tx
+            .send_command("subscribe", format!("{:#x}", ghash(id)).as_str())
+            .unwrap();
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        assert_contains_matches!(
+            feed_messages,
+            SubscribedTo { genesis_hash } if genesis_hash == ghash(id),
+            AddedNode { node_id: 0, node: NodeDetails { name, .. }, .. } if name == format!("Alice {}", id)
+        );
+
+        node_tx.send_json_text(json!(
+            {"id":id, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":id},"ts":"2021-07-12T10:37:48.330433+01:00" }
+        )).unwrap();
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        assert_contains_matches!(
+            feed_messages,
+            NodeStatsUpdate { node_id: 0, stats } if stats.peers == id
+        );
+    }
+
+    // Every chain goes away once the node disconnects:
+    node_tx.close().await.unwrap();
+    let mut removed = Vec::new();
+    while removed.len() < 3 {
+        for msg in feed_rx.recv_feed_messages().await.unwrap() {
+            if let RemovedChain { genesis_hash } = msg {
+                removed.push(genesis_hash);
+            }
+        }
+    }
+    removed.sort();
+    assert_eq!(removed, vec![ghash(1), ghash(2), ghash(3)]);
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// A feed can subscribe to several chains at once, and messages about each chain are
+/// preceded by the genesis hash of the chain they're about.
+#[tokio::test]
+async fn e2e_feed_can_subscribe_to_several_chains() {
+    use FeedMessage::*;
+
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    for id in 1..=2 {
+        node_tx
+            .send_json_text(json!(
+                {
+                    "id":id,
+                    "ts":"2021-07-12T10:37:47.714666+01:00",
+                    "payload": {
+                        "authority":true,
+                        "chain":format!("Local Testnet {}", id),
+                        "config":"",
+                        "genesis_hash": ghash(id),
+                        "implementation":"Substrate Node",
+                        "msg":"system.connected",
+                        "name":format!("Alice {}", id),
+                        "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                        "startup_time":"1625565542717",
+                        "version":"2.0.0-07a1af348-aarch64-macos"
+                    },
+                }
+            ))
+            .unwrap();
+    }
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    // Subscribe to both chains:
+    for id in 1..=2 {
+        feed_tx
+            .send_command("subscribe", &format!("{:#x}", ghash(id)))
+            .unwrap();
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        assert_contains_matches!(
+            &feed_messages,
+            ChainContext { genesis_hash } if *genesis_hash == ghash(id),
+            SubscribedTo { genesis_hash } if *genesis_hash == ghash(id),
+            AddedNode { node: NodeDetails { name, .. }, ..} if *name == format!("Alice {}", id),
+        );
+        assert!(!feed_messages
+            .iter()
+            .any(|m| matches!(m, UnsubscribedFrom { .. })));
+    }
+
+    // We hear about updates to nodes on either chain, tagged with the chain:
+    for id in 1..=2 {
+        node_tx.send_json_text(json!(
+            {"id":id, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":5},"ts":"2021-07-12T10:37:48.330433+01:00" }
+        )).unwrap();
+        let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+        assert_contains_matches!(
+            &feed_messages,
+            ChainContext { genesis_hash } if *genesis_hash == ghash(id),
+            NodeStatsUpdate { node_id: 0, .. },
+        );
+    }
+
+    // Unsubscribing from one chain leaves us subscribed to the other:
+    feed_tx
+        .send_command("unsubscribe", &format!("{:#x}", ghash(1)))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_eq!(
+        feed_messages,
+        vec![UnsubscribedFrom {
+            genesis_hash: ghash(1)
+        }]
+    );
+
+    node_tx.send_json_text(json!(
+        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":6},"ts":"2021-07-12T10:37:49.330433+01:00" }
+    )).unwrap();
+    tokio::time::timeout(Duration::from_secs(1), feed_rx.recv_feed_messages())
+        .await
+        .expect_err("Timeout should elapse since no messages sent");
+
+    node_tx.send_json_text(json!(
+        {"id":2, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":6},"ts":"2021-07-12T10:37:49.330433+01:00" }
+    )).unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        ChainContext { genesis_hash } if *genesis_hash == ghash(2),
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds can ask to only hear about the nodes on a chain that match some filter.
+#[tokio::test]
+async fn e2e_feed_can_filter_nodes_on_a_chain() {
+    use FeedMessage::*;
+
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    for (id, name) in [(1, "ops-alice"), (2, "bob")] {
+        node_tx
+            .send_json_text(json!(
+                {
+                    "id":id,
+                    "ts":"2021-07-12T10:37:47.714666+01:00",
+                    "payload": {
+                        "authority":true,
+                        "chain":"Local Testnet",
+                        "config":"",
+                        "genesis_hash": ghash(1),
+                        "implementation":"Substrate Node",
+                        "msg":"system.connected",
+                        "name":name,
+                        "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                        "startup_time":"1625565542717",
+                        "version":"2.0.0-07a1af348-aarch64-macos"
+                    },
+                }
+            ))
+            .unwrap();
+    }
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+
+    // Only the matching node is sent on subscribing:
+    let filter = json!({ "chain": format!("{:#x}", ghash(1)), "namePrefix": "ops-" });
+    feed_tx.send_command("filter", &filter.to_string()).unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        SubscribedTo { genesis_hash } if *genesis_hash == ghash(1),
+        AddedNode { node_id: 0, node: NodeDetails { name, .. }, ..} if &**name == "ops-alice",
+    );
+    assert!(!feed_messages
+        .iter()
+        .any(|m| matches!(m, AddedNode { node_id: 1, .. })));
+
+    // Updates about the node that doesn't match aren't sent either:
+    node_tx.send_json_text(json!(
+        {"id":2, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":5},"ts":"2021-07-12T10:37:48.330433+01:00" }
+    )).unwrap();
+    tokio::time::timeout(Duration::from_secs(1), feed_rx.recv_feed_messages())
+        .await
+        .expect_err("Timeout should elapse since no messages sent");
+
+    node_tx.send_json_text(json!(
+        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":5},"ts":"2021-07-12T10:37:48.330433+01:00" }
+    )).unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(&feed_messages, NodeStatsUpdate { node_id: 0, .. });
+
+    // Subscribing again without a filter sends every node:
+    feed_tx
+        .send_command("subscribe", &format!("{:#x}", ghash(1)))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        AddedNode { node_id: 0, .. },
+        AddedNode { node_id: 1, .. },
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds are told which authorities in the set that nodes report have no node reporting
+/// as them, and how many validators and full nodes run each version.
+#[tokio::test]
+async fn e2e_feed_told_about_authorities_without_a_node() {
+    use FeedMessage::*;
+
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    for (id, name) in [(1, "alice"), (2, "bob")] {
+        node_tx
+            .send_json_text(json!(
+                {
+                    "id":id,
+                    "ts":"2021-07-12T10:37:47.714666+01:00",
+                    "payload": {
+                        "authority":true,
+                        "chain":"Local Testnet",
+                        "config":"",
+                        "genesis_hash": ghash(1),
+                        "implementation":"Substrate Node",
+                        "msg":"system.connected",
+                        "name":name,
+                        "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                        "startup_time":"1625565542717",
+                        "version":"2.0.0-07a1af348-aarch64-macos"
+                    },
+                }
+            ))
+            .unwrap();
+    }
+
+    // Alice is one of the two authorities in the set; bob is a full node:
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:48.714666+01:00",
+                "payload": {
+                    "msg":"afg.authority_set",
+                    "authority_id":"5Alice",
+                    "authority_set_id":"2",
+                    "authorities":"[\"5Alice\",\"5Charlie\"]"
+                },
+            }
+        ))
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    feed_tx
+        .send_command("subscribe", &format!("{:#x}", ghash(1)))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        AuthoritiesUpdate {
+            set_id: Some(2),
+            set_size: Some(2),
+            reporting: 1,
+            stale,
+            stopped,
+            missing,
+            versions,
+        } if stale.is_empty()
+            && stopped.is_empty()
+            && missing == &["5Charlie"]
+            && versions == &[("2.0.0-07a1af348".to_owned(), 1, 1)],
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Commands sent in the JSON form are acknowledged, or rejected with a reason.
+#[tokio::test]
+async fn e2e_feed_json_commands_are_answered() {
+    use FeedMessage::*;
+
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    for (id, name) in [(1, "ops-alice"), (2, "bob")] {
+        node_tx
+            .send_json_text(json!(
+                {
+                    "id":id,
+                    "ts":"2021-07-12T10:37:47.714666+01:00",
+                    "payload": {
+                        "authority":true,
+                        "chain":"Local Testnet",
+                        "config":"",
+                        "genesis_hash": ghash(1),
+                        "implementation":"Substrate Node",
+                        "msg":"system.connected",
+                        "name":name,
+                        "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                        "startup_time":"1625565542717",
+                        "version":"2.0.0-07a1af348-aarch64-macos"
+                    },
+                }
+            ))
+            .unwrap();
+    }
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    let chain = format!("{:#x}", ghash(1));
+
+    // Subscribing to a chain that doesn't exist fails, as do commands we don't understand:
+    feed_tx
+        .send_json_command(
+            json!({ "id": 1, "cmd": "subscribe", "chain": format!("{:#x}", ghash(2)) }),
+        )
+        .unwrap();
+    feed_tx
+        .send_json_command(json!({ "id": 2, "cmd": "launch" }))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        CommandError { request_id: 1, reason } if reason.starts_with("Unknown chain"),
+        CommandError { request_id: 2, .. },
+    );
+
+    // Subscribing to a chain with a filter is acknowledged after the chain state is sent:
+    feed_tx
+        .send_json_command(
+            json!({ "id": 3, "cmd": "filter", "chain": chain, "namePrefix": "ops-" }),
+        )
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        SubscribedTo { .. },
+        AddedNode { node_id: 0, .. },
+        CommandAck { request_id: 3 },
+    );
+    assert!(!feed_messages
+        .iter()
+        .any(|m| matches!(m, AddedNode { node_id: 1, .. })));
+
+    // Pinning a node that the filter leaves out sends it and its updates:
+    feed_tx
+        .send_json_command(json!({ "id": 4, "cmd": "pin", "chain": chain, "node": 1 }))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        AddedNode { node_id: 1, .. },
+        CommandAck { request_id: 4 },
+    );
+    node_tx.send_json_text(json!(
+        {"id":2, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":5},"ts":"2021-07-12T10:37:48.330433+01:00" }
+    )).unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(&feed_messages, NodeStatsUpdate { node_id: 1, .. });
+
+    // ... and unpinning it removes it again:
+    feed_tx
+        .send_json_command(json!({ "id": 5, "cmd": "unpin", "chain": chain, "node": 1 }))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        RemovedNode { node_id: 1 },
+        CommandAck { request_id: 5 },
+    );
+
+    // A snapshot sends the chain state again without subscribing:
+    feed_tx
+        .send_json_command(json!({ "id": 6, "cmd": "snapshot", "chain": chain }))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        BestBlock { .. },
+        AddedNode { node_id: 0, .. },
+        CommandAck { request_id: 6 },
+    );
+    assert!(!feed_messages
+        .iter()
+        .any(|m| matches!(m, SubscribedTo { .. })));
+
+    // The update rate can be lowered, within limits:
+    feed_tx
+        .send_json_command(json!({ "id": 7, "cmd": "setUpdateRate", "intervalMs": 3_600_000 }))
+        .unwrap();
+    feed_tx
+        .send_json_command(json!({ "id": 8, "cmd": "setUpdateRate", "intervalMs": 500 }))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        CommandError { request_id: 7, .. },
+        CommandAck { request_id: 8 },
+    );
+
+    // Unsubscribing from a chain that we aren't subscribed to fails:
+    for id in [9, 10] {
+        feed_tx
+            .send_json_command(json!({ "id": id, "cmd": "unsubscribe", "chain": chain }))
+            .unwrap();
+    }
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        UnsubscribedFrom { .. },
+        CommandAck { request_id: 9 },
+        CommandError { request_id: 10, .. },
+    );
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds which ask for a slower update rate are sent one merged update per interval.
+#[tokio::test]
+async fn e2e_feed_updates_can_be_digested() {
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": ghash(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }
+        ))
+        .unwrap();
+
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    feed_tx
+        .send_json_command(
+            json!({ "id": 1, "cmd": "subscribe", "chain": format!("{:#x}", ghash(1)) }),
+        )
+        .unwrap();
+    feed_tx
+        .send_json_command(json!({ "id": 2, "cmd": "setUpdateRate", "rate": "5s" }))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(&feed_messages, FeedMessage::CommandAck { request_id: 2 });
+
+    // Send a few stats updates in quick succession:
+    for peers in 1..=5 {
+        node_tx.send_json_text(json!(
+            {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":peers},"ts":"2021-07-12T10:38:48.330433+01:00" }
+        )).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    // The first may be sent straight away, but the rest arrive as one websocket message,
+    // with the node's samples merged:
+    let mut peers_seen = Vec::new();
+    let mut websocket_messages = 0;
+    while peers_seen.last() != Some(&5) {
+        let feed_messages = feed_rx
+            .recv_feed_messages_once_timeout(Duration::from_secs(10))
+            .await
+            .unwrap();
+        websocket_messages += 1;
+        let hardware_deltas = feed_messages
+            .iter()
+            .filter(|msg| matches!(msg, FeedMessage::HardwareDelta { .. }))
+            .count();
+        assert!(hardware_deltas <= 1, "{hardware_deltas} hardware deltas");
+        for msg in feed_messages {
+            if let FeedMessage::NodeStatsUpdate { stats, .. } = msg {
+                peers_seen.push(stats.peers);
+            }
+        }
+    }
+    assert!(
+        websocket_messages <= 2,
+        "{websocket_messages} websocket messages"
+    );
+    assert!(peers_seen.len() <= 2, "too many updates: {peers_seen:?}");
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds that fall behind are sent each node's hardware and IO samples merged into one
+/// update, which leaves them with the same series and no gap in the sequence numbers.
+#[tokio::test]
+async fn e2e_feed_backlog_samples_are_merged() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            feed_coalesce_backlog: Some(2),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": ghash(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }
+        ))
+        .unwrap();
+
+    // A feed with a slow update rate builds up a backlog between updates:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    feed_tx
+        .send_json_command(
+            json!({ "id": 1, "cmd": "subscribe", "chain": format!("{:#x}", ghash(1)) }),
+        )
+        .unwrap();
+    feed_tx
+        .send_json_command(json!({ "id": 2, "cmd": "setUpdateRate", "rate": "5s" }))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(&feed_messages, FeedMessage::CommandAck { request_id: 2 });
+
+    for bandwidth in 1..=5 {
+        node_tx.send_json_text(json!(
+            {"id":1, "payload":{ "bandwidth_download":bandwidth,"bandwidth_upload":bandwidth,"msg":"system.interval","used_state_cache_size":bandwidth},"ts":"2021-07-12T10:38:48.330433+01:00" }
+        )).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    // Apply the updates as a feed would, checking that none are missed:
+    let (mut hardware_seq, mut io_seq) = (0, 0);
+    let (mut upload, mut state_cache) = (Vec::new(), Vec::new());
+    let mut merged = false;
+    while hardware_seq < 5 || io_seq < 5 {
+        let feed_messages = feed_rx
+            .recv_feed_messages_once_timeout(Duration::from_secs(10))
+            .await
+            .unwrap();
+        for msg in feed_messages {
+            match msg {
+                FeedMessage::HardwareDelta {
+                    seq,
+                    upload: (drop, samples),
+                    first_seq,
+                    ..
+                } => {
+                    assert_eq!(first_seq, hardware_seq + 1);
+                    merged |= first_seq < seq;
+                    hardware_seq = seq;
+                    upload.drain(..drop as usize);
+                    upload.extend(samples);
+                }
+                FeedMessage::NodeIODelta {
+                    seq,
+                    used_state_cache_size: (drop, samples),
+                    first_seq,
+                    ..
+                } => {
+                    assert_eq!(first_seq, io_seq + 1);
+                    io_seq = seq;
+                    state_cache.drain(..drop as usize);
+                    state_cache.extend(samples);
+                }
+                _ => {}
+            }
+        }
+    }
+    assert!(merged, "expected some updates to be merged");
+    assert_eq!(upload, [1.0, 2.0, 3.0, 4.0, 5.0]);
+    assert_eq!(state_cache, [1.0, 2.0, 3.0, 4.0, 5.0]);
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Feeds that reconnect can resume a subscription, and are sent just the messages they missed.
+#[tokio::test]
+async fn e2e_feed_can_resume_after_reconnecting() {
+    use FeedMessage::*;
+
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .unwrap();
+
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": ghash(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }
+        ))
+        .unwrap();
+
+    // Subscribe, and note how far along the chain's messages we are:
+    let chain = format!("{:#x}", ghash(1));
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    feed_tx.send_command("subscribe", &chain).unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    let last_seq = |messages: &[FeedMessage]| {
+        messages.iter().rev().find_map(|m| match m {
+            ChainSeq { seq } => Some(*seq),
+            _ => None,
+        })
+    };
+    let seq = last_seq(&feed_messages).expect("subscribing should send a ChainSeq");
+
+    // Drop the connection, and miss an update:
+    drop((feed_tx, feed_rx));
+    node_tx.send_json_text(json!(
+        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":5},"ts":"2021-07-12T10:37:48.330433+01:00" }
+    )).unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // Resuming sends just the update we missed:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    feed_tx
+        .send_command("resume", &format!("{chain}:{seq}"))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        Resumed { genesis_hash } if *genesis_hash == ghash(1),
+        NodeStatsUpdate { node_id: 0, .. },
+    );
+    assert!(last_seq(&feed_messages).unwrap() > seq);
+    assert!(!feed_messages
+        .iter()
+        .any(|m| matches!(m, SubscribedTo { .. } | AddedNode { .. })));
+
+    // We stay subscribed after resuming:
+    node_tx.send_json_text(json!(
+        {"id":1, "payload":{ "bandwidth_download":576,"bandwidth_upload":576,"msg":"system.interval","peers":6},"ts":"2021-07-12T10:37:49.330433+01:00" }
+    )).unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(&feed_messages, NodeStatsUpdate { node_id: 0, .. });
+
+    // If we've missed more than we still have, we're sent everything again:
+    feed_tx
+        .send_command("resume", &format!("{chain}:1"))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        &feed_messages,
+        SubscribedTo { .. },
+        AddedNode { node_id: 0, .. },
+    );
 
     // Tidy up:
     server.shutdown().await;
@@ -87,7 +87,8 @@ async fn run_soak_test(opts: SoakTestOpts) {
         },
         CoreOpts {
             worker_threads: opts.core_worker_threads,
-            num_aggregators: opts.core_num_aggregators,
+            feed_workers: opts.core_feed_workers,
+            chain_partitions: opts.core_chain_partitions,
             ..Default::default()
         },
         ShardOpts {
@@ -284,9 +285,12 @@ struct SoakTestOpts {
     /// The number of different virtual nodes to connect per actual node socket connection
     #[structopt(long, default_value = "1")]
     ids_per_node: usize,
-    /// Number of aggregator loops to use in the core
+    /// Number of feed workers to use in the core
     #[structopt(long)]
-    core_num_aggregators: Option<usize>,
+    core_feed_workers: Option<usize>,
+    /// Number of loops to partition chains across in the core
+    #[structopt(long)]
+    core_chain_partitions: Option<usize>,
     /// Number of worker threads the core will use
     #[structopt(long)]
     core_worker_threads: Option<usize>,
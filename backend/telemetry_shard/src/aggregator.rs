@@ -14,15 +14,16 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::connection::{create_ws_connection_to_core, Message};
+use crate::connection::{create_ws_connection_to_core, FailoverUris, Message};
 use common::{
     internal_messages::{self, ShardNodeId},
     node_message,
-    node_types::BlockHash,
+    node_types::{BlockHash, NodeDetails},
+    tls::ClientConfig,
     AssignId,
 };
 use futures::{Sink, SinkExt};
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
 
@@ -46,6 +47,9 @@ enum ToAggregator {
     FromWebsocket(ConnId, FromWebsocket),
     /// Send when a message comes in from the telemetry core.
     FromTelemetryCore(internal_messages::FromTelemetryCore),
+    /// Tell the core to remove our nodes, close the connection to it, and
+    /// then stop handling messages. A message is sent back when we're done.
+    Shutdown(flume::Sender<()>),
 }
 
 /// An incoming socket connection can provide these messages.
@@ -53,19 +57,11 @@ enum ToAggregator {
 /// messages from it will be ignored.
 #[derive(Clone, Debug)]
 pub enum FromWebsocket {
-    /// Fire this when the connection is established.
-    Initialize {
-        /// When a message is sent back up this channel, we terminate
-        /// the websocket connection and force the node to reconnect
-        /// so that it sends its system info again incase the telemetry
-        /// core has restarted.
-        close_connection: flume::Sender<()>,
-    },
     /// Tell the aggregator about a new node.
     Add {
         message_id: node_message::NodeMessageId,
         ip: std::net::IpAddr,
-        node: common::node_types::NodeDetails,
+        node: NodeDetails,
         genesis_hash: BlockHash,
     },
     /// Update/pass through details about a node.
@@ -83,6 +79,30 @@ pub enum FromWebsocket {
 
 pub type FromAggregator = internal_messages::FromShardAggregator;
 
+/// Options to configure the aggregator.
+#[derive(Debug, Clone)]
+pub struct AggregatorOpts {
+    /// Url(s) of the telemetry core endpoint accepting shard connections. If more
+    /// than one is given, we fail over to the next one when a connection fails.
+    pub telemetry_uri: FailoverUris,
+    /// How many node updates to hold on to while we're disconnected from the
+    /// telemetry core, to be sent on once we reconnect. If more updates than this
+    /// arrive, the oldest are dropped. If 0, updates are dropped while disconnected.
+    pub core_buffer_len: usize,
+    /// The TLS config to use when connecting to a `wss://` core. If not given,
+    /// we trust the usual web roots and don't present a client certificate.
+    pub core_tls: Option<Arc<ClientConfig>>,
+}
+
+/// Everything we need to tell the telemetry core about a node. We hold on to this
+/// for each node so that we can tell a freshly (re)connected core about it.
+#[derive(Clone, Debug)]
+struct NodeToAdd {
+    ip: std::net::IpAddr,
+    node: NodeDetails,
+    genesis_hash: BlockHash,
+}
+
 /// The aggregator loop handles incoming messages from nodes, or from the telemetry core.
 /// this is where we decide what effect messages will have.
 #[derive(Clone)]
@@ -102,12 +122,12 @@ struct AggregatorInternal {
 
 impl Aggregator {
     /// Spawn a new Aggregator. This connects to the telemetry backend
-    pub async fn spawn(telemetry_uri: http::Uri) -> anyhow::Result<Aggregator> {
+    pub async fn spawn(opts: AggregatorOpts) -> anyhow::Result<Aggregator> {
         let (tx_to_aggregator, rx_from_external) = flume::bounded(10);
 
         // Establish a resilient connection to the core (this retries as needed):
-        let (tx_to_telemetry_core, rx_from_telemetry_core) =
-            create_ws_connection_to_core(telemetry_uri).await;
+        let (tx_to_telemetry_core, rx_from_telemetry_core, core_connection) =
+            create_ws_connection_to_core(opts.telemetry_uri.clone(), opts.core_tls.clone()).await;
 
         // Forward messages from the telemetry core into the aggregator:
         let tx_to_aggregator2 = tx_to_aggregator.clone();
@@ -129,6 +149,9 @@ impl Aggregator {
         tokio::spawn(Aggregator::handle_messages(
             rx_from_external,
             tx_to_telemetry_core,
+            core_connection,
+            opts.telemetry_uri,
+            opts.core_buffer_len,
         ));
 
         // Return a handle to our aggregator so that we can send in messages to it:
@@ -144,21 +167,29 @@ impl Aggregator {
     async fn handle_messages(
         rx_from_external: flume::Receiver<ToAggregator>,
         tx_to_telemetry_core: flume::Sender<FromAggregator>,
+        core_connection: tokio::task::JoinHandle<()>,
+        telemetry_uri: FailoverUris,
+        core_buffer_len: usize,
     ) {
         use internal_messages::{FromShardAggregator, FromTelemetryCore};
 
-        // Just as an optimisation, we can keep track of whether we're connected to the backend
-        // or not, and ignore incoming messages while we aren't.
+        // Keep track of whether we're connected to the backend or not. While we aren't,
+        // we keep track of nodes coming and going but don't try to send anything.
         let mut connected_to_telemetry_core = false;
 
-        // A list of close channels for the currently connected substrate nodes. Send an empty
-        // tuple to these to ask the connections to be closed.
-        let mut close_connections: HashMap<ConnId, flume::Sender<()>> = HashMap::new();
-
         // Maintain mappings from the connection ID and node message ID to the "local ID" which we
         // broadcast to the telemetry core.
         let mut to_local_id = AssignId::new();
 
+        // The details needed to add each of our nodes to the telemetry core. When we (re)connect
+        // to the core, we replay these so that nodes don't have to reconnect to send them again.
+        let mut nodes: HashMap<ShardNodeId, NodeToAdd> = HashMap::new();
+
+        // Updates that arrived while we were disconnected from the core, oldest first. These are
+        // sent on after the nodes themselves have been replayed to the core.
+        let mut buffered_updates: VecDeque<(ShardNodeId, node_message::Payload)> = VecDeque::new();
+        let mut num_dropped_updates: usize = 0;
+
         // Any messages coming from nodes that have been muted are ignored:
         let mut muted: HashSet<ShardNodeId> = HashSet::new();
 
@@ -166,34 +197,47 @@ impl Aggregator {
         while let Ok(msg) = rx_from_external.recv_async().await {
             match msg {
                 ToAggregator::ConnectedToTelemetryCore => {
-                    // Take hold of the connection closers and run them all.
-                    let closers = close_connections;
+                    // The core decides afresh which nodes to mute, and will tell us again as we
+                    // replay nodes to it:
+                    muted.clear();
 
-                    for (_, closer) in closers {
-                        // if this fails, it probably means the connection has died already anyway.
-                        let _ = closer.send_async(()).await;
+                    // Whether the core restarted or just dropped our connection, it no longer
+                    // knows about any of our nodes, so tell it about them all again:
+                    for (&local_id, node) in &nodes {
+                        let _ = tx_to_telemetry_core
+                            .send_async(FromShardAggregator::AddNode {
+                                ip: node.ip,
+                                node: node.node.clone(),
+                                genesis_hash: node.genesis_hash,
+                                local_id,
+                            })
+                            .await;
                     }
 
-                    // We've told everything to disconnect. Now, reset our state:
-                    close_connections = HashMap::new();
-                    to_local_id.clear();
-                    muted.clear();
+                    // Send on anything we buffered while disconnected for nodes that are still around:
+                    let num_buffered_updates = buffered_updates.len();
+                    for (local_id, payload) in buffered_updates.drain(..) {
+                        if !nodes.contains_key(&local_id) {
+                            continue;
+                        }
+                        let _ = tx_to_telemetry_core
+                            .send_async(FromShardAggregator::UpdateNode { local_id, payload })
+                            .await;
+                    }
 
                     connected_to_telemetry_core = true;
-                    log::info!("Connected to telemetry core");
+                    log::info!(
+                        core:% = telemetry_uri,
+                        nodes = nodes.len(),
+                        buffered_updates = num_buffered_updates,
+                        dropped_updates = num_dropped_updates;
+                        "Connected to telemetry core at {telemetry_uri}"
+                    );
+                    num_dropped_updates = 0;
                 }
                 ToAggregator::DisconnectedFromTelemetryCore => {
                     connected_to_telemetry_core = false;
-                    log::info!("Disconnected from telemetry core");
-                }
-                ToAggregator::FromWebsocket(
-                    conn_id,
-                    FromWebsocket::Initialize { close_connection },
-                ) => {
-                    // We boot all connections on a reconnect-to-core to force new systemconnected
-                    // messages to be sent. We could boot on muting, but need to be careful not to boot
-                    // connections where we mute one set of messages it sends and not others.
-                    close_connections.insert(conn_id, close_connection);
+                    log::info!(core:% = telemetry_uri; "Disconnected from telemetry core at {telemetry_uri}");
                 }
                 ToAggregator::FromWebsocket(
                     conn_id,
@@ -204,24 +248,28 @@ impl Aggregator {
                         genesis_hash,
                     },
                 ) => {
-                    // Don't bother doing anything else if we're disconnected, since we'll force the
-                    // node to reconnect anyway when the backend does:
-                    if !connected_to_telemetry_core {
-                        continue;
-                    }
-
                     // Generate a new "local ID" for messages from this connection:
                     let local_id = to_local_id.assign_id((conn_id, message_id));
+                    let node = NodeToAdd {
+                        ip,
+                        node,
+                        genesis_hash,
+                    };
 
-                    // Send the message to the telemetry core with this local ID:
-                    let _ = tx_to_telemetry_core
-                        .send_async(FromShardAggregator::AddNode {
-                            ip,
-                            node,
-                            genesis_hash,
-                            local_id,
-                        })
-                        .await;
+                    // Send the message to the telemetry core with this local ID. If we're
+                    // disconnected, this will be sent when we reconnect:
+                    if connected_to_telemetry_core {
+                        let _ = tx_to_telemetry_core
+                            .send_async(FromShardAggregator::AddNode {
+                                ip: node.ip,
+                                node: node.node.clone(),
+                                genesis_hash: node.genesis_hash,
+                                local_id,
+                            })
+                            .await;
+                    }
+
+                    nodes.insert(local_id, node);
                 }
                 ToAggregator::FromWebsocket(
                     conn_id,
@@ -230,17 +278,25 @@ impl Aggregator {
                         payload,
                     },
                 ) => {
-                    // Ignore incoming messages if we're not connected to the backend:
-                    if !connected_to_telemetry_core {
-                        continue;
-                    }
-
                     // Get the local ID, ignoring the message if none match:
                     let local_id = match to_local_id.get_id(&(conn_id, message_id)) {
                         Some(id) => id,
                         None => continue,
                     };
 
+                    // Hold on to a limited number of updates while we're disconnected from the core:
+                    if !connected_to_telemetry_core {
+                        if core_buffer_len == 0 {
+                            continue;
+                        }
+                        if buffered_updates.len() >= core_buffer_len {
+                            buffered_updates.pop_front();
+                            num_dropped_updates += 1;
+                        }
+                        buffered_updates.push_back((local_id, payload));
+                        continue;
+                    }
+
                     // ignore the message if this node has been muted:
                     if muted.contains(&local_id) {
                         continue;
@@ -260,6 +316,7 @@ impl Aggregator {
 
                     // Remove references to this single node:
                     to_local_id.remove_by_id(local_id);
+                    nodes.remove(&local_id);
                     muted.remove(&local_id);
 
                     // If we're not connected to the core, don't buffer up remove messages. The core will remove
@@ -280,10 +337,9 @@ impl Aggregator {
                         .map(|(local_id, _)| local_id)
                         .collect();
 
-                    close_connections.remove(&disconnected_conn_id);
-
                     for local_id in local_ids_disconnected {
                         to_local_id.remove_by_id(local_id);
+                        nodes.remove(&local_id);
                         muted.remove(&local_id);
 
                         // If we're not connected to the core, don't buffer up remove messages. The core will remove
@@ -302,12 +358,42 @@ impl Aggregator {
                     // Mute the local ID we've been told to:
                     muted.insert(local_id);
                 }
+                ToAggregator::Shutdown(tx_done) => {
+                    // Remove any nodes that are still around, rather than leaving the core
+                    // to notice that our connection has gone:
+                    if connected_to_telemetry_core {
+                        for &local_id in nodes.keys() {
+                            let _ = tx_to_telemetry_core
+                                .send_async(FromShardAggregator::RemoveNode { local_id })
+                                .await;
+                        }
+                    }
+                    log::info!(core:% = telemetry_uri, nodes = nodes.len(); "Disconnecting from telemetry core at {telemetry_uri}");
+
+                    // Dropping this closes the connection once the above have been sent:
+                    drop(tx_to_telemetry_core);
+                    let _ = core_connection.await;
+                    let _ = tx_done.send(());
+                    return;
+                }
             }
         }
     }
 
+    /// Tell the telemetry core to remove all of our nodes, and then close the
+    /// connection to it. Messages sent to the aggregator after this are ignored.
+    pub async fn shutdown(&self) {
+        let (tx, rx) = flume::bounded(1);
+        let msg = ToAggregator::Shutdown(tx);
+        if self.0.tx_to_aggregator.send_async(msg).await.is_ok() {
+            let _ = rx.recv_async().await;
+        }
+    }
+
     /// Return a sink that a node can send messages into to be handled by the aggregator.
-    pub fn subscribe_node(&self) -> impl Sink<FromWebsocket, Error = anyhow::Error> + Unpin {
+    pub fn subscribe_node(
+        &self,
+    ) -> impl Sink<FromWebsocket, Error = anyhow::Error> + Send + Unpin + 'static {
         // Assign a unique aggregator-local ID to each connection that subscribes, and pass
         // that along with every message to the aggregator loop:
         let conn_id: ConnId = self
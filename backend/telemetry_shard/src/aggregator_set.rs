@@ -0,0 +1,268 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::aggregator::{Aggregator, AggregatorOpts, FromWebsocket};
+use common::EitherSink;
+use futures::{Sink, SinkExt};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// How many messages from each node we'll queue up for each core. If a core can't
+/// keep up, updates from the node beyond this are dropped for that core alone.
+const CORE_QUEUE_LEN: usize = 64;
+
+/// A set of aggregators, one for each telemetry core that we forward node messages to.
+/// Each aggregator has its own connection to its core, as well as its own local IDs and
+/// set of muted nodes, so that one core muting a node doesn't silence it for the others.
+#[derive(Clone)]
+pub struct AggregatorSet(Arc<Vec<Core>>);
+
+struct Core {
+    aggregator: Aggregator,
+    /// The core's URI(s), for logging.
+    telemetry_uri: String,
+    /// How many node updates have been dropped because the core couldn't keep up.
+    dropped_updates: AtomicU64,
+}
+
+impl AggregatorSet {
+    /// Spawn an aggregator for each of the telemetry cores we're given options for.
+    pub async fn spawn(opts: Vec<AggregatorOpts>) -> anyhow::Result<AggregatorSet> {
+        assert!(
+            !opts.is_empty(),
+            "You must connect to 1 or more telemetry core"
+        );
+
+        let cores = futures::future::try_join_all(opts.into_iter().map(|opts| async move {
+            let telemetry_uri = opts.telemetry_uri.to_string();
+            let aggregator = Aggregator::spawn(opts).await?;
+            anyhow::Ok(Core {
+                aggregator,
+                telemetry_uri,
+                dropped_updates: AtomicU64::new(0),
+            })
+        }))
+        .await?;
+
+        Ok(AggregatorSet(Arc::new(cores)))
+    }
+
+    /// Return a sink that a node can send messages into to be handled by all aggregators.
+    pub fn subscribe_node(
+        &self,
+    ) -> impl Sink<FromWebsocket, Error = anyhow::Error> + Send + Unpin + 'static {
+        // Special case 1 aggregator to avoid the extra indirection and so on
+        // if we don't actually need it.
+        if self.0.len() == 1 {
+            return EitherSink::a(self.0[0].aggregator.subscribe_node());
+        }
+
+        // Each core is handed messages by its own task, from its own queue, so that
+        // one core being slow to take them doesn't hold up the others:
+        let queues: Vec<_> = self
+            .0
+            .iter()
+            .map(|core| {
+                let (tx, rx) = flume::bounded::<FromWebsocket>(CORE_QUEUE_LEN);
+                let mut conn = core.aggregator.subscribe_node();
+                tokio::spawn(async move {
+                    while let Ok(msg) = rx.recv_async().await {
+                        if let Err(e) = conn.send(msg).await {
+                            log::error!("Aggregator connection has failed: {}", e);
+                            return;
+                        }
+                    }
+                });
+                tx
+            })
+            .collect();
+
+        // Bounded, so that nodes are slowed down rather than building up messages
+        // if we can't keep up with them.
+        let (tx, rx) = flume::bounded::<FromWebsocket>(10);
+
+        // Send every incoming message to all aggregators.
+        let cores = Arc::clone(&self.0);
+        tokio::spawn(async move {
+            let dropped = forward_to_cores(rx, queues).await;
+            for (core, dropped) in cores.iter().zip(dropped) {
+                if dropped == 0 {
+                    continue;
+                }
+                let total = core.dropped_updates.fetch_add(dropped, Ordering::Relaxed) + dropped;
+                log::warn!(
+                    core:% = core.telemetry_uri;
+                    "Dropped {dropped} updates from a node that the telemetry core at {} \
+                    couldn't keep up with ({total} in total)",
+                    core.telemetry_uri
+                );
+            }
+        });
+
+        EitherSink::b(tx.into_sink().sink_map_err(|e| anyhow::anyhow!("{}", e)))
+    }
+
+    /// Shut down every aggregator, removing our nodes from, and closing the
+    /// connection to, each telemetry core.
+    pub async fn shutdown(&self) {
+        futures::future::join_all(self.0.iter().map(|core| core.aggregator.shutdown())).await;
+    }
+}
+
+/// The messages from a node that are waiting to be handed to one core.
+struct CoreQueue {
+    tx: flume::Sender<FromWebsocket>,
+    /// Messages which didn't fit in the queue. Updates are dropped rather than held
+    /// back, but nodes being added or removed can't be, or the core would lose track
+    /// of them.
+    held_back: VecDeque<FromWebsocket>,
+    dropped_updates: u64,
+}
+
+impl CoreQueue {
+    /// Queue a message without waiting, holding it back or dropping it if it doesn't fit.
+    fn push(&mut self, msg: FromWebsocket) {
+        // Messages held back are sent first, so that they arrive in order:
+        while let Some(held) = self.held_back.pop_front() {
+            if let Err(flume::TrySendError::Full(held)) = self.tx.try_send(held) {
+                self.held_back.push_front(held);
+                break;
+            }
+        }
+
+        let msg = if self.held_back.is_empty() {
+            match self.tx.try_send(msg) {
+                // If the core's task has gone, it's logged why, so there's nothing to do:
+                Ok(()) | Err(flume::TrySendError::Disconnected(_)) => return,
+                Err(flume::TrySendError::Full(msg)) => msg,
+            }
+        } else {
+            msg
+        };
+
+        match msg {
+            FromWebsocket::Update { .. } => self.dropped_updates += 1,
+            msg => self.held_back.push_back(msg),
+        }
+    }
+
+    /// Wait for anything held back to be queued.
+    async fn flush(&mut self) {
+        for msg in self.held_back.drain(..) {
+            if self.tx.send_async(msg).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Hand each message from a node to every core's queue without waiting on any of them.
+/// Returns how many updates were dropped for each core.
+async fn forward_to_cores(
+    rx: flume::Receiver<FromWebsocket>,
+    queues: Vec<flume::Sender<FromWebsocket>>,
+) -> Vec<u64> {
+    let mut queues: Vec<_> = queues
+        .into_iter()
+        .map(|tx| CoreQueue {
+            tx,
+            held_back: VecDeque::new(),
+            dropped_updates: 0,
+        })
+        .collect();
+
+    while let Ok(msg) = rx.recv_async().await {
+        for queue in &mut queues {
+            queue.push(msg.clone());
+        }
+    }
+
+    // Nothing else is coming from the node, so it's fine to wait for room now:
+    futures::future::join_all(queues.iter_mut().map(|queue| queue.flush())).await;
+    queues.iter().map(|queue| queue.dropped_updates).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::node_message::{Payload, SystemInterval};
+
+    fn update() -> FromWebsocket {
+        FromWebsocket::Update {
+            message_id: 1,
+            payload: Payload::SystemInterval(SystemInterval {
+                peers: Some(1),
+                txcount: None,
+                bandwidth_upload: None,
+                bandwidth_download: None,
+                finalized_height: None,
+                finalized_hash: None,
+                block: None,
+                used_state_cache_size: None,
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_stalled_core_does_not_hold_up_the_others() {
+        let (tx, rx) = flume::bounded(10);
+        let (fast_tx, fast_rx) = flume::bounded(CORE_QUEUE_LEN);
+        let (stalled_tx, stalled_rx) = flume::bounded(CORE_QUEUE_LEN);
+        let forwarding = tokio::spawn(forward_to_cores(rx, vec![fast_tx, stalled_tx]));
+
+        // Nothing reads from the stalled core's queue, but the other core is sent
+        // every message regardless:
+        let num_updates = CORE_QUEUE_LEN + 100;
+        for _ in 0..num_updates {
+            tx.send_async(update()).await.unwrap();
+            assert!(matches!(
+                fast_rx.recv_async().await.unwrap(),
+                FromWebsocket::Update { .. }
+            ));
+        }
+        tx.send_async(FromWebsocket::Remove { message_id: 1 })
+            .await
+            .unwrap();
+        tx.send_async(FromWebsocket::Disconnected).await.unwrap();
+        drop(tx);
+        assert!(matches!(
+            fast_rx.recv_async().await.unwrap(),
+            FromWebsocket::Remove { .. }
+        ));
+
+        // The stalled core gets as many updates as fit, and then the messages that
+        // can't be dropped, in order, once it catches up:
+        let mut stalled = Vec::new();
+        for _ in 0..CORE_QUEUE_LEN + 2 {
+            stalled.push(stalled_rx.recv_async().await.unwrap());
+        }
+        assert!(stalled[..CORE_QUEUE_LEN]
+            .iter()
+            .all(|msg| matches!(msg, FromWebsocket::Update { .. })));
+        assert!(matches!(
+            stalled[CORE_QUEUE_LEN],
+            FromWebsocket::Remove { .. }
+        ));
+        assert!(matches!(
+            stalled[CORE_QUEUE_LEN + 1],
+            FromWebsocket::Disconnected
+        ));
+
+        let dropped = forwarding.await.unwrap();
+        assert_eq!(dropped, vec![0, 100]);
+    }
+}
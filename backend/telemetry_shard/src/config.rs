@@ -0,0 +1,182 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::connection::FailoverUris;
+use common::byte_size::ByteSize;
+use common::logging::{LogFormat, ModuleLevel};
+use common::tls::{ClientTlsOpts, ServerTlsOpts};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The effective configuration of the shard, built up from the defaults, a config file,
+/// environment variables and command line flags (see [`common::config`]). Each field
+/// corresponds to the command line flag of the same name; see `Opts` for details.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub listen: SocketAddr,
+    pub log: log::LevelFilter,
+    pub log_format: LogFormat,
+    pub log_module: Vec<ModuleLevel>,
+    pub core: Vec<FailoverUris>,
+    pub core_buffer_len: usize,
+    pub max_nodes_per_connection: usize,
+    pub max_node_data_per_second: ByteSize,
+    pub node_block_seconds: u64,
+    pub worker_threads: Option<usize>,
+    pub stale_node_timeout: u64,
+    pub shutdown_deadline: u64,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_reload_interval: u64,
+    pub core_tls_ca: Option<PathBuf>,
+    pub core_tls_cert: Option<PathBuf>,
+    pub core_tls_key: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: ([127, 0, 0, 1], 8001).into(),
+            log: log::LevelFilter::Info,
+            log_format: LogFormat::Text,
+            log_module: Vec::new(),
+            core: vec!["ws://127.0.0.1:8000/shard_submit/"
+                .parse()
+                .expect("hardcoded URL should be valid")],
+            core_buffer_len: 0,
+            max_nodes_per_connection: 20,
+            max_node_data_per_second: ByteSize::new(256 * 1000),
+            node_block_seconds: 600,
+            worker_threads: None,
+            stale_node_timeout: 60,
+            shutdown_deadline: 10,
+            tls_cert: None,
+            tls_key: None,
+            tls_reload_interval: 60,
+            core_tls_ca: None,
+            core_tls_cert: None,
+            core_tls_key: None,
+        }
+    }
+}
+
+impl Config {
+    /// Load the config file at `path` (if given), apply the `overrides` given by
+    /// environment variables and command line flags, and check the result.
+    pub fn load<O: Serialize>(path: Option<&Path>, overrides: &O) -> anyhow::Result<Config> {
+        let config: Config = common::config::load(path, overrides)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.core.is_empty() {
+            anyhow::bail!("At least one 'core' URL must be given");
+        }
+        let must_be_nonzero = [
+            (
+                "max_nodes_per_connection",
+                self.max_nodes_per_connection as u64,
+            ),
+            (
+                "max_node_data_per_second",
+                self.max_node_data_per_second.num_bytes() as u64,
+            ),
+            ("stale_node_timeout", self.stale_node_timeout),
+            ("tls_reload_interval", self.tls_reload_interval),
+        ];
+        for (name, value) in must_be_nonzero {
+            if value == 0 {
+                anyhow::bail!("'{name}' must be greater than 0");
+            }
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            anyhow::bail!("'tls_cert' and 'tls_key' must be given together");
+        }
+        if self.core_tls_cert.is_some() != self.core_tls_key.is_some() {
+            anyhow::bail!("'core_tls_cert' and 'core_tls_key' must be given together");
+        }
+        Ok(())
+    }
+
+    /// How to terminate TLS, if we've been asked to.
+    pub fn tls_opts(&self) -> Option<ServerTlsOpts> {
+        Some(ServerTlsOpts {
+            cert: self.tls_cert.clone()?,
+            key: self.tls_key.clone()?,
+            client_ca: None,
+            reload_interval: Duration::from_secs(self.tls_reload_interval),
+        })
+    }
+
+    /// How to configure TLS when connecting to a core, if anything non-default was asked for.
+    pub fn core_tls_opts(&self) -> Option<ClientTlsOpts> {
+        if self.core_tls_ca.is_none() && self.core_tls_cert.is_none() {
+            return None;
+        }
+        Some(ClientTlsOpts {
+            ca: self.core_tls_ca.clone(),
+            cert: self.core_tls_cert.clone(),
+            key: self.core_tls_key.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn defaults_are_valid() {
+        let config = Config::default();
+        assert!(config.validate().is_ok());
+
+        let json = serde_json::to_value(&config).unwrap();
+        let config: Config = serde_json::from_value(json).unwrap();
+        assert_eq!(config.max_node_data_per_second.num_bytes(), 256 * 1000);
+        assert_eq!(config.core, Config::default().core);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let config = Config {
+            core: vec![],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            stale_node_timeout: 0,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            tls_key: Some("key.pem".into()),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = Config {
+            core_tls_cert: Some("client.pem".into()),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+}
@@ -15,8 +15,13 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use bincode::Options;
+use common::tls::ClientConfig;
 use common::ws_client;
 use futures::StreamExt;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
 
 #[derive(Clone, Debug)]
 pub enum Message<Out> {
@@ -25,17 +30,86 @@ pub enum Message<Out> {
     Data(Out),
 }
 
+/// One or more URLs that the same telemetry core can be reached at, parsed from a
+/// comma separated list. We connect to the first URL, and move on to the next one
+/// whenever a connection fails, so that a standby core can take over from the primary.
+/// Once a connection that was established drops, we go back to trying the first URL.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailoverUris(Vec<http::Uri>);
+
+impl FailoverUris {
+    pub fn new(uris: Vec<http::Uri>) -> Option<FailoverUris> {
+        (!uris.is_empty()).then_some(FailoverUris(uris))
+    }
+
+    /// The index of the URL to try connecting to next, given the one we last tried
+    /// and whether we managed to connect to it. Having been connected, we start again
+    /// from the primary URL rather than staying on a standby for good.
+    fn next_idx(&self, idx: usize, was_connected: bool) -> usize {
+        if was_connected {
+            0
+        } else {
+            (idx + 1) % self.0.len()
+        }
+    }
+}
+
+impl FromStr for FailoverUris {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let uris = s
+            .split(',')
+            .map(|uri| uri.trim().parse())
+            .collect::<Result<Vec<http::Uri>, _>>()?;
+        FailoverUris::new(uris).ok_or_else(|| anyhow::anyhow!("Expecting at least one URL"))
+    }
+}
+
+impl fmt::Display for FailoverUris {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, uri) in self.0.iter().enumerate() {
+            if idx > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{uri}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for FailoverUris {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for FailoverUris {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 /// Connect to the telemetry core, retrying the connection if we're disconnected.
 /// - Sends `Message::Connected` and `Message::Disconnected` when the connection goes up/down.
+/// - If more than one URL is given, moves on to the next URL each time a connection fails,
+///   and goes back to the first URL each time an established connection drops.
 /// - Returns a channel that allows you to send messages to the connection.
+/// - Once that channel is dropped, any messages already sent on it are forwarded on and the
+///   connection is closed cleanly; the returned handle completes when this is done.
 /// - Messages are all encoded/decoded to/from bincode, and so need to support being (de)serialized from
 ///   a non self-describing encoding.
 ///
 /// Note: have a look at [`common::internal_messages`] to see the different message types exchanged
 /// between aggregator and core.
 pub async fn create_ws_connection_to_core<In, Out>(
-    telemetry_uri: http::Uri,
-) -> (flume::Sender<In>, flume::Receiver<Message<Out>>)
+    telemetry_uris: FailoverUris,
+    tls_config: Option<Arc<ClientConfig>>,
+) -> (
+    flume::Sender<In>,
+    flume::Receiver<Message<Out>>,
+    tokio::task::JoinHandle<()>,
+)
 where
     In: serde::Serialize + Send + 'static,
     Out: serde::de::DeserializeOwned + Send + 'static,
@@ -45,8 +119,16 @@ where
 
     let mut is_connected = false;
 
-    tokio::spawn(async move {
+    let handle = tokio::spawn(async move {
+        let mut uri_idx = 0;
         loop {
+            let telemetry_uri = &telemetry_uris.0[uri_idx];
+
+            // The aggregator has gone away, so there's no point (re)connecting.
+            if rx_in.is_disconnected() {
+                return;
+            }
+
             // Throw away any pending messages from the incoming channel so that it
             // doesn't get filled up and begin blocking while we're looping and waiting
             // for a reconnection.
@@ -55,10 +137,11 @@ where
             // Try to connect. If connection established, we serialize and forward messages
             // to/from the core. If the external channels break, we end for good. If the internal
             // channels break, we loop around and try connecting again.
-            match ws_client::connect(&telemetry_uri).await {
+            match ws_client::connect_with_tls(telemetry_uri, tls_config.clone()).await {
                 Ok(connection) => {
-                    let (tx_to_core, mut rx_from_core) = connection.into_channels();
+                    let (mut tx_to_core, mut rx_from_core) = connection.into_channels();
                     is_connected = true;
+                    log::info!(core:% = telemetry_uri; "Established connection to {telemetry_uri}");
                     let tx_out = tx_out.clone();
 
                     if let Err(e) = tx_out.send_async(Message::Connected).await {
@@ -75,7 +158,7 @@ where
                                     Some(Ok(msg)) => msg,
                                     // No more messages from core? core WS is disconnected.
                                     _ => {
-                                        log::warn!("No more messages from core: shutting down connection (will reconnect)");
+                                        log::warn!(core:% = telemetry_uri; "No more messages from core: shutting down connection (will reconnect)");
                                         break
                                     }
                                 };
@@ -97,7 +180,11 @@ where
                                 let msg = match msg {
                                     Ok(msg) => msg,
                                     Err(flume::RecvError::Disconnected) => {
-                                        log::error!("Aggregator is no longer sending messages to core; disconnecting (permanently)");
+                                        // Everything we were sent has been passed on by now, so close
+                                        // the connection once the core has been sent it all.
+                                        log::info!(core:% = telemetry_uri; "Aggregator is no longer sending messages to core; disconnecting (permanently)");
+                                        let _ = tx_to_core.unbounded_send(ws_client::SentMessage::Close);
+                                        tx_to_core.finished().await;
                                         return
                                     }
                                 };
@@ -108,7 +195,7 @@ where
                                 let ws_msg = ws_client::SentMessage::Binary(bytes);
 
                                 if let Err(e) = tx_to_core.unbounded_send(ws_msg) {
-                                    log::warn!("Unable to send message to core; shutting down connection (will reconnect): {}", e);
+                                    log::warn!(core:% = telemetry_uri; "Unable to send message to core; shutting down connection (will reconnect): {}", e);
                                     break;
                                 }
                             }
@@ -118,12 +205,15 @@ where
                 Err(connect_err) => {
                     // Issue connecting? Wait and try again on the next loop iteration.
                     log::error!(
-                        "Error connecting to websocker server (will reconnect): {}",
+                        core:% = telemetry_uri;
+                        "Error connecting to websocker server {} (will reconnect): {}",
+                        telemetry_uri,
                         connect_err
                     );
                 }
             }
 
+            let was_connected = is_connected;
             if is_connected {
                 is_connected = false;
                 if let Err(e) = tx_out.send_async(Message::Disconnected).await {
@@ -132,10 +222,70 @@ where
                 }
             }
 
+            // Fail over to the next URL (if there is one) for our next attempt.
+            let next_idx = telemetry_uris.next_idx(uri_idx, was_connected);
+            if next_idx != uri_idx {
+                uri_idx = next_idx;
+                log::warn!(
+                    core:% = telemetry_uris.0[uri_idx];
+                    "Failing over to {} for the next connection attempt",
+                    telemetry_uris.0[uri_idx]
+                );
+            }
+
             // Wait a little before we try to connect again.
             tokio::time::sleep(std::time::Duration::from_secs(1)).await;
         }
     });
 
-    (tx_in, rx_out)
+    (tx_in, rx_out, handle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn failover_uris_can_be_parsed() {
+        let uris: FailoverUris = "ws://a:8000/shard_submit, ws://b:8000/shard_submit"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            uris.0,
+            vec![
+                http::Uri::from_static("ws://a:8000/shard_submit"),
+                http::Uri::from_static("ws://b:8000/shard_submit"),
+            ]
+        );
+        assert_eq!(
+            uris.to_string(),
+            "ws://a:8000/shard_submit,ws://b:8000/shard_submit"
+        );
+
+        assert!("".parse::<FailoverUris>().is_err());
+        assert!("ws://a:8000,".parse::<FailoverUris>().is_err());
+    }
+
+    #[test]
+    fn failover_returns_to_the_primary_uri() {
+        let uris: FailoverUris =
+            "ws://a:8000/shard_submit,ws://b:8000/shard_submit,ws://c:8000/shard_submit"
+                .parse()
+                .unwrap();
+
+        // Failed attempts move on through the standbys, wrapping around:
+        assert_eq!(uris.next_idx(0, false), 1);
+        assert_eq!(uris.next_idx(1, false), 2);
+        assert_eq!(uris.next_idx(2, false), 0);
+
+        // Once a connection to a standby drops, we try the primary again first:
+        assert_eq!(uris.next_idx(1, true), 0);
+        assert_eq!(uris.next_idx(2, true), 0);
+        assert_eq!(uris.next_idx(0, true), 0);
+
+        // With a single URL there's nowhere else to go:
+        let uri: FailoverUris = "ws://a:8000/shard_submit".parse().unwrap();
+        assert_eq!(uri.next_idx(0, false), 0);
+        assert_eq!(uri.next_idx(0, true), 0);
+    }
 }
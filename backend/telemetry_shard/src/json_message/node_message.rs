@@ -159,12 +159,19 @@ impl From<Finalized> for internal::Finalized {
 #[derive(Deserialize, Debug)]
 pub struct AfgAuthoritySet {
     pub authority_id: Box<str>,
+    pub authority_set_id: Option<Box<str>>,
+    /// The authorities in the set, as a JSON encoded array of strings.
+    pub authorities: Option<Box<str>>,
 }
 
 impl From<AfgAuthoritySet> for internal::AfgAuthoritySet {
     fn from(msg: AfgAuthoritySet) -> Self {
         internal::AfgAuthoritySet {
             authority_id: msg.authority_id,
+            authority_set_id: msg.authority_set_id.and_then(|id| id.parse().ok()),
+            authorities: msg
+                .authorities
+                .and_then(|authorities| serde_json::from_str(&authorities).ok()),
         }
     }
 }
@@ -407,6 +414,46 @@ mod tests {
         );
     }
 
+    #[test]
+    fn afg_authority_set_authorities_are_decoded() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "msg":"afg.authority_set",
+                "number":"1000",
+                "hash":"0xcc41708573f2acaded9dd75e07dac2d4163d136ca35b3061c558d7a35a09dd8d",
+                "authority_id":"5FHneW46xGXgs5mUiveU4sbTyGBzmstUspZC92UhjJM694ty",
+                "authority_set_id":"12",
+                "authorities":"[\"5FHneW46xGXgs5mUiveU4sbTyGBzmstUspZC92UhjJM694ty\",\"5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY\"]"
+            }
+        }"#;
+        let msg: internal::NodeMessage = serde_json::from_str::<NodeMessage>(json).unwrap().into();
+        let internal::Payload::AfgAuthoritySet(set) = msg.into_payload() else {
+            panic!("message did not match the expected output");
+        };
+        assert_eq!(set.authority_set_id, Some(12));
+        assert_eq!(
+            set.authorities.unwrap(),
+            vec![
+                "5FHneW46xGXgs5mUiveU4sbTyGBzmstUspZC92UhjJM694ty".into(),
+                "5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY".into()
+            ]
+        );
+
+        // Older nodes only send their own authority ID:
+        let json = r#"{
+            "msg":"afg.authority_set",
+            "authority_id":"5FHneW46xGXgs5mUiveU4sbTyGBzmstUspZC92UhjJM694ty"
+        }"#;
+        let msg: internal::NodeMessage = serde_json::from_str::<NodeMessage>(json).unwrap().into();
+        let internal::Payload::AfgAuthoritySet(set) = msg.into_payload() else {
+            panic!("message did not match the expected output");
+        };
+        assert_eq!(set.authority_set_id, None);
+        assert_eq!(set.authorities, None);
+    }
+
     #[test]
     fn split_old_style_version_works() {
         let (version, target_arch, target_os, target_env) =
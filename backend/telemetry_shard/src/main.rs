@@ -16,7 +16,9 @@
 
 #[warn(missing_docs)]
 mod aggregator;
+mod aggregator_set;
 mod blocked_addrs;
+mod config;
 mod connection;
 mod json_message;
 mod real_ip;
@@ -24,20 +26,27 @@ mod real_ip;
 use std::{
     collections::HashMap,
     net::IpAddr,
+    path::PathBuf,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
-use aggregator::{Aggregator, FromWebsocket};
+use aggregator::{AggregatorOpts, FromWebsocket};
+use aggregator_set::AggregatorSet;
 use blocked_addrs::BlockedAddrs;
 use common::byte_size::ByteSize;
 use common::http_utils;
+use common::logging::{self, LogFormat, ModuleLevel};
 use common::node_message;
 use common::node_message::NodeMessageId;
 use common::rolling_total::RollingTotalBuilder;
+use common::shutdown::{self, Shutdown, ShutdownListener};
+use common::tls::{self, TlsAcceptor};
+use config::Config;
+use connection::FailoverUris;
 use futures::{SinkExt, StreamExt};
-use http::Uri;
 use hyper::{Method, Response};
-use simple_logger::SimpleLogger;
+use serde::Serialize;
 use structopt::StructOpt;
 
 #[cfg(not(target_env = "msvc"))]
@@ -53,68 +62,134 @@ const NAME: &str = "Substrate Telemetry Backend Shard";
 const ABOUT: &str = "This is the Telemetry Backend Shard that forwards the \
                      data sent by Substrate/Polkadot nodes to the Backend Core";
 
-#[derive(StructOpt, Debug)]
+/// Command line flags, each of which can also be given in a config file (see '--config').
+/// These, and the corresponding environment variables, take precedence over the config file.
+#[derive(StructOpt, Serialize, Debug)]
 #[structopt(name = NAME, version = VERSION, author = AUTHORS, about = ABOUT)]
 struct Opts {
+    /// A TOML config file to load settings from. Keys are the names of the flags here with
+    /// any '-' replaced by '_', eg 'core_buffer_len = 1000' or 'core = ["ws://core/shard_submit"]'.
+    #[structopt(long, env = "TELEMETRY_SHARD_CONFIG")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
     /// This is the socket address that this shard is listening to. This is restricted to
-    /// localhost (127.0.0.1) by default and should be fine for most use cases. If
+    /// localhost (127.0.0.1:8001) by default and should be fine for most use cases. If
     /// you are using Telemetry in a container, you likely want to set this to '0.0.0.0:8000'
-    #[structopt(short = "l", long = "listen", default_value = "127.0.0.1:8001")]
-    socket: std::net::SocketAddr,
+    #[structopt(short = "l", long, env = "TELEMETRY_SHARD_LISTEN")]
+    listen: Option<std::net::SocketAddr>,
     /// The desired log level; one of 'error', 'warn', 'info', 'debug' or 'trace', where
-    /// 'error' only logs errors and 'trace' logs everything.
-    #[structopt(long = "log", default_value = "info")]
-    log_level: log::LevelFilter,
-    /// Url to the Backend Core endpoint accepting shard connections
+    /// 'error' only logs errors and 'trace' logs everything. Defaults to 'info'.
+    #[structopt(long, env = "TELEMETRY_SHARD_LOG")]
+    log: Option<log::LevelFilter>,
+    /// The format to write logs out in; one of 'text' (the default) or 'json'. JSON logs are
+    /// written as one object per line, with fields like 'conn_id', 'ip' and 'message_id'
+    /// where relevant.
+    #[structopt(long, env = "TELEMETRY_SHARD_LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+    /// Override the log level for a given module, eg 'soketto=warn' or
+    /// 'telemetry_shard::aggregator=debug'. Can be provided multiple times, or as a comma
+    /// separated list in the environment variable.
     #[structopt(
-        short = "c",
-        long = "core",
-        default_value = "ws://127.0.0.1:8000/shard_submit/"
+        long,
+        required = false,
+        env = "TELEMETRY_SHARD_LOG_MODULE",
+        use_delimiter = true
     )]
-    core_url: Uri,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    log_module: Vec<ModuleLevel>,
+    /// Url to the Backend Core endpoint accepting shard connections. This can be provided
+    /// multiple times to forward node messages to several cores (eg a staging core alongside
+    /// production). Each core has its own connection, and decides which nodes to mute
+    /// independently of the others. Defaults to 'ws://127.0.0.1:8000/shard_submit/'.
+    ///
+    /// Each value can also be a comma separated list of URLs for the same core, eg
+    /// 'ws://primary/shard_submit,ws://standby/shard_submit'. We connect to the first, and
+    /// fail over to the next URL whenever the current connection fails, going back to the
+    /// first URL once a connection that was established drops.
+    #[structopt(short = "c", long, required = false, number_of_values = 1)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    core: Vec<FailoverUris>,
+    /// How many node updates to hold on to while the connection to the Backend Core is down.
+    /// Nodes themselves are always remembered and re-sent to the core when it comes back; this
+    /// also lets the most recent updates from them survive a short outage. If more updates than
+    /// this arrive while disconnected, the oldest are dropped. Defaults to 0.
+    #[structopt(long, env = "TELEMETRY_SHARD_CORE_BUFFER_LEN")]
+    core_buffer_len: Option<usize>,
     /// How many different nodes is a given connection to the /submit endpoint allowed to
-    /// tell us about before we ignore the rest?
+    /// tell us about before we ignore the rest? Defaults to 20.
     ///
     /// This is important because without a limit, a single connection could exhaust
     /// RAM by suggesting that it accounts for billions of nodes.
-    #[structopt(long, default_value = "20")]
-    max_nodes_per_connection: usize,
+    #[structopt(long, env = "TELEMETRY_SHARD_MAX_NODES_PER_CONNECTION")]
+    max_nodes_per_connection: Option<usize>,
     /// What is the maximum number of bytes per second, on average, that a connection from a
     /// node is allowed to send to a shard before it gets booted. This is averaged over a
     /// rolling window of 10 seconds, and so spikes beyond this limit are allowed as long as
-    /// the average traffic in the last 10 seconds falls below this value.
+    /// the average traffic in the last 10 seconds falls below this value. Defaults to '256k'.
     ///
     /// As a reference point, syncing a new Polkadot node leads to a maximum of about 25k of
     /// traffic on average (at least initially).
-    #[structopt(long, default_value = "256k")]
-    max_node_data_per_second: ByteSize,
+    #[structopt(long, env = "TELEMETRY_SHARD_MAX_NODE_DATA_PER_SECOND")]
+    max_node_data_per_second: Option<ByteSize>,
     /// How many seconds is a "/feed" connection that violates the '--max-node-data-per-second'
-    /// value prevented from reconnecting to this shard for, in seconds.
-    #[structopt(long, default_value = "600")]
-    node_block_seconds: u64,
+    /// value prevented from reconnecting to this shard for, in seconds. Defaults to 600.
+    #[structopt(long, env = "TELEMETRY_SHARD_NODE_BLOCK_SECONDS")]
+    node_block_seconds: Option<u64>,
     /// Number of worker threads to spawn. If "0" is given, use the number of CPUs available
     /// on the machine. If no value is given, use an internal default that we have deemed sane.
-    #[structopt(long)]
+    #[structopt(long, env = "TELEMETRY_SHARD_WORKER_THREADS")]
     worker_threads: Option<usize>,
     /// Roughly how long to wait in seconds for new telemetry data to arrive from a node. If
     /// telemetry for a node does not arrive in this time frame, we remove the corresponding node
     /// state, and if no messages are received on the connection at all in this time, it will be
-    /// dropped.
-    #[structopt(long, default_value = "60")]
-    stale_node_timeout: u64,
+    /// dropped. Defaults to 60.
+    #[structopt(long, env = "TELEMETRY_SHARD_STALE_NODE_TIMEOUT")]
+    stale_node_timeout: Option<u64>,
+    /// On SIGTERM or SIGINT, we stop accepting connections, close the connections from nodes,
+    /// tell each core to remove our nodes and then disconnect from it. If this takes longer
+    /// than this many seconds, we exit anyway. Defaults to 10.
+    #[structopt(long, env = "TELEMETRY_SHARD_SHUTDOWN_DEADLINE")]
+    shutdown_deadline: Option<u64>,
+    /// Serve '/submit' and every other endpoint over TLS, using the PEM encoded certificate
+    /// chain in this file. Requires '--tls-key'.
+    #[structopt(long, env = "TELEMETRY_SHARD_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+    /// The PEM encoded private key for '--tls-cert'.
+    #[structopt(long, env = "TELEMETRY_SHARD_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+    /// How often, in seconds, to check '--tls-cert' and '--tls-key' for changes. Changed
+    /// certificates are used for new connections without needing a restart. Defaults to 60.
+    #[structopt(long, env = "TELEMETRY_SHARD_TLS_RELOAD_INTERVAL")]
+    tls_reload_interval: Option<u64>,
+    /// When connecting to a 'wss://' core, also trust the CA certificates in this PEM file
+    /// (by default, only the usual web roots are trusted).
+    #[structopt(long, env = "TELEMETRY_SHARD_CORE_TLS_CA")]
+    core_tls_ca: Option<PathBuf>,
+    /// Present the PEM encoded certificate chain in this file when connecting to a 'wss://'
+    /// core, for cores started with '--tls-client-ca'. Requires '--core-tls-key'.
+    #[structopt(long, env = "TELEMETRY_SHARD_CORE_TLS_CERT")]
+    core_tls_cert: Option<PathBuf>,
+    /// The PEM encoded private key for '--core-tls-cert'.
+    #[structopt(long, env = "TELEMETRY_SHARD_CORE_TLS_KEY")]
+    core_tls_key: Option<PathBuf>,
 }
 
 fn main() {
     let opts = Opts::from_args();
+    let config = match Config::load(opts.config.as_deref(), &opts) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {e:#}");
+            std::process::exit(1);
+        }
+    };
 
-    SimpleLogger::new()
-        .with_level(opts.log_level)
-        .init()
+    logging::init(config.log, config.log_format, &config.log_module)
         .expect("Must be able to start a logger");
 
     log::info!("Starting Telemetry Shard version: {}", VERSION);
 
-    let worker_threads = match opts.worker_threads {
+    let worker_threads = match config.worker_threads {
         Some(0) => num_cpus::get(),
         Some(n) => n,
         // By default, use a max of 4 worker threads, as we don't
@@ -129,78 +204,139 @@ fn main() {
         .build()
         .unwrap()
         .block_on(async {
-            if let Err(e) = start_server(opts).await {
+            if let Err(e) = start_server(config).await {
                 log::error!("Error starting server: {}", e);
             }
         });
 }
 
 /// Declare our routes and start the server.
-async fn start_server(opts: Opts) -> anyhow::Result<()> {
-    let block_list = BlockedAddrs::new(Duration::from_secs(opts.node_block_seconds));
-    let aggregator = Aggregator::spawn(opts.core_url).await?;
-    let socket_addr = opts.socket;
-    let max_nodes_per_connection = opts.max_nodes_per_connection;
-    let bytes_per_second = opts.max_node_data_per_second;
-    let stale_node_timeout = Duration::from_secs(opts.stale_node_timeout);
-
-    let server = http_utils::start_server(socket_addr, move |addr, req| {
-        let aggregator = aggregator.clone();
-        let block_list = block_list.clone();
-        async move {
-            match (req.method(), req.uri().path().trim_end_matches('/')) {
-                // Check that the server is up and running:
-                (&Method::GET, "/health") => Ok(Response::new("OK".into())),
-                // Nodes send messages here:
-                (&Method::GET, "/submit") => {
-                    let (real_addr, real_addr_source) = real_ip::real_ip(addr, req.headers());
-
-                    if let Some(reason) = block_list.blocked_reason(&real_addr) {
-                        return Ok(Response::builder().status(403).body(reason.into()).unwrap());
-                    }
+async fn start_server(config: Config) -> anyhow::Result<()> {
+    let config_json = Arc::new(serde_json::to_string_pretty(&config)?);
+    let block_list = BlockedAddrs::new(Duration::from_secs(config.node_block_seconds));
+    let core_buffer_len = config.core_buffer_len;
+    let core_tls = config
+        .core_tls_opts()
+        .map(|opts| tls::client_config(&opts))
+        .transpose()?;
+    let tls = config.tls_opts().map(TlsAcceptor::new).transpose()?;
+    let aggregator = AggregatorSet::spawn(
+        config
+            .core
+            .into_iter()
+            .map(|telemetry_uri| AggregatorOpts {
+                telemetry_uri,
+                core_buffer_len,
+                core_tls: core_tls.clone(),
+            })
+            .collect(),
+    )
+    .await?;
+    let socket_addr = config.listen;
+    let max_nodes_per_connection = config.max_nodes_per_connection;
+    let bytes_per_second = config.max_node_data_per_second;
+    let stale_node_timeout = Duration::from_secs(config.stale_node_timeout);
+    let shutdown_deadline = Duration::from_secs(config.shutdown_deadline);
+    let aggregator_for_shutdown = aggregator.clone();
+    let shutdown = Shutdown::new();
+    let shutdown_listener = shutdown.listener();
+
+    let server =
+        http_utils::start_server(socket_addr, tls, shutdown.started(), move |addr, req| {
+            let aggregator = aggregator.clone();
+            let block_list = block_list.clone();
+            let shutdown_listener = shutdown_listener.clone();
+            let config_json = Arc::clone(&config_json);
+            async move {
+                match (req.method(), req.uri().path().trim_end_matches('/')) {
+                    // Check that the server is up and running:
+                    (&Method::GET, "/health") => Ok(Response::new("OK".into())),
+                    // Return the configuration that we're running with:
+                    (&Method::GET, "/config") => Ok(Response::builder()
+                        .header("Content-Type", "application/json")
+                        .body(config_json.to_string().into())
+                        .unwrap()),
+                    // Nodes send messages here:
+                    (&Method::GET, "/submit") => {
+                        let (real_addr, real_addr_source) = real_ip::real_ip(addr, req.headers());
+
+                        if let Some(reason) = block_list.blocked_reason(&real_addr) {
+                            return Ok(Response::builder()
+                                .status(403)
+                                .body(reason.into())
+                                .unwrap());
+                        }
 
-                    Ok(http_utils::upgrade_to_websocket(
-                        req,
-                        move |ws_send, ws_recv| async move {
-                            log::info!(
-                                "Opening /submit connection from {:?} (address source: {})",
-                                real_addr,
-                                real_addr_source
-                            );
-                            let tx_to_aggregator = aggregator.subscribe_node();
-                            let (mut tx_to_aggregator, mut ws_send) =
-                                handle_node_websocket_connection(
+                        Ok(http_utils::upgrade_to_websocket(
+                            req,
+                            move |ws_send, ws_recv| async move {
+                                log::info!(
+                                    ip:% = real_addr;
+                                    "Opening /submit connection from {:?} (address source: {})",
+                                    real_addr,
+                                    real_addr_source
+                                );
+                                let tx_to_aggregator = aggregator.subscribe_node();
+                                let (mut tx_to_aggregator, mut ws_send) =
+                                    handle_node_websocket_connection(
+                                        real_addr,
+                                        ws_send,
+                                        ws_recv,
+                                        tx_to_aggregator,
+                                        max_nodes_per_connection,
+                                        bytes_per_second,
+                                        block_list,
+                                        stale_node_timeout,
+                                        shutdown_listener,
+                                    )
+                                    .await;
+                                log::info!(
+                                    ip:% = real_addr;
+                                    "Closing /submit connection from {:?} (address source: {})",
                                     real_addr,
-                                    ws_send,
-                                    ws_recv,
-                                    tx_to_aggregator,
-                                    max_nodes_per_connection,
-                                    bytes_per_second,
-                                    block_list,
-                                    stale_node_timeout,
-                                )
-                                .await;
-                            log::info!(
-                                "Closing /submit connection from {:?} (address source: {})",
-                                real_addr,
-                                real_addr_source
-                            );
-                            // Tell the aggregator that this connection has closed, so it can tidy up.
-                            let _ = tx_to_aggregator.send(FromWebsocket::Disconnected).await;
-                            let _ = ws_send.close().await;
-                        },
-                    ))
+                                    real_addr_source
+                                );
+                                // Tell the aggregator that this connection has closed, so it can tidy up.
+                                let _ = tx_to_aggregator.send(FromWebsocket::Disconnected).await;
+                                let _ = ws_send.close().await;
+                            },
+                        ))
+                    }
+                    // 404 for anything else:
+                    _ => Ok(Response::builder()
+                        .status(404)
+                        .body("Not found".into())
+                        .unwrap()),
                 }
-                // 404 for anything else:
-                _ => Ok(Response::builder()
-                    .status(404)
-                    .body("Not found".into())
-                    .unwrap()),
             }
-        }
-    });
+        });
+
+    let mut server = tokio::spawn(server);
+    tokio::select! {
+        res = &mut server => return res?,
+        _ = shutdown::signal() => {}
+    }
+
+    // Stop accepting connections and close the ones from nodes. Each node connection
+    // tells the aggregators that it's gone, which removes its nodes from the core(s).
+    log::info!("Shutting down");
+    let deadline = Instant::now() + shutdown_deadline;
+    if !shutdown.shutdown(shutdown_deadline).await {
+        log::warn!("Some node connections didn't close in time");
+    }
 
-    server.await?;
+    // Remove any nodes still around from the core(s), and disconnect from them:
+    let deadline = tokio::time::Instant::from_std(deadline);
+    if tokio::time::timeout_at(deadline, aggregator_for_shutdown.shutdown())
+        .await
+        .is_err()
+    {
+        log::warn!(
+            "Didn't disconnect from the telemetry core(s) within {}s; exiting anyway",
+            shutdown_deadline.as_secs()
+        );
+    }
+    let _ = tokio::time::timeout(Duration::from_secs(1), server).await;
     Ok(())
 }
 
@@ -214,6 +350,7 @@ async fn handle_node_websocket_connection<S>(
     bytes_per_second: ByteSize,
     block_list: BlockedAddrs,
     stale_node_timeout: Duration,
+    shutdown: ShutdownListener,
 ) -> (S, http_utils::WsSender)
 where
     S: futures::Sink<FromWebsocket, Error = anyhow::Error> + Unpin + Send + 'static,
@@ -230,19 +367,9 @@ where
         .window_size_multiple(10)
         .start();
 
-    // This could be a oneshot channel, but it's useful to be able to clone
-    // messages, and we can't clone oneshot channel senders.
+    // Used to tell the receiving task below to end once our main loop has finished.
     let (close_connection_tx, close_connection_rx) = flume::bounded(1);
 
-    // Tell the aggregator about this new connection, and give it a way to close this connection:
-    let init_msg = FromWebsocket::Initialize {
-        close_connection: close_connection_tx.clone(),
-    };
-    if let Err(e) = tx_to_aggregator.send(init_msg).await {
-        log::error!("Shutting down websocket connection from {real_addr:?}: Error sending message to aggregator: {e}");
-        return (tx_to_aggregator, ws_send);
-    }
-
     // Receiving data isn't cancel safe, so let it happen in a separate task.
     // If this loop ends, the outer will receive a `None` message and end too.
     // If the outer loop ends, it fires a msg on `close_connection_rx` to ensure this ends too.
@@ -254,7 +381,7 @@ where
                 // The close channel has fired, so end the loop. `ws_recv.receive_data` is
                 // *not* cancel safe, but since we're closing the connection we don't care.
                 _ = close_connection_rx.recv_async() => {
-                    log::info!("connection to {real_addr:?} being closed");
+                    log::info!(ip:% = real_addr; "connection to {real_addr:?} being closed");
                     break
                 },
                 // Receive data and relay it on to our main select loop below.
@@ -263,7 +390,7 @@ where
                         break;
                     }
                     if let Err(e) = msg_info {
-                        log::error!("Shutting down websocket connection from {real_addr:?}: Failed to receive data: {e}");
+                        log::error!(ip:% = real_addr; "Shutting down websocket connection from {real_addr:?}: Failed to receive data: {e}");
                         break;
                     }
                     if ws_tx_atomic.unbounded_send(bytes).is_err() {
@@ -282,6 +409,11 @@ where
     // and periodically checks for stale connections to keep our node state tidy.
     loop {
         tokio::select! {
+            // Close the connection if we're shutting down:
+            _ = shutdown.recv() => {
+                log::info!(ip:% = real_addr; "Closing connection from {real_addr:?}: the shard is shutting down");
+                break;
+            },
             // We periodically check for stale message IDs and remove nodes associated with
             // them, to prevent a buildup. We boot the whole connection if no interpretable
             // messages have been sent at all in the time period.
@@ -292,14 +424,14 @@ where
                     .collect();
 
                 for &message_id in &stale_ids {
-                    log::info!("Removing stale node with message ID {message_id} from {real_addr:?}");
+                    log::info!(message_id = message_id, ip:% = real_addr; "Removing stale node with message ID {message_id} from {real_addr:?}");
                     allowed_message_ids.remove(&message_id);
                     let _ = tx_to_aggregator.send(FromWebsocket::Remove { message_id } ).await;
                 }
 
                 if !stale_ids.is_empty() && allowed_message_ids.is_empty() {
                     // End the entire connection if no recent messages came in for any ID.
-                    log::info!("Closing stale connection from {real_addr:?}");
+                    log::info!(ip:% = real_addr; "Closing stale connection from {real_addr:?}");
                     break;
                 }
             },
@@ -347,19 +479,19 @@ where
                 if let node_message::Payload::SystemConnected(info) = payload {
                     // Too many nodes seen on this connection? Ignore this one.
                     if allowed_message_ids.len() >= max_nodes_per_connection {
-                        log::info!("Ignoring new node with ID {message_id} from {real_addr:?} (we've hit the max of {max_nodes_per_connection} nodes per connection)");
+                        log::info!(message_id = message_id, ip:% = real_addr; "Ignoring new node with ID {message_id} from {real_addr:?} (we've hit the max of {max_nodes_per_connection} nodes per connection)");
                         continue;
                     }
 
                     // Note of the message ID, allowing telemetry for it.
                     let prev_join_time = allowed_message_ids.insert(message_id, Instant::now());
                     if prev_join_time.is_some() {
-                        log::info!("Ignoring duplicate new node with ID {message_id} from {real_addr:?}");
+                        log::info!(message_id = message_id, ip:% = real_addr; "Ignoring duplicate new node with ID {message_id} from {real_addr:?}");
                         continue;
                     }
 
                     // Tell the aggregator loop about the new node.
-                    log::info!("Adding node with message ID {message_id} from {real_addr:?}");
+                    log::info!(message_id = message_id, ip:% = real_addr; "Adding node with message ID {message_id} from {real_addr:?}");
                     let _ = tx_to_aggregator.send(FromWebsocket::Add {
                         message_id,
                         ip: real_addr,
@@ -377,7 +509,7 @@ where
                             continue;
                         }
                     } else {
-                        log::info!("Ignoring message with ID {message_id} from {real_addr:?} (we've hit the max of {max_nodes_per_connection} nodes per connection)");
+                        log::info!(message_id = message_id, ip:% = real_addr; "Ignoring message with ID {message_id} from {real_addr:?} (we've hit the max of {max_nodes_per_connection} nodes per connection)");
                         continue;
                     }
                 }
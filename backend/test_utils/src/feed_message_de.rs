@@ -43,6 +43,8 @@ pub enum FeedMessage {
         location: Option<NodeLocation>,
         startup_time: Option<Timestamp>,
         hwbench: Option<NodeHwBench>,
+        hardware_seq: u64,
+        io_seq: u64,
     },
     RemovedNode {
         node_id: usize,
@@ -122,6 +124,48 @@ pub enum FeedMessage {
         node_id: usize,
         // details: NodeIO, // can't losslessly deserialize
     },
+    HardwareDelta {
+        node_id: usize,
+        seq: u64,
+        upload: SeriesDelta,
+        download: SeriesDelta,
+        // chart_stamps: SeriesDelta, // depends on the time
+        /// The same as `seq` unless several deltas were merged into this one.
+        first_seq: u64,
+    },
+    NodeIODelta {
+        node_id: usize,
+        seq: u64,
+        used_state_cache_size: SeriesDelta,
+        /// The same as `seq` unless several deltas were merged into this one.
+        first_seq: u64,
+    },
+    ChainContext {
+        genesis_hash: BlockHash,
+    },
+    CommandAck {
+        request_id: u64,
+    },
+    CommandError {
+        request_id: u64,
+        reason: String,
+    },
+    ChainSeq {
+        seq: u64,
+    },
+    Resumed {
+        genesis_hash: BlockHash,
+    },
+    AuthoritiesUpdate {
+        set_id: Option<u64>,
+        set_size: Option<usize>,
+        reporting: usize,
+        stale: Vec<String>,
+        stopped: Vec<(String, Timestamp)>,
+        missing: Vec<String>,
+        /// Version, validator count and full node count.
+        versions: Vec<(String, u64, u64)>,
+    },
     /// A "special" case when we don't know how to decode an action:
     UnknownValue {
         action: u8,
@@ -129,6 +173,9 @@ pub enum FeedMessage {
     },
 }
 
+/// The number of samples to drop from the start of a series, and the samples to append.
+pub type SeriesDelta = (u8, Vec<f64>);
+
 #[derive(Debug, PartialEq)]
 pub struct NodeDetails {
     pub name: String,
@@ -196,6 +243,8 @@ impl FeedMessage {
                     block_details,
                     location,
                     startup_time,
+                    hardware_seq,
+                    io_seq,
                 ) = serde_json::from_str(raw_val.get())?;
 
                 // Give these two types but don't use the results:
@@ -217,6 +266,8 @@ impl FeedMessage {
                     location,
                     startup_time,
                     hwbench,
+                    hardware_seq,
+                    io_seq,
                 }
             }
             // RemoveNode
@@ -348,6 +399,69 @@ impl FeedMessage {
                 let (node_id, _node_io): (_, &RawValue) = serde_json::from_str(raw_val.get())?;
                 FeedMessage::NodeIOUpdate { node_id }
             }
+            // HardwareDelta
+            23 => {
+                let (fields, first_seq) = split_first_seq(raw_val, 5)?;
+                let (node_id, seq, upload, download, _chart_stamps): (_, _, _, _, SeriesDelta) =
+                    serde_json::from_value(fields)?;
+                FeedMessage::HardwareDelta {
+                    node_id,
+                    seq,
+                    upload,
+                    download,
+                    first_seq: first_seq.unwrap_or(seq),
+                }
+            }
+            // NodeIODelta
+            24 => {
+                let (fields, first_seq) = split_first_seq(raw_val, 3)?;
+                let (node_id, seq, used_state_cache_size) = serde_json::from_value(fields)?;
+                FeedMessage::NodeIODelta {
+                    node_id,
+                    seq,
+                    used_state_cache_size,
+                    first_seq: first_seq.unwrap_or(seq),
+                }
+            }
+            // ChainContext
+            25 => {
+                let genesis_hash = serde_json::from_str(raw_val.get())?;
+                FeedMessage::ChainContext { genesis_hash }
+            }
+            // CommandAck
+            26 => {
+                let request_id = serde_json::from_str(raw_val.get())?;
+                FeedMessage::CommandAck { request_id }
+            }
+            // CommandError
+            27 => {
+                let (request_id, reason) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::CommandError { request_id, reason }
+            }
+            // ChainSeq
+            28 => {
+                let seq = serde_json::from_str(raw_val.get())?;
+                FeedMessage::ChainSeq { seq }
+            }
+            // Resumed
+            29 => {
+                let genesis_hash = serde_json::from_str(raw_val.get())?;
+                FeedMessage::Resumed { genesis_hash }
+            }
+            // AuthoritiesUpdate
+            31 => {
+                let (set_id, set_size, reporting, stale, stopped, missing, versions) =
+                    serde_json::from_str(raw_val.get())?;
+                FeedMessage::AuthoritiesUpdate {
+                    set_id,
+                    set_size,
+                    reporting,
+                    stale,
+                    stopped,
+                    missing,
+                    versions,
+                }
+            }
             // A catchall for messages we don't know/care about yet:
             _ => {
                 let value = raw_val.to_string();
@@ -359,6 +473,20 @@ impl FeedMessage {
     }
 }
 
+/// Deltas merged from several are followed by the sequence number of the first of them.
+/// Split that off from the `len` fields that every delta has.
+fn split_first_seq(
+    raw_val: &RawValue,
+    len: usize,
+) -> Result<(serde_json::Value, Option<u64>), anyhow::Error> {
+    let mut fields: Vec<serde_json::Value> = serde_json::from_str(raw_val.get())?;
+    let first_seq = match fields.len() > len {
+        true => Some(serde_json::from_value(fields.pop().unwrap())?),
+        false => None,
+    };
+    Ok((fields.into(), first_seq))
+}
+
 #[cfg(test)]
 mod test {
 
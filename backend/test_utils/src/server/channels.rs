@@ -130,6 +130,14 @@ impl FeedSender {
             param.as_ref()
         )))
     }
+    /// Send a command in the JSON form, eg `{"id":1,"cmd":"subscribe","chain":"0x..."}`.
+    pub fn send_json_command(
+        &self,
+        json: serde_json::Value,
+    ) -> Result<(), channel::mpsc::SendError> {
+        let s = serde_json::to_string(&json).expect("valid string");
+        self.unbounded_send(ws_client::SentMessage::Text(s))
+    }
 }
 
 /// Wrap a `ws_client::Receiver` with convenient utility methods for feed connections
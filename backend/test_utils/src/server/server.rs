@@ -19,6 +19,7 @@ use common::ws_client;
 use common::{id_type, DenseMap};
 use std::ffi::OsString;
 use std::marker::PhantomData;
+use std::process::ExitStatus;
 use tokio::process::{self, Command as TokioCommand};
 
 id_type! {
@@ -80,6 +81,8 @@ pub enum ServerMode {
     ShardAndCoreMode {
         /// Command to run to start a new shard.
         shard_command: Command,
+        /// Command to run to (re)start the core.
+        core_command: Command,
         /// Shard processes that we can connect to.
         shards: DenseMap<ProcessId, ShardProcess>,
     },
@@ -106,10 +109,16 @@ pub enum Error {
     ErrorObtainingPort(anyhow::Error),
     #[error("Whoops; attempt to kill a process we didn't start (and so have no handle to)")]
     CannotKillNoHandle,
+    #[error("No shard exists with the ID given")]
+    NoSuchShard,
     #[error(
         "Can't add a shard: command not provided, or we are not in charge of spawning processes"
     )]
     CannotAddShard,
+    #[error(
+        "Can't restart the core: we are not in charge of spawning the core and shard processes"
+    )]
+    CannotRestartCore,
     #[error("The URI provided was invalid: {0}")]
     InvalidUri(#[from] http::uri::InvalidUri),
 }
@@ -150,6 +159,56 @@ impl Server {
         true
     }
 
+    /// Ask a shard to shut down gracefully, as it would be by a process manager, and
+    /// wait for it to exit. Returns the exit status of the shard process.
+    pub async fn terminate_shard(&mut self, id: ProcessId) -> Result<ExitStatus, Error> {
+        let shard = match &mut self.mode {
+            ServerMode::SingleProcessMode { .. } => None,
+            ServerMode::ShardAndCoreMode { shards, .. } => shards.remove(id),
+            ServerMode::ConnectToExistingMode { shards, .. } => shards.remove(id),
+        };
+
+        shard.ok_or(Error::NoSuchShard)?.terminate().await
+    }
+
+    /// Kill the core process, leaving any shards that we've started running. The core can
+    /// be started again with [`Server::restart_core`].
+    pub async fn stop_core(&mut self) -> Result<(), Error> {
+        if !matches!(self.mode, ServerMode::ShardAndCoreMode { .. }) {
+            return Err(Error::CannotRestartCore);
+        }
+
+        let host = self.core.host.clone();
+        let old_core = std::mem::replace(
+            &mut self.core,
+            Process {
+                id: ProcessId(0),
+                host,
+                handle: None,
+                _channel_type: PhantomData,
+            },
+        );
+        old_core.kill().await
+    }
+
+    /// Start a new core process listening on the same address as the last one, killing the
+    /// current one first if it's still running. Shards that we've started are not touched,
+    /// and will reconnect to the new core on their own.
+    pub async fn restart_core(&mut self) -> Result<(), Error> {
+        let core_command = match &self.mode {
+            ServerMode::ShardAndCoreMode { core_command, .. } => core_command.clone(),
+            _ => return Err(Error::CannotRestartCore),
+        };
+
+        if self.core.handle.is_some() {
+            self.stop_core().await?;
+        }
+
+        let host = self.core.host.clone();
+        self.core = Server::start_core(self.log_output, core_command, &host).await?;
+        Ok(())
+    }
+
     /// Kill everything and tidy up
     pub async fn shutdown(self) {
         // Spawn so we don't need to await cleanup if we don't care.
@@ -172,6 +231,16 @@ impl Server {
 
     /// Connect a new shard and return a process that you can interact with:
     pub async fn add_shard(&mut self) -> Result<ProcessId, Error> {
+        self.add_shard_with_standby_cores(&[]).await
+    }
+
+    /// Connect a new shard which will fail over to the standby core hosts given (eg
+    /// `127.0.0.1:8000`) if its connection to our core fails, and return a process that
+    /// you can interact with. Standby cores are ignored unless we started the shard.
+    pub async fn add_shard_with_standby_cores(
+        &mut self,
+        standby_core_hosts: &[&str],
+    ) -> Result<ProcessId, Error> {
         match &mut self.mode {
             // Always get back the same "virtual" shard; we're always just talking to the core anyway.
             ServerMode::SingleProcessMode { virtual_shard, .. } => Ok(virtual_shard.id),
@@ -202,9 +271,14 @@ impl Server {
             ServerMode::ShardAndCoreMode {
                 shard_command,
                 shards,
+                ..
             } => {
-                // Where is the URI we'll want to submit things to?
-                let core_shard_submit_uri = format!("http://{}/shard_submit", self.core.host);
+                // Where is the URI we'll want to submit things to (followed by any we'll fail over to)?
+                let core_shard_submit_uri = std::iter::once(self.core.host.as_str())
+                    .chain(standby_core_hosts.iter().copied())
+                    .map(|host| format!("http://{}/shard_submit", host))
+                    .collect::<Vec<_>>()
+                    .join(",");
 
                 let mut shard_cmd: TokioCommand = shard_command.clone().into();
                 shard_cmd
@@ -226,8 +300,9 @@ impl Server {
 
                 // Attempt to wait until we've received word that the shard is connected to the
                 // core before continuing. If we don't wait for this, the connection may happen
-                // after we've attempted to connect node sockets, and they would be booted and
-                // made to reconnect, which we don't want to deal with in general.
+                // after we've attempted to connect node sockets, and updates sent by those nodes
+                // in the meantime would not make it to the core, which we don't want to deal
+                // with in general.
                 let _ = utils::wait_for_line_containing(
                     &mut child_stdout,
                     |s| s.contains("Connected to telemetry core"),
@@ -262,7 +337,7 @@ impl Server {
                 command,
                 log_output,
             } => {
-                let core_process = Server::start_core(log_output, command).await?;
+                let core_process = Server::start_core(log_output, command, "127.0.0.1:0").await?;
                 let virtual_shard_host = core_process.host.clone();
                 Server {
                     log_output,
@@ -282,12 +357,14 @@ impl Server {
                 shard_command,
                 log_output,
             } => {
-                let core_process = Server::start_core(log_output, core_command).await?;
+                let core_process =
+                    Server::start_core(log_output, core_command.clone(), "127.0.0.1:0").await?;
                 Server {
                     log_output,
                     core: core_process,
                     mode: ServerMode::ShardAndCoreMode {
                         shard_command,
+                        core_command,
                         shards: DenseMap::new(),
                     },
                 }
@@ -315,12 +392,17 @@ impl Server {
         Ok(server)
     }
 
-    /// Start up a core process and return it.
-    async fn start_core(log_output: bool, command: Command) -> Result<CoreProcess, Error> {
+    /// Start up a core process listening on the address given (use port 0 to have
+    /// a port picked by the kernel) and return it.
+    async fn start_core(
+        log_output: bool,
+        command: Command,
+        listen: &str,
+    ) -> Result<CoreProcess, Error> {
         let mut tokio_core_cmd: TokioCommand = command.into();
         let mut child = tokio_core_cmd
             .arg("--listen")
-            .arg("127.0.0.1:0") // 0 to have a port picked by the kernel
+            .arg(listen)
             .arg("--log")
             .arg("info")
             .kill_on_drop(true)
@@ -383,6 +465,20 @@ impl<Channel> Process<Channel> {
         &self.host
     }
 
+    /// Send SIGTERM to the process and wait for it to exit.
+    /// Not public: terminating is done via Server.
+    async fn terminate(self) -> Result<ExitStatus, Error> {
+        let mut handle = self.handle.ok_or(Error::CannotKillNoHandle)?;
+        if let Some(pid) = handle.id() {
+            TokioCommand::new("kill")
+                .arg("-TERM")
+                .arg(pid.to_string())
+                .status()
+                .await?;
+        }
+        Ok(handle.wait().await?)
+    }
+
     /// Kill the process and wait for this to complete
     /// Not public: Klling done via Server.
     async fn kill(self) -> Result<(), Error> {
@@ -461,6 +557,15 @@ impl CoreProcess {
         connect_to_uri_raw(&uri).await
     }
 
+    /// Establish a raw connection to the process, adding the query string given to the feed URI
+    pub async fn connect_feed_raw_with_query(
+        &self,
+        query: &str,
+    ) -> Result<(ws_client::RawSender, ws_client::RawReceiver), Error> {
+        let uri = format!("http://{}/feed?{}", self.host, query).parse()?;
+        connect_to_uri_raw(&uri).await
+    }
+
     /// Establish a connection to the process
     pub async fn connect_feed(
         &self,
@@ -24,19 +24,24 @@ use tokio::time::Duration;
 /// with the side benefit that we'll wait for it to start listening before returning. We do this
 /// because we want to allow the kernel to assign ports and so don't specify a port as an arg.
 pub async fn get_port<R: AsyncRead + Unpin>(reader: R) -> Result<u16, anyhow::Error> {
-    // For the new service:
-    let new_expected_text = "listening on http://127.0.0.1:";
-    // For the older non-sharded actix based service:
-    let old_expected_text = "service on 127.0.0.1:";
+    let expected_texts = [
+        // For the new service:
+        "listening on http://127.0.0.1:",
+        // For the new service when it's terminating TLS:
+        "listening on https://127.0.0.1:",
+        // For the older non-sharded actix based service:
+        "service on 127.0.0.1:",
+    ];
 
-    let is_text = |s: &str| s.contains(new_expected_text) || s.contains(old_expected_text);
+    let is_text = |s: &str| expected_texts.iter().any(|text| s.contains(text));
     wait_for_line_containing(reader, is_text, Duration::from_secs(240))
         .await
         .and_then(|line| {
             // The line must match one of our expected strings:
-            let (_, port_str) = line
-                .rsplit_once(new_expected_text)
-                .unwrap_or_else(|| line.rsplit_once(old_expected_text).unwrap());
+            let (_, port_str) = expected_texts
+                .iter()
+                .find_map(|text| line.rsplit_once(text))
+                .unwrap();
             // Grab the port after the string:
             port_str
                 .trim()
@@ -35,16 +35,37 @@ impl Default for ServerOpts {
 /// Additional options to pass to the core command.
 pub struct CoreOpts {
     pub feed_timeout: Option<u64>,
+    pub feed_coalesce_backlog: Option<usize>,
     pub worker_threads: Option<usize>,
-    pub num_aggregators: Option<usize>,
+    pub feed_workers: Option<usize>,
+    pub chain_partitions: Option<usize>,
+    pub denylist: Option<Vec<String>>,
+    /// Host of a core (eg `127.0.0.1:8000`) that this core should stand by for.
+    pub standby_of: Option<String>,
+    pub standby_interval: Option<u64>,
+    /// File to periodically write state snapshots to.
+    pub snapshot_path: Option<std::path::PathBuf>,
+    pub snapshot_interval: Option<u64>,
+    /// File to restore state from when the core starts.
+    pub restore_from: Option<std::path::PathBuf>,
+    pub restore_timeout: Option<u64>,
 }
 
 impl Default for CoreOpts {
     fn default() -> Self {
         Self {
             feed_timeout: None,
+            feed_coalesce_backlog: None,
             worker_threads: None,
-            num_aggregators: None,
+            feed_workers: None,
+            chain_partitions: None,
+            denylist: None,
+            standby_of: None,
+            standby_interval: None,
+            snapshot_path: None,
+            snapshot_interval: None,
+            restore_from: None,
+            restore_timeout: None,
         }
     }
 }
@@ -55,6 +76,10 @@ pub struct ShardOpts {
     pub max_node_data_per_second: Option<usize>,
     pub node_block_seconds: Option<u64>,
     pub worker_threads: Option<usize>,
+    pub core_buffer_len: Option<usize>,
+    /// Hosts of any other cores (eg `127.0.0.1:8000`) that shards should
+    /// forward node messages to, in addition to the core we start.
+    pub extra_core_hosts: Vec<String>,
 }
 
 impl Default for ShardOpts {
@@ -64,6 +89,8 @@ impl Default for ShardOpts {
             max_node_data_per_second: None,
             node_block_seconds: None,
             worker_threads: None,
+            core_buffer_len: None,
+            extra_core_hosts: Vec::new(),
         }
     }
 }
@@ -142,6 +169,14 @@ pub async fn start_server(
     if let Some(val) = shard_opts.worker_threads {
         shard_command = shard_command.arg("--worker-threads").arg(val.to_string());
     }
+    if let Some(val) = shard_opts.core_buffer_len {
+        shard_command = shard_command.arg("--core-buffer-len").arg(val.to_string());
+    }
+    for host in shard_opts.extra_core_hosts {
+        shard_command = shard_command
+            .arg("--core")
+            .arg(format!("http://{}/shard_submit", host));
+    }
 
     // Build the core command
     let mut core_command = std::env::var("TELEMETRY_CORE_BIN")
@@ -155,11 +190,45 @@ pub async fn start_server(
     if let Some(val) = core_opts.feed_timeout {
         core_command = core_command.arg("--feed-timeout").arg(val.to_string());
     }
+    if let Some(val) = core_opts.feed_coalesce_backlog {
+        core_command = core_command
+            .arg("--feed-coalesce-backlog")
+            .arg(val.to_string());
+    }
     if let Some(val) = core_opts.worker_threads {
         core_command = core_command.arg("--worker-threads").arg(val.to_string());
     }
-    if let Some(val) = core_opts.num_aggregators {
-        core_command = core_command.arg("--num-aggregators").arg(val.to_string());
+    if let Some(val) = core_opts.feed_workers {
+        core_command = core_command.arg("--feed-workers").arg(val.to_string());
+    }
+    if let Some(val) = core_opts.chain_partitions {
+        core_command = core_command.arg("--chain-partitions").arg(val.to_string());
+    }
+    if let Some(val) = core_opts.standby_of {
+        core_command = core_command
+            .arg("--standby-of")
+            .arg(format!("http://{}", val));
+    }
+    if let Some(val) = core_opts.standby_interval {
+        core_command = core_command.arg("--standby-interval").arg(val.to_string());
+    }
+    if let Some(val) = core_opts.snapshot_path {
+        core_command = core_command.arg("--snapshot-path").arg(val);
+    }
+    if let Some(val) = core_opts.snapshot_interval {
+        core_command = core_command.arg("--snapshot-interval").arg(val.to_string());
+    }
+    if let Some(val) = core_opts.restore_from {
+        core_command = core_command.arg("--restore-from").arg(val);
+    }
+    if let Some(val) = core_opts.restore_timeout {
+        core_command = core_command.arg("--restore-timeout").arg(val.to_string());
+    }
+    if let Some(val) = core_opts.denylist {
+        core_command = core_command.arg("--denylist");
+        for chain in val {
+            core_command = core_command.arg(chain);
+        }
     }
 
     // Start the server
@@ -113,6 +113,7 @@ export default class App extends React.Component {
       selectedColumns: this.selectedColumns(this.settings.raw()),
       tab,
       chainStats: null,
+      authorities: null,
     });
     this.appState = this.appUpdate({});
 
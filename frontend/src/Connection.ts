@@ -123,6 +123,11 @@ export class Connection {
   private pingSent: Maybe<Types.Timestamp> = null;
   // chain label to resubsribe to on reconnect
   private resubscribeTo: Maybe<Types.GenesisHash> = getHashData().chain;
+  // the last numbered batch of messages seen about a chain, so that we can
+  // resume from there on reconnect
+  private lastSeq: Maybe<[Types.GenesisHash, number]> = null;
+  // chain we've asked to resume, and are waiting to hear back about
+  private resuming: Maybe<Types.GenesisHash> = null;
 
   constructor(
     private socket: WebSocket,
@@ -134,14 +139,16 @@ export class Connection {
   }
 
   public subscribe(chain: Types.GenesisHash) {
-    if (
-      this.appState.subscribed != null &&
-      this.appState.subscribed !== chain
-    ) {
+    const { subscribed } = this.appState;
+
+    if (subscribed != null && subscribed !== chain) {
       this.appUpdate({
         tab: 'list',
       });
       setHashData({ chain, tab: 'list' });
+
+      // Feeds can follow several chains at once, but we only show one:
+      this.socket.send(`unsubscribe:${subscribed}`);
     } else {
       setHashData({ chain });
     }
@@ -155,6 +162,7 @@ export class Connection {
     const nodesStateRef = nodes.ref;
 
     let sortByColumn: Maybe<Column> = null;
+    let context: Maybe<Types.GenesisHash> = null;
 
     if (sortBy != null) {
       sortByColumn =
@@ -199,6 +207,8 @@ export class Connection {
             blockDetails,
             location,
             startupTime,
+            hardwareSeq,
+            ioSeq,
           ] = message.payload;
           const pinned = this.pins.has(nodeDetails[0]);
           const node = new Node(
@@ -210,7 +220,9 @@ export class Connection {
             nodeHardware,
             blockDetails,
             location,
-            startupTime
+            startupTime,
+            hardwareSeq,
+            ioSeq
           );
 
           nodes.add(node);
@@ -303,6 +315,51 @@ export class Connection {
           break;
         }
 
+        case ACTIONS.NodeHardwareDelta: {
+          const [id, seq, upload, download, chartstamps, firstSeq = seq] =
+            message.payload;
+          let inSequence = true;
+
+          nodes.mutAndMaybeSort(
+            id,
+            (node) => {
+              inSequence = node.applyHardwareDelta(
+                firstSeq,
+                seq,
+                upload,
+                download,
+                chartstamps
+              );
+            },
+            sortByColumn === UploadColumn || sortByColumn === DownloadColumn
+          );
+
+          if (!inSequence) {
+            this.requestSeries(id);
+          }
+
+          break;
+        }
+
+        case ACTIONS.NodeIODelta: {
+          const [id, seq, stateCacheSize, firstSeq = seq] = message.payload;
+          let inSequence = true;
+
+          nodes.mutAndMaybeSort(
+            id,
+            (node) => {
+              inSequence = node.applyIODelta(firstSeq, seq, stateCacheSize);
+            },
+            sortByColumn === StateCacheColumn
+          );
+
+          if (!inSequence) {
+            this.requestSeries(id);
+          }
+
+          break;
+        }
+
         case ACTIONS.TimeSync: {
           this.appUpdate({
             timeDiff: (timestamp() - message.payload) as Types.Milliseconds,
@@ -339,12 +396,36 @@ export class Connection {
 
         case ACTIONS.SubscribedTo: {
           nodes.clear();
+          this.resuming = null;
 
           this.appUpdate({ subscribed: message.payload, nodes });
 
           break;
         }
 
+        case ACTIONS.Resumed: {
+          // We keep the nodes we had, and are sent what we missed next:
+          this.resuming = null;
+
+          this.appUpdate({ subscribed: message.payload });
+
+          break;
+        }
+
+        case ACTIONS.ChainContext: {
+          context = message.payload;
+
+          break;
+        }
+
+        case ACTIONS.ChainSeq: {
+          if (context) {
+            this.lastSeq = [context, message.payload];
+          }
+
+          break;
+        }
+
         case ACTIONS.UnsubscribedFrom: {
           if (this.appState.subscribed === message.payload) {
             nodes.clear();
@@ -366,6 +447,22 @@ export class Connection {
           break;
         }
 
+        case ACTIONS.ChainStatsDelta: {
+          const { chainStats } = this.appState;
+
+          if (chainStats) {
+            this.appUpdate({
+              chainStats: { ...chainStats, ...message.payload },
+            });
+          }
+          break;
+        }
+
+        case ACTIONS.AuthoritiesUpdate: {
+          this.appUpdate({ authorities: message.payload });
+          break;
+        }
+
         default: {
           break;
         }
@@ -389,10 +486,12 @@ export class Connection {
     // Ping periodically to keep the above happy even if no other data is coming in:
     this.ping();
 
-    if (this.appState) {
+    // Keep the nodes we have if we'll be able to resume where we left off:
+    if (this.appState && !this.canResume(this.appState.subscribed)) {
       const { nodes } = this.appState;
       nodes.clear();
     }
+    this.resuming = null;
 
     this.appUpdate({
       status: 'online',
@@ -491,13 +590,13 @@ export class Connection {
     const { subscribed, chains } = this.appState;
     const { resubscribeTo } = this;
 
-    if (subscribed) {
+    if (subscribed || this.resuming) {
       return;
     }
 
     if (resubscribeTo) {
       if (chains.has(resubscribeTo)) {
-        this.subscribe(resubscribeTo);
+        this.resubscribe(resubscribeTo);
         return;
       }
     }
@@ -520,6 +619,32 @@ export class Connection {
     }
   }
 
+  private canResume(chain: Maybe<Types.GenesisHash>): boolean {
+    return chain != null && this.lastSeq != null && this.lastSeq[0] === chain;
+  }
+
+  // Pick up where we left off with a chain after reconnecting, if we can:
+  private resubscribe(chain: Types.GenesisHash) {
+    if (!this.lastSeq || !this.canResume(chain)) {
+      this.subscribe(chain);
+      return;
+    }
+
+    setHashData({ chain });
+    this.resuming = chain;
+    this.socket.send(`resume:${chain}:${this.lastSeq[1]}`);
+  }
+
+  // Ask for the whole of a node's hardware and IO series again, having missed
+  // some updates to them:
+  private requestSeries(id: Types.NodeId) {
+    const { subscribed } = this.appState;
+
+    if (subscribed != null) {
+      this.socket.send(`series:${subscribed}:${id}`);
+    }
+  }
+
   private handleDisconnect = async () => {
     console.warn('Disconnecting; will attempt reconnect');
     this.appUpdate({ status: 'offline' });
@@ -29,6 +29,9 @@ import {
   NodeIO,
   NodeHardware,
   NodeLocation,
+  SeriesDelta,
+  Bytes,
+  BytesPerSecond,
   BlockNumber,
   BlockHash,
   BlockDetails,
@@ -37,6 +40,7 @@ import {
   ChainLabel,
   GenesisHash,
   AuthoritySetInfo,
+  AuthoritiesSummary,
   ChainStats,
 } from './types';
 
@@ -64,6 +68,13 @@ export const ACTIONS = {
   StaleNode: 0x14 as const,
   NodeIO: 0x15 as const,
   ChainStatsUpdate: 0x16 as const,
+  NodeHardwareDelta: 0x17 as const,
+  NodeIODelta: 0x18 as const,
+  ChainContext: 0x19 as const,
+  ChainSeq: 0x1c as const,
+  Resumed: 0x1d as const,
+  ChainStatsDelta: 0x1e as const,
+  AuthoritiesUpdate: 0x1f as const,
 };
 
 export type Action = typeof ACTIONS[keyof typeof ACTIONS];
@@ -98,7 +109,9 @@ interface AddedNodeMessage extends MessageBase {
     NodeHardware,
     BlockDetails,
     Maybe<NodeLocation>,
-    Maybe<Timestamp>
+    Maybe<Timestamp>,
+    number,
+    number
   ];
 }
 
@@ -137,6 +150,41 @@ interface NodeIOMessage extends MessageBase {
   payload: [NodeId, NodeIO];
 }
 
+interface NodeHardwareDeltaMessage extends MessageBase {
+  action: typeof ACTIONS.NodeHardwareDelta;
+  payload: [
+    NodeId,
+    number,
+    SeriesDelta<BytesPerSecond>,
+    SeriesDelta<BytesPerSecond>,
+    SeriesDelta<Timestamp>,
+    // The seq of the first delta, if several were merged into this one:
+    number?
+  ];
+}
+
+interface NodeIODeltaMessage extends MessageBase {
+  action: typeof ACTIONS.NodeIODelta;
+  payload: [NodeId, number, SeriesDelta<Bytes>, number?];
+}
+
+// The messages after this one are about the chain with this genesis hash:
+interface ChainContextMessage extends MessageBase {
+  action: typeof ACTIONS.ChainContext;
+  payload: GenesisHash;
+}
+
+// The messages about the current chain up to this one are numbered this:
+interface ChainSeqMessage extends MessageBase {
+  action: typeof ACTIONS.ChainSeq;
+  payload: number;
+}
+
+interface ResumedMessage extends MessageBase {
+  action: typeof ACTIONS.Resumed;
+  payload: GenesisHash;
+}
+
 interface TimeSyncMessage extends MessageBase {
   action: typeof ACTIONS.TimeSync;
   payload: Timestamp;
@@ -197,6 +245,16 @@ interface ChainStatsUpdate extends MessageBase {
   payload: ChainStats;
 }
 
+interface ChainStatsDelta extends MessageBase {
+  action: typeof ACTIONS.ChainStatsDelta;
+  payload: Partial<ChainStats>;
+}
+
+interface AuthoritiesUpdate extends MessageBase {
+  action: typeof ACTIONS.AuthoritiesUpdate;
+  payload: AuthoritiesSummary;
+}
+
 export type Message =
   | FeedVersionMessage
   | BestBlockMessage
@@ -220,7 +278,14 @@ export type Message =
   | StaleNodeMessage
   | PongMessage
   | NodeIOMessage
-  | ChainStatsUpdate;
+  | NodeHardwareDeltaMessage
+  | NodeIODeltaMessage
+  | ChainContextMessage
+  | ChainSeqMessage
+  | ResumedMessage
+  | ChainStatsUpdate
+  | ChainStatsDelta
+  | AuthoritiesUpdate;
 
 /**
  * Data type to be sent to the feed. Passing through strings means we can only serialize once,
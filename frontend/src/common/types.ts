@@ -66,6 +66,8 @@ export type NodeHardware = [
   Array<Timestamp>
 ];
 export type NodeLocation = [Latitude, Longitude, City];
+// The number of samples to drop from the start of a series, followed by the samples to append.
+export type SeriesDelta<T> = [number, Array<T>];
 
 export interface Authority {
   Address: Address;
@@ -74,6 +76,17 @@ export interface Authority {
 }
 export declare type Authorities = Array<Address>;
 export declare type AuthoritySetId = Opaque<number, 'AuthoritySetId'>;
+// A version, followed by how many validators and full nodes are running it.
+export type VersionValidators = [NodeVersion, number, number];
+export type AuthoritiesSummary = [
+  Maybe<AuthoritySetId>,
+  Maybe<number>,
+  number,
+  Authorities,
+  Array<[Address, Timestamp]>,
+  Authorities,
+  Array<VersionValidators>
+];
 export declare type AuthoritySetInfo = [
   AuthoritySetId,
   Authorities,
@@ -112,4 +125,8 @@ export type ChainStats = {
   disk_sequential_write_score: Maybe<Ranking<Range>>;
   disk_random_write_score: Maybe<Ranking<Range>>;
   cpu_vendor: Maybe<Ranking<string>>;
+  implementation: Maybe<Ranking<string>>;
+  validator: Maybe<Ranking<boolean>>;
+  country: Maybe<Ranking<string>>;
+  startup_age: Maybe<Ranking<Range>>;
 };
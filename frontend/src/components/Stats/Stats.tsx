@@ -17,7 +17,7 @@
 import * as React from 'react';
 import { Maybe } from '../../common';
 import { State as AppState } from '../../state';
-import { Ranking, Range } from '../../common/types';
+import { AuthoritiesSummary, Ranking, Range } from '../../common/types';
 
 import './Stats.css';
 
@@ -95,6 +95,101 @@ function generateRankingTable<T>(
   );
 }
 
+function generateAuthoritiesTables(authorities: AuthoritiesSummary) {
+  const [setId, setSize, reporting, stale, stopped, missing, versions] =
+    authorities;
+
+  const label =
+    setSize === null
+      ? 'Authorities'
+      : setId === null
+      ? `Authorities (${setSize} in set)`
+      : `Authorities (${setSize} in set #${setId})`;
+
+  const entries: React.ReactNode[] = [
+    <tr key="reporting">
+      <td className="Stats-percent">{reporting}</td>
+      <td className="Stats-count">Reporting</td>
+      <td className="Stats-value" />
+    </tr>,
+  ];
+  stale.forEach((address) => {
+    entries.push(
+      <tr key={'stale-' + address}>
+        <td className="Stats-percent" />
+        <td className="Stats-count">Stale</td>
+        <td className="Stats-value">{address}</td>
+      </tr>
+    );
+  });
+  stopped.forEach(([address, lastSeen]) => {
+    entries.push(
+      <tr key={'stopped-' + address}>
+        <td className="Stats-percent" />
+        <td className="Stats-count">Stopped</td>
+        <td className="Stats-value">
+          {address}{' '}
+          <span className="Stats-unknown">
+            (last seen {new Date(lastSeen).toLocaleString()})
+          </span>
+        </td>
+      </tr>
+    );
+  });
+  missing.forEach((address) => {
+    entries.push(
+      <tr key={'missing-' + address}>
+        <td className="Stats-percent" />
+        <td className="Stats-count">No node</td>
+        <td className="Stats-value">{address}</td>
+      </tr>
+    );
+  });
+
+  const versionEntries = versions.map(([version, validators, fullNodes]) => (
+    <tr key={version}>
+      <td className="Stats-percent">{validators}</td>
+      <td className="Stats-count">{fullNodes}</td>
+      <td className="Stats-value">{version}</td>
+    </tr>
+  ));
+
+  const tables: React.ReactNode[] = [];
+  if (setSize !== null || entries.length > 1 || reporting > 0) {
+    tables.push(
+      <div className="Stats-category" key="authorities">
+        <table>
+          <thead>
+            <tr>
+              <th className="Stats-percent" />
+              <th className="Stats-count" />
+              <th className="Stats-value">{label}</th>
+            </tr>
+          </thead>
+          <tbody>{entries}</tbody>
+        </table>
+      </div>
+    );
+  }
+  if (versionEntries.length > 0) {
+    tables.push(
+      <div className="Stats-category" key="validator_versions">
+        <table>
+          <thead>
+            <tr>
+              <th className="Stats-percent">Validators</th>
+              <th className="Stats-count">Full Nodes</th>
+              <th className="Stats-value">Version</th>
+            </tr>
+          </thead>
+          <tbody>{versionEntries}</tbody>
+        </table>
+      </div>
+    );
+  }
+  return tables;
+}
+
 function identity(value: string | number): string {
   return value + '';
 }
@@ -132,6 +227,25 @@ function formatScore(value: Range): string {
   return (min / 100).toFixed(1) + 'x';
 }
 
+function formatHours(hours: number): string {
+  if (hours % 24 === 0 && hours > 0) {
+    const days = hours / 24;
+    return days + (days === 1 ? ' day' : ' days');
+  }
+  return hours + (hours === 1 ? ' hour' : ' hours');
+}
+
+function formatAge(value: Range): string {
+  const [min, max] = value;
+  if (max === null) {
+    return 'More than ' + formatHours(min);
+  }
+  if (min === 0) {
+    return 'Less than ' + formatHours(max);
+  }
+  return formatHours(min) + ' to ' + formatHours(max);
+}
+
 export class Stats extends React.Component<StatsProps> {
   public render() {
     const { appState } = this.props;
@@ -151,9 +265,17 @@ export class Stats extends React.Component<StatsProps> {
       }
     }
 
+    if (appState.authorities) {
+      children.push(...generateAuthoritiesTables(appState.authorities));
+    }
+
     const stats = appState.chainStats;
     if (stats) {
       add('version', 'Version', identity, stats.version);
+      add('implementation', 'Implementation', identity, stats.implementation);
+      add('validator', 'Is Validator?', formatYesNo, stats.validator);
+      add('country', 'Country', identity, stats.country);
+      add('startup_age', 'Running For', formatAge, stats.startup_age);
       add('target_os', 'Operating System', identity, stats.target_os);
       add('target_arch', 'CPU Architecture', identity, stats.target_arch);
       add('cpu', 'CPU', identity, stats.cpu);
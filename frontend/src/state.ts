@@ -84,6 +84,10 @@ export class Node {
   public finalized = 0 as Types.BlockNumber;
   public finalizedHash = '' as Types.BlockHash;
 
+  // The sequence numbers of the last hardware and IO deltas applied, if any:
+  private hardwareSeq: Maybe<number> = null;
+  private ioSeq: Maybe<number> = null;
+
   public lat: Maybe<Types.Latitude>;
   public lon: Maybe<Types.Longitude>;
   public city: Maybe<Types.City>;
@@ -100,7 +104,9 @@ export class Node {
     nodeHardware: Types.NodeHardware,
     blockDetails: Types.BlockDetails,
     location: Maybe<Types.NodeLocation>,
-    startupTime: Maybe<Types.Timestamp>
+    startupTime: Maybe<Types.Timestamp>,
+    hardwareSeq: number,
+    ioSeq: number
   ) {
     const [name, implementation, version, validator, networkId] = nodeDetails;
 
@@ -125,6 +131,8 @@ export class Node {
     this.updateIO(nodeIO);
     this.updateHardware(nodeHardware);
     this.updateBlock(blockDetails);
+    this.hardwareSeq = hardwareSeq;
+    this.ioSeq = ioSeq;
 
     if (location) {
       this.updateLocation(location);
@@ -158,6 +166,60 @@ export class Node {
     this.trigger();
   }
 
+  // Returns false if some updates were missed, in which case the series is
+  // dropped until the whole of it is sent again.
+  public applyIODelta(
+    firstSeq: number,
+    seq: number,
+    stateCacheSize: Types.SeriesDelta<Types.Bytes>
+  ): boolean {
+    const inSequence =
+      isWholeSeries(stateCacheSize) ||
+      this.checkSeq('IO', this.ioSeq, firstSeq);
+
+    if (inSequence) {
+      this.ioSeq = seq;
+      this.stateCacheSize = applyDelta(this.stateCacheSize, stateCacheSize);
+    } else {
+      this.ioSeq = null;
+      this.stateCacheSize = [];
+    }
+
+    this.trigger();
+
+    return inSequence;
+  }
+
+  public applyHardwareDelta(
+    firstSeq: number,
+    seq: number,
+    upload: Types.SeriesDelta<Types.BytesPerSecond>,
+    download: Types.SeriesDelta<Types.BytesPerSecond>,
+    chartstamps: Types.SeriesDelta<Types.Timestamp>
+  ): boolean {
+    const inSequence =
+      (isWholeSeries(upload) &&
+        isWholeSeries(download) &&
+        isWholeSeries(chartstamps)) ||
+      this.checkSeq('hardware', this.hardwareSeq, firstSeq);
+
+    if (inSequence) {
+      this.hardwareSeq = seq;
+      this.upload = applyDelta(this.upload, upload);
+      this.download = applyDelta(this.download, download);
+      this.chartstamps = applyDelta(this.chartstamps, chartstamps);
+    } else {
+      this.hardwareSeq = null;
+      this.upload = [];
+      this.download = [];
+      this.chartstamps = [];
+    }
+
+    this.trigger();
+
+    return inSequence;
+  }
+
   public updateBlock(block: Types.BlockDetails) {
     const [height, hash, blockTime, blockTimestamp, propagationTime] = block;
 
@@ -214,6 +276,39 @@ export class Node {
   private trigger() {
     this._changeRef += 1;
   }
+
+  private checkSeq(
+    series: string,
+    last: Maybe<number>,
+    seq: number
+  ): boolean {
+    const expected = last == null ? seq : last + 1;
+    if (seq !== expected) {
+      console.warn(
+        `Missed ${series} updates for node ${this.id} (expected ${expected}, got ${seq}); asking for the whole series`
+      );
+      return false;
+    }
+    return true;
+  }
+}
+
+// The backend keeps at most this many samples in each series
+const MAX_SERIES_LEN = 20;
+
+// A delta which drops every sample carries the whole series, and so doesn't
+// depend on having seen the updates before it:
+function isWholeSeries<T>([drop]: Types.SeriesDelta<T>): boolean {
+  return drop >= MAX_SERIES_LEN;
+}
+
+function applyDelta<T>(
+  series: T[],
+  [drop, append]: Types.SeriesDelta<T>
+): T[] {
+  // After missing some updates the deltas may not line up exactly, so make sure
+  // that the series doesn't grow beyond what it could be:
+  return series.slice(drop).concat(append).slice(-MAX_SERIES_LEN);
 }
 
 export function bindState(bind: React.Component, state: State): Update {
@@ -273,6 +368,7 @@ export interface State {
   sortBy: Readonly<Maybe<number>>;
   selectedColumns: Column[];
   chainStats: Maybe<Types.ChainStats>;
+  authorities: Maybe<Types.AuthoritiesSummary>;
 }
 
 export type Update = <K extends keyof State>(